@@ -1,30 +1,71 @@
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{SampleRate, StreamConfig};
-
 use std::collections::VecDeque;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
+use crate::audio::loudness::LoudnessMeter;
+use crate::audio::output::{AudioOutput, OutputConfig, SampleSource};
+use crate::audio::resampler::Resampler;
 use crate::demux::demultiplexer::RawAudioMessage;
-use crate::helpers::types::{BytesWithTimestamp, Res};
+use crate::helpers::clock::PlaybackClock;
+use crate::helpers::device::DeviceControl;
+use crate::helpers::error::PlaybackError;
+use crate::helpers::pause::PauseControl;
+use crate::helpers::seek::SeekControl;
+use crate::helpers::types::{AudioFormat, Res, SampleFormat};
+use crate::helpers::volume::VolumeControl;
+
+/* How far ahead of the output the buffer is filled, in milliseconds */
+const MAX_BUFFERED_MS: usize = 200;
+
+/* Output that is playing, with the sample rate and channel count of the decoded audio it was opened for */
+struct OpenedOutput {
+    config: OutputConfig,
+    source_format: (u32, u16),
+}
 
 pub struct AudioAdapter {
     producer_rx: Receiver<RawAudioMessage>,
+    output: Box<dyn AudioOutput>,
     audio_buffer: Arc<Mutex<VecDeque<f32>>>,
     cancel_flag: Option<Arc<AtomicBool>>,
+    seek_control: Option<Arc<SeekControl>>,
+    pause_control: Option<Arc<PauseControl>>,
+    playback_clock: Option<Arc<PlaybackClock>>,
+    volume_control: Option<Arc<VolumeControl>>,
+    device_control: Option<Arc<DeviceControl>>,
+    /// Opened with the format of the first samples, so that the device plays them as close as it can
+    opened: Option<OpenedOutput>,
+    resampler: Option<Resampler>,
+    generation: usize,
+    stream_error: Arc<Mutex<Option<String>>>,
+    /// Measures what the output plays when normalizing its loudness
+    loudness_meter: Option<LoudnessMeter>,
+    normalization_gain: f32,
 }
 
 impl AudioAdapter {
-    pub fn new(producer_rx: Receiver<RawAudioMessage>) -> Res<Self> {
+    pub fn new(producer_rx: Receiver<RawAudioMessage>, output: Box<dyn AudioOutput>) -> Res<Self> {
         let audio_buffer = Arc::new(Mutex::new(VecDeque::new()));
 
         Ok(AudioAdapter {
             producer_rx,
+            output,
             audio_buffer,
             cancel_flag: None,
+            seek_control: None,
+            pause_control: None,
+            playback_clock: None,
+            volume_control: None,
+            device_control: None,
+            opened: None,
+            resampler: None,
+            generation: 0,
+            stream_error: Arc::new(Mutex::new(None)),
+            loudness_meter: None,
+            normalization_gain: 1.0,
         })
     }
 
@@ -32,101 +73,391 @@ impl AudioAdapter {
         self.cancel_flag = Some(flag);
     }
 
-    fn process_element(&self, sample: BytesWithTimestamp) -> Res<()> {
-        let interleaved_data = self.planar_to_interleaved(&sample.data);
-        let float_samples: Vec<f32> = interleaved_data
-            .chunks_exact(4)
-            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
-            .collect();
-        
+    pub fn set_seek_control(&mut self, seek_control: Arc<SeekControl>) {
+        self.seek_control = Some(seek_control);
+    }
+
+    pub fn set_pause_control(&mut self, pause_control: Arc<PauseControl>) {
+        self.pause_control = Some(pause_control);
+    }
+
+    pub fn set_playback_clock(&mut self, playback_clock: Arc<PlaybackClock>) {
+        self.playback_clock = Some(playback_clock);
+    }
+
+    pub fn set_volume_control(&mut self, volume_control: Arc<VolumeControl>) {
+        self.volume_control = Some(volume_control);
+    }
+
+    pub fn set_device_control(&mut self, device_control: Arc<DeviceControl>) {
+        self.device_control = Some(device_control);
+    }
+
+    /* Lets the video run on its own timer once there is no more audio to follow */
+    fn stop_clock(&self) {
+        if let Some(ref playback_clock) = self.playback_clock {
+            playback_clock.stop();
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel_flag
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::SeqCst))
+    }
+
+    /* Whether the terminal adapter has shown the last frame */
+    fn is_finished(&self) -> bool {
+        self.seek_control
+            .as_ref()
+            .is_none_or(|seek_control| seek_control.is_finished())
+    }
+
+    /* Whether samples received now were decoded before the latest seek */
+    fn is_flushing(&self) -> bool {
+        self.seek_control
+            .as_ref()
+            .is_some_and(|seek_control| seek_control.is_stale(self.generation))
+    }
+
+    fn process_element(&self, timestamp_in_ms: usize, samples: Vec<f32>, config: OutputConfig) {
         let mut buffer = self.audio_buffer.lock().unwrap();
-        buffer.extend(float_samples);
-        Ok(())
+
+        // Under the buffer lock, so that the output can't take samples before the clock counts them
+        if let Some(ref playback_clock) = self.playback_clock {
+            if !playback_clock.is_running() {
+                playback_clock.start(timestamp_in_ms, config.sample_rate);
+            }
+        }
+
+        buffer.extend(samples);
     }
 
-    pub fn run(&mut self) -> Res<()> {
-        let host = cpal::default_host();
-        let device = host.default_output_device().ok_or("No output device available")?;
-        
-        let config = StreamConfig {
-            channels: 2,
-            sample_rate: SampleRate(44100),
-            buffer_size: cpal::BufferSize::Default,
+    /* Measures the loudness again for a new output, whose rate and channels the filters depend on */
+    fn start_loudness_meter(&mut self, config: OutputConfig) {
+        let normalizing = self
+            .volume_control
+            .as_ref()
+            .is_some_and(|volume_control| volume_control.is_normalizing());
+
+        self.loudness_meter =
+            normalizing.then(|| LoudnessMeter::new(config.sample_rate, config.channels));
+        self.normalization_gain = 1.0;
+    }
+
+    /*
+     * Brings the samples to the target loudness, measured on everything played so far.
+     * The gain moves across the samples, so that its updates don't click.
+     */
+    fn normalize(&mut self, mut samples: Vec<f32>, channels: u16) -> Vec<f32> {
+        let Some(ref mut loudness_meter) = self.loudness_meter else {
+            return samples;
+        };
+
+        loudness_meter.measure(&samples);
+        let target_gain = loudness_meter.normalization_gain();
+        let frame_count = samples.len() / channels.max(1) as usize;
+
+        for (index, frame) in samples.chunks_mut(channels.max(1) as usize).enumerate() {
+            let progress = (index + 1) as f32 / frame_count.max(1) as f32;
+            let gain = self.normalization_gain + (target_gain - self.normalization_gain) * progress;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+
+        self.normalization_gain = target_gain;
+        samples
+    }
+
+    /* Fails with the error the output reported from its own thread, if any */
+    fn check_stream_error(&self) -> Res<()> {
+        match self.stream_error.lock().unwrap().take() {
+            Some(error) => {
+                self.stop_clock();
+                Err(PlaybackError::AudioDevice(error))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /*
+     * Waits until the output has room for more samples,
+     * returns false if playback was cancelled or seeked in the meantime.
+     * An output that failed never takes them, so its error ends the wait.
+     * The output also takes nothing while paused, so device switches are applied from here too.
+     */
+    fn wait_for_buffer_space(&mut self, max_samples: usize) -> Res<bool> {
+        while self.audio_buffer.lock().unwrap().len() > max_samples {
+            if self.is_cancelled() || self.is_flushing() {
+                return Ok(false);
+            }
+
+            self.check_stream_error()?;
+            self.apply_device_request()?;
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Ok(true)
+    }
+
+    /* What the output pulls its samples from */
+    fn sample_source(&self) -> SampleSource {
+        SampleSource {
+            buffer: Arc::clone(&self.audio_buffer),
+            pause_control: self.pause_control.clone(),
+            playback_clock: self.playback_clock.clone(),
+            volume_control: self.volume_control.clone(),
+            error: Arc::clone(&self.stream_error),
+        }
+    }
+
+    /* Switches to the device asked for last, if any */
+    fn apply_device_request(&mut self) -> Res<()> {
+        let device_request = self
+            .device_control
+            .as_ref()
+            .and_then(|device_control| device_control.take_request());
+
+        match device_request {
+            Some(name) => self.switch_device(name),
+            None => Ok(()),
+        }
+    }
+
+    /*
+     * Moves playback to another device, keeping the buffered samples and the clock position.
+     * If the device can't be opened, playback goes on with the previous one.
+     */
+    fn switch_device(&mut self, name: String) -> Res<()> {
+        let previous_name = match self.output.set_device(Some(name)) {
+            Ok(previous_name) => previous_name,
+            Err(error) => {
+                self.report_device_error(error.to_string());
+                return Ok(());
+            }
+        };
+
+        // Nothing plays yet, the device is opened with the first samples
+        let Some(current_config) = self.opened.as_ref().map(|opened| opened.config) else {
+            return Ok(());
+        };
+
+        // The buffered samples are already in the format of the previous device, which is asked for first
+        let buffered_format = AudioFormat {
+            sample_rate: current_config.sample_rate,
+            channels: current_config.channels,
+            sample_format: SampleFormat::F32,
+            planar: false,
+        };
+
+        self.output.close();
+        let config = match self.output.open(buffered_format, self.sample_source()) {
+            Ok(config) => config,
+            Err(error) => {
+                self.report_device_error(error.to_string());
+                self.output.set_device(previous_name)?;
+                self.output.open(buffered_format, self.sample_source())?
+            }
         };
 
-        let buffer_clone = Arc::clone(&self.audio_buffer);
-        let stream = device.build_output_stream(
-            &config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                let mut buffer = buffer_clone.lock().unwrap();
-                for sample in data.iter_mut() {
-                    *sample = buffer.pop_front().unwrap_or(0.0);
+        if config != current_config {
+            self.convert_buffer(buffered_format, config)?;
+            self.resampler = None;
+            self.start_loudness_meter(config);
+
+            // Carries on from the position being played, counted in frames of the new device
+            if let Some(ref playback_clock) = self.playback_clock {
+                if let Some(position_ms) = playback_clock.position_ms() {
+                    playback_clock.start(position_ms, config.sample_rate);
                 }
-            },
-            |err| eprintln!("Audio stream error: {}", err),
-            None,
-        )?;
-        
-        stream.play()?;
-        
-        let mut start_time = Instant::now();
-        let mut started_playing = false;
+            }
+
+            if let Some(ref mut opened) = self.opened {
+                opened.config = config;
+            }
+        }
+
+        Ok(())
+    }
+
+    /* Converts the buffered samples for a device that plays at another rate or channel count */
+    fn convert_buffer(&self, buffered_format: AudioFormat, config: OutputConfig) -> Res<()> {
+        let mut buffer_resampler =
+            Resampler::new(buffered_format, config.sample_rate, config.channels)?;
+
+        let mut buffer = self.audio_buffer.lock().unwrap();
+        let bytes: Vec<u8> = buffer
+            .iter()
+            .flat_map(|sample| sample.to_ne_bytes())
+            .collect();
+        *buffer = buffer_resampler.convert(&bytes)?.into();
+
+        Ok(())
+    }
+
+    fn report_device_error(&self, error: String) {
+        if let Some(ref device_control) = self.device_control {
+            device_control.report_error(error);
+        }
+    }
+
+    /*
+     * Plays the samples as the output takes them. Their end comes in well before it is heard,
+     * so this keeps going until the video is over, in case a seek restarts the demultiplexer.
+     */
+    pub fn run(&mut self) -> Res<()> {
+        let mut ended = false;
 
         loop {
-            if let Some(ref flag) = self.cancel_flag {
-                if flag.load(Ordering::SeqCst) {
-                    return Ok(());
-                }
+            if self.is_cancelled() || (ended && self.is_finished()) {
+                return Ok(());
             }
 
+            self.check_stream_error()?;
+            self.apply_device_request()?;
+
             match self.producer_rx.recv_timeout(Duration::from_millis(16)) {
                 Ok(message) => match message {
-                    RawAudioMessage::AudioMessage(sample) => {
-                        if !started_playing {
-                            started_playing = true;
-                            start_time = Instant::now();
+                    RawAudioMessage::AudioMessage { samples, format } => {
+                        if self.is_flushing() {
+                            continue;
+                        }
+
+                        let source_changed = self.opened.as_ref().is_none_or(|opened| {
+                            opened.source_format != (format.sample_rate, format.channels)
+                        });
+
+                        if source_changed {
+                            // Play what was buffered in the previous format first
+                            if self.opened.is_some() && !self.wait_for_buffer_space(0)? {
+                                continue;
+                            }
+
+                            self.output.close();
+                            self.resampler = None;
+                            self.stop_clock();
+
+                            let config = self.output.open(format, self.sample_source())?;
+                            self.start_loudness_meter(config);
+                            self.opened = Some(OpenedOutput {
+                                config,
+                                source_format: (format.sample_rate, format.channels),
+                            });
                         }
 
-                        if sample.timestamp_in_ms > start_time.elapsed().as_millis() as usize {
-                            thread::sleep(Duration::from_millis(
-                                (sample.timestamp_in_ms - start_time.elapsed().as_millis() as usize)
-                                    as u64,
-                            ));
+                        let Some(config) = self.opened.as_ref().map(|opened| opened.config) else {
+                            continue;
+                        };
+
+                        // The output sets the pace, and stops taking samples while paused
+                        let max_buffered_samples = config.sample_rate as usize
+                            * config.channels as usize
+                            * MAX_BUFFERED_MS
+                            / 1000;
+                        if !self.wait_for_buffer_space(max_buffered_samples)? {
+                            continue;
                         }
 
-                        self.process_element(sample).unwrap();
+                        // A device switch while waiting may have changed the output config
+                        let Some(config) = self.opened.as_ref().map(|opened| opened.config) else {
+                            continue;
+                        };
+
+                        if self
+                            .resampler
+                            .as_ref()
+                            .is_none_or(|resampler| resampler.input_format() != format)
+                        {
+                            self.resampler =
+                                Some(Resampler::new(format, config.sample_rate, config.channels)?);
+                        }
+
+                        let Some(ref mut current_resampler) = self.resampler else {
+                            continue;
+                        };
+
+                        let converted = current_resampler.convert(&samples.data)?;
+                        let converted = self.normalize(converted, config.channels);
+                        self.process_element(samples.timestamp_in_ms, converted, config);
+                    }
+                    RawAudioMessage::Flush(generation) => {
+                        self.generation = generation;
+
+                        // Drop what the output stream hasn't played yet, and what the resampler holds back
+                        let mut buffer = self.audio_buffer.lock().unwrap();
+                        buffer.clear();
+                        self.resampler = None;
+                        if let Some(ref playback_clock) = self.playback_clock {
+                            playback_clock.stop();
+                        }
                     }
                     RawAudioMessage::Done => {
-                        return Ok(());
+                        if self.is_flushing() {
+                            continue;
+                        }
+
+                        // Play what is left before handing the clock back to the video
+                        if !self.wait_for_buffer_space(0)? {
+                            continue;
+                        }
+
+                        self.stop_clock();
+                        ended = true;
                     }
                 },
                 Err(RecvTimeoutError::Timeout) => continue,
-                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.stop_clock();
+                    return Ok(());
+                }
             }
         }
     }
+}
 
-    fn planar_to_interleaved(&self, planar_data: &[u8]) -> Vec<u8> {
-        let samples_per_channel = planar_data.len() / (4 * 2);
-        let mut left_channel = Vec::with_capacity(samples_per_channel);
-        let mut right_channel = Vec::with_capacity(samples_per_channel);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::output::NullOutput;
+    use std::sync::mpsc;
 
-        for chunk in planar_data[..planar_data.len() / 2].chunks_exact(4) {
-            let sample = f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
-            left_channel.push(sample);
-        }
+    #[test]
+    fn test_output_error_ends_the_wait_for_buffer_space() {
+        let mut adapter = AudioAdapter::new(mpsc::channel().1, Box::new(NullOutput::default())).unwrap();
 
-        for chunk in planar_data[planar_data.len() / 2..].chunks_exact(4) {
-            let sample = f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
-            right_channel.push(sample);
-        }
+        // Nothing takes the samples, as when the stream has failed
+        adapter.audio_buffer.lock().unwrap().extend([0.0; 100]);
+        *adapter.stream_error.lock().unwrap() = Some("Device unplugged".to_string());
 
-        let mut interleaved = Vec::with_capacity(planar_data.len());
-        for (left, right) in left_channel.iter().zip(right_channel.iter()) {
-            interleaved.extend_from_slice(&left.to_le_bytes());
-            interleaved.extend_from_slice(&right.to_le_bytes());
+        match adapter.wait_for_buffer_space(0) {
+            Err(PlaybackError::AudioDevice(error)) => assert_eq!(error, "Device unplugged"),
+            _ => panic!("Expected the error of the output"),
         }
+    }
+
+    #[test]
+    fn test_device_switch_while_waiting_for_buffer_space() {
+        let mut adapter = AudioAdapter::new(mpsc::channel().1, Box::new(NullOutput::default())).unwrap();
+        let device_control = Arc::new(DeviceControl::new());
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        adapter.set_device_control(device_control.clone());
+        adapter.set_cancel_flag(cancel_flag.clone());
+
+        // Nothing takes the samples, as while paused
+        adapter.audio_buffer.lock().unwrap().extend([0.0; 100]);
+        device_control.switch_to("Headphones".to_string());
+
+        let cancel = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel_flag.store(true, Ordering::SeqCst);
+        });
+
+        assert!(!adapter.wait_for_buffer_space(0).unwrap());
+        cancel.join().unwrap();
 
-        interleaved
+        // The null output has no devices, so the switch was tried and reported as failed
+        assert_eq!(device_control.take_request(), None);
+        assert!(device_control.take_error().is_some());
     }
 }
@@ -0,0 +1,255 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{
+    FromSample, SampleFormat as CpalSampleFormat, SampleRate, SizedSample, StreamConfig,
+    SupportedStreamConfig, SupportedStreamConfigRange,
+};
+
+use crate::audio::output::{AudioOutput, OutputConfig, SampleSource};
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::{AudioFormat, Res};
+
+/// Plays on an output device, the default one unless named, in the config
+/// it supports that is closest to the decoded audio.
+pub struct CpalOutput {
+    device_name: Option<String>,
+    stream: Option<cpal::Stream>,
+}
+
+impl CpalOutput {
+    pub fn new(device_name: Option<String>) -> Self {
+        CpalOutput {
+            device_name,
+            stream: None,
+        }
+    }
+}
+
+/// Names of the output devices of the default host, with the name of the default one
+pub fn output_device_names() -> Res<(Vec<String>, Option<String>)> {
+    let host = cpal::default_host();
+    let devices = host.output_devices().map_err(|e| {
+        PlaybackError::AudioDevice(format!("Could not list the output devices: {}", e))
+    })?;
+
+    let names = devices.filter_map(|device| device.name().ok()).collect();
+    let default_name = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+
+    Ok((names, default_name))
+}
+
+fn find_device(name: Option<&str>) -> Res<cpal::Device> {
+    let host = cpal::default_host();
+
+    let Some(name) = name else {
+        return host
+            .default_output_device()
+            .ok_or_else(|| PlaybackError::AudioDevice("No output device available".to_string()));
+    };
+
+    host.output_devices()
+        .map_err(|e| {
+            PlaybackError::AudioDevice(format!("Could not list the output devices: {}", e))
+        })?
+        .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+        .ok_or_else(|| {
+            PlaybackError::AudioDevice(format!(
+                "No output device named '{}', see --list-audio-devices",
+                name
+            ))
+        })
+}
+
+impl AudioOutput for CpalOutput {
+    fn open(&mut self, format: AudioFormat, source: SampleSource) -> Res<OutputConfig> {
+        // Some hosts can't open a second stream on the same device
+        self.close();
+
+        let device = find_device(self.device_name.as_deref())?;
+
+        let supported_configs = device
+            .supported_output_configs()
+            .map_err(|e| {
+                PlaybackError::AudioDevice(format!("Could not query the output device: {}", e))
+            })?
+            .collect();
+
+        let supported_config =
+            match choose_output_config(supported_configs, format.sample_rate, format.channels) {
+                Some(supported_config) => supported_config,
+                None => device.default_output_config().map_err(|e| {
+                    PlaybackError::AudioDevice(format!("Output device has no usable config: {}", e))
+                })?,
+            };
+
+        let config = supported_config.config();
+        let stream = match supported_config.sample_format() {
+            CpalSampleFormat::I16 => build_stream::<i16>(&device, &config, source)?,
+            CpalSampleFormat::U16 => build_stream::<u16>(&device, &config, source)?,
+            CpalSampleFormat::I32 => build_stream::<i32>(&device, &config, source)?,
+            CpalSampleFormat::F32 => build_stream::<f32>(&device, &config, source)?,
+            sample_format => {
+                return Err(PlaybackError::AudioDevice(format!(
+                    "Output device only plays {:?} samples, which are not supported",
+                    sample_format
+                )))
+            }
+        };
+
+        stream.play().map_err(|e| {
+            PlaybackError::AudioDevice(format!("Could not start the output stream: {}", e))
+        })?;
+        self.stream = Some(stream);
+
+        Ok(OutputConfig {
+            sample_rate: config.sample_rate.0,
+            channels: config.channels,
+        })
+    }
+
+    fn close(&mut self) {
+        self.stream.take();
+    }
+
+    fn set_device(&mut self, name: Option<String>) -> Res<Option<String>> {
+        Ok(std::mem::replace(&mut self.device_name, name))
+    }
+}
+
+/*
+ * Builds an output stream that plays the samples of the source,
+ * converted to the sample type of the device
+ */
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    source: SampleSource,
+) -> Res<cpal::Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let error_source = source.clone();
+    let channels = config.channels;
+    let mut samples = Vec::new();
+
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                samples.resize(data.len(), 0.0);
+                source.fill(&mut samples, channels);
+
+                for (sample, value) in data.iter_mut().zip(&samples) {
+                    *sample = T::from_sample(*value);
+                }
+            },
+            move |err| error_source.report_error(err.to_string()),
+            None,
+        )
+        .map_err(|e| PlaybackError::AudioDevice(format!("Could not open the output stream: {}", e)))
+}
+
+/*
+ * Rank of the sample types the output stream can be built with, f32 first
+ */
+fn sample_format_rank(sample_format: CpalSampleFormat) -> Option<u8> {
+    match sample_format {
+        CpalSampleFormat::F32 => Some(0),
+        CpalSampleFormat::I32 => Some(1),
+        CpalSampleFormat::I16 => Some(2),
+        CpalSampleFormat::U16 => Some(3),
+        _ => None,
+    }
+}
+
+/*
+ * Picks the supported config closest to the decoded audio: the same channel count,
+ * or else the closest one above it, then the same sample rate or the closest one in range
+ */
+fn choose_output_config(
+    supported_configs: Vec<SupportedStreamConfigRange>,
+    sample_rate: u32,
+    channels: u16,
+) -> Option<SupportedStreamConfig> {
+    supported_configs
+        .into_iter()
+        .filter_map(|range| {
+            let format_rank = sample_format_rank(range.sample_format())?;
+
+            // Remixing to more channels loses nothing, to fewer is a last resort
+            let channel_distance = if range.channels() >= channels {
+                (range.channels() - channels) as u32
+            } else {
+                u16::MAX as u32 + (channels - range.channels()) as u32
+            };
+
+            let rate = sample_rate.clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+            let rate_distance = rate.abs_diff(sample_rate);
+
+            Some((
+                (channel_distance, rate_distance, format_rank),
+                range.with_sample_rate(SampleRate(rate)),
+            ))
+        })
+        .min_by_key(|(key, _)| *key)
+        .map(|(_, config)| config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SupportedBufferSize;
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        format: CpalSampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    #[test]
+    fn test_choose_output_config() {
+        // A 48 kHz-only device
+        let config = choose_output_config(
+            vec![range(2, 48000, 48000, CpalSampleFormat::F32)],
+            44100,
+            2,
+        )
+        .unwrap();
+        assert_eq!(
+            (config.channels(), config.sample_rate()),
+            (2, SampleRate(48000))
+        );
+
+        // Mono audio goes to the smallest layout above it, 5.1 audio is mixed down
+        let supported = vec![
+            range(1, 8000, 96000, CpalSampleFormat::U8),
+            range(2, 8000, 96000, CpalSampleFormat::I16),
+            range(2, 8000, 96000, CpalSampleFormat::F32),
+            range(8, 8000, 96000, CpalSampleFormat::F32),
+        ];
+        let config = choose_output_config(supported.clone(), 44100, 1).unwrap();
+        assert_eq!(
+            (config.channels(), config.sample_rate()),
+            (2, SampleRate(44100))
+        );
+        assert_eq!(config.sample_format(), CpalSampleFormat::F32);
+
+        let config = choose_output_config(supported[..3].to_vec(), 48000, 6).unwrap();
+        assert_eq!(config.channels(), 2);
+
+        assert!(
+            choose_output_config(vec![range(2, 8000, 96000, CpalSampleFormat::U8)], 44100, 2)
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::audio::output::{AudioOutput, OutputConfig, PacedReader, SampleSource};
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::{AudioFormat, Res};
+
+/* Format of the raw PCM output, which the reading end has no header to learn from */
+const PCM_SAMPLE_RATE: u32 = 48000;
+const PCM_CHANNELS: u16 = 2;
+
+const WAV_HEADER_SIZE: usize = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+struct WavFile {
+    writer: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavFile {
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        write_pcm(&mut self.writer, samples)?;
+        self.data_size = self.data_size.saturating_add(samples.len() as u32 * 2);
+        Ok(())
+    }
+
+    /* Writes the sizes the header was created without, so that the file is valid so far */
+    fn finish(&mut self, config: OutputConfig) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&wav_header(
+            config.sample_rate,
+            config.channels,
+            self.data_size,
+        ))?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+/// Writes what would be played to a 16-bit WAV file, in real time.
+///
+/// The file keeps the format of the first decoded samples, later ones are
+/// converted to it.
+pub struct WavOutput {
+    path: PathBuf,
+    file: Option<Arc<Mutex<WavFile>>>,
+    config: Option<OutputConfig>,
+    reader: Option<PacedReader>,
+}
+
+impl WavOutput {
+    pub fn new(path: PathBuf) -> Self {
+        WavOutput {
+            path,
+            file: None,
+            config: None,
+            reader: None,
+        }
+    }
+
+    fn create_file(&self, config: OutputConfig) -> Res<WavFile> {
+        let file = File::create(&self.path).map_err(|e| {
+            PlaybackError::AudioDevice(format!("Could not create {}: {}", self.path.display(), e))
+        })?;
+
+        let mut writer = BufWriter::new(file);
+        writer
+            .write_all(&wav_header(config.sample_rate, config.channels, 0))
+            .map_err(|e| {
+                PlaybackError::AudioDevice(format!(
+                    "Could not write {}: {}",
+                    self.path.display(),
+                    e
+                ))
+            })?;
+
+        Ok(WavFile {
+            writer,
+            data_size: 0,
+        })
+    }
+}
+
+impl AudioOutput for WavOutput {
+    fn open(&mut self, format: AudioFormat, source: SampleSource) -> Res<OutputConfig> {
+        self.close();
+
+        let config = *self.config.get_or_insert(OutputConfig {
+            sample_rate: format.sample_rate,
+            channels: format.channels,
+        });
+
+        let file = match self.file {
+            Some(ref file) => file.clone(),
+            None => Arc::new(Mutex::new(self.create_file(config)?)),
+        };
+        self.file = Some(file.clone());
+
+        self.reader = Some(PacedReader::start(source, config, move |samples| {
+            file.lock().unwrap().write_samples(samples)
+        }));
+
+        Ok(config)
+    }
+
+    fn close(&mut self) {
+        self.reader.take();
+
+        if let (Some(file), Some(config)) = (&self.file, self.config) {
+            // The header is only needed by whoever reads the file, an error here is not worth stopping for
+            let _ = file.lock().unwrap().finish(config);
+        }
+    }
+}
+
+impl Drop for WavOutput {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Writes what would be played as raw 16-bit little-endian PCM, at 48 kHz
+/// in stereo, to stdout or to a file or named pipe, in real time.
+pub struct PcmOutput {
+    path: Option<PathBuf>,
+    writer: Option<Arc<Mutex<Box<dyn Write + Send>>>>,
+    reader: Option<PacedReader>,
+}
+
+impl PcmOutput {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        PcmOutput {
+            path,
+            writer: None,
+            reader: None,
+        }
+    }
+
+    fn open_writer(&self) -> Res<Box<dyn Write + Send>> {
+        match self.path {
+            // Opened for writing only, so that named pipes work too
+            Some(ref path) => OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .map(|file| Box::new(BufWriter::new(file)) as Box<dyn Write + Send>)
+                .map_err(|e| {
+                    PlaybackError::AudioDevice(format!("Could not open {}: {}", path.display(), e))
+                }),
+            None => Ok(Box::new(io::stdout())),
+        }
+    }
+}
+
+impl AudioOutput for PcmOutput {
+    fn open(&mut self, _format: AudioFormat, source: SampleSource) -> Res<OutputConfig> {
+        self.close();
+
+        let config = OutputConfig {
+            sample_rate: PCM_SAMPLE_RATE,
+            channels: PCM_CHANNELS,
+        };
+
+        let writer = match self.writer {
+            Some(ref writer) => writer.clone(),
+            None => Arc::new(Mutex::new(self.open_writer()?)),
+        };
+        self.writer = Some(writer.clone());
+
+        self.reader = Some(PacedReader::start(source, config, move |samples| {
+            let mut writer = writer.lock().unwrap();
+            write_pcm(&mut *writer, samples)?;
+            writer.flush()
+        }));
+
+        Ok(config)
+    }
+
+    fn close(&mut self) {
+        self.reader.take();
+    }
+}
+
+fn write_pcm(writer: &mut impl Write, samples: &[f32]) -> io::Result<()> {
+    let bytes: Vec<u8> = samples
+        .iter()
+        .flat_map(|sample| ((sample * i16::MAX as f32).round() as i16).to_le_bytes())
+        .collect();
+
+    writer.write_all(&bytes)
+}
+
+/*
+ * Header of a 16-bit PCM WAV file, with the size of the samples that follow it
+ */
+fn wav_header(sample_rate: u32, channels: u16, data_size: u32) -> [u8; WAV_HEADER_SIZE] {
+    let block_align = channels * BITS_PER_SAMPLE / 8;
+    let byte_rate = sample_rate * block_align as u32;
+
+    let mut header = [0; WAV_HEADER_SIZE];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&data_size.saturating_add(36).to_le_bytes());
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes());
+    header[20..22].copy_from_slice(&1u16.to_le_bytes());
+    header[22..24].copy_from_slice(&channels.to_le_bytes());
+    header[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+    header[28..32].copy_from_slice(&byte_rate.to_le_bytes());
+    header[32..34].copy_from_slice(&block_align.to_le_bytes());
+    header[34..36].copy_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::types::SampleFormat;
+    use std::collections::VecDeque;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_wav_output_writes_played_samples() {
+        let path = std::env::temp_dir().join(format!("yt-term-test-{}.wav", std::process::id()));
+        let source = SampleSource {
+            buffer: Arc::new(Mutex::new(VecDeque::from(vec![0.5; 4000]))),
+            pause_control: None,
+            playback_clock: None,
+            volume_control: None,
+            error: Arc::new(Mutex::new(None)),
+        };
+        let format = AudioFormat {
+            sample_rate: 8000,
+            channels: 1,
+            sample_format: SampleFormat::I16,
+            planar: false,
+        };
+
+        let mut output = WavOutput::new(path.clone());
+        assert_eq!(
+            output.open(format, source).unwrap(),
+            OutputConfig {
+                sample_rate: 8000,
+                channels: 1
+            }
+        );
+        thread::sleep(Duration::from_millis(100));
+        drop(output);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let data_size = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+        assert_eq!(&bytes[..WAV_HEADER_SIZE], &wav_header(8000, 1, data_size));
+        assert_eq!(bytes.len(), WAV_HEADER_SIZE + data_size as usize);
+        assert!(data_size > 0);
+        assert_eq!(&bytes[44..46], &16384i16.to_le_bytes());
+    }
+}
@@ -0,0 +1,238 @@
+use std::f64::consts::PI;
+
+/* Loudness that normalized playback aims for, as ReplayGain 2.0 does, in LUFS */
+const TARGET_LOUDNESS: f64 = -18.0;
+
+/* Most a quiet video is boosted and a loud one is attenuated by, in dB */
+const MAX_BOOST_DB: f64 = 12.0;
+const MAX_CUT_DB: f64 = 24.0;
+
+/* Gating blocks are 400 ms long and start every 100 ms */
+const STEPS_PER_BLOCK: usize = 4;
+const STEP_MS: usize = 100;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+/* Filter of one stage of the K-weighting, with its state for each channel */
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: Vec<[f64; 2]>,
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2], channels: usize) -> Self {
+        Biquad {
+            b,
+            a,
+            state: vec![[0.0; 2]; channels],
+        }
+    }
+
+    fn process(&mut self, channel: usize, input: f64) -> f64 {
+        let state = &mut self.state[channel];
+        let output = self.b[0] * input + state[0];
+        state[0] = self.b[1] * input - self.a[0] * output + state[1];
+        state[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+}
+
+/// Measures the integrated loudness of the audio as it is played, as defined by
+/// EBU R128, and gives the gain that brings it to a common level.
+pub struct LoudnessMeter {
+    shelf: Biquad,
+    high_pass: Biquad,
+    channel_weights: Vec<f64>,
+    step_frames: usize,
+    frames_in_step: usize,
+    step_energy: f64,
+    /// Weighted energy of the last steps, which the overlapping blocks are made of
+    recent_steps: Vec<f64>,
+    /// Mean square of every block above the absolute gate
+    block_powers: Vec<f64>,
+    integrated: Option<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let rate = sample_rate.max(1) as f64;
+        let channels = channels.max(1) as usize;
+
+        // High shelf modelling the acoustic effect of the head
+        let k = (PI * 1681.974450955533 / rate).tan();
+        let q = 0.7071752369554196;
+        let vh = 10f64.powf(3.999843853973347 / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            channels,
+        );
+
+        // High pass, the RLB weighting
+        let k = (PI * 38.13547087602444 / rate).tan();
+        let q = 0.5003270373238773;
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            channels,
+        );
+
+        LoudnessMeter {
+            shelf,
+            high_pass,
+            channel_weights: channel_weights(channels),
+            step_frames: (sample_rate as usize * STEP_MS / 1000).max(1),
+            frames_in_step: 0,
+            step_energy: 0.0,
+            recent_steps: Vec::with_capacity(STEPS_PER_BLOCK),
+            block_powers: Vec::new(),
+            integrated: None,
+        }
+    }
+
+    /// Integrated loudness of what was measured so far, in LUFS,
+    /// or None before the first block above the absolute gate
+    pub fn integrated_loudness(&self) -> Option<f64> {
+        self.integrated
+    }
+
+    /// Linear gain that brings the measured loudness to the target
+    pub fn normalization_gain(&self) -> f32 {
+        match self.integrated_loudness() {
+            Some(loudness) => {
+                let gain_db = (TARGET_LOUDNESS - loudness).clamp(-MAX_CUT_DB, MAX_BOOST_DB);
+                10f64.powf(gain_db / 20.0) as f32
+            }
+            None => 1.0,
+        }
+    }
+
+    /// Measures interleaved samples, in the channel count the meter was created with
+    pub fn measure(&mut self, samples: &[f32]) {
+        let channels = self.channel_weights.len();
+
+        for frame in samples.chunks_exact(channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let weighted = self
+                    .high_pass
+                    .process(channel, self.shelf.process(channel, *sample as f64));
+                self.step_energy += self.channel_weights[channel] * weighted * weighted;
+            }
+
+            self.frames_in_step += 1;
+            if self.frames_in_step == self.step_frames {
+                self.end_step();
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        if self.recent_steps.len() == STEPS_PER_BLOCK {
+            self.recent_steps.remove(0);
+        }
+        self.recent_steps.push(self.step_energy);
+        self.step_energy = 0.0;
+        self.frames_in_step = 0;
+
+        if self.recent_steps.len() < STEPS_PER_BLOCK {
+            return;
+        }
+
+        let block_power =
+            self.recent_steps.iter().sum::<f64>() / (STEPS_PER_BLOCK * self.step_frames) as f64;
+        if loudness(block_power) > ABSOLUTE_GATE {
+            self.block_powers.push(block_power);
+            self.integrated = integrated_loudness(&self.block_powers);
+        }
+    }
+}
+
+/*
+ * Weight of each channel in the default layout for its count:
+ * the LFE channel isn't measured, and the surround channels count more
+ */
+fn channel_weights(channels: usize) -> Vec<f64> {
+    let mut weights = vec![1.0; channels];
+
+    if channels == 6 {
+        weights[3] = 0.0;
+        weights[4] = 1.41;
+        weights[5] = 1.41;
+    }
+
+    weights
+}
+
+fn loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/*
+ * Mean of the blocks above the absolute gate that are also less than 10 LU
+ * below their own mean, the relative gate
+ */
+fn integrated_loudness(block_powers: &[f64]) -> Option<f64> {
+    if block_powers.is_empty() {
+        return None;
+    }
+
+    let mean = block_powers.iter().sum::<f64>() / block_powers.len() as f64;
+    let relative_gate = loudness(mean) + RELATIVE_GATE;
+
+    let gated: Vec<f64> = block_powers
+        .iter()
+        .copied()
+        .filter(|power| loudness(*power) > relative_gate)
+        .collect();
+
+    if gated.is_empty() {
+        return None;
+    }
+
+    Some(loudness(gated.iter().sum::<f64>() / gated.len() as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stereo_sine(frequency: f64, amplitude: f64, sample_rate: u32, seconds: usize) -> Vec<f32> {
+        (0..sample_rate as usize * seconds)
+            .flat_map(|index| {
+                let value =
+                    amplitude * (2.0 * PI * frequency * index as f64 / sample_rate as f64).sin();
+                [value as f32, value as f32]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_integrated_loudness_of_sine() {
+        // A full scale 1 kHz sine reads -3.01 LUFS on each of the two channels
+        let mut meter = LoudnessMeter::new(48000, 2);
+        meter.measure(&stereo_sine(997.0, 1.0, 48000, 2));
+        assert!((meter.integrated_loudness().unwrap() - 0.0).abs() < 0.1);
+
+        let mut meter = LoudnessMeter::new(44100, 2);
+        meter.measure(&stereo_sine(997.0, 0.1, 44100, 2));
+        assert!((meter.integrated_loudness().unwrap() + 20.0).abs() < 0.1);
+        assert!((meter.normalization_gain() - 10f32.powf(2.0 / 20.0)).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_silence_is_gated() {
+        let mut meter = LoudnessMeter::new(48000, 1);
+        meter.measure(&vec![0.0; 48000]);
+        assert_eq!(meter.integrated_loudness(), None);
+        assert_eq!(meter.normalization_gain(), 1.0);
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audio::cpal_output::CpalOutput;
+use crate::audio::file_output::{PcmOutput, WavOutput};
+use crate::helpers::clock::PlaybackClock;
+use crate::helpers::error::PlaybackError;
+use crate::helpers::pause::PauseControl;
+use crate::helpers::types::{AudioFormat, Res};
+use crate::helpers::volume::VolumeControl;
+
+/* How often the outputs without a device take samples, in milliseconds */
+const PACE_INTERVAL_MS: u64 = 10;
+
+/// Where the audio is played, picked with --audio-out
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioOutputKind {
+    /// An output device through cpal, the default one when None
+    Device(Option<String>),
+    /// Plays nothing, at the pace of a device
+    Null,
+    /// A 16-bit WAV file, in the format of the first decoded samples
+    Wav(PathBuf),
+    /// Raw 16-bit little-endian PCM, at 48 kHz in stereo, to a file or pipe, or stdout when None
+    Pcm(Option<PathBuf>),
+}
+
+impl AudioOutputKind {
+    /// Created on the audio thread, since device streams can't move between threads
+    pub fn create(&self) -> Box<dyn AudioOutput> {
+        match self {
+            AudioOutputKind::Device(name) => Box::new(CpalOutput::new(name.clone())),
+            AudioOutputKind::Null => Box::new(NullOutput::default()),
+            AudioOutputKind::Wav(path) => Box::new(WavOutput::new(path.clone())),
+            AudioOutputKind::Pcm(path) => Box::new(PcmOutput::new(path.clone())),
+        }
+    }
+}
+
+impl FromStr for AudioOutputKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            None if value == "device" => Ok(AudioOutputKind::Device(None)),
+            None if value == "null" => Ok(AudioOutputKind::Null),
+            None if value == "pcm" => Ok(AudioOutputKind::Pcm(None)),
+            Some(("wav", path)) if !path.is_empty() => Ok(AudioOutputKind::Wav(path.into())),
+            Some(("pcm", "-")) => Ok(AudioOutputKind::Pcm(None)),
+            Some(("pcm", path)) if !path.is_empty() => Ok(AudioOutputKind::Pcm(Some(path.into()))),
+            _ => Err(format!(
+                "unknown audio output '{}', expected device, null, wav:FILE, pcm or pcm:FILE",
+                value
+            )),
+        }
+    }
+}
+
+/// Rate and channel count an output plays at, which the decoded audio is converted to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputConfig {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// Somewhere the audio adapter can play its samples.
+///
+/// Once opened, the output pulls the samples from the source at its own pace,
+/// which drives the playback clock.
+pub trait AudioOutput {
+    /// Starts playing what the source gives, for decoded audio in this format
+    fn open(&mut self, format: AudioFormat, source: SampleSource) -> Res<OutputConfig>;
+
+    /// Stops taking samples from the source
+    fn close(&mut self);
+
+    /// Picks the device the next open plays on, returns the previous one
+    fn set_device(&mut self, _name: Option<String>) -> Res<Option<String>> {
+        Err(PlaybackError::AudioDevice(
+            "This audio output has no devices to pick from".to_string(),
+        ))
+    }
+}
+
+/// What every output pulls the samples it plays from: the buffer of the
+/// audio adapter, with the volume, pause and clock applied on the way out.
+#[derive(Clone)]
+pub struct SampleSource {
+    pub buffer: Arc<Mutex<VecDeque<f32>>>,
+    pub pause_control: Option<Arc<PauseControl>>,
+    pub playback_clock: Option<Arc<PlaybackClock>>,
+    pub volume_control: Option<Arc<VolumeControl>>,
+    /// Errors are reported on the output thread, the adapter returns them
+    pub error: Arc<Mutex<Option<String>>>,
+}
+
+impl SampleSource {
+    pub fn is_paused(&self) -> bool {
+        self.pause_control
+            .as_ref()
+            .is_some_and(|pause_control| pause_control.is_paused())
+    }
+
+    /// Fills interleaved samples, with silence when paused or starved
+    pub fn fill(&self, data: &mut [f32], channels: u16) {
+        // Keep what is buffered for when playback resumes
+        if self.is_paused() {
+            data.fill(0.0);
+            return;
+        }
+
+        let gain = self
+            .volume_control
+            .as_ref()
+            .map_or(1.0, |volume_control| volume_control.gain());
+
+        let mut buffer = self.buffer.lock().unwrap();
+        let available = data.len().min(buffer.len());
+        for (index, sample) in data.iter_mut().enumerate() {
+            let value = if index < available {
+                buffer.pop_front().unwrap_or(0.0)
+            } else {
+                0.0
+            };
+            // Clipped, since a boost can push the samples past full scale
+            *sample = (value * gain).clamp(-1.0, 1.0);
+        }
+
+        // Silence played while starved doesn't move the clock
+        if let Some(ref playback_clock) = self.playback_clock {
+            playback_clock.advance(available / channels.max(1) as usize);
+        }
+    }
+
+    pub fn report_error(&self, error: String) {
+        self.error.lock().unwrap().get_or_insert(error);
+    }
+}
+
+/// Takes samples from a source in real time on its own thread, for the
+/// outputs that have no device to set the pace.
+pub struct PacedReader {
+    stop_flag: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl PacedReader {
+    /// Hands the samples to write, and stops on its first error
+    pub fn start<W>(source: SampleSource, config: OutputConfig, mut write: W) -> Self
+    where
+        W: FnMut(&[f32]) -> io::Result<()> + Send + 'static,
+    {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop = stop_flag.clone();
+
+        let handle = thread::spawn(move || {
+            let channels = config.channels as usize;
+            let mut started_at = Instant::now();
+            let mut frames_read = 0;
+            let mut samples = Vec::new();
+
+            while !stop.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(PACE_INTERVAL_MS));
+
+                // A pause is not written out, the pace starts again on resume
+                if source.is_paused() {
+                    started_at = Instant::now();
+                    frames_read = 0;
+                    continue;
+                }
+
+                let frames_due = (started_at.elapsed().as_micros() * config.sample_rate as u128
+                    / 1_000_000) as usize;
+                if frames_due <= frames_read {
+                    continue;
+                }
+
+                samples.resize((frames_due - frames_read) * channels, 0.0);
+                source.fill(&mut samples, config.channels);
+                frames_read = frames_due;
+
+                if let Err(error) = write(&samples) {
+                    source.report_error(format!("Could not write audio: {}", error));
+                    return;
+                }
+            }
+        });
+
+        PacedReader {
+            stop_flag,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.stop_flag.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for PacedReader {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Drops the samples, so that playback runs without a sound card
+#[derive(Default)]
+pub struct NullOutput {
+    reader: Option<PacedReader>,
+}
+
+impl AudioOutput for NullOutput {
+    fn open(&mut self, format: AudioFormat, source: SampleSource) -> Res<OutputConfig> {
+        let config = OutputConfig {
+            sample_rate: format.sample_rate,
+            channels: format.channels,
+        };
+
+        self.reader = Some(PacedReader::start(source, config, |_| Ok(())));
+        Ok(config)
+    }
+
+    fn close(&mut self) {
+        self.reader.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::types::SampleFormat;
+
+    #[test]
+    fn test_parse_audio_output_kind() {
+        assert_eq!("device".parse(), Ok(AudioOutputKind::Device(None)));
+        assert_eq!("null".parse(), Ok(AudioOutputKind::Null));
+        assert_eq!(
+            "wav:out.wav".parse(),
+            Ok(AudioOutputKind::Wav(PathBuf::from("out.wav")))
+        );
+        assert_eq!("pcm".parse(), Ok(AudioOutputKind::Pcm(None)));
+        assert_eq!("pcm:-".parse(), Ok(AudioOutputKind::Pcm(None)));
+        assert_eq!(
+            "pcm:/tmp/fifo".parse(),
+            Ok(AudioOutputKind::Pcm(Some(PathBuf::from("/tmp/fifo"))))
+        );
+        assert!("wav:".parse::<AudioOutputKind>().is_err());
+        assert!("speakers".parse::<AudioOutputKind>().is_err());
+    }
+
+    #[test]
+    fn test_null_output_plays_in_real_time() {
+        let buffer = Arc::new(Mutex::new(VecDeque::from(vec![0.5; 48000])));
+        let playback_clock = Arc::new(PlaybackClock::new());
+        playback_clock.start(0, 48000);
+
+        let source = SampleSource {
+            buffer: buffer.clone(),
+            pause_control: None,
+            playback_clock: Some(playback_clock.clone()),
+            volume_control: None,
+            error: Arc::new(Mutex::new(None)),
+        };
+        let format = AudioFormat {
+            sample_rate: 48000,
+            channels: 2,
+            sample_format: SampleFormat::F32,
+            planar: false,
+        };
+
+        let mut output = NullOutput::default();
+        output.open(format, source).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        output.close();
+
+        // A quarter of the buffer lasts 125 ms
+        let remaining = buffer.lock().unwrap().len();
+        assert!(remaining < 48000 && remaining > 12000, "{}", remaining);
+        assert!(playback_clock.position_ms().is_some());
+    }
+}
@@ -0,0 +1,112 @@
+use ffmpeg_next::format::{sample::Type, Sample};
+use ffmpeg_next::software::resampling;
+use ffmpeg_next::{frame, ChannelLayout};
+
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::{AudioFormat, Res, SampleFormat};
+
+/* Room for the samples swresample holds back between two calls */
+const EXTRA_OUTPUT_SAMPLES: usize = 256;
+
+/// Converts decoded audio, in any sample format and channel layout, to the
+/// interleaved f32 samples the output device plays, at its rate and channel count.
+pub struct Resampler {
+    context: resampling::Context,
+    input_format: AudioFormat,
+    output_rate: u32,
+    output_channels: u16,
+}
+
+impl Resampler {
+    pub fn new(input_format: AudioFormat, output_rate: u32, output_channels: u16) -> Res<Self> {
+        let context = resampling::Context::get(
+            ffmpeg_sample(input_format),
+            ChannelLayout::default(input_format.channels as i32),
+            input_format.sample_rate,
+            Sample::F32(Type::Packed),
+            ChannelLayout::default(output_channels as i32),
+            output_rate,
+        )
+        .map_err(|e| {
+            PlaybackError::AudioDevice(format!(
+                "Could not convert {} Hz, {} channel audio to {} Hz, {} channels: {}",
+                input_format.sample_rate, input_format.channels, output_rate, output_channels, e
+            ))
+        })?;
+
+        Ok(Resampler {
+            context,
+            input_format,
+            output_rate,
+            output_channels,
+        })
+    }
+
+    pub fn input_format(&self) -> AudioFormat {
+        self.input_format
+    }
+
+    /// Converts samples laid out as described by the input format
+    pub fn convert(&mut self, data: &[u8]) -> Res<Vec<f32>> {
+        let format = self.input_format;
+        let channels = format.channels.max(1) as usize;
+        let samples = data.len() / (format.sample_format.bytes_per_sample() * channels);
+
+        if samples == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut input = frame::Audio::new(
+            ffmpeg_sample(format),
+            samples,
+            ChannelLayout::default(format.channels as i32),
+        );
+        input.set_rate(format.sample_rate);
+
+        let planes = if format.planar { channels } else { 1 };
+        for (plane, plane_data) in data.chunks_exact(data.len() / planes).enumerate() {
+            // As for decoded frames, only the size of the first plane is set
+            unsafe {
+                let plane_ptr = *(*input.as_mut_ptr()).extended_data.add(plane);
+                std::ptr::copy_nonoverlapping(plane_data.as_ptr(), plane_ptr, plane_data.len());
+            }
+        }
+
+        // Allocated here, since swresample would otherwise make it as long as the input
+        let output_samples = samples * self.output_rate as usize
+            / format.sample_rate.max(1) as usize
+            + EXTRA_OUTPUT_SAMPLES;
+        let mut output = frame::Audio::new(
+            Sample::F32(Type::Packed),
+            output_samples,
+            ChannelLayout::default(self.output_channels as i32),
+        );
+
+        self.context
+            .run(&input, &mut output)
+            .map_err(|e| PlaybackError::AudioDevice(format!("Could not resample audio: {}", e)))?;
+
+        let output_size = output.samples() * self.output_channels as usize;
+        let output_data = unsafe {
+            std::slice::from_raw_parts((*output.as_ptr()).data[0] as *const f32, output_size)
+        };
+
+        Ok(output_data.to_vec())
+    }
+}
+
+fn ffmpeg_sample(format: AudioFormat) -> Sample {
+    let sample_type = if format.planar {
+        Type::Planar
+    } else {
+        Type::Packed
+    };
+
+    match format.sample_format {
+        SampleFormat::U8 => Sample::U8(sample_type),
+        SampleFormat::I16 => Sample::I16(sample_type),
+        SampleFormat::I32 => Sample::I32(sample_type),
+        SampleFormat::F32 => Sample::F32(sample_type),
+        SampleFormat::F64 => Sample::F64(sample_type),
+    }
+}
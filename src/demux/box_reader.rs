@@ -0,0 +1,708 @@
+use std::io::{ErrorKind, Read};
+
+use crate::demux::get_moof_box::{get_moof_box, SIDXBox};
+use crate::demux::get_moov_box::{get_moov_box, FTYPBox, MOOVBox, Streams};
+use crate::demux::get_sample_map::{
+    get_fragment_sample_map, get_sample_map, parse_mvhd_duration_in_ms, parse_sidx,
+    seek_sample_map, SampleData, SampleMap, SegmentReference,
+};
+use crate::demux::input_source::{InputSource, OpenedInput};
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::Res;
+
+pub enum BoxReaderEvent {
+    /// A moov box was parsed, and can be read with BoxReader::moov_box
+    Moov,
+    /// A sidx box was parsed, so a fragmented file can now seek
+    SegmentIndex,
+    /// The next sample of the file, with its bytes
+    Sample(SampleData, Vec<u8>),
+}
+
+enum BoxStep {
+    /// More bytes are needed to parse the next box
+    NeedData,
+    /// A box was consumed, keep going
+    Continue,
+    /// A moov box was parsed
+    Moov,
+    /// The first sidx box was parsed
+    SegmentIndex,
+    /// An mdat box starts, its samples can be read
+    Mdat,
+}
+
+/// Walks the top-level boxes of one MP4 input and yields its samples in file order.
+pub struct BoxReader {
+    source: InputSource,
+    input: Option<OpenedInput>,
+    buffer: Vec<u8>,
+    accumulated_data: Vec<u8>,
+    // Absolute offset in the input of the first byte of accumulated_data
+    position: u64,
+    ftyp_box: Option<FTYPBox>,
+    moov_box: Option<MOOVBox>,
+    sample_map: Option<SampleMap>,
+    // Set while reading the samples of an mdat box, to the offset where the box ends
+    mdat_end: Option<u64>,
+    moov_after_mdat: bool,
+    skipped_mdat_end: u64,
+    fragmented: bool,
+    // The input has no more bytes, so a box with a size of 0 ends with the accumulated data
+    at_eof: bool,
+    finished: bool,
+    // Subsegments listed in the first sidx box of a fragmented file
+    segment_references: Option<Vec<SegmentReference>>,
+    // After seeking in a fragmented file, audio samples before this time are dropped
+    seek_floor_ms: Option<u64>,
+}
+
+impl BoxReader {
+    pub fn new(source: InputSource) -> Self {
+        Self {
+            source,
+            input: None,
+            buffer: vec![0; 1000000],
+            accumulated_data: vec![],
+            position: 0,
+            ftyp_box: None,
+            moov_box: None,
+            sample_map: None,
+            mdat_end: None,
+            moov_after_mdat: false,
+            skipped_mdat_end: 0,
+            fragmented: false,
+            at_eof: false,
+            finished: false,
+            segment_references: None,
+            seek_floor_ms: None,
+        }
+    }
+
+    /// Continues from an input that was already opened, and the bytes already read from it
+    pub fn from_opened(source: InputSource, input: OpenedInput, read_data: Vec<u8>) -> Self {
+        let mut reader = Self::new(source);
+        reader.input = Some(input);
+        reader.accumulated_data = read_data;
+        reader
+    }
+
+    pub fn moov_box(&self) -> Option<&MOOVBox> {
+        self.moov_box.as_ref()
+    }
+
+    /// Duration from the mvhd box, or from the sidx box of fragmented files
+    pub fn duration_in_ms(&self) -> u64 {
+        let mvhd_duration = self
+            .moov_box
+            .as_ref()
+            .map(|moov_box| parse_mvhd_duration_in_ms(&moov_box.mvhd))
+            .unwrap_or(0);
+
+        if mvhd_duration > 0 {
+            return mvhd_duration;
+        }
+
+        self.segment_references
+            .as_ref()
+            .and_then(|references| references.last())
+            .map(|last| last.time_in_ms + last.duration_in_ms)
+            .unwrap_or(0)
+    }
+
+    /// Whether seek can be called: the input must be seekable, and fragmented
+    /// files need a sidx box to find their fragments
+    pub fn is_seekable(&self) -> bool {
+        self.source.is_seekable()
+            && self.moov_box.is_some()
+            && (!self.fragmented || self.segment_references.is_some())
+    }
+
+    /*
+     * Restarts the input so that the next samples are the ones needed to play from target_ms:
+     * the closest sync sample at or before it for video, and the samples from its time on for audio.
+     * Returns the time playback resumes at.
+     */
+    pub fn seek(&mut self, target_ms: u64) -> Res<u64> {
+        if !self.is_seekable() {
+            return Err(PlaybackError::Input("Input is not seekable".to_string()));
+        }
+
+        let moov_box = self
+            .moov_box
+            .as_ref()
+            .ok_or_else(|| PlaybackError::missing_box("moov"))?;
+
+        let (position, resume_ms) = if self.fragmented {
+            let has_video = moov_box
+                .traks
+                .iter()
+                .any(|trak| matches!(trak.media.minf.header, Streams::Video));
+
+            let references = self.segment_references.as_deref().unwrap_or_default();
+
+            // Subsegments start with a sync sample
+            let reference = references
+                .iter()
+                .rev()
+                .find(|reference| reference.time_in_ms <= target_ms)
+                .or(references.first())
+                .ok_or_else(|| PlaybackError::Container("sidx box has no subsegments".to_string()))?;
+
+            let resume_ms = if has_video {
+                reference.time_in_ms
+            } else {
+                target_ms
+            };
+
+            self.sample_map = Some(SampleMap::new());
+            self.mdat_end = None;
+            self.seek_floor_ms = Some(resume_ms);
+
+            (reference.offset, resume_ms)
+        } else {
+            let (sample_map, resume_ms) = seek_sample_map(get_sample_map(moov_box)?, target_ms);
+
+            let position = sample_map
+                .front()
+                .map(|sample| sample.offset)
+                .unwrap_or(self.position);
+
+            self.sample_map = Some(sample_map);
+
+            // Every sample is in the sample map, so the bounds of the mdat box don't matter
+            self.mdat_end = Some(u64::MAX);
+
+            (position, resume_ms)
+        };
+
+        self.position = position;
+        self.finished = false;
+        self.reopen()?;
+
+        Ok(resume_ms)
+    }
+
+    /// Stops the process backing the input, if any
+    pub fn close(&mut self) {
+        if let Some(mut input) = self.input.take() {
+            input.close();
+        }
+    }
+
+    /*
+     * Returns the next moov box or sample of the input,
+     * or None once every sample has been read
+     */
+    pub fn next_event(&mut self) -> Res<Option<BoxReaderEvent>> {
+        if self.input.is_none() && !self.finished {
+            self.input = Some(self.source.open()?);
+        }
+
+        loop {
+            if self.finished {
+                return Ok(None);
+            }
+
+            if let Some(event) = self.parse_next()? {
+                return Ok(Some(event));
+            }
+
+            if self.finished {
+                return Ok(None);
+            }
+
+            let input = self
+                .input
+                .as_mut()
+                .ok_or_else(|| PlaybackError::Input("Input is closed".to_string()))?;
+
+            match input.read(&mut self.buffer) {
+                Ok(0) => {
+                    // One more pass, for a last box that runs to the end of the file
+                    if !self.at_eof {
+                        self.at_eof = true;
+                        continue;
+                    }
+
+                    self.finished = true;
+                    input.check_exit_status()?;
+
+                    if self.moov_box.is_none() {
+                        return Err(PlaybackError::Container(
+                            "Input ended before a moov box was found".to_string(),
+                        ));
+                    }
+                }
+                Ok(bytes_read) => {
+                    self.accumulated_data
+                        .extend_from_slice(&self.buffer[..bytes_read]);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(PlaybackError::Input(format!("Could not read input: {}", e)));
+                }
+            }
+        }
+    }
+
+    /*
+     * Parses boxes and samples out of the bytes read so far,
+     * returning None when more bytes are needed
+     */
+    fn parse_next(&mut self) -> Res<Option<BoxReaderEvent>> {
+        loop {
+            if self.mdat_end.is_none() {
+                match self.parse_box()? {
+                    BoxStep::NeedData => return Ok(None),
+                    BoxStep::Continue => continue,
+                    BoxStep::Moov => return Ok(Some(BoxReaderEvent::Moov)),
+                    BoxStep::SegmentIndex => return Ok(Some(BoxReaderEvent::SegmentIndex)),
+                    BoxStep::Mdat => {}
+                }
+            }
+
+            let Some(current_mdat_end) = self.mdat_end else {
+                continue;
+            };
+
+            if let Some(sample_map) = self.sample_map.as_mut() {
+                if let Some(next_sample) = sample_map.front() {
+                    // A fragment's samples are all inside the mdat box following it
+                    if !(self.fragmented && next_sample.offset >= current_mdat_end) {
+                        // Skip any bytes between the current position and the next sample
+                        if next_sample.offset > self.position {
+                            let gap = (next_sample.offset - self.position)
+                                .min(self.accumulated_data.len() as u64);
+
+                            self.accumulated_data.drain(..gap as usize);
+                            self.position += gap;
+
+                            if next_sample.offset > self.position {
+                                return Ok(None);
+                            }
+                        }
+
+                        if self.accumulated_data.len() < next_sample.size as usize {
+                            return Ok(None);
+                        }
+
+                        let current_sample_data = sample_map.pop_front().unwrap();
+
+                        let sample: Vec<u8> = self
+                            .accumulated_data
+                            .drain(..current_sample_data.size as usize)
+                            .collect();
+
+                        self.position += current_sample_data.size as u64;
+
+                        return Ok(Some(BoxReaderEvent::Sample(current_sample_data, sample)));
+                    }
+                }
+            }
+
+            if !self.fragmented {
+                // Every sample of the moov box has been read
+                self.finished = true;
+                return Ok(None);
+            }
+
+            // Skip what is left of the mdat box, then look for the next fragment
+            let remaining =
+                (current_mdat_end - self.position).min(self.accumulated_data.len() as u64);
+
+            self.accumulated_data.drain(..remaining as usize);
+            self.position += remaining;
+
+            if self.position < current_mdat_end {
+                return Ok(None);
+            }
+
+            self.mdat_end = None;
+        }
+    }
+
+    /*
+     * Parses the top-level box at the start of the accumulated data
+     */
+    fn parse_box(&mut self) -> Res<BoxStep> {
+        if self.accumulated_data.len() < 8 {
+            return Ok(BoxStep::NeedData);
+        }
+
+        let box_size_bytes: [u8; 4] = self.accumulated_data[0..4].try_into().unwrap();
+        let compact_size = u32::from_be_bytes(box_size_bytes);
+
+        let box_title_bytes: [u8; 4] = self.accumulated_data[4..8].try_into().unwrap();
+        let box_title = String::from_utf8_lossy(&box_title_bytes).to_string();
+
+        // A size of 1 means a 64-bit largesize follows the title
+        let header_size = if compact_size == 1 { 16 } else { 8 };
+
+        if self.accumulated_data.len() < header_size {
+            return Ok(BoxStep::NeedData);
+        }
+
+        // A size of 0 means the box runs to the end of the file
+        let box_size = match compact_size {
+            0 => None,
+            1 => Some(u64::from_be_bytes(self.accumulated_data[8..16].try_into().unwrap())),
+            size => Some(size as u64),
+        };
+
+        if box_title.as_str() != "mdat" {
+            let box_size = match box_size {
+                Some(box_size) => box_size,
+                None if self.at_eof => self.accumulated_data.len() as u64,
+                None => return Ok(BoxStep::NeedData),
+            };
+
+            if (self.accumulated_data.len() as u64) < box_size {
+                return Ok(BoxStep::NeedData);
+            }
+        }
+
+        if box_title.as_str() == "mdat" && self.moov_box.is_none() {
+            /*
+             * The moov box comes after mdat (not a faststart file).
+             * Skip over the media data, read the moov box at the end,
+             * then come back for the samples.
+             */
+            if !self.source.is_seekable() {
+                return Err(PlaybackError::Input(
+                    "moov box comes after mdat, which needs a seekable input".to_string(),
+                ));
+            }
+
+            let Some(box_size) = box_size else {
+                return Err(PlaybackError::Container(
+                    "mdat box runs to the end of the file, with no moov box before it".to_string(),
+                ));
+            };
+
+            self.moov_after_mdat = true;
+            self.position += box_size;
+            self.skipped_mdat_end = self.position;
+            self.reopen()?;
+            return Ok(BoxStep::NeedData);
+        }
+
+        let box_size = box_size.unwrap_or(self.accumulated_data.len() as u64);
+
+        if box_size < header_size as u64 {
+            return Err(PlaybackError::Container(format!(
+                "{} box has an invalid size of {}",
+                box_title, box_size
+            )));
+        }
+
+        self.accumulated_data.drain(..header_size);
+        self.position += header_size as u64;
+
+        let box_data_size = (box_size - header_size as u64) as usize;
+
+        match box_title.as_str() {
+            "ftyp" => {
+                self.ftyp_box = Some(FTYPBox {
+                    size: box_size as u32,
+                    data: self.accumulated_data.drain(..box_data_size).collect(),
+                });
+
+                self.position += box_data_size as u64;
+            }
+            "moov" => {
+                let moov_size = u32::try_from(box_size).map_err(|_| {
+                    PlaybackError::Container(format!("moov box is too large: {} bytes", box_size))
+                })?;
+
+                let parsed_moov_box = get_moov_box(
+                    moov_size,
+                    self.accumulated_data.drain(..box_data_size).collect(),
+                )?;
+
+                // Fragmented files describe their samples in moof boxes instead
+                self.fragmented = parsed_moov_box.mvex.is_some();
+
+                self.sample_map = Some(if self.fragmented {
+                    SampleMap::new()
+                } else {
+                    get_sample_map(&parsed_moov_box)?
+                });
+
+                self.moov_box = Some(parsed_moov_box);
+
+                self.position += box_data_size as u64;
+
+                if self.moov_after_mdat {
+                    // Go back to the first sample, using its real offset
+                    let first_sample_offset = self
+                        .sample_map
+                        .as_ref()
+                        .and_then(|sample_map| sample_map.front())
+                        .map(|sample| sample.offset)
+                        .unwrap_or(self.position);
+
+                    self.position = first_sample_offset;
+                    self.reopen()?;
+                    self.mdat_end = Some(self.skipped_mdat_end);
+                }
+
+                return Ok(BoxStep::Moov);
+            }
+            "moof" => {
+                let moof_offset = self.position - header_size as u64;
+
+                let moof_box = get_moof_box(
+                    box_size as u32,
+                    self.accumulated_data.drain(..box_data_size).collect(),
+                )?;
+
+                let fragment_sample_map = get_fragment_sample_map(
+                    &moof_box,
+                    moof_offset,
+                    self.moov_box.as_ref().ok_or_else(|| {
+                        PlaybackError::Container("moof box found before moov".to_string())
+                    })?,
+                )?;
+
+                let seek_floor_ms = self.seek_floor_ms;
+
+                self.sample_map.get_or_insert_with(SampleMap::new).extend(
+                    fragment_sample_map.into_iter().filter(|sample| {
+                        sample.is_video
+                            || seek_floor_ms
+                                .is_none_or(|floor| sample.presentation_time_in_ms() >= floor as i64)
+                    }),
+                );
+
+                self.position += box_data_size as u64;
+            }
+            "mdat" => {
+                self.mdat_end = Some(match compact_size {
+                    0 => u64::MAX,
+                    _ => self.position + box_data_size as u64,
+                });
+
+                return Ok(BoxStep::Mdat);
+            }
+            "sidx" => {
+                let sidx_box = SIDXBox {
+                    size: box_size as u32,
+                    data: self.accumulated_data.drain(..box_data_size).collect(),
+                };
+
+                self.position += box_data_size as u64;
+
+                // Later sidx boxes index the same fragments at a finer level
+                if self.segment_references.is_none() {
+                    self.segment_references = Some(parse_sidx(&sidx_box, self.position)?);
+                    return Ok(BoxStep::SegmentIndex);
+                }
+            }
+            _ => {
+                // e.g. styp, free, uuid or mfra, none of which are needed to play the samples in order
+                self.accumulated_data.drain(..box_data_size);
+                self.position += box_data_size as u64;
+            }
+        }
+
+        Ok(BoxStep::Continue)
+    }
+
+    /*
+     * Restarts the input at the current position, dropping any buffered bytes
+     */
+    fn reopen(&mut self) -> Res<()> {
+        self.close();
+        self.accumulated_data.clear();
+        self.at_eof = false;
+        self.input = Some(self.source.open_at(self.position)?);
+        Ok(())
+    }
+}
+
+impl Drop for BoxReader {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn fragmented_file() -> Vec<u8> {
+        vec![
+            // ftyp box
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x66, 0x74, 0x79, 0x70, // "ftyp"
+            0x69, 0x73, 0x6F, 0x36, // major brand "iso6"
+            0x00, 0x00, 0x00, 0x00, // minor version
+            // moov box
+            0x00, 0x00, 0x00, 0x3C, // size = 60
+            0x6D, 0x6F, 0x6F, 0x76, // "moov"
+            0x00, 0x00, 0x00, 0x0C, // size = 12
+            0x6D, 0x76, 0x68, 0x64, // "mvhd"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x28, // size = 40
+            0x6D, 0x76, 0x65, 0x78, // "mvex"
+            0x00, 0x00, 0x00, 0x20, // size = 32
+            0x74, 0x72, 0x65, 0x78, // "trex"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x01, // track id = 1
+            0x00, 0x00, 0x00, 0x01, // default sample description index
+            0x00, 0x00, 0x02, 0x00, // default sample duration = 512
+            0x00, 0x00, 0x00, 0x00, // default sample size
+            0x00, 0x01, 0x00, 0x00, // default sample flags
+            // sidx box
+            0x00, 0x00, 0x00, 0x2C, // size = 44
+            0x73, 0x69, 0x64, 0x78, // "sidx"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x01, // reference id
+            0x00, 0x00, 0x03, 0xE8, // timescale = 1000
+            0x00, 0x00, 0x00, 0x00, // earliest presentation time
+            0x00, 0x00, 0x00, 0x00, // first offset
+            0x00, 0x00, 0x00, 0x01, // reserved + reference count = 1
+            0x00, 0x00, 0x00, 0x24, // referenced size = 36
+            0x00, 0x00, 0x03, 0xE8, // subsegment duration = 1000
+            0x90, 0x00, 0x00, 0x00, // starts with SAP
+            // moof box without any traf
+            0x00, 0x00, 0x00, 0x18, // size = 24
+            0x6D, 0x6F, 0x6F, 0x66, // "moof"
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x6D, 0x66, 0x68, 0x64, // "mfhd"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x01, // sequence number = 1
+            // mdat box
+            0x00, 0x00, 0x00, 0x0C, // size = 12
+            0x6D, 0x64, 0x61, 0x74, // "mdat"
+            0x01, 0x02, 0x03, 0x04, // unreferenced data
+        ]
+    }
+
+    #[test]
+    fn test_fragmented_file_without_samples() {
+        let mut reader = BoxReader::new(InputSource::from_reader(Cursor::new(fragmented_file())));
+
+        assert!(matches!(reader.next_event().unwrap(), Some(BoxReaderEvent::Moov)));
+        assert!(reader.moov_box().unwrap().mvex.is_some());
+
+        assert!(matches!(
+            reader.next_event().unwrap(),
+            Some(BoxReaderEvent::SegmentIndex)
+        ));
+
+        assert!(reader.next_event().unwrap().is_none());
+        assert!(reader.next_event().unwrap().is_none());
+
+        // A stream can't be restarted at another offset
+        assert!(!reader.is_seekable());
+        assert!(reader.seek(0).is_err());
+    }
+
+    #[test]
+    fn test_seek_fragmented_file() {
+        let path = std::env::temp_dir().join("yt-term-box-reader-seek-test.mp4");
+        std::fs::write(&path, fragmented_file()).unwrap();
+
+        let mut reader = BoxReader::new(InputSource::File(path.clone()));
+
+        assert!(matches!(reader.next_event().unwrap(), Some(BoxReaderEvent::Moov)));
+        assert!(!reader.is_seekable());
+
+        assert!(matches!(
+            reader.next_event().unwrap(),
+            Some(BoxReaderEvent::SegmentIndex)
+        ));
+        assert!(reader.is_seekable());
+        assert_eq!(reader.duration_in_ms(), 1000);
+
+        assert!(reader.next_event().unwrap().is_none());
+
+        // Seeking goes back to the first subsegment, right after the sidx box.
+        // There is no video track, so playback resumes at the target itself
+        assert_eq!(reader.seek(500).unwrap(), 500);
+        assert_eq!(reader.position, 120);
+        assert!(reader.next_event().unwrap().is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_largesize_and_size_zero_boxes() {
+        let mut data = vec![
+            // ftyp box with a 64-bit size
+            0x00, 0x00, 0x00, 0x01, // size = 1, largesize follows
+            0x66, 0x74, 0x79, 0x70, // "ftyp"
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, // largesize = 24
+            0x69, 0x73, 0x6F, 0x36, // major brand "iso6"
+            0x00, 0x00, 0x00, 0x00, // minor version
+            // moov box running to the end of the file
+            0x00, 0x00, 0x00, 0x00, // size = 0
+            0x6D, 0x6F, 0x6F, 0x76, // "moov"
+        ];
+
+        // Same mvhd and mvex as the fragmented file
+        data.extend_from_slice(&fragmented_file()[24..76]);
+
+        let mut reader = BoxReader::new(InputSource::from_reader(Cursor::new(data)));
+
+        assert!(matches!(reader.next_event().unwrap(), Some(BoxReaderEvent::Moov)));
+        assert_eq!(reader.ftyp_box.as_ref().unwrap().data.len(), 8);
+        assert!(reader.moov_box().unwrap().mvex.is_some());
+        assert_eq!(reader.position, 84);
+        assert!(reader.next_event().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unknown_top_level_boxes_are_skipped() {
+        let mut data = vec![
+            // free box
+            0x00, 0x00, 0x00, 0x0C, // size = 12
+            0x66, 0x72, 0x65, 0x65, // "free"
+            0x00, 0x00, 0x00, 0x00, // padding
+        ];
+        data.extend_from_slice(&fragmented_file());
+
+        let mut reader = BoxReader::new(InputSource::from_reader(Cursor::new(data)));
+        let mut reader_without_free =
+            BoxReader::new(InputSource::from_reader(Cursor::new(fragmented_file())));
+
+        assert!(matches!(reader.next_event().unwrap(), Some(BoxReaderEvent::Moov)));
+        assert!(matches!(reader_without_free.next_event().unwrap(), Some(BoxReaderEvent::Moov)));
+        assert_eq!(reader.position, reader_without_free.position + 12);
+    }
+
+    #[test]
+    fn test_moov_after_mdat_needs_seekable_input() {
+        let data = vec![
+            0x00, 0x00, 0x00, 0x0C, // size = 12
+            0x6D, 0x64, 0x61, 0x74, // "mdat"
+            0x01, 0x02, 0x03, 0x04,
+        ];
+
+        let mut reader = BoxReader::new(InputSource::from_reader(Cursor::new(data)));
+
+        let result = reader.next_event();
+        assert!(result.is_err());
+        assert!(result.err().unwrap().to_string().contains("seekable"));
+    }
+
+    #[test]
+    fn test_input_without_moov_box() {
+        let data = vec![
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x66, 0x74, 0x79, 0x70, // "ftyp"
+            0x69, 0x73, 0x6F, 0x6D, // major brand = "isom"
+            0x00, 0x00, 0x02, 0x00, // minor version
+        ];
+
+        let mut reader = BoxReader::new(InputSource::from_reader(Cursor::new(data)));
+
+        assert!(matches!(
+            reader.next_event(),
+            Err(PlaybackError::Container(ref message)) if message.contains("moov")
+        ));
+    }
+}
@@ -2,148 +2,154 @@ use ffmpeg_next as ffmpeg;
 use ffmpeg_next::ffi;
 use std::ptr;
 
+use crate::demux::get_moov_box::{AudioCodec, VideoCodec};
+
 const AV_INPUT_BUFFER_PADDING_SIZE: usize = 64;
 
-/// Creates an H.264 video decoder from avcC extradata.
-///
-/// # Safety
-/// This function uses raw FFmpeg FFI calls to allocate and configure codec contexts.
-/// The caller must ensure ffmpeg has been initialized via `ffmpeg::init()`.
-pub unsafe fn create_h264_decoder(
-    avcc_extradata: &[u8],
-) -> Result<ffmpeg::decoder::Video, ffmpeg::Error> {
-    // Allocate AVCodecParameters
-    let params = ffi::avcodec_parameters_alloc();
-    if params.is_null() {
-        return Err(ffmpeg::Error::Unknown);
+fn video_codec_id(codec: VideoCodec) -> ffi::AVCodecID {
+    match codec {
+        VideoCodec::H264 => ffi::AVCodecID::AV_CODEC_ID_H264,
+        VideoCodec::Hevc => ffi::AVCodecID::AV_CODEC_ID_HEVC,
+        VideoCodec::Vp9 => ffi::AVCodecID::AV_CODEC_ID_VP9,
+        VideoCodec::Av1 => ffi::AVCodecID::AV_CODEC_ID_AV1,
     }
+}
 
-    // Set video codec parameters
-    (*params).codec_type = ffi::AVMediaType::AVMEDIA_TYPE_VIDEO;
-    (*params).codec_id = ffi::AVCodecID::AV_CODEC_ID_H264;
-    (*params).width = 640;
-    (*params).height = 360;
-    (*params).format = ffi::AVPixelFormat::AV_PIX_FMT_YUV420P as i32;
+fn audio_codec_id(codec: AudioCodec) -> ffi::AVCodecID {
+    match codec {
+        AudioCodec::Aac => ffi::AVCodecID::AV_CODEC_ID_AAC,
+        AudioCodec::Mp3 => ffi::AVCodecID::AV_CODEC_ID_MP3,
+        AudioCodec::Opus => ffi::AVCodecID::AV_CODEC_ID_OPUS,
+    }
+}
 
-    // Allocate and copy avcC extradata with required padding
-    let extradata_size = avcc_extradata.len();
-    let extradata = ffi::av_malloc(extradata_size + AV_INPUT_BUFFER_PADDING_SIZE);
-    if extradata.is_null() {
-        ffi::avcodec_parameters_free(&mut (params as *mut _));
+/*
+ * Allocates codec parameters holding a copy of the extradata,
+ * followed by the zeroed padding decoders expect
+ */
+unsafe fn alloc_parameters(
+    codec_type: ffi::AVMediaType,
+    codec_id: ffi::AVCodecID,
+    extradata: &[u8],
+) -> Result<*mut ffi::AVCodecParameters, ffmpeg::Error> {
+    let mut params = ffi::avcodec_parameters_alloc();
+    if params.is_null() {
         return Err(ffmpeg::Error::Unknown);
     }
 
-    // Copy extradata and zero the padding
-    ptr::copy_nonoverlapping(avcc_extradata.as_ptr(), extradata as *mut u8, extradata_size);
-    ptr::write_bytes(
-        (extradata as *mut u8).add(extradata_size),
-        0,
-        AV_INPUT_BUFFER_PADDING_SIZE,
-    );
+    (*params).codec_type = codec_type;
+    (*params).codec_id = codec_id;
+
+    if !extradata.is_empty() {
+        let extradata_copy = ffi::av_malloc(extradata.len() + AV_INPUT_BUFFER_PADDING_SIZE);
+        if extradata_copy.is_null() {
+            ffi::avcodec_parameters_free(&mut params);
+            return Err(ffmpeg::Error::Unknown);
+        }
+
+        ptr::copy_nonoverlapping(extradata.as_ptr(), extradata_copy as *mut u8, extradata.len());
+        ptr::write_bytes(
+            (extradata_copy as *mut u8).add(extradata.len()),
+            0,
+            AV_INPUT_BUFFER_PADDING_SIZE,
+        );
+
+        // Freed along with the parameters
+        (*params).extradata = extradata_copy as *mut u8;
+        (*params).extradata_size = extradata.len() as i32;
+    }
 
-    (*params).extradata = extradata as *mut u8;
-    (*params).extradata_size = extradata_size as i32;
+    Ok(params)
+}
 
-    // Find the H.264 decoder
-    let codec = ffi::avcodec_find_decoder(ffi::AVCodecID::AV_CODEC_ID_H264);
+/*
+ * Opens a decoder for the codec parameters, which are freed either way
+ */
+unsafe fn open_decoder(
+    mut params: *mut ffi::AVCodecParameters,
+) -> Result<ffmpeg::codec::context::Context, ffmpeg::Error> {
+    // For AV1, this needs ffmpeg built with libdav1d or libaom
+    let codec = ffi::avcodec_find_decoder((*params).codec_id);
     if codec.is_null() {
-        ffi::av_free(extradata);
-        ffi::avcodec_parameters_free(&mut (params as *mut _));
+        ffi::avcodec_parameters_free(&mut params);
         return Err(ffmpeg::Error::DecoderNotFound);
     }
 
-    // Allocate codec context
     let ctx = ffi::avcodec_alloc_context3(codec);
     if ctx.is_null() {
-        ffi::av_free(extradata);
-        ffi::avcodec_parameters_free(&mut (params as *mut _));
+        ffi::avcodec_parameters_free(&mut params);
         return Err(ffmpeg::Error::Unknown);
     }
 
-    // Copy parameters to context
+    // The context gets its own copy of the parameters, extradata included
     let ret = ffi::avcodec_parameters_to_context(ctx, params);
+    ffi::avcodec_parameters_free(&mut params);
     if ret < 0 {
         ffi::avcodec_free_context(&mut (ctx as *mut _));
-        ffi::av_free(extradata);
-        ffi::avcodec_parameters_free(&mut (params as *mut _));
         return Err(ffmpeg::Error::from(ret));
     }
 
-    // Open the decoder
     let ret = ffi::avcodec_open2(ctx, codec, ptr::null_mut());
     if ret < 0 {
         ffi::avcodec_free_context(&mut (ctx as *mut _));
-        ffi::av_free(extradata);
-        ffi::avcodec_parameters_free(&mut (params as *mut _));
         return Err(ffmpeg::Error::from(ret));
     }
 
-    // Clean up parameters (extradata ownership transferred to context)
-    // Note: Don't free extradata here - it's now owned by the context
-    (*params).extradata = ptr::null_mut();
-    (*params).extradata_size = 0;
-    ffi::avcodec_parameters_free(&mut (params as *mut _));
-
-    // Wrap in safe Context type and get video decoder
-    let context = ffmpeg::codec::context::Context::wrap(ctx, None);
-    context.decoder().video()
+    Ok(ffmpeg::codec::context::Context::wrap(ctx, None))
 }
 
-/// Creates an AAC audio decoder.
+/// Creates a video decoder for the given codec, from the content of the
+/// avcC, hvcC or av1C box of its sample entry, for a video of the given dimensions.
+/// VP9 decoders take no extradata, so its vpcC box isn't passed on.
 ///
 /// # Safety
 /// This function uses raw FFmpeg FFI calls to allocate and configure codec contexts.
 /// The caller must ensure ffmpeg has been initialized via `ffmpeg::init()`.
-pub unsafe fn create_aac_decoder() -> Result<ffmpeg::decoder::Audio, ffmpeg::Error> {
-    // Allocate AVCodecParameters
-    let params = ffi::avcodec_parameters_alloc();
-    if params.is_null() {
-        return Err(ffmpeg::Error::Unknown);
-    }
-
-    // Set audio codec parameters
-    (*params).codec_type = ffi::AVMediaType::AVMEDIA_TYPE_AUDIO;
-    (*params).codec_id = ffi::AVCodecID::AV_CODEC_ID_AAC;
-    (*params).sample_rate = 44100;
-    (*params).format = ffi::AVSampleFormat::AV_SAMPLE_FMT_FLTP as i32;
-
-    // Set stereo channel layout
-    ffi::av_channel_layout_default(&mut (*params).ch_layout, 2);
-
-    // Find the AAC decoder
-    let codec = ffi::avcodec_find_decoder(ffi::AVCodecID::AV_CODEC_ID_AAC);
-    if codec.is_null() {
-        ffi::avcodec_parameters_free(&mut (params as *mut _));
-        return Err(ffmpeg::Error::DecoderNotFound);
-    }
-
-    // Allocate codec context
-    let ctx = ffi::avcodec_alloc_context3(codec);
-    if ctx.is_null() {
-        ffi::avcodec_parameters_free(&mut (params as *mut _));
-        return Err(ffmpeg::Error::Unknown);
-    }
-
-    // Copy parameters to context
-    let ret = ffi::avcodec_parameters_to_context(ctx, params);
-    if ret < 0 {
-        ffi::avcodec_free_context(&mut (ctx as *mut _));
-        ffi::avcodec_parameters_free(&mut (params as *mut _));
-        return Err(ffmpeg::Error::from(ret));
-    }
-
-    // Open the decoder
-    let ret = ffi::avcodec_open2(ctx, codec, ptr::null_mut());
-    if ret < 0 {
-        ffi::avcodec_free_context(&mut (ctx as *mut _));
-        ffi::avcodec_parameters_free(&mut (params as *mut _));
-        return Err(ffmpeg::Error::from(ret));
-    }
+pub unsafe fn create_video_decoder(
+    codec: VideoCodec,
+    codec_config: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<ffmpeg::decoder::Video, ffmpeg::Error> {
+    let extradata = match codec {
+        VideoCodec::Vp9 => &[][..],
+        _ => codec_config,
+    };
+
+    let params = alloc_parameters(
+        ffi::AVMediaType::AVMEDIA_TYPE_VIDEO,
+        video_codec_id(codec),
+        extradata,
+    )?;
+
+    (*params).width = width as i32;
+    (*params).height = height as i32;
+    (*params).format = ffi::AVPixelFormat::AV_PIX_FMT_YUV420P as i32;
 
-    // Clean up parameters
-    ffi::avcodec_parameters_free(&mut (params as *mut _));
+    open_decoder(params)?.decoder().video()
+}
 
-    // Wrap in safe Context type and get audio decoder
-    let context = ffmpeg::codec::context::Context::wrap(ctx, None);
-    context.decoder().audio()
+/// Creates an audio decoder for the given codec, from the AudioSpecificConfig of
+/// an AAC esds box or the OpusHead built from a dOps box. The sample rate and
+/// channel count of the sample entry are only hints, the decoded frames have the real ones.
+///
+/// # Safety
+/// This function uses raw FFmpeg FFI calls to allocate and configure codec contexts.
+/// The caller must ensure ffmpeg has been initialized via `ffmpeg::init()`.
+pub unsafe fn create_audio_decoder(
+    codec: AudioCodec,
+    codec_config: &[u8],
+    sample_rate: u32,
+    channels: u16,
+) -> Result<ffmpeg::decoder::Audio, ffmpeg::Error> {
+    let params = alloc_parameters(
+        ffi::AVMediaType::AVMEDIA_TYPE_AUDIO,
+        audio_codec_id(codec),
+        codec_config,
+    )?;
+
+    (*params).sample_rate = sample_rate as i32;
+    ffi::av_channel_layout_default(&mut (*params).ch_layout, channels as i32);
+
+    open_decoder(params)?.decoder().audio()
 }
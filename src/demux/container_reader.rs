@@ -0,0 +1,203 @@
+use std::io::{ErrorKind, Read};
+
+use crate::demux::box_reader::{BoxReader, BoxReaderEvent};
+use crate::demux::get_moov_box::{AudioCodec, VideoCodec};
+use crate::demux::get_sample_map::{parse_tkhd_dimensions, SampleData};
+use crate::demux::input_source::InputSource;
+use crate::demux::matroska_reader::{MatroskaEvent, MatroskaReader};
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::Res;
+
+/* First bytes of an EBML header, which Matroska and WebM files start with */
+const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+
+pub enum ContainerEvent {
+    /// The tracks were parsed, and can be read with ContainerReader::tracks
+    Tracks,
+    /// A sidx box or Cues element was parsed, so the input can now seek
+    SeekIndex,
+    /// The next sample of the input, with its bytes
+    Sample(SampleData, Vec<u8>),
+}
+
+/// What the demultiplexer needs to create the decoder of a track,
+/// whichever container it comes from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackConfig {
+    pub video_codec: Option<VideoCodec>,
+    pub audio_codec: Option<AudioCodec>,
+    pub codec_config: Option<Vec<u8>>,
+    pub dimensions: Option<(u32, u32)>,
+    /// Channel count and sample rate
+    pub audio_format: Option<(u16, u32)>,
+}
+
+/// Reads one input as MP4 or Matroska, depending on its first bytes.
+pub enum ContainerReader {
+    /// Not opened yet, so the container isn't known
+    Pending(Option<InputSource>),
+    Mp4(BoxReader),
+    Matroska(MatroskaReader),
+}
+
+impl ContainerReader {
+    pub fn new(source: InputSource) -> Self {
+        ContainerReader::Pending(Some(source))
+    }
+
+    pub fn tracks(&self) -> Vec<TrackConfig> {
+        match self {
+            ContainerReader::Pending(_) => vec![],
+            ContainerReader::Mp4(reader) => reader
+                .moov_box()
+                .map(|moov_box| {
+                    moov_box
+                        .traks
+                        .iter()
+                        .map(|trak| {
+                            let stsd = &trak.media.minf.stbl.stsd;
+
+                            TrackConfig {
+                                video_codec: stsd.video_codec,
+                                audio_codec: stsd.audio_codec,
+                                codec_config: stsd.codec_config.clone(),
+                                // The sample entry has the coded size, tkhd the presentation size
+                                dimensions: stsd
+                                    .dimensions
+                                    .map(|(width, height)| (width as u32, height as u32))
+                                    .or_else(|| Some(parse_tkhd_dimensions(&trak.tkhd))),
+                                audio_format: stsd.audio_format,
+                            }
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            ContainerReader::Matroska(reader) => reader
+                .played_tracks()
+                .map(|track| TrackConfig {
+                    video_codec: track.video_codec(),
+                    audio_codec: track.audio_codec(),
+                    codec_config: track.codec_private.clone(),
+                    dimensions: track.dimensions,
+                    audio_format: track.audio_format,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn duration_in_ms(&self) -> u64 {
+        match self {
+            ContainerReader::Pending(_) => 0,
+            ContainerReader::Mp4(reader) => reader.duration_in_ms(),
+            ContainerReader::Matroska(reader) => reader.duration_in_ms(),
+        }
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        match self {
+            ContainerReader::Pending(_) => false,
+            ContainerReader::Mp4(reader) => reader.is_seekable(),
+            ContainerReader::Matroska(reader) => reader.is_seekable(),
+        }
+    }
+
+    pub fn seek(&mut self, target_ms: u64) -> Res<u64> {
+        match self {
+            ContainerReader::Pending(_) => {
+                Err(PlaybackError::Input("Input is not seekable".to_string()))
+            }
+            ContainerReader::Mp4(reader) => reader.seek(target_ms),
+            ContainerReader::Matroska(reader) => reader.seek(target_ms),
+        }
+    }
+
+    pub fn close(&mut self) {
+        match self {
+            ContainerReader::Pending(_) => {}
+            ContainerReader::Mp4(reader) => reader.close(),
+            ContainerReader::Matroska(reader) => reader.close(),
+        }
+    }
+
+    /*
+     * Returns the next tracks, seek index or sample of the input,
+     * or None once every sample has been read
+     */
+    pub fn next_event(&mut self) -> Res<Option<ContainerEvent>> {
+        if let ContainerReader::Pending(source) = self {
+            let source = source
+                .take()
+                .ok_or_else(|| PlaybackError::Input("Input could not be opened".to_string()))?;
+            *self = Self::detect(source)?;
+        }
+
+        match self {
+            ContainerReader::Pending(_) => Ok(None),
+            ContainerReader::Mp4(reader) => Ok(reader.next_event()?.map(|event| match event {
+                BoxReaderEvent::Moov => ContainerEvent::Tracks,
+                BoxReaderEvent::SegmentIndex => ContainerEvent::SeekIndex,
+                BoxReaderEvent::Sample(sample_data, sample) => {
+                    ContainerEvent::Sample(sample_data, sample)
+                }
+            })),
+            ContainerReader::Matroska(reader) => {
+                Ok(reader.next_event()?.map(|event| match event {
+                    MatroskaEvent::Tracks => ContainerEvent::Tracks,
+                    MatroskaEvent::Cues => ContainerEvent::SeekIndex,
+                    MatroskaEvent::Frame(sample_data, frame) => {
+                        ContainerEvent::Sample(sample_data, frame)
+                    }
+                }))
+            }
+        }
+    }
+
+    /*
+     * Opens the input and reads its first bytes to pick the reader,
+     * which then continues from these bytes since streams can't be reopened
+     */
+    fn detect(mut source: InputSource) -> Res<Self> {
+        let mut input = source.open()?;
+        let mut first_bytes = vec![0; EBML_MAGIC.len()];
+        let mut bytes_read = 0;
+
+        while bytes_read < first_bytes.len() {
+            match input.read(&mut first_bytes[bytes_read..]) {
+                Ok(0) => break,
+                Ok(count) => bytes_read += count,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(PlaybackError::Input(format!("Could not read input: {}", e)));
+                }
+            }
+        }
+
+        first_bytes.truncate(bytes_read);
+
+        Ok(if first_bytes == EBML_MAGIC {
+            ContainerReader::Matroska(MatroskaReader::from_opened(source, input, first_bytes))
+        } else {
+            ContainerReader::Mp4(BoxReader::from_opened(source, input, first_bytes))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_detects_container_from_first_bytes() {
+        let webm = vec![0x1A, 0x45, 0xDF, 0xA3, 0x80];
+        let mut reader = ContainerReader::new(InputSource::from_reader(Cursor::new(webm)));
+        // An EBML header without tracks
+        assert!(reader.next_event().is_err());
+        assert!(matches!(reader, ContainerReader::Matroska(_)));
+
+        let mp4 = vec![0x00, 0x00, 0x00, 0x08, b'f', b'r', b'e', b'e'];
+        let mut reader = ContainerReader::new(InputSource::from_reader(Cursor::new(mp4)));
+        assert!(reader.next_event().is_err());
+        assert!(matches!(reader, ContainerReader::Mp4(_)));
+    }
+}
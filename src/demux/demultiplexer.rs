@@ -1,63 +1,89 @@
 use ffmpeg_next::{self as ffmpeg, frame, Packet};
-use std::io::Read;
-use std::process::{Command, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Sender, SyncSender};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use std::usize;
 
 use crate::demux::codec_context;
-use crate::demux::get_moov_box::{get_moov_box, FTYPBox, MOOVBox, Streams};
+use crate::demux::container_reader::{ContainerEvent, ContainerReader, TrackConfig};
+use crate::demux::get_moov_box::VideoCodec;
+use crate::demux::input_source::InputSource;
+use crate::helpers::error::PlaybackError;
+use crate::helpers::seek::SeekControl;
+use crate::helpers::types::{AudioFormat, BytesWithTimestamp, Res, SampleFormat};
 
-use crate::demux::get_sample_map::get_sample_map;
-use crate::helpers::types::{BytesWithTimestamp, Res};
-
-use super::get_sample_map::SampleMap;
+use super::get_sample_map::SampleData;
 
 pub enum RawAudioMessage {
-    AudioMessage(BytesWithTimestamp),
+    /// Decoded samples, laid out as described by their format
+    AudioMessage {
+        samples: BytesWithTimestamp,
+        format: AudioFormat,
+    },
+    /// Everything sent before this belongs to the position before a seek
+    Flush(usize),
     Done,
 }
 
 pub enum RawVideoMessage {
-    VideoMessage(BytesWithTimestamp),
+    /// A packed RGB24 frame and its dimensions in pixels
+    VideoMessage {
+        frame: BytesWithTimestamp,
+        width: usize,
+        height: usize,
+    },
+    /// Everything sent before this belongs to the position before a seek
+    Flush(usize),
     Done,
 }
 
 pub struct Demultiplexer {
-    pub url: String,
-    pub raw_video_message_tx: Sender<RawVideoMessage>,
+    pub input: Option<InputSource>,
+    pub audio_input: Option<InputSource>,
+    pub raw_video_message_tx: SyncSender<RawVideoMessage>,
     pub raw_audio_message_tx: Sender<RawAudioMessage>,
     pub video_decoder: Option<ffmpeg::decoder::Video>,
     pub audio_decoder: Option<ffmpeg::decoder::Audio>,
     pub nal_length_size: u8,
-    frame_interval_ms: Option<usize>,
-    sample_interval_ms: usize,
+    video_codec: Option<VideoCodec>,
+    converter: Option<ffmpeg::software::scaling::Context>,
+    video_width: u32,
+    video_height: u32,
+    video_timescale: u32,
+    video_timestamp_in_ms: usize,
+    audio_timestamp_in_ms: usize,
+    /// Where the last decoded samples end, which the samples drained at the end follow
+    audio_end_in_ms: i64,
     cancel_flag: Option<Arc<AtomicBool>>,
+    seek_control: Option<Arc<SeekControl>>,
 }
 
 impl Demultiplexer {
     pub fn new(
-        raw_video_message_tx: Sender<RawVideoMessage>,
+        raw_video_message_tx: SyncSender<RawVideoMessage>,
         raw_audio_message_tx: Sender<RawAudioMessage>,
-        url: String,
+        input: InputSource,
     ) -> Self {
-        let audio_bytes_per_second = 44100 * 2 * 4;
-        let audio_sample_size = 8192;
-
-        let samples_per_second = audio_bytes_per_second / audio_sample_size;
-        let sample_interval_ms = 1000 / samples_per_second;
-
         Self {
             raw_video_message_tx,
             raw_audio_message_tx,
             video_decoder: None,
             audio_decoder: None,
             nal_length_size: 4,
-            url,
-            frame_interval_ms: None,
-            sample_interval_ms,
+            video_codec: None,
+            input: Some(input),
+            audio_input: None,
+            converter: None,
+            video_width: 0,
+            video_height: 0,
+            video_timescale: 1000,
+            video_timestamp_in_ms: 0,
+            audio_timestamp_in_ms: 0,
+            audio_end_in_ms: 0,
             cancel_flag: None,
+            seek_control: None,
         }
     }
 
@@ -65,6 +91,16 @@ impl Demultiplexer {
         self.cancel_flag = Some(flag);
     }
 
+    pub fn set_seek_control(&mut self, seek_control: Arc<SeekControl>) {
+        self.seek_control = Some(seek_control);
+    }
+
+    /// Reads audio from a separate stream, e.g. an audio-only DASH format,
+    /// instead of from the audio track of the main input
+    pub fn set_audio_input(&mut self, input: InputSource) {
+        self.audio_input = Some(input);
+    }
+
     fn is_cancelled(&self) -> bool {
         self.cancel_flag
             .as_ref()
@@ -84,10 +120,28 @@ impl Demultiplexer {
     }
 
     /*
-     * Converts from a NAL with its length at the beginning
+     * Reads the size of the length in front of each NAL unit from an avcC or hvcC box,
+     * VP9 and AV1 samples are sent to the decoder as they are
+     */
+    fn nal_length_size(&self, codec: VideoCodec, codec_config: &[u8]) -> Res<Option<u8>> {
+        let index = match codec {
+            VideoCodec::H264 => 4,
+            VideoCodec::Hevc => 21,
+            VideoCodec::Vp9 | VideoCodec::Av1 => return Ok(None),
+        };
+
+        let byte = codec_config.get(index).ok_or_else(|| {
+            PlaybackError::Container(format!("{} box is too short", codec.config_box_title()))
+        })?;
+
+        Ok(Some(self.get_bit(*byte, 0) + self.get_bit(*byte, 1) * 2 + 1))
+    }
+
+    /*
+     * Converts from a H.264 or HEVC NAL with its length at the beginning
      * to a NAL with a start code at the beginning (as expected by decoder)
      */
-    fn convert_avcc_to_annexb(&self, data: &[u8]) -> Vec<u8> {
+    fn convert_to_annexb(&self, data: &[u8]) -> Vec<u8> {
         let mut result = Vec::new();
         let mut offset = 0;
 
@@ -119,323 +173,500 @@ impl Demultiplexer {
         result
     }
 
-    pub fn demux(&mut self) -> Res<()> {
+    /*
+     * Copies the rows of a decoded RGB24 plane, leaving out the padding
+     * the decoder may add at the end of each row
+     */
+    fn packed_rgb_data(data: &[u8], stride: usize, width: usize, height: usize) -> Vec<u8> {
+        let row_size = width * 3;
+
+        if stride == row_size {
+            return data[..row_size * height].to_vec();
+        }
+
+        data.chunks(stride)
+            .take(height)
+            .flat_map(|row| &row[..row_size])
+            .copied()
+            .collect()
+    }
+
+    /*
+     * Maps the sample format of the decoder, along with whether it is planar
+     */
+    fn sample_format(format: ffmpeg::format::Sample) -> Option<(SampleFormat, bool)> {
+        use ffmpeg::format::sample::Type;
+        use ffmpeg::format::Sample;
+
+        let (sample_format, sample_type) = match format {
+            Sample::U8(sample_type) => (SampleFormat::U8, sample_type),
+            Sample::I16(sample_type) => (SampleFormat::I16, sample_type),
+            Sample::I32(sample_type) => (SampleFormat::I32, sample_type),
+            Sample::F32(sample_type) => (SampleFormat::F32, sample_type),
+            Sample::F64(sample_type) => (SampleFormat::F64, sample_type),
+            _ => return None,
+        };
+
+        Some((sample_format, sample_type == Type::Planar))
+    }
+
+    /*
+     * Copies the samples of a decoded audio frame, leaving out the padding
+     * at the end of its planes, along with the format they are in
+     */
+    fn audio_frame_data(frame: &frame::Audio) -> Option<(Vec<u8>, AudioFormat)> {
+        let (sample_format, planar) = Self::sample_format(frame.format())?;
+        let channels = frame.channels();
+
+        if channels == 0 || frame.rate() == 0 {
+            return None;
+        }
+
+        let bytes_per_sample = sample_format.bytes_per_sample();
+        let (planes, plane_size) = if planar {
+            (channels as usize, frame.samples() * bytes_per_sample)
+        } else {
+            (1, frame.samples() * channels as usize * bytes_per_sample)
+        };
+
         /*
-         * This starts the yt-dlp program for a given url, looking for format 18
-         * Format 18 corresponds to a mp4 file with audio and video tracks
-         * Video is encoded in H264, at 640x360
-         * Audio is encoded in AAC-LC
+         * Only the size of the first plane is set for audio, so frame.data can't be used
+         * past it. extended_data also holds the planes past the eighth one.
          */
-        let mut yt_dlp_process = Command::new("yt-dlp")
-            .args(["-o", "-", "--no-part", "-f", "18", &self.url])
-            .stderr(Stdio::null())
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("Could not start yt-dlp process");
+        let mut data = Vec::with_capacity(planes * plane_size);
+        for plane in 0..planes {
+            unsafe {
+                let plane_data = *(*frame.as_ptr()).extended_data.add(plane);
+                data.extend_from_slice(std::slice::from_raw_parts(plane_data, plane_size));
+            }
+        }
+
+        let format = AudioFormat {
+            sample_rate: frame.rate(),
+            channels,
+            sample_format,
+            planar,
+        };
+
+        Some((data, format))
+    }
+
+    /*
+     * Converts the presentation timestamp of a decoded frame,
+     * in the timescale of its track, to milliseconds
+     */
+    fn frame_timestamp_in_ms(timestamp: Option<i64>, timescale: u32) -> Option<usize> {
+        timestamp.map(|timestamp| (timestamp.max(0) as u64 * 1000 / timescale.max(1) as u64) as usize)
+    }
+
+    /*
+     * Creates the decoders from the tracks of the input
+     * Only the video or audio one is created when the other stream comes from another input
+     */
+    fn setup_decoders(&mut self, tracks: &[TrackConfig], video: bool, audio: bool) -> Res<()> {
+        if video {
+            for track in tracks {
+                let Some(codec) = track.video_codec else {
+                    continue;
+                };
+
+                // H.264 and HEVC need their configuration box, VP9 and AV1 can do without
+                let codec_config = match track.codec_config {
+                    Some(ref codec_config) => codec_config.as_slice(),
+                    None if matches!(codec, VideoCodec::Vp9 | VideoCodec::Av1) => &[],
+                    None => continue,
+                };
+
+                if let Some(nal_length_size) = self.nal_length_size(codec, codec_config)? {
+                    self.nal_length_size = nal_length_size;
+                }
+                self.video_codec = Some(codec);
+
+                let (width, height) = track.dimensions.unwrap_or_default();
+
+                unsafe {
+                    self.video_decoder = Some(
+                        codec_context::create_video_decoder(codec, codec_config, width, height)
+                            .map_err(|e| {
+                                PlaybackError::Codec(format!(
+                                    "Could not create the {:?} video decoder: {}",
+                                    codec, e
+                                ))
+                            })?,
+                    );
+                }
+            }
+        }
+
+        if audio {
+            for track in tracks {
+                let Some(codec) = track.audio_codec else {
+                    continue;
+                };
+
+                let codec_config = track.codec_config.as_deref().unwrap_or(&[]);
+                let (channels, sample_rate) = track.audio_format.unwrap_or((2, 44100));
+
+                unsafe {
+                    self.audio_decoder = Some(
+                        codec_context::create_audio_decoder(
+                            codec,
+                            codec_config,
+                            sample_rate,
+                            channels,
+                        )
+                        .map_err(|e| {
+                            PlaybackError::Codec(format!(
+                                "Could not create the {:?} audio decoder: {}",
+                                codec, e
+                            ))
+                        })?,
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Decodes a sample and sends the resulting frames downstream
+     */
+    fn process_sample(&mut self, sample_data: &SampleData, sample: Vec<u8>) -> Res<()> {
+        if sample_data.is_video {
+            self.video_timescale = sample_data.timescale.max(1);
+            self.video_timestamp_in_ms = sample_data.decode_time_in_ms() as usize;
+
+            let packet_data = match self.video_codec {
+                Some(VideoCodec::H264 | VideoCodec::Hevc) => self.convert_to_annexb(&sample),
+                _ => sample,
+            };
+
+            if !packet_data.is_empty() {
+                // In the track timescale, the decoder hands them back in presentation order
+                let mut packet = Packet::copy(&packet_data);
+                packet.set_pts(Some(sample_data.presentation_time()));
+                packet.set_dts(Some(sample_data.decode_time as i64));
+
+                // A damaged sample only costs the frames that depend on it
+                let sent = self
+                    .video_decoder
+                    .as_mut()
+                    .is_some_and(|video_decoder| video_decoder.send_packet(&packet).is_ok());
+                if sent {
+                    self.receive_video_frames()?;
+                }
+            }
+        } else {
+            let packet = Packet::copy(&sample);
+
+            let sent = self
+                .audio_decoder
+                .as_mut()
+                .is_some_and(|audio_decoder| audio_decoder.send_packet(&packet).is_ok());
+            if sent {
+                self.receive_audio_frames(sample_data.presentation_time_in_ms());
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Sends the frames the decoders still hold once the input ends or is left for a seek,
+     * e.g. the frames a B-frame stream keeps back to reorder them
+     */
+    fn drain_decoders(&mut self) -> Res<()> {
+        let video_draining = self
+            .video_decoder
+            .as_mut()
+            .is_some_and(|video_decoder| video_decoder.send_eof().is_ok());
+        if video_draining {
+            self.receive_video_frames()?;
+        }
+
+        let audio_draining = self
+            .audio_decoder
+            .as_mut()
+            .is_some_and(|audio_decoder| audio_decoder.send_eof().is_ok());
+        if audio_draining {
+            self.receive_audio_frames(self.audio_end_in_ms);
+        }
+
+        Ok(())
+    }
+
+    /* Converts the frames the video decoder has ready to RGB and sends them downstream */
+    fn receive_video_frames(&mut self) -> Res<()> {
+        let Some(ref mut video_decoder) = self.video_decoder else {
+            return Ok(());
+        };
+
+        let mut yup_frame = frame::Video::empty();
+        let mut rgb_frame = frame::Video::empty();
+        while video_decoder.receive_frame(&mut yup_frame).is_ok() {
+            let (width, height) = (yup_frame.width(), yup_frame.height());
+
+            // (Re)create the converter whenever the decoded size changes
+            if self.converter.is_none() || width != self.video_width || height != self.video_height {
+                let converter = ffmpeg::software::scaling::Context::get(
+                    yup_frame.format(),
+                    width,
+                    height,
+                    ffmpeg::format::Pixel::RGB24,
+                    width,
+                    height,
+                    ffmpeg::software::scaling::Flags::BILINEAR,
+                )
+                .map_err(|error| {
+                    PlaybackError::Codec(format!(
+                        "Could not convert {}x{} frames to RGB: {}",
+                        width, height, error
+                    ))
+                })?;
+
+                self.converter = Some(converter);
+                self.video_width = width;
+                self.video_height = height;
+            }
+
+            // A frame that fails to convert is skipped, like a damaged sample
+            let converted = self
+                .converter
+                .as_mut()
+                .is_some_and(|conv| conv.run(&yup_frame, &mut rgb_frame).is_ok());
+
+            if !converted {
+                yup_frame = frame::Video::empty();
+                rgb_frame = frame::Video::empty();
+                continue;
+            }
+
+            let data = Self::packed_rgb_data(
+                rgb_frame.data(0),
+                rgb_frame.stride(0),
+                width as usize,
+                height as usize,
+            );
+
+            // A stage that stopped early has cancelled playback, or will report why it failed
+            self.raw_video_message_tx
+                .send(RawVideoMessage::VideoMessage {
+                    frame: BytesWithTimestamp {
+                        data,
+                        timestamp_in_ms: Self::frame_timestamp_in_ms(
+                            yup_frame.timestamp(),
+                            self.video_timescale,
+                        )
+                        .unwrap_or(self.video_timestamp_in_ms),
+                    },
+                    width: width as usize,
+                    height: height as usize,
+                })
+                .ok();
+
+            yup_frame = frame::Video::empty();
+            rgb_frame = frame::Video::empty();
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Sends the samples the audio decoder has ready downstream,
+     * timed from packet_time_in_ms on in the order they come
+     */
+    fn receive_audio_frames(&mut self, packet_time_in_ms: i64) {
+        let Some(ref mut audio_decoder) = self.audio_decoder else {
+            return;
+        };
+
+        let mut samples_in_packet = 0;
+        let mut frame = frame::Audio::empty();
+        while audio_decoder.receive_frame(&mut frame).is_ok() {
+            let Some((data, format)) = Self::audio_frame_data(&frame) else {
+                frame = frame::Audio::empty();
+                continue;
+            };
+
+            let rate = frame.rate().max(1) as i64;
+            let start_ms = packet_time_in_ms + samples_in_packet * 1000 / rate;
+            samples_in_packet += frame.samples() as i64;
+            let end_ms = packet_time_in_ms + samples_in_packet * 1000 / rate;
+            self.audio_end_in_ms = end_ms;
+
+            // Priming samples that the edit list cuts from the start
+            if end_ms <= 0 {
+                frame = frame::Audio::empty();
+                continue;
+            }
 
-        let mut yt_dlp_stdout = yt_dlp_process.stdout.take().unwrap();
+            self.audio_timestamp_in_ms = start_ms.max(0) as usize;
 
-        let mut buffer = vec![0; 1000000];
+            self.raw_audio_message_tx
+                .send(RawAudioMessage::AudioMessage {
+                    samples: BytesWithTimestamp {
+                        data,
+                        timestamp_in_ms: self.audio_timestamp_in_ms,
+                    },
+                    format,
+                })
+                .ok();
 
-        let mut accumulated_data: Vec<u8> = vec![];
+            frame = frame::Audio::empty();
+        }
+    }
 
-        let mut ftyp_box = None;
-        let mut sample_map: Option<SampleMap> = None;
+    pub fn demux(&mut self) -> Res<()> {
+        let input = self
+            .input
+            .take()
+            .ok_or_else(|| PlaybackError::Input("Input has already been demultiplexed".to_string()))?;
 
-        let mut mdat_reached = false;
+        let mut video_reader = ContainerReader::new(input);
+        let mut audio_reader = self.audio_input.take().map(ContainerReader::new);
 
-        // Converter will be initialized after video decoder is created from moov data
-        let mut converter: Option<ffmpeg::software::scaling::Context> = None;
+        // With a separate audio stream, each stream only feeds its own decoder
+        let separate_streams = audio_reader.is_some();
 
-        let mut audio_timestamp_in_ms = 0;
-        let mut video_timestamp_in_ms = 0;
+        let mut video_done = false;
+        let mut audio_done = !separate_streams;
+        let mut done_sent = false;
 
         loop {
             if self.is_cancelled() {
-                let _ = yt_dlp_process.kill();
+                video_reader.close();
+                if let Some(ref mut audio_reader) = audio_reader {
+                    audio_reader.close();
+                }
                 self.raw_video_message_tx.send(RawVideoMessage::Done).ok();
                 self.raw_audio_message_tx.send(RawAudioMessage::Done).ok();
                 return Ok(());
             }
 
-            match yt_dlp_stdout.read(&mut buffer) {
-                Ok(0) => {
-                    break;
+            let seek_request = self
+                .seek_control
+                .as_ref()
+                .and_then(|seek_control| seek_control.take_request());
+
+            if let Some((target_ms, generation)) = seek_request {
+                // Unless they were drained already when the end was reached
+                if !done_sent {
+                    self.drain_decoders()?;
+                }
+                self.seek(&mut video_reader, audio_reader.as_mut(), target_ms, generation)?;
+
+                video_done = false;
+                audio_done = !separate_streams;
+                done_sent = false;
+            }
+
+            if video_done && audio_done {
+                if !done_sent {
+                    self.drain_decoders()?;
+                    self.raw_video_message_tx.send(RawVideoMessage::Done).ok();
+                    self.raw_audio_message_tx.send(RawAudioMessage::Done).ok();
+                    done_sent = true;
                 }
-                Ok(bytes_read) => {
-                    accumulated_data.extend_from_slice(&buffer[..bytes_read]);
-
-                    if !mdat_reached {
-                        while accumulated_data.len() >= 8 {
-                            let box_size_bytes: [u8; 4] = [
-                                accumulated_data[0],
-                                accumulated_data[1],
-                                accumulated_data[2],
-                                accumulated_data[3],
-                            ];
-
-                            let box_size = u32::from_be_bytes(box_size_bytes);
-
-                            let box_title_bytes: [u8; 4] = [
-                                accumulated_data[4],
-                                accumulated_data[5],
-                                accumulated_data[6],
-                                accumulated_data[7],
-                            ];
-
-                            let box_title = String::from_utf8_lossy(&box_title_bytes);
-
-                            if box_title.to_string().as_str() != "mdat"
-                                && accumulated_data.len() < box_size as usize
-                            {
-                                break;
-                            }
-
-                            accumulated_data.drain(..8);
-
-                            match box_title.to_string().as_str() {
-                                "ftyp" => {
-                                    ftyp_box = Some(FTYPBox {
-                                        size: box_size,
-                                        data: accumulated_data
-                                            .drain(..(box_size - 8) as usize)
-                                            .collect(),
-                                    });
-
-                                    assert_eq!(box_size, ftyp_box.as_ref().unwrap().size);
-                                }
-                                "moov" => {
-                                    let moov_box: Option<MOOVBox>;
-
-                                    match get_moov_box(
-                                        box_size,
-                                        accumulated_data.drain(..(box_size - 8) as usize).collect(),
-                                    ) {
-                                        Ok(ok_box) => {
-                                            moov_box = Some(ok_box);
-                                        }
-                                        Err(error) => {
-                                            panic!("{}", error);
-                                        }
-                                    }
-
-                                    assert_eq!(box_size, moov_box.as_ref().unwrap().size);
-
-                                    for trak in &moov_box.as_ref().unwrap().traks {
-                                        if let Some(ref avcc_data) = trak.media.minf.stbl.stsd.avcc
-                                        {
-                                            self.nal_length_size = self.get_bit(avcc_data[4], 0)
-                                                + self.get_bit(avcc_data[4], 1) * 2
-                                                + 1;
-
-                                            // Initialize H.264 video decoder from avcC extradata
-                                            unsafe {
-                                                self.video_decoder = Some(
-                                                    codec_context::create_h264_decoder(avcc_data)
-                                                        .expect("Failed to create H264 decoder"),
-                                                );
-                                            }
-
-                                            // Initialize converter now that video decoder exists
-                                            converter = Some(
-                                                self.video_decoder
-                                                    .as_ref()
-                                                    .unwrap()
-                                                    .converter(ffmpeg_next::format::Pixel::RGB24)
-                                                    .unwrap(),
-                                            );
-                                        }
-
-                                        if let Streams::Video = trak.media.minf.header {
-                                            let mdhd = trak.media.mdhd.clone();
-
-                                            let version_byte = mdhd.data[0];
-
-                                            let timescale: u32;
-
-                                            match version_byte {
-                                                0 => {
-                                                    let timescale_bytes: [u8; 4] =
-                                                        mdhd.data[12..=15].try_into().unwrap();
-
-                                                    timescale = u32::from_be_bytes(timescale_bytes);
-                                                }
-                                                1 => {
-                                                    let timescale_bytes: [u8; 4] =
-                                                        mdhd.data[20..=23].try_into().unwrap();
-
-                                                    timescale = u32::from_be_bytes(timescale_bytes);
-                                                }
-                                                _ => {
-                                                    panic!("Unknown mdhd version");
-                                                }
-                                            }
-
-                                            let mut stts = trak.media.minf.stbl.stts.clone();
-
-                                            stts.data.drain(..4);
-
-                                            let entry_count_bytes: [u8; 4] = stts
-                                                .data
-                                                .drain(..4)
-                                                .collect::<Vec<u8>>()
-                                                .try_into()
-                                                .unwrap();
-
-                                            let entry_count: u32 =
-                                                u32::from_be_bytes(entry_count_bytes);
-
-                                            assert_eq!(entry_count, 1);
-
-                                            stts.data.drain(..4);
-
-                                            let sample_delta_bytes: [u8; 4] = stts
-                                                .data
-                                                .drain(..4)
-                                                .collect::<Vec<u8>>()
-                                                .try_into()
-                                                .unwrap();
-
-                                            let sample_delta: u32 =
-                                                u32::from_be_bytes(sample_delta_bytes);
-
-                                            self.frame_interval_ms =
-                                                Some(1000 / (timescale / sample_delta) as usize);
-                                        }
-                                    }
-
-                                    // Initialize AAC audio decoder
-                                    unsafe {
-                                        self.audio_decoder = Some(
-                                            codec_context::create_aac_decoder()
-                                                .expect("Failed to create AAC decoder"),
-                                        );
-                                    }
-
-                                    sample_map = Some(get_sample_map(moov_box.unwrap()).unwrap());
-                                }
-                                "mdat" => {
-                                    if ftyp_box.is_none() {
-                                        println!("We are f'ed in the B by ftyp");
-                                    }
-                                    if sample_map.clone().is_none() {
-                                        println!("We are f'ed in the B by moov");
-                                    }
-
-                                    mdat_reached = true;
-
-                                    break;
-                                }
-                                _ => {
-                                    panic!(
-                                        "So this is new, we got a {} box",
-                                        box_title.to_string()
-                                    );
-                                }
-                            }
-                        }
+
+                // Playback lags behind the demultiplexer, so wait for seeks until the last frame is shown
+                match self.seek_control {
+                    Some(ref seek_control) if !seek_control.is_finished() => {
+                        thread::sleep(Duration::from_millis(16));
+                        continue;
                     }
+                    _ => return Ok(()),
+                }
+            }
+
+            // Read from whichever stream is behind, so both share one timeline
+            let from_audio_reader = !audio_done
+                && (video_done || self.audio_timestamp_in_ms < self.video_timestamp_in_ms);
 
-                    if sample_map.is_some() {
-                        while !sample_map.as_ref().unwrap().is_empty()
-                            && accumulated_data.len()
-                                >= sample_map.as_ref().unwrap().front().unwrap().size as usize
-                        {
-                            let current_sample_data =
-                                sample_map.as_mut().unwrap().pop_front().unwrap();
-
-                            let sample: Vec<u8> = accumulated_data
-                                .drain(..current_sample_data.size as usize)
-                                .collect();
-
-                            if current_sample_data.is_video {
-                                let annexb_data = self.convert_avcc_to_annexb(&sample);
-                                if !annexb_data.is_empty() {
-                                    let packet = Packet::copy(&annexb_data);
-
-                                    if let Some(ref mut video_decoder) = self.video_decoder {
-                                        match video_decoder.send_packet(&packet) {
-                                            Ok(_) => {
-                                                let mut yup_frame = frame::Video::empty();
-                                                let mut rgb_frame = frame::Video::empty();
-                                                while video_decoder
-                                                    .receive_frame(&mut yup_frame)
-                                                    .is_ok()
-                                                {
-                                                    if let Some(ref mut conv) = converter {
-                                                        let _ = conv.run(&yup_frame, &mut rgb_frame);
-                                                    }
-                                                    let data = rgb_frame.data(0);
-
-                                                    assert_eq!(data.len(), 640 * 360 * 3);
-
-                                                    self.raw_video_message_tx
-                                                        .send(RawVideoMessage::VideoMessage(
-                                                            BytesWithTimestamp {
-                                                                data: data.to_vec(),
-                                                                timestamp_in_ms: video_timestamp_in_ms,
-                                                            },
-                                                        ))
-                                                        .unwrap();
-
-                                                    video_timestamp_in_ms +=
-                                                        self.frame_interval_ms.unwrap();
-                                                    yup_frame = frame::Video::empty();
-                                                    rgb_frame = frame::Video::empty();
-                                                }
-                                            }
-                                            Err(e) => {
-                                                println!("Failed to send video packet: {:?}", e);
-                                            }
-                                        }
-                                    }
-                                }
-                            } else {
-                                let packet = Packet::copy(&sample);
-
-                                if let Some(ref mut audio_decoder) = self.audio_decoder {
-                                    match audio_decoder.send_packet(&packet) {
-                                        Ok(_) => {
-                                            let mut frame = frame::Audio::empty();
-                                            while audio_decoder.receive_frame(&mut frame).is_ok() {
-                                                let data = frame.data(0);
-
-                                                assert_eq!(data.len(), 8192);
-
-                                                self.raw_audio_message_tx
-                                                    .send(RawAudioMessage::AudioMessage(
-                                                        BytesWithTimestamp {
-                                                            data: data.to_vec(),
-                                                            timestamp_in_ms: audio_timestamp_in_ms,
-                                                        },
-                                                    ))
-                                                    .unwrap();
-
-                                                audio_timestamp_in_ms += self.sample_interval_ms;
-                                                frame = frame::Audio::empty();
-                                            }
-                                        }
-                                        Err(e) => {
-                                            println!("Failed to send audio packet: {:?}", e);
-                                        }
-                                    }
-                                }
-                            }
-                        }
+            let (reader, done) = match audio_reader {
+                Some(ref mut audio_reader) if from_audio_reader => (audio_reader, &mut audio_done),
+                _ => (&mut video_reader, &mut video_done),
+            };
+
+            match reader.next_event()? {
+                Some(ContainerEvent::Tracks) => {
+                    self.setup_decoders(
+                        &reader.tracks(),
+                        !from_audio_reader,
+                        from_audio_reader || !separate_streams,
+                    )?;
+
+                    self.update_seek_control(&video_reader, audio_reader.as_ref());
+                }
+                Some(ContainerEvent::SeekIndex) => {
+                    self.update_seek_control(&video_reader, audio_reader.as_ref());
+                }
+                Some(ContainerEvent::Sample(sample_data, sample)) => {
+                    if !separate_streams || sample_data.is_video != from_audio_reader {
+                        self.process_sample(&sample_data, sample)?;
                     }
                 }
-                Err(e) => {
-                    eprintln!("Error reading from yt-dlp: {}", e);
+                None => {
+                    *done = true;
                 }
             }
         }
+    }
+
+    /*
+     * Tells the seek control whether the inputs can seek, and how long the video is
+     */
+    fn update_seek_control(
+        &self,
+        video_reader: &ContainerReader,
+        audio_reader: Option<&ContainerReader>,
+    ) {
+        if let Some(ref seek_control) = self.seek_control {
+            seek_control.set_seekable(
+                video_reader.is_seekable()
+                    && audio_reader.is_none_or(|audio_reader| audio_reader.is_seekable()),
+            );
+            seek_control.set_duration_ms(video_reader.duration_in_ms() as usize);
+        }
+    }
+
+    /*
+     * Restarts the inputs at the closest keyframe before target_ms,
+     * then flushes the decoders and tells the next stages to drop what they have queued.
+     * An input that fails to seek or reopen ends playback with the error, rather than
+     * leaving it closed or the audio playing from the old position.
+     */
+    fn seek(
+        &mut self,
+        video_reader: &mut ContainerReader,
+        audio_reader: Option<&mut ContainerReader>,
+        target_ms: usize,
+        generation: usize,
+    ) -> Res<()> {
+        let resume_ms = video_reader.seek(target_ms as u64)?;
+
+        // The audio stream starts at the video keyframe, not at the target
+        if let Some(audio_reader) = audio_reader {
+            audio_reader.seek(resume_ms)?;
+        }
+
+        self.video_timestamp_in_ms = resume_ms as usize;
+        self.audio_timestamp_in_ms = resume_ms as usize;
+
+        if let Some(ref mut video_decoder) = self.video_decoder {
+            video_decoder.flush();
+        }
+        if let Some(ref mut audio_decoder) = self.audio_decoder {
+            audio_decoder.flush();
+        }
 
         self.raw_video_message_tx
-            .send(RawVideoMessage::Done)
-            .unwrap();
+            .send(RawVideoMessage::Flush(generation))
+            .ok();
         self.raw_audio_message_tx
-            .send(RawAudioMessage::Done)
-            .unwrap();
+            .send(RawAudioMessage::Flush(generation))
+            .ok();
+
         Ok(())
     }
 }
@@ -443,7 +674,8 @@ impl Demultiplexer {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::sync::mpsc::channel;
+    use crate::demux::input_source::DEFAULT_FORMAT;
+    use std::sync::mpsc::{channel, sync_channel};
 
     #[test]
     fn test_get_bit() {
@@ -469,6 +701,24 @@ mod tests {
         demux.get_bit(0xFF, 8); // Should panic
     }
 
+    #[test]
+    fn test_nal_length_size() {
+        let demux = create_test_demux();
+
+        let mut avcc = vec![0x01, 0x64, 0x00, 0x1F, 0xFF];
+        assert_eq!(demux.nal_length_size(VideoCodec::H264, &avcc).unwrap(), Some(4));
+        avcc[4] = 0xFD;
+        assert_eq!(demux.nal_length_size(VideoCodec::H264, &avcc).unwrap(), Some(2));
+
+        let mut hvcc = vec![0; 23];
+        hvcc[21] = 0x0F; // avgFrameRate and constantFrameRate bits, then lengthSizeMinusOne = 3
+        assert_eq!(demux.nal_length_size(VideoCodec::Hevc, &hvcc).unwrap(), Some(4));
+        assert!(demux.nal_length_size(VideoCodec::Hevc, &hvcc[..8]).is_err());
+
+        assert_eq!(demux.nal_length_size(VideoCodec::Vp9, &[]).unwrap(), None);
+        assert_eq!(demux.nal_length_size(VideoCodec::Av1, &[]).unwrap(), None);
+    }
+
     #[test]
     fn test_convert_avcc_to_annexb_basic() {
         let demux = create_test_demux();
@@ -479,7 +729,7 @@ mod tests {
             0x67, 0x42, 0x00, 0x1F, // NAL unit data (SPS header example)
         ];
 
-        let annexb_data = demux.convert_avcc_to_annexb(&avcc_data);
+        let annexb_data = demux.convert_to_annexb(&avcc_data);
 
         // Expected: start code (0x00000001) + NAL data
         let expected = vec![
@@ -502,7 +752,7 @@ mod tests {
             0x68, 0x43, 0x44, // Second NAL unit
         ];
 
-        let annexb_data = demux.convert_avcc_to_annexb(&avcc_data);
+        let annexb_data = demux.convert_to_annexb(&avcc_data);
 
         let expected = vec![
             0x00, 0x00, 0x00, 0x01, // Start code for first NAL
@@ -514,11 +764,36 @@ mod tests {
         assert_eq!(annexb_data, expected);
     }
 
+    #[test]
+    fn test_packed_rgb_data_without_padding() {
+        let data: Vec<u8> = (0..12).collect();
+        let packed = Demultiplexer::packed_rgb_data(&data, 6, 2, 2);
+
+        assert_eq!(packed, data);
+    }
+
+    #[test]
+    fn test_packed_rgb_data_strips_row_padding() {
+        // 1x2 frame with 5 bytes of padding after each 3-byte row
+        let data = vec![1, 2, 3, 0, 0, 0, 0, 0, 4, 5, 6, 0, 0, 0, 0, 0];
+        let packed = Demultiplexer::packed_rgb_data(&data, 8, 1, 2);
+
+        assert_eq!(packed, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_frame_timestamp_in_ms() {
+        assert_eq!(Demultiplexer::frame_timestamp_in_ms(Some(90000), 90000), Some(1000));
+        assert_eq!(Demultiplexer::frame_timestamp_in_ms(Some(1001), 30000), Some(33));
+        assert_eq!(Demultiplexer::frame_timestamp_in_ms(Some(-512), 12800), Some(0));
+        assert_eq!(Demultiplexer::frame_timestamp_in_ms(None, 12800), None);
+    }
+
     #[test]
     fn test_convert_avcc_to_annexb_empty_data() {
         let demux = create_test_demux();
         let empty_data = vec![];
-        let result = demux.convert_avcc_to_annexb(&empty_data);
+        let result = demux.convert_to_annexb(&empty_data);
         assert!(result.is_empty());
     }
 
@@ -532,15 +807,22 @@ mod tests {
             0x67, 0x42, // Only 2 bytes of data
         ];
 
-        let result = demux.convert_avcc_to_annexb(&invalid_data);
+        let result = demux.convert_to_annexb(&invalid_data);
         assert!(result.is_empty()); // Should return empty due to invalid length
     }
 
     // Helper function to create a test demux instance
     fn create_test_demux() -> Demultiplexer {
         let (audio_tx, _audio_rx) = channel();
-        let (video_tx, _video_rx) = channel();
-
-        Demultiplexer::new(audio_tx, video_tx, "https://example.com/video".to_string())
+        let (video_tx, _video_rx) = sync_channel(1);
+
+        Demultiplexer::new(
+            video_tx,
+            audio_tx,
+            InputSource::YtDlp {
+                url: "https://example.com/video".to_string(),
+                format: DEFAULT_FORMAT.to_string(),
+            },
+        )
     }
 }
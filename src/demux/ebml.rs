@@ -0,0 +1,663 @@
+use crate::demux::get_moov_box::{AudioCodec, VideoCodec};
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::Res;
+
+pub const EBML_HEADER_ID: u32 = 0x1A45DFA3;
+pub const DOC_TYPE_ID: u32 = 0x4282;
+pub const SEGMENT_ID: u32 = 0x18538067;
+pub const SEEK_HEAD_ID: u32 = 0x114D9B74;
+pub const SEEK_ID: u32 = 0x4DBB;
+pub const SEEK_ELEMENT_ID: u32 = 0x53AB;
+pub const SEEK_POSITION_ID: u32 = 0x53AC;
+pub const INFO_ID: u32 = 0x1549A966;
+pub const TIMESTAMP_SCALE_ID: u32 = 0x2AD7B1;
+pub const DURATION_ID: u32 = 0x4489;
+pub const TRACKS_ID: u32 = 0x1654AE6B;
+pub const TRACK_ENTRY_ID: u32 = 0xAE;
+pub const TRACK_NUMBER_ID: u32 = 0xD7;
+pub const TRACK_TYPE_ID: u32 = 0x83;
+pub const CODEC_ID_ID: u32 = 0x86;
+pub const CODEC_PRIVATE_ID: u32 = 0x63A2;
+pub const CODEC_DELAY_ID: u32 = 0x56AA;
+pub const DEFAULT_DURATION_ID: u32 = 0x23E383;
+pub const VIDEO_ID: u32 = 0xE0;
+pub const PIXEL_WIDTH_ID: u32 = 0xB0;
+pub const PIXEL_HEIGHT_ID: u32 = 0xBA;
+pub const AUDIO_ID: u32 = 0xE1;
+pub const SAMPLING_FREQUENCY_ID: u32 = 0xB5;
+pub const CHANNELS_ID: u32 = 0x9F;
+pub const CUES_ID: u32 = 0x1C53BB6B;
+pub const CUE_POINT_ID: u32 = 0xBB;
+pub const CUE_TIME_ID: u32 = 0xB3;
+pub const CUE_TRACK_POSITIONS_ID: u32 = 0xB7;
+pub const CUE_TRACK_ID: u32 = 0xF7;
+pub const CUE_CLUSTER_POSITION_ID: u32 = 0xF1;
+pub const CLUSTER_ID: u32 = 0x1F43B675;
+pub const TIMESTAMP_ID: u32 = 0xE7;
+pub const SIMPLE_BLOCK_ID: u32 = 0xA3;
+pub const BLOCK_GROUP_ID: u32 = 0xA0;
+pub const BLOCK_ID: u32 = 0xA1;
+pub const BLOCK_DURATION_ID: u32 = 0x9B;
+pub const REFERENCE_BLOCK_ID: u32 = 0xFB;
+
+/// The ID and size of an EBML element, read from the start of its bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ElementHeader {
+    pub id: u32,
+    /// None when the size is unknown, e.g. for a Segment or Cluster being streamed live
+    pub size: Option<u64>,
+    pub header_size: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackType {
+    Video,
+    Audio,
+    Other,
+}
+
+/// A TrackEntry element
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatroskaTrack {
+    pub number: u64,
+    pub track_type: TrackType,
+    pub codec_id: String,
+    pub codec_private: Option<Vec<u8>>,
+    /// Samples the decoder outputs before the first real one, in nanoseconds
+    pub codec_delay_ns: u64,
+    /// Duration of each frame in nanoseconds, when they all last as long
+    pub default_duration_ns: Option<u64>,
+    pub dimensions: Option<(u32, u32)>,
+    /// Channel count and sample rate
+    pub audio_format: Option<(u16, u32)>,
+}
+
+impl MatroskaTrack {
+    pub fn video_codec(&self) -> Option<VideoCodec> {
+        match self.codec_id.as_str() {
+            "V_MPEG4/ISO/AVC" => Some(VideoCodec::H264),
+            "V_MPEGH/ISO/HEVC" => Some(VideoCodec::Hevc),
+            "V_VP9" => Some(VideoCodec::Vp9),
+            "V_AV1" => Some(VideoCodec::Av1),
+            _ => None,
+        }
+    }
+
+    pub fn audio_codec(&self) -> Option<AudioCodec> {
+        match self.codec_id.as_str() {
+            codec_id if codec_id.starts_with("A_AAC") => Some(AudioCodec::Aac),
+            "A_MPEG/L3" => Some(AudioCodec::Mp3),
+            "A_OPUS" => Some(AudioCodec::Opus),
+            _ => None,
+        }
+    }
+}
+
+/// A CuePoint element: where the cluster holding a keyframe of a track starts
+#[derive(Clone, Debug, PartialEq)]
+pub struct CuePoint {
+    /// In TimestampScale units
+    pub time: u64,
+    pub track: u64,
+    /// Relative to the start of the Segment data
+    pub cluster_position: u64,
+}
+
+/// A SimpleBlock or Block element, with its frames split out of the lacing
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatroskaBlock {
+    pub track_number: u64,
+    /// Relative to the timestamp of the cluster
+    pub relative_timestamp: i16,
+    pub keyframe: bool,
+    pub frames: Vec<Vec<u8>>,
+}
+
+/*
+ * Reads a variable-size integer: the number of leading zero bits of its first byte
+ * gives its length. Returns its value, with or without the length marker, and its length,
+ * or None when data doesn't hold all of it yet.
+ */
+fn read_vint(data: &[u8], max_length: usize, keep_marker: bool) -> Res<Option<(u64, usize)>> {
+    let Some(&first_byte) = data.first() else {
+        return Ok(None);
+    };
+
+    let length = first_byte.leading_zeros() as usize + 1;
+    if length > max_length {
+        return Err(PlaybackError::Container(format!(
+            "Invalid EBML variable-size integer starting with {:#04x}",
+            first_byte
+        )));
+    }
+
+    if data.len() < length {
+        return Ok(None);
+    }
+
+    let first_value = if keep_marker {
+        first_byte as u64
+    } else {
+        (first_byte as u64) & (0xFF >> length)
+    };
+
+    let value = data[1..length]
+        .iter()
+        .fold(first_value, |value, byte| (value << 8) | *byte as u64);
+
+    Ok(Some((value, length)))
+}
+
+/*
+ * Parses the ID and size at the start of data, or returns None when more bytes are needed
+ */
+pub fn parse_element_header(data: &[u8]) -> Res<Option<ElementHeader>> {
+    let Some((id, id_length)) = read_vint(data, 4, true)? else {
+        return Ok(None);
+    };
+
+    let Some((size, size_length)) = read_vint(&data[id_length..], 8, false)? else {
+        return Ok(None);
+    };
+
+    // All the value bits set means the size is unknown
+    let unknown_size = size == (1 << (7 * size_length)) - 1;
+
+    Ok(Some(ElementHeader {
+        id: id as u32,
+        size: if unknown_size { None } else { Some(size) },
+        header_size: id_length + size_length,
+    }))
+}
+
+/*
+ * Splits the data of a master element into the IDs and data of its children
+ */
+pub fn get_children(mut data: &[u8]) -> Res<Vec<(u32, &[u8])>> {
+    let mut children = vec![];
+
+    while !data.is_empty() {
+        let header = parse_element_header(data)?.ok_or_else(|| {
+            PlaybackError::Container("EBML element header is truncated".to_string())
+        })?;
+
+        // An element of unknown size runs to the end of its parent
+        let end = match header.size {
+            Some(size) => header.header_size as u64 + size,
+            None => data.len() as u64,
+        };
+
+        if end > data.len() as u64 {
+            return Err(PlaybackError::Container(format!(
+                "EBML element {:#x} is truncated",
+                header.id
+            )));
+        }
+
+        children.push((header.id, &data[header.header_size..end as usize]));
+        data = &data[end as usize..];
+    }
+
+    Ok(children)
+}
+
+pub fn read_uint(data: &[u8]) -> u64 {
+    data.iter()
+        .take(8)
+        .fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+pub fn read_float(data: &[u8]) -> f64 {
+    match data.len() {
+        4 => f32::from_be_bytes(data.try_into().unwrap()) as f64,
+        8 => f64::from_be_bytes(data.try_into().unwrap()),
+        _ => 0.0,
+    }
+}
+
+pub fn read_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+/*
+ * Checks the DocType of the EBML header, which comes first in the stream
+ */
+pub fn check_ebml_header(data: &[u8]) -> Res<()> {
+    let doc_type = get_children(data)?
+        .into_iter()
+        .find(|(id, _)| *id == DOC_TYPE_ID)
+        .map(|(_, data)| read_string(data))
+        .unwrap_or_else(|| "matroska".to_string());
+
+    match doc_type.as_str() {
+        "matroska" | "webm" => Ok(()),
+        _ => Err(PlaybackError::Container(format!(
+            "Unsupported EBML document type {}",
+            doc_type
+        ))),
+    }
+}
+
+/*
+ * Reads the TimestampScale, in nanoseconds, and the Duration, in TimestampScale units,
+ * of an Info element
+ */
+pub fn parse_info(data: &[u8]) -> Res<(u64, Option<f64>)> {
+    let mut timestamp_scale = 1_000_000;
+    let mut duration = None;
+
+    for (id, data) in get_children(data)? {
+        match id {
+            TIMESTAMP_SCALE_ID => timestamp_scale = read_uint(data).max(1),
+            DURATION_ID => duration = Some(read_float(data)),
+            _ => {}
+        }
+    }
+
+    Ok((timestamp_scale, duration))
+}
+
+/*
+ * Reads the position of each top-level element listed in a SeekHead element,
+ * relative to the start of the Segment data
+ */
+pub fn parse_seek_head(data: &[u8]) -> Res<Vec<(u32, u64)>> {
+    let mut positions = vec![];
+
+    for (id, data) in get_children(data)? {
+        if id != SEEK_ID {
+            continue;
+        }
+
+        let mut element_id = None;
+        let mut position = None;
+
+        for (id, data) in get_children(data)? {
+            match id {
+                SEEK_ELEMENT_ID => element_id = Some(read_uint(data) as u32),
+                SEEK_POSITION_ID => position = Some(read_uint(data)),
+                _ => {}
+            }
+        }
+
+        if let (Some(element_id), Some(position)) = (element_id, position) {
+            positions.push((element_id, position));
+        }
+    }
+
+    Ok(positions)
+}
+
+fn parse_track_entry(data: &[u8]) -> Res<MatroskaTrack> {
+    let mut track = MatroskaTrack {
+        number: 0,
+        track_type: TrackType::Other,
+        codec_id: String::new(),
+        codec_private: None,
+        codec_delay_ns: 0,
+        default_duration_ns: None,
+        dimensions: None,
+        audio_format: None,
+    };
+
+    for (id, data) in get_children(data)? {
+        match id {
+            TRACK_NUMBER_ID => track.number = read_uint(data),
+            TRACK_TYPE_ID => {
+                track.track_type = match read_uint(data) {
+                    1 => TrackType::Video,
+                    2 => TrackType::Audio,
+                    _ => TrackType::Other,
+                }
+            }
+            CODEC_ID_ID => track.codec_id = read_string(data),
+            CODEC_PRIVATE_ID => track.codec_private = Some(data.to_vec()),
+            CODEC_DELAY_ID => track.codec_delay_ns = read_uint(data),
+            DEFAULT_DURATION_ID => track.default_duration_ns = Some(read_uint(data)),
+            VIDEO_ID => {
+                let mut width = 0;
+                let mut height = 0;
+
+                for (id, data) in get_children(data)? {
+                    match id {
+                        PIXEL_WIDTH_ID => width = read_uint(data) as u32,
+                        PIXEL_HEIGHT_ID => height = read_uint(data) as u32,
+                        _ => {}
+                    }
+                }
+
+                track.dimensions = Some((width, height));
+            }
+            AUDIO_ID => {
+                // Defaults from the Matroska specification
+                let mut channels = 1;
+                let mut sample_rate = 8000.0;
+
+                for (id, data) in get_children(data)? {
+                    match id {
+                        CHANNELS_ID => channels = read_uint(data) as u16,
+                        SAMPLING_FREQUENCY_ID => sample_rate = read_float(data),
+                        _ => {}
+                    }
+                }
+
+                track.audio_format = Some((channels, sample_rate as u32));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(track)
+}
+
+pub fn parse_tracks(data: &[u8]) -> Res<Vec<MatroskaTrack>> {
+    get_children(data)?
+        .into_iter()
+        .filter(|(id, _)| *id == TRACK_ENTRY_ID)
+        .map(|(_, data)| parse_track_entry(data))
+        .collect()
+}
+
+pub fn parse_cues(data: &[u8]) -> Res<Vec<CuePoint>> {
+    let mut cue_points = vec![];
+
+    for (id, data) in get_children(data)? {
+        if id != CUE_POINT_ID {
+            continue;
+        }
+
+        let mut time = 0;
+        let mut track_positions = vec![];
+
+        for (id, data) in get_children(data)? {
+            match id {
+                CUE_TIME_ID => time = read_uint(data),
+                CUE_TRACK_POSITIONS_ID => track_positions.push(data),
+                _ => {}
+            }
+        }
+
+        for data in track_positions {
+            let mut track = 0;
+            let mut cluster_position = None;
+
+            for (id, data) in get_children(data)? {
+                match id {
+                    CUE_TRACK_ID => track = read_uint(data),
+                    CUE_CLUSTER_POSITION_ID => cluster_position = Some(read_uint(data)),
+                    _ => {}
+                }
+            }
+
+            if let Some(cluster_position) = cluster_position {
+                cue_points.push(CuePoint {
+                    time,
+                    track,
+                    cluster_position,
+                });
+            }
+        }
+    }
+
+    Ok(cue_points)
+}
+
+/*
+ * Splits the frames out of a laced block, from the byte holding the number of frames
+ */
+fn split_laced_frames(data: &[u8], lacing: u8) -> Res<Vec<Vec<u8>>> {
+    let truncated = || PlaybackError::Container("Laced block is truncated".to_string());
+
+    let frame_count = *data.first().ok_or_else(truncated)? as usize + 1;
+    let mut offset = 1;
+    let mut sizes = Vec::with_capacity(frame_count);
+
+    match lacing {
+        // Xiph lacing: each size is a run of bytes added up, ending with one below 255
+        0x02 => {
+            for _ in 1..frame_count {
+                let mut size = 0;
+                loop {
+                    let byte = *data.get(offset).ok_or_else(truncated)?;
+                    offset += 1;
+                    size += byte as usize;
+
+                    if byte != 255 {
+                        break;
+                    }
+                }
+                sizes.push(size);
+            }
+        }
+        // EBML lacing: the first size, then each one as a signed difference to the previous
+        0x06 => {
+            for _ in 1..frame_count {
+                let (value, length) =
+                    read_vint(&data[offset..], 8, false)?.ok_or_else(truncated)?;
+                offset += length;
+
+                let size = match sizes.last() {
+                    Some(&previous) => {
+                        let bias = (1i64 << (7 * length - 1)) - 1;
+                        previous as i64 + value as i64 - bias
+                    }
+                    None => value as i64,
+                };
+                sizes.push(usize::try_from(size).map_err(|_| truncated())?);
+            }
+        }
+        // Fixed-size lacing: every frame has the same size
+        _ => {
+            let remaining = data.len() - offset;
+            if !remaining.is_multiple_of(frame_count) {
+                return Err(PlaybackError::Container(
+                    "Fixed-size laced block doesn't split evenly".to_string(),
+                ));
+            }
+
+            sizes.resize(frame_count - 1, remaining / frame_count);
+        }
+    }
+
+    let mut frames = Vec::with_capacity(frame_count);
+    for size in sizes {
+        let frame = data.get(offset..offset + size).ok_or_else(truncated)?;
+        frames.push(frame.to_vec());
+        offset += size;
+    }
+
+    // The last frame takes what is left
+    frames.push(data[offset.min(data.len())..].to_vec());
+
+    Ok(frames)
+}
+
+/*
+ * Parses the data of a SimpleBlock or Block element. Only SimpleBlock has a keyframe flag,
+ * whether a Block is a keyframe comes from its BlockGroup.
+ */
+pub fn parse_block(data: &[u8], simple_block: bool) -> Res<MatroskaBlock> {
+    let truncated = || PlaybackError::Container("Block is truncated".to_string());
+
+    let (track_number, length) = read_vint(data, 8, false)?.ok_or_else(truncated)?;
+    let header = data.get(length..length + 3).ok_or_else(truncated)?;
+
+    let relative_timestamp = i16::from_be_bytes([header[0], header[1]]);
+    let flags = header[2];
+    let payload = &data[length + 3..];
+
+    let lacing = flags & 0x06;
+    let frames = if lacing == 0 {
+        vec![payload.to_vec()]
+    } else {
+        split_laced_frames(payload, lacing)?
+    };
+
+    Ok(MatroskaBlock {
+        track_number,
+        relative_timestamp,
+        keyframe: simple_block && flags & 0x80 != 0,
+        frames,
+    })
+}
+
+/*
+ * Parses a BlockGroup element, returning its block and its duration in TimestampScale units.
+ * A block without a ReferenceBlock doesn't depend on another one, so it is a keyframe.
+ */
+pub fn parse_block_group(data: &[u8]) -> Res<(MatroskaBlock, Option<u64>)> {
+    let mut block = None;
+    let mut duration = None;
+    let mut references_other_block = false;
+
+    for (id, data) in get_children(data)? {
+        match id {
+            BLOCK_ID => block = Some(parse_block(data, false)?),
+            BLOCK_DURATION_ID => duration = Some(read_uint(data)),
+            REFERENCE_BLOCK_ID => references_other_block = true,
+            _ => {}
+        }
+    }
+
+    let mut block =
+        block.ok_or_else(|| PlaybackError::Container("BlockGroup has no Block".to_string()))?;
+    block.keyframe = !references_other_block;
+
+    Ok((block, duration))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_element_header() {
+        // Segment of unknown size
+        let data = [
+            0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        let header = parse_element_header(&data).unwrap().unwrap();
+        assert_eq!(header.id, SEGMENT_ID);
+        assert_eq!(header.size, None);
+        assert_eq!(header.header_size, 12);
+
+        let header = parse_element_header(&[0xA3, 0x42, 0x10]).unwrap().unwrap();
+        assert_eq!(header.id, SIMPLE_BLOCK_ID);
+        assert_eq!(header.size, Some(0x210));
+        assert_eq!(header.header_size, 3);
+
+        assert_eq!(parse_element_header(&[0x1A, 0x45]).unwrap(), None);
+        assert!(parse_element_header(&[0x00, 0x81]).is_err());
+    }
+
+    #[test]
+    fn test_parse_tracks() {
+        let data = [
+            0xAE, 0x9F, // TrackEntry
+            0xD7, 0x81, 0x01, // TrackNumber = 1
+            0x83, 0x81, 0x02, // TrackType = audio
+            0x86, 0x86, b'A', b'_', b'O', b'P', b'U', b'S', // CodecID
+            0x56, 0xAA, 0x83, 0x63, 0x2E, 0xA0, // CodecDelay = 6500000
+            0xE1, 0x89, // Audio
+            0x9F, 0x81, 0x02, // Channels = 2
+            0xB5, 0x84, 0x47, 0x3B, 0x80, 0x00, // SamplingFrequency = 48000
+        ];
+
+        let tracks = parse_tracks(&data).unwrap();
+
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].number, 1);
+        assert_eq!(tracks[0].track_type, TrackType::Audio);
+        assert_eq!(tracks[0].audio_codec(), Some(AudioCodec::Opus));
+        assert_eq!(tracks[0].codec_delay_ns, 6_500_000);
+        assert_eq!(tracks[0].audio_format, Some((2, 48000)));
+    }
+
+    #[test]
+    fn test_parse_cues() {
+        let data = [
+            0xBB, 0x8B, // CuePoint
+            0xB3, 0x82, 0x03, 0xE8, // CueTime = 1000
+            0xB7, 0x85, // CueTrackPositions
+            0xF7, 0x81, 0x01, // CueTrack = 1
+            0xF1, 0x80, // CueClusterPosition = 0
+        ];
+
+        assert_eq!(
+            parse_cues(&data).unwrap(),
+            vec![CuePoint {
+                time: 1000,
+                track: 1,
+                cluster_position: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_simple_block_with_xiph_lacing() {
+        let data = [
+            0x82, // track 2
+            0xFF, 0xFE, // relative timestamp = -2
+            0x82, // keyframe, Xiph lacing
+            0x02, // 3 frames
+            0xFF, 0x01, // first frame: 256 bytes
+            0x02, // second frame: 2 bytes
+        ];
+        let mut data = data.to_vec();
+        data.extend_from_slice(&[1; 256]);
+        data.extend_from_slice(&[2; 2]);
+        data.extend_from_slice(&[3; 3]);
+
+        let block = parse_block(&data, true).unwrap();
+
+        assert_eq!(block.track_number, 2);
+        assert_eq!(block.relative_timestamp, -2);
+        assert!(block.keyframe);
+        assert_eq!(block.frames, vec![vec![1; 256], vec![2; 2], vec![3; 3]]);
+    }
+
+    #[test]
+    fn test_parse_block_with_ebml_and_fixed_lacing() {
+        let mut data = vec![
+            0x81, 0x00, 0x00, 0x06, // track 1, EBML lacing
+            0x02, // 3 frames
+            0x83, // first frame: 3 bytes
+            0xBE, // second frame: 3 - 1 = 2 bytes
+        ];
+        data.extend_from_slice(&[1, 1, 1, 2, 2, 3]);
+
+        let block = parse_block(&data, false).unwrap();
+        assert!(!block.keyframe);
+        assert_eq!(block.frames, vec![vec![1, 1, 1], vec![2, 2], vec![3]]);
+
+        // A single EBML laced frame has no sizes, its first byte is data
+        let data = [0x81, 0x00, 0x00, 0x06, 0x00, 0x83, 4, 4];
+        let block = parse_block(&data, false).unwrap();
+        assert_eq!(block.frames, vec![vec![0x83, 4, 4]]);
+
+        let data = [0x81, 0x00, 0x00, 0x04, 0x01, 1, 1, 2, 2];
+        let block = parse_block(&data, false).unwrap();
+        assert_eq!(block.frames, vec![vec![1, 1], vec![2, 2]]);
+
+        let data = [0x81, 0x00, 0x00, 0x04, 0x01, 1, 1, 2];
+        assert!(parse_block(&data, false).is_err());
+    }
+
+    #[test]
+    fn test_parse_block_group() {
+        let data = [
+            0xA1, 0x85, 0x81, 0x00, 0x10, 0x00, 0xAA, // Block of track 1 at +16
+            0x9B, 0x81, 0x14, // BlockDuration = 20
+            0xFB, 0x81, 0xF0, // ReferenceBlock = -16
+        ];
+
+        let (block, duration) = parse_block_group(&data).unwrap();
+
+        assert_eq!(block.relative_timestamp, 16);
+        assert!(!block.keyframe);
+        assert_eq!(block.frames, vec![vec![0xAA]]);
+        assert_eq!(duration, Some(20));
+    }
+}
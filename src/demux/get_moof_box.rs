@@ -0,0 +1,188 @@
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::Res;
+
+use super::get_moov_box::DrainToBox;
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct MFHDBox {
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct TFHDBox {
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct TFDTBox {
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct TRUNBox {
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
+/// Segment index, listing the size and duration of each subsegment that follows it
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct SIDXBox {
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct TRAFBox {
+    pub size: u32,
+    pub tfhd: TFHDBox,
+    pub tfdt: Option<TFDTBox>,
+    pub truns: Vec<TRUNBox>,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct MOOFBox {
+    pub size: u32,
+    pub mfhd: MFHDBox,
+    pub trafs: Vec<TRAFBox>,
+}
+
+/*
+ * A moof box describes the samples of the mdat box that follows it,
+ * with one traf box per track present in the fragment
+ */
+pub fn get_moof_box(size: u32, mut data: Vec<u8>) -> Res<MOOFBox> {
+    let mut mfhd_box = None;
+    let mut trafs: Vec<TRAFBox> = vec![];
+
+    while !data.is_empty() {
+        let (box_size, title) = data.get_next_box_size_and_title()?;
+
+        match title.as_str() {
+            "mfhd" => {
+                mfhd_box = Some(MFHDBox {
+                    size: box_size,
+                    data: data.drain_box_data(box_size)?,
+                });
+            }
+            "traf" => {
+                trafs.push(get_traf_box(box_size, data.drain_box_data(box_size)?)?);
+            }
+            _ => {
+                data.drain_box_data(box_size)?;
+            }
+        }
+    }
+
+    Ok(MOOFBox {
+        size,
+        mfhd: mfhd_box.ok_or_else(|| PlaybackError::missing_box("mfhd"))?,
+        trafs,
+    })
+}
+
+pub fn get_traf_box(size: u32, mut data: Vec<u8>) -> Res<TRAFBox> {
+    let mut tfhd_box = None;
+    let mut tfdt_box = None;
+    let mut truns: Vec<TRUNBox> = vec![];
+
+    while !data.is_empty() {
+        let (box_size, title) = data.get_next_box_size_and_title()?;
+
+        match title.as_str() {
+            "tfhd" => {
+                tfhd_box = Some(TFHDBox {
+                    size: box_size,
+                    data: data.drain_box_data(box_size)?,
+                });
+            }
+            "tfdt" => {
+                tfdt_box = Some(TFDTBox {
+                    size: box_size,
+                    data: data.drain_box_data(box_size)?,
+                });
+            }
+            "trun" => {
+                truns.push(TRUNBox {
+                    size: box_size,
+                    data: data.drain_box_data(box_size)?,
+                });
+            }
+            _ => {
+                data.drain_box_data(box_size)?;
+            }
+        }
+    }
+
+    Ok(TRAFBox {
+        size,
+        tfhd: tfhd_box.ok_or_else(|| PlaybackError::missing_box("tfhd"))?,
+        tfdt: tfdt_box,
+        truns,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_moof_box() {
+        let data = vec![
+            // mfhd box
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x6D, 0x66, 0x68, 0x64, // "mfhd"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x01, // sequence number = 1
+            // traf box
+            0x00, 0x00, 0x00, 0x38, // size = 56
+            0x74, 0x72, 0x61, 0x66, // "traf"
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x74, 0x66, 0x68, 0x64, // "tfhd"
+            0x00, 0x02, 0x00, 0x00, // version + flags (default-base-is-moof)
+            0x00, 0x00, 0x00, 0x01, // track id = 1
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x74, 0x66, 0x64, 0x74, // "tfdt"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x04, 0x00, // base media decode time = 1024
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x74, 0x72, 0x75, 0x6E, // "trun"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x00, // sample count = 0
+        ];
+
+        let moof_box = get_moof_box(80, data).unwrap();
+
+        assert_eq!(moof_box.size, 80);
+        assert_eq!(moof_box.mfhd.size, 16);
+        assert_eq!(moof_box.trafs.len(), 1);
+
+        let traf_box = &moof_box.trafs[0];
+        assert_eq!(traf_box.tfhd.data.len(), 8);
+        assert!(traf_box.tfdt.is_some());
+        assert_eq!(traf_box.truns.len(), 1);
+    }
+
+    #[test]
+    fn test_get_traf_box_missing_tfhd() {
+        let data = vec![
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x74, 0x72, 0x75, 0x6E, // "trun"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x00, // sample count = 0
+        ];
+
+        let result = get_traf_box(24, data);
+        assert!(result.is_err());
+        assert!(result.err().unwrap().to_string().contains("No tfhd box found"));
+    }
+}
@@ -1,10 +1,65 @@
-use std::error::Error;
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::Res;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum Streams {
     Audio,
     Video,
+    /// Timecode, subtitle or hint tracks, which are not played
+    Other,
+}
+
+/// Video codecs the player can decode, by the format of their sample entry
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VideoCodec {
+    H264,
+    Hevc,
+    Vp9,
+    Av1,
+}
+
+impl VideoCodec {
+    pub fn from_sample_entry(format: &str) -> Option<Self> {
+        match format {
+            "avc1" => Some(VideoCodec::H264),
+            "hvc1" | "hev1" => Some(VideoCodec::Hevc),
+            "vp09" => Some(VideoCodec::Vp9),
+            "av01" => Some(VideoCodec::Av1),
+            _ => None,
+        }
+    }
+
+    /// Title of the box holding the decoder configuration, inside the sample entry
+    pub fn config_box_title(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "avcC",
+            VideoCodec::Hevc => "hvcC",
+            VideoCodec::Vp9 => "vpcC",
+            VideoCodec::Av1 => "av1C",
+        }
+    }
+}
+
+/// Audio codecs the player can decode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioCodec {
+    Aac,
+    Mp3,
+    Opus,
+}
+
+impl AudioCodec {
+    /// Maps the objectTypeIndication of an esds box to a codec
+    pub fn from_object_type(object_type: u8) -> Option<Self> {
+        match object_type {
+            // MPEG-4 audio, then the AAC profiles of MPEG-2
+            0x40 | 0x66 | 0x67 | 0x68 => Some(AudioCodec::Aac),
+            // MPEG-2 and MPEG-1 audio
+            0x69 | 0x6B => Some(AudioCodec::Mp3),
+            _ => None,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -14,6 +69,15 @@ pub struct FTYPBox {
     pub data: Vec<u8>,
 }
 
+/// A box the player doesn't use, kept as is so that it can still be inspected
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct UnknownBox {
+    pub title: String,
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct MVHDBox {
@@ -26,7 +90,17 @@ pub struct MVHDBox {
 pub struct STSDBox {
     pub size: u32,
     pub data: Vec<u8>,
-    pub avcc: Option<Vec<u8>>,
+    /// Codec of the first sample entry, when it is a video codec the player can decode
+    pub video_codec: Option<VideoCodec>,
+    /// Codec of the first sample entry, when it is an audio codec the player can decode
+    pub audio_codec: Option<AudioCodec>,
+    /// Decoder configuration of the sample entry: the content of the avcC, hvcC,
+    /// vpcC or av1C box, the AudioSpecificConfig of AAC or the OpusHead of Opus
+    pub codec_config: Option<Vec<u8>>,
+    /// Width and height of the video, from the visual sample entry
+    pub dimensions: Option<(u16, u16)>,
+    /// Channel count and sample rate of the audio, from the audio sample entry
+    pub audio_format: Option<(u16, u32)>,
 }
 
 #[allow(dead_code)]
@@ -64,6 +138,13 @@ pub struct STCOBox {
     pub data: Vec<u8>,
 }
 
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct CO64Box {
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct STSSBox {
@@ -79,9 +160,11 @@ pub struct STBLBbox {
     pub ctts: Option<CTTSBox>,
     pub stts: STTSBox,
     pub stsz: STSZBox,
-    pub stco: STCOBox,
+    pub stco: Option<STCOBox>,
+    pub co64: Option<CO64Box>,
     pub stsc: STSCBox,
     pub stss: Option<STSSBox>,
+    pub unknown_boxes: Vec<UnknownBox>,
 }
 
 #[allow(dead_code)]
@@ -112,13 +195,14 @@ pub struct MINFBox {
     pub header: Streams,
     pub dinf: DINFBox,
     pub stbl: STBLBbox,
+    pub unknown_boxes: Vec<UnknownBox>,
 }
 
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct HDLRBox {
     pub size: u32,
-    data: Vec<u8>,
+    pub data: Vec<u8>,
 }
 
 #[allow(dead_code)]
@@ -135,6 +219,7 @@ pub struct MDIABox {
     pub mdhd: MDHDBox,
     pub hdlr: HDLRBox,
     pub minf: MINFBox,
+    pub unknown_boxes: Vec<UnknownBox>,
 }
 
 #[allow(dead_code)]
@@ -144,12 +229,44 @@ pub struct TKHDBox {
     pub data: Vec<u8>,
 }
 
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct ELSTBox {
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct EDTSBox {
+    pub size: u32,
+    pub elst: Option<ELSTBox>,
+    pub unknown_boxes: Vec<UnknownBox>,
+}
+
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct TRAKBox {
     pub size: u32,
     pub tkhd: TKHDBox,
+    pub edts: Option<EDTSBox>,
     pub media: MDIABox,
+    pub unknown_boxes: Vec<UnknownBox>,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct TREXBox {
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct MVEXBox {
+    pub size: u32,
+    pub trexs: Vec<TREXBox>,
+    pub unknown_boxes: Vec<UnknownBox>,
 }
 
 #[allow(dead_code)]
@@ -158,316 +275,618 @@ pub struct MOOVBox {
     pub size: u32,
     pub mvhd: MVHDBox,
     pub traks: Vec<TRAKBox>,
+    pub mvex: Option<MVEXBox>,
+    pub unknown_boxes: Vec<UnknownBox>,
 }
 
 pub trait DrainToBox {
-    fn drain_box_data(&mut self, box_size: u32) -> Vec<u8>;
-    fn get_next_box_size_and_title(&mut self) -> (u32, String);
+    fn drain_box_data(&mut self, box_size: u32) -> Res<Vec<u8>>;
+    fn drain_unknown_box(&mut self, box_size: u32, title: String) -> Res<UnknownBox>;
+    fn get_next_box_size_and_title(&mut self) -> Res<(u32, String)>;
+}
+
+/* Takes the next bytes, failing when the data ends before them */
+fn drain_bytes<const N: usize>(data: &mut Vec<u8>, what: &str) -> Res<[u8; N]> {
+    if data.len() < N {
+        return Err(PlaybackError::Container(format!(
+            "Truncated box: {} needs {} bytes, {} left",
+            what,
+            N,
+            data.len()
+        )));
+    }
+
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(&data[..N]);
+    data.drain(..N);
+    Ok(bytes)
 }
 
 impl DrainToBox for Vec<u8> {
-    fn drain_box_data(&mut self, box_size: u32) -> Vec<u8> {
-        self.drain(..(box_size - 8) as usize).collect()
+    fn drain_box_data(&mut self, box_size: u32) -> Res<Vec<u8>> {
+        let payload_size = box_size.checked_sub(8).ok_or_else(|| {
+            PlaybackError::Container(format!("Box size {} is smaller than its header", box_size))
+        })? as usize;
+
+        if payload_size > self.len() {
+            return Err(PlaybackError::Container(format!(
+                "Truncated box: {} bytes of payload, {} left",
+                payload_size,
+                self.len()
+            )));
+        }
+
+        Ok(self.drain(..payload_size).collect())
+    }
+
+    fn drain_unknown_box(&mut self, box_size: u32, title: String) -> Res<UnknownBox> {
+        Ok(UnknownBox {
+            title,
+            size: box_size,
+            data: self.drain_box_data(box_size)?,
+        })
     }
 
-    fn get_next_box_size_and_title(&mut self) -> (u32, String) {
-        let size_bytes: [u8; 4] = self.drain(..4).collect::<Vec<u8>>().try_into().unwrap();
-        let box_size = u32::from_be_bytes(size_bytes);
+    /*
+     * Reads a box header. The size is returned as if the header was 8 bytes long,
+     * so that drain_box_data takes the payload of 64-bit largesize boxes too,
+     * and of size 0 boxes, which run to the end of the data
+     */
+    fn get_next_box_size_and_title(&mut self) -> Res<(u32, String)> {
+        let box_size = u32::from_be_bytes(drain_bytes(self, "box size")?);
 
-        let title_bytes: [u8; 4] = self.drain(..4).collect::<Vec<u8>>().try_into().unwrap();
+        let title_bytes: [u8; 4] = drain_bytes(self, "box title")?;
         let title = String::from_utf8_lossy(&title_bytes).to_string();
 
-        (box_size, title)
+        let box_size = match box_size {
+            0 => u32::try_from(self.len() + 8).ok(),
+            1 => {
+                let largesize = u64::from_be_bytes(drain_bytes(self, "box largesize")?);
+                largesize
+                    .checked_sub(8)
+                    .and_then(|size| u32::try_from(size).ok())
+            }
+            _ => Some(box_size),
+        }
+        .ok_or_else(|| {
+            PlaybackError::Container(format!("Unsupported size for the {} box", title))
+        })?;
+
+        Ok((box_size, title))
     }
 }
 
-pub fn get_moov_box(size: u32, mut data: Vec<u8>) -> Result<MOOVBox, Box<dyn Error>> {
+pub fn get_moov_box(size: u32, mut data: Vec<u8>) -> Res<MOOVBox> {
     let mut mvhd_box = None;
     let mut traks: Vec<TRAKBox> = vec![];
+    let mut mvex_box = None;
+    let mut unknown_boxes = vec![];
 
     while data.len() > 0 {
-        let (box_size, title) = data.get_next_box_size_and_title();
+        let (box_size, title) = data.get_next_box_size_and_title()?;
 
         match title.as_str() {
             "mvhd" => {
                 mvhd_box = Some(MVHDBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 });
             }
             "trak" => {
-                let trak_box = get_trak_box(box_size, data.drain_box_data(box_size))?;
+                let trak_box = get_trak_box(box_size, data.drain_box_data(box_size)?)?;
 
                 traks.push(trak_box);
             }
+            "mvex" => {
+                mvex_box = Some(get_mvex_box(box_size, data.drain_box_data(box_size)?)?);
+            }
             _ => {
-                data.drain(..(box_size - 8) as usize);
+                unknown_boxes.push(data.drain_unknown_box(box_size, title)?);
             }
         }
     }
 
     Ok(MOOVBox {
         size,
-        mvhd: mvhd_box.ok_or("No mvhd box found")?,
+        mvhd: mvhd_box.ok_or_else(|| PlaybackError::missing_box("mvhd"))?,
         traks,
+        mvex: mvex_box,
+        unknown_boxes,
     })
 }
 
-pub fn get_trak_box(size: u32, mut data: Vec<u8>) -> Result<TRAKBox, Box<dyn Error>> {
+/*
+ * The mvex box is only present in fragmented files.
+ * It holds the per-track sample defaults used by the moof boxes.
+ */
+pub fn get_mvex_box(size: u32, mut data: Vec<u8>) -> Res<MVEXBox> {
+    let mut trexs: Vec<TREXBox> = vec![];
+    let mut unknown_boxes = vec![];
+
+    while !data.is_empty() {
+        let (box_size, title) = data.get_next_box_size_and_title()?;
+
+        match title.as_str() {
+            "trex" => {
+                trexs.push(TREXBox {
+                    size: box_size,
+                    data: data.drain_box_data(box_size)?,
+                });
+            }
+            _ => {
+                unknown_boxes.push(data.drain_unknown_box(box_size, title)?);
+            }
+        }
+    }
+
+    Ok(MVEXBox {
+        size,
+        trexs,
+        unknown_boxes,
+    })
+}
+
+pub fn get_trak_box(size: u32, mut data: Vec<u8>) -> Res<TRAKBox> {
     let mut tkhd_box = None;
+    let mut edts_box = None;
     let mut mdia_box = None;
+    let mut unknown_boxes = vec![];
 
     while data.len() > 0 {
-        let (box_size, title) = data.get_next_box_size_and_title();
+        let (box_size, title) = data.get_next_box_size_and_title()?;
 
         match title.as_str() {
             "tkhd" => {
                 tkhd_box = Some(TKHDBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 });
             }
+            "edts" => {
+                edts_box = Some(get_edts_box(box_size, data.drain_box_data(box_size)?)?);
+            }
             "mdia" => {
-                mdia_box = Some(get_mdia_box(box_size, data.drain_box_data(box_size))?);
+                mdia_box = Some(get_mdia_box(box_size, data.drain_box_data(box_size)?)?);
             }
             _ => {
-                data.drain(..(box_size - 8) as usize);
+                unknown_boxes.push(data.drain_unknown_box(box_size, title)?);
             }
         }
     }
 
     Ok(TRAKBox {
         size,
-        tkhd: tkhd_box.ok_or("No tkhd box found")?,
-        media: mdia_box.ok_or("No mdia box found")?,
+        tkhd: tkhd_box.ok_or_else(|| PlaybackError::missing_box("tkhd"))?,
+        edts: edts_box,
+        media: mdia_box.ok_or_else(|| PlaybackError::missing_box("mdia"))?,
+        unknown_boxes,
+    })
+}
+
+pub fn get_edts_box(size: u32, mut data: Vec<u8>) -> Res<EDTSBox> {
+    let mut elst_box = None;
+    let mut unknown_boxes = vec![];
+
+    while !data.is_empty() {
+        let (box_size, title) = data.get_next_box_size_and_title()?;
+
+        match title.as_str() {
+            "elst" => {
+                elst_box = Some(ELSTBox {
+                    size: box_size,
+                    data: data.drain_box_data(box_size)?,
+                });
+            }
+            _ => {
+                unknown_boxes.push(data.drain_unknown_box(box_size, title)?);
+            }
+        }
+    }
+
+    Ok(EDTSBox {
+        size,
+        elst: elst_box,
+        unknown_boxes,
     })
 }
 
-pub fn get_mdia_box(size: u32, mut data: Vec<u8>) -> Result<MDIABox, Box<dyn Error>> {
+pub fn get_mdia_box(size: u32, mut data: Vec<u8>) -> Res<MDIABox> {
     let mut mdhd_box = None;
     let mut hdlr_box = None;
     let mut minf_box = None;
+    let mut unknown_boxes = vec![];
 
     while data.len() > 0 {
-        let (box_size, title) = data.get_next_box_size_and_title();
+        let (box_size, title) = data.get_next_box_size_and_title()?;
 
         match title.as_str() {
             "mdhd" => {
                 mdhd_box = Some(MDHDBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 });
             }
             "hdlr" => {
                 hdlr_box = Some(HDLRBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 });
             }
             "minf" => {
-                minf_box = Some(get_minf_box(box_size, data.drain_box_data(box_size))?);
+                minf_box = Some(get_minf_box(box_size, data.drain_box_data(box_size)?)?);
             }
             _ => {
-                return Err(format!("Unknown mdia sub-box, got {}", title).into());
+                unknown_boxes.push(data.drain_unknown_box(box_size, title)?);
             }
         }
     }
 
     Ok(MDIABox {
         size,
-        mdhd: mdhd_box.ok_or("No mdhd box found")?,
-        hdlr: hdlr_box.ok_or("No hdlr box found")?,
-        minf: minf_box.ok_or("No minf box found")?,
+        mdhd: mdhd_box.ok_or_else(|| PlaybackError::missing_box("mdhd"))?,
+        hdlr: hdlr_box.ok_or_else(|| PlaybackError::missing_box("hdlr"))?,
+        minf: minf_box.ok_or_else(|| PlaybackError::missing_box("minf"))?,
+        unknown_boxes,
     })
 }
 
-pub fn get_minf_box(size: u32, mut data: Vec<u8>) -> Result<MINFBox, Box<dyn Error>> {
+pub fn get_minf_box(size: u32, mut data: Vec<u8>) -> Res<MINFBox> {
     let mut dinf_box = None;
     let mut stbl_box = None;
     let mut stream_header = None;
+    let mut unknown_boxes = vec![];
 
     while data.len() > 0 {
-        let (box_size, title) = data.get_next_box_size_and_title();
+        let (box_size, title) = data.get_next_box_size_and_title()?;
 
         match title.as_str() {
             "dinf" => {
                 dinf_box = Some(DINFBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 });
             }
             "stbl" => {
-                stbl_box = Some(get_stbl_box(box_size, data.drain_box_data(box_size))?);
+                stbl_box = Some(get_stbl_box(box_size, data.drain_box_data(box_size)?)?);
             }
             "vmhd" => {
                 let _vmhd_box = VMHDBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 };
                 stream_header = Some(Streams::Video);
             }
             "smhd" => {
                 let _smhd_box = SMHDBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 };
                 stream_header = Some(Streams::Audio);
             }
             _ => {
-                return Err(format!("Unknown minf sub-box, got {}", title).into());
+                unknown_boxes.push(data.drain_unknown_box(box_size, title)?);
             }
         }
     }
 
     Ok(MINFBox {
         size,
-        header: stream_header.ok_or("No stream header found")?,
-        dinf: dinf_box.ok_or("No dinf box found")?,
-        stbl: stbl_box.ok_or("No stbl box found")?,
+        header: stream_header.unwrap_or(Streams::Other),
+        dinf: dinf_box.ok_or_else(|| PlaybackError::missing_box("dinf"))?,
+        stbl: stbl_box.ok_or_else(|| PlaybackError::missing_box("stbl"))?,
+        unknown_boxes,
     })
 }
 
-pub fn get_stbl_box(size: u32, mut data: Vec<u8>) -> Result<STBLBbox, Box<dyn Error>> {
-    let (box_size, title) = data.get_next_box_size_and_title();
+/*
+ * Reads the width and height of a visual sample entry,
+ * from the bytes that follow its size and format
+ */
+fn parse_visual_sample_entry_dimensions(entry: &[u8]) -> Option<(u16, u16)> {
+    // Width and height come after 24 bytes of reserved and pre-defined fields
+    (entry.len() >= 28).then(|| {
+        (
+            u16::from_be_bytes(entry[24..26].try_into().unwrap()),
+            u16::from_be_bytes(entry[26..28].try_into().unwrap()),
+        )
+    })
+}
+
+/*
+ * Reads the channel count and sample rate of an audio sample entry, from the bytes
+ * that follow its size and format, with the offset of the boxes that come after them
+ */
+fn parse_audio_sample_entry(entry: &[u8]) -> Option<(u16, u32, usize)> {
+    if entry.len() < 28 {
+        return None;
+    }
+
+    let version = u16::from_be_bytes(entry[8..10].try_into().unwrap());
+    let channels = u16::from_be_bytes(entry[16..18].try_into().unwrap());
+    // A 16.16 fixed-point number
+    let sample_rate = u16::from_be_bytes(entry[24..26].try_into().unwrap()) as u32;
+
+    // QuickTime sound descriptions add fields to the 28 bytes of the first version
+    let boxes_offset = match version {
+        1 => 44,
+        2 => 64,
+        _ => 28,
+    };
+
+    Some((channels, sample_rate, boxes_offset))
+}
+
+/*
+ * Reads the length of an MPEG-4 descriptor, in 1 to 4 bytes of 7 bits, the
+ * high bit set when another byte follows. Returns it with the size of the tag and length.
+ */
+fn parse_descriptor_header(data: &[u8], offset: usize) -> Option<(u8, usize, usize)> {
+    let tag = *data.get(offset)?;
+    let mut length = 0;
+
+    for index in 1..=4 {
+        let byte = *data.get(offset + index)?;
+        length = (length << 7) | (byte & 0x7F) as usize;
+
+        if byte & 0x80 == 0 {
+            return Some((tag, length, 1 + index));
+        }
+    }
+
+    None
+}
+
+/*
+ * Reads the objectTypeIndication and the decoder specific info, e.g. the
+ * AudioSpecificConfig of AAC, from the descriptors of an esds box
+ */
+fn parse_esds(data: &[u8]) -> Res<(u8, Vec<u8>)> {
+    let truncated = || PlaybackError::Container("esds box is too short".to_string());
+
+    // After the version and flags
+    let mut offset = 4;
+    let mut object_type = None;
+
+    while let Some((tag, length, header_size)) = parse_descriptor_header(data, offset) {
+        let body = offset + header_size;
+
+        match tag {
+            // ES_Descriptor: ES_ID, then flags for the optional fields before its own descriptors
+            0x03 => {
+                let flags = *data.get(body + 2).ok_or_else(truncated)?;
+                offset = body + 3;
 
-    if title != "stsd" {
-        return Err(format!("not stsd, got {}", title).into());
+                if flags & 0x80 != 0 {
+                    offset += 2;
+                }
+                if flags & 0x40 != 0 {
+                    offset += 1 + *data.get(offset).ok_or_else(truncated)? as usize;
+                }
+                if flags & 0x20 != 0 {
+                    offset += 2;
+                }
+            }
+            // DecoderConfigDescriptor: objectTypeIndication, stream type, buffer size
+            // and bitrates, then its DecoderSpecificInfo
+            0x04 => {
+                object_type = Some(*data.get(body).ok_or_else(truncated)?);
+                offset = body + 13;
+            }
+            0x05 => {
+                let config = data.get(body..body + length).ok_or_else(truncated)?;
+                let object_type = object_type.ok_or_else(truncated)?;
+
+                return Ok((object_type, config.to_vec()));
+            }
+            _ => offset = body + length,
+        }
     }
 
+    // MP3 has no decoder specific info
+    object_type
+        .map(|object_type| (object_type, vec![]))
+        .ok_or_else(|| PlaybackError::Container("esds box has no decoder config".to_string()))
+}
+
+/*
+ * Builds the OpusHead header ffmpeg expects from the content of a dOps box,
+ * which has the same fields but in big-endian
+ */
+fn opus_head_from_dops(dops: &[u8]) -> Res<Vec<u8>> {
+    if dops.len() < 11 {
+        return Err(PlaybackError::Container("dOps box is too short".to_string()));
+    }
+
+    let mut opus_head = b"OpusHead".to_vec();
+    opus_head.push(1); // version
+    opus_head.push(dops[1]); // output channel count
+    opus_head.extend(dops[2..4].iter().rev()); // pre-skip
+    opus_head.extend(dops[4..8].iter().rev()); // input sample rate
+    opus_head.extend(dops[8..10].iter().rev()); // output gain
+    opus_head.extend_from_slice(&dops[10..]); // channel mapping family and table
+
+    Ok(opus_head)
+}
+
+/*
+ * Returns the content of the box with the given title among the boxes of a sample entry,
+ * which start at offset in the bytes that follow its size and format
+ */
+fn parse_sample_entry_box(entry: &[u8], mut offset: usize, title: &str) -> Res<Option<Vec<u8>>> {
+    while offset + 8 <= entry.len() {
+        let child_size = u32::from_be_bytes(entry[offset..offset + 4].try_into().unwrap()) as usize;
+        let child_title = String::from_utf8_lossy(&entry[offset + 4..offset + 8]);
+
+        if child_size < 8 {
+            break;
+        }
+
+        if child_title == title {
+            let child_end = offset + child_size;
+
+            if child_end > entry.len() {
+                return Err(PlaybackError::Container(format!(
+                    "Not enough data for {} box content. Expected end: {}, Actual len: {}",
+                    child_title,
+                    child_end,
+                    entry.len()
+                )));
+            }
+
+            return Ok(Some(entry[offset + 8..child_end].to_vec()));
+        }
+
+        offset += child_size;
+    }
+
+    Ok(None)
+}
+
+/*
+ * Reads the codec, and its configuration, from the first sample description entry
+ */
+fn get_stsd_box(size: u32, data: Vec<u8>) -> Res<STSDBox> {
     let mut stsd_box = STSDBox {
-        size: box_size,
-        data: data.drain_box_data(box_size),
-        avcc: None,
+        size,
+        data,
+        video_codec: None,
+        audio_codec: None,
+        codec_config: None,
+        dimensions: None,
+        audio_format: None,
     };
 
+    if stsd_box.data.len() < 8 {
+        return Err(PlaybackError::Container("stsd box is too short".to_string()));
+    }
+
     let _version_flags = u32::from_be_bytes(stsd_box.data[0..4].try_into().unwrap());
     let entry_count = u32::from_be_bytes(stsd_box.data[4..8].try_into().unwrap());
 
-    if entry_count >= 1 {
-        let mut current_offset_in_stsd_data = 8;
-
-        // Read the size and format of the first sample description entry
-        if stsd_box.data.len() >= current_offset_in_stsd_data + 8 {
-            // Check if enough bytes for size + format
-            let _entry_size = u32::from_be_bytes(
-                stsd_box.data[current_offset_in_stsd_data..current_offset_in_stsd_data + 4]
-                    .try_into()
-                    .unwrap(),
-            );
-
-            current_offset_in_stsd_data += 4;
-
-            let format_bytes =
-                &stsd_box.data[current_offset_in_stsd_data..current_offset_in_stsd_data + 4];
-            let format = String::from_utf8_lossy(format_bytes);
-
-            current_offset_in_stsd_data += 4;
-
-            if format == "avc1" {
-                let avc_header_fixed_size: usize = 78; // Bytes for avc1 specific fields before any sub-boxes
-
-                let avcc_offset_in_stsd_data = current_offset_in_stsd_data + avc_header_fixed_size;
-
-                if stsd_box.data.len() >= avcc_offset_in_stsd_data + 8 {
-                    let avcc_size = u32::from_be_bytes(
-                        stsd_box.data[avcc_offset_in_stsd_data..avcc_offset_in_stsd_data + 4]
-                            .try_into()
-                            .unwrap(),
-                    );
-                    let avcc_title_bytes =
-                        &stsd_box.data[avcc_offset_in_stsd_data + 4..avcc_offset_in_stsd_data + 8];
-                    let avcc_title = String::from_utf8_lossy(avcc_title_bytes);
-
-                    if avcc_title == "avcC" {
-                        let avcc_data_start = avcc_offset_in_stsd_data + 8;
-
-                        let avcc_data_len = (avcc_size - 8) as usize;
-                        let avcc_data_end = avcc_data_start + avcc_data_len;
-
-                        if stsd_box.data.len() >= avcc_data_end {
-                            let avcc_data = stsd_box.data[avcc_data_start..avcc_data_end].to_vec();
-                            stsd_box.avcc = Some(avcc_data);
-                        } else {
-                            println!("Not enough data for avcC box content. Expected end: {}, Actual len: {}", avcc_data_end, stsd_box.data.len());
-                        }
-                    } else {
-                        println!("Expected avcC box, but got {}", avcc_title);
+    // Only the first sample description entry is used, starting with its size and format
+    if entry_count >= 1 && stsd_box.data.len() >= 16 {
+        let entry_size = u32::from_be_bytes(stsd_box.data[8..12].try_into().unwrap()) as usize;
+        let format = String::from_utf8_lossy(&stsd_box.data[12..16]).to_string();
+
+        let entry_end = (8 + entry_size).clamp(16, stsd_box.data.len());
+        let entry = &stsd_box.data[16..entry_end];
+
+        if let Some(codec) = VideoCodec::from_sample_entry(&format) {
+            // The fixed fields of a visual sample entry take 78 bytes, then come its boxes,
+            // e.g. pasp, colr or btrt next to the decoder configuration
+            stsd_box.dimensions = parse_visual_sample_entry_dimensions(entry);
+            stsd_box.codec_config = parse_sample_entry_box(entry, 78, codec.config_box_title())?;
+            stsd_box.video_codec = Some(codec);
+        } else if let Some((channels, sample_rate, boxes_offset)) = parse_audio_sample_entry(entry)
+        {
+            match format.as_str() {
+                "mp4a" => {
+                    if let Some(esds) = parse_sample_entry_box(entry, boxes_offset, "esds")? {
+                        let (object_type, decoder_config) = parse_esds(&esds)?;
+
+                        stsd_box.audio_codec = AudioCodec::from_object_type(object_type);
+                        stsd_box.codec_config = Some(decoder_config);
+                    }
+                }
+                "Opus" => {
+                    if let Some(dops) = parse_sample_entry_box(entry, boxes_offset, "dOps")? {
+                        stsd_box.audio_codec = Some(AudioCodec::Opus);
+                        stsd_box.codec_config = Some(opus_head_from_dops(&dops)?);
                     }
-                } else {
-                    println!(
-                        "Not enough data for avcC box header. Required: {}, Actual: {}",
-                        avcc_offset_in_stsd_data + 8,
-                        stsd_box.data.len()
-                    );
                 }
+                _ => {}
+            }
+
+            if stsd_box.audio_codec.is_some() {
+                stsd_box.audio_format = Some((channels, sample_rate));
             }
-        } else {
-            println!("Not enough data for sample description entry size and format.");
         }
-    } else {
-        println!("No sample description entries found in stsd box.");
     }
 
+    Ok(stsd_box)
+}
+
+pub fn get_stbl_box(size: u32, mut data: Vec<u8>) -> Res<STBLBbox> {
+    let mut stsd_box: Option<STSDBox> = None;
     let mut stts_box: Option<STTSBox> = None;
     let mut ctts_box: Option<CTTSBox> = None;
     let mut stsc_box: Option<STSCBox> = None;
     let mut stsz_box: Option<STSZBox> = None;
     let mut stco_box: Option<STCOBox> = None;
+    let mut co64_box: Option<CO64Box> = None;
     let mut stss_box: Option<STSSBox> = None;
+    let mut unknown_boxes = vec![];
 
     while data.len() > 0 {
-        let (box_size, title) = data.get_next_box_size_and_title();
+        let (box_size, title) = data.get_next_box_size_and_title()?;
 
         match title.as_ref() {
+            "stsd" => {
+                stsd_box = Some(get_stsd_box(box_size, data.drain_box_data(box_size)?)?);
+            }
             "stts" => {
                 stts_box = Some(STTSBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 });
             }
             "ctts" => {
                 ctts_box = Some(CTTSBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 });
             }
             "stsc" => {
                 stsc_box = Some(STSCBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 });
             }
             "stsz" => {
                 stsz_box = Some(STSZBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 });
             }
             "stco" => {
                 stco_box = Some(STCOBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
+                });
+            }
+            "co64" => {
+                co64_box = Some(CO64Box {
+                    size: box_size,
+                    data: data.drain_box_data(box_size)?,
                 });
             }
             "stss" => {
                 stss_box = Some(STSSBox {
                     size: box_size,
-                    data: data.drain_box_data(box_size),
+                    data: data.drain_box_data(box_size)?,
                 });
             }
-            _ => return Err(format!("Unknown stbl sub-box, got {}", title).into()),
+            _ => {
+                unknown_boxes.push(data.drain_unknown_box(box_size, title)?);
+            }
         }
     }
 
+    if stco_box.is_none() && co64_box.is_none() {
+        return Err(PlaybackError::Container("stco or co64 not found".to_string()));
+    }
+
     Ok(STBLBbox {
         size,
-        stsd: stsd_box,
+        stsd: stsd_box.ok_or_else(|| PlaybackError::missing_box("stsd"))?,
         ctts: ctts_box,
-        stts: stts_box.ok_or("stts not found")?,
-        stsz: stsz_box.ok_or("stts not found")?,
-        stco: stco_box.ok_or("stts not found")?,
-        stsc: stsc_box.ok_or("stts not found")?,
+        stts: stts_box.ok_or_else(|| PlaybackError::missing_box("stts"))?,
+        stsz: stsz_box.ok_or_else(|| PlaybackError::missing_box("stsz"))?,
+        stco: stco_box,
+        co64: co64_box,
+        stsc: stsc_box.ok_or_else(|| PlaybackError::missing_box("stsc"))?,
         stss: stss_box,
+        unknown_boxes,
     })
 }
 
@@ -484,11 +903,11 @@ mod tests {
             0x05, 0x06, 0x07, 0x08,
         ];
         
-        let (size, title) = data.get_next_box_size_and_title();
+        let (size, title) = data.get_next_box_size_and_title().unwrap();
         assert_eq!(size, 16);
         assert_eq!(title, "moov");
         
-        let box_data = data.drain_box_data(size);
+        let box_data = data.drain_box_data(size).unwrap();
         assert_eq!(box_data, vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
     }
 
@@ -500,12 +919,59 @@ mod tests {
             0x69, 0x73, 0x6F, 0x6D, // remaining data
         ];
         
-        let (size, title) = data.get_next_box_size_and_title();
+        let (size, title) = data.get_next_box_size_and_title().unwrap();
         assert_eq!(size, 24);
         assert_eq!(title, "ftyp");
         assert_eq!(data.len(), 4); // Only remaining data left
     }
 
+    #[test]
+    fn test_get_next_box_size_and_title_with_largesize() {
+        let mut data = vec![
+            0x00, 0x00, 0x00, 0x01, // size = 1, largesize follows
+            0x66, 0x72, 0x65, 0x65, // "free"
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, // largesize = 20
+            0x01, 0x02, 0x03, 0x04, // payload
+        ];
+
+        let (size, title) = data.get_next_box_size_and_title().unwrap();
+        assert_eq!(title, "free");
+        assert_eq!(data.drain_box_data(size).unwrap(), vec![0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn test_get_next_box_size_and_title_to_end() {
+        let mut data = vec![
+            0x00, 0x00, 0x00, 0x00, // size = 0, runs to the end
+            0x66, 0x72, 0x65, 0x65, // "free"
+            0x01, 0x02, 0x03, // payload
+        ];
+
+        let (size, title) = data.get_next_box_size_and_title().unwrap();
+        assert_eq!(title, "free");
+        assert_eq!(data.drain_box_data(size).unwrap(), vec![0x01, 0x02, 0x03]);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_truncated_box_header() {
+        let mut data = vec![0x00, 0x00, 0x00, 0x10, 0x6D, 0x6F]; // size = 16, half a title
+        assert!(matches!(data.get_next_box_size_and_title(), Err(PlaybackError::Container(_))));
+
+        let mut data = vec![0x00, 0x00, 0x00, 0x01, 0x66, 0x72, 0x65, 0x65, 0x00, 0x00]; // half a largesize
+        assert!(matches!(data.get_next_box_size_and_title(), Err(PlaybackError::Container(_))));
+    }
+
+    #[test]
+    fn test_drain_box_data_with_invalid_size() {
+        let mut data = vec![0x01, 0x02, 0x03, 0x04];
+
+        // Smaller than the header, and larger than what is left
+        assert!(matches!(data.drain_box_data(4), Err(PlaybackError::Container(_))));
+        assert!(matches!(data.drain_box_data(16), Err(PlaybackError::Container(_))));
+        assert_eq!(data.len(), 4);
+    }
+
     #[test]
     fn test_get_moov_box_with_mvhd() {
         let data = vec![
@@ -523,6 +989,56 @@ mod tests {
         assert_eq!(moov_box.size, 24);
         assert_eq!(moov_box.mvhd.size, 16);
         assert_eq!(moov_box.traks.len(), 0);
+        assert!(moov_box.mvex.is_none());
+    }
+
+    #[test]
+    fn test_get_moov_box_with_mvex() {
+        let data = vec![
+            // mvhd box
+            0x00, 0x00, 0x00, 0x0C, // size = 12
+            0x6D, 0x76, 0x68, 0x64, // "mvhd"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            // mvex box
+            0x00, 0x00, 0x00, 0x28, // size = 40
+            0x6D, 0x76, 0x65, 0x78, // "mvex"
+            0x00, 0x00, 0x00, 0x20, // size = 32
+            0x74, 0x72, 0x65, 0x78, // "trex"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x01, // track id = 1
+            0x00, 0x00, 0x00, 0x01, // default sample description index
+            0x00, 0x00, 0x02, 0x00, // default sample duration = 512
+            0x00, 0x00, 0x00, 0x00, // default sample size
+            0x00, 0x01, 0x00, 0x00, // default sample flags
+        ];
+
+        let moov_box = get_moov_box(60, data).unwrap();
+        let mvex_box = moov_box.mvex.unwrap();
+
+        assert_eq!(mvex_box.size, 40);
+        assert_eq!(mvex_box.trexs.len(), 1);
+        assert_eq!(mvex_box.trexs[0].data.len(), 24);
+    }
+
+    #[test]
+    fn test_get_edts_box() {
+        let data = vec![
+            // elst box
+            0x00, 0x00, 0x00, 0x1C, // size = 28
+            0x65, 0x6C, 0x73, 0x74, // "elst"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x01, // entry count = 1
+            0x00, 0x00, 0x03, 0xE8, // segment duration = 1000
+            0x00, 0x00, 0x04, 0x00, // media time = 1024
+            0x00, 0x01, 0x00, 0x00, // media rate = 1.0
+        ];
+
+        let edts_box = get_edts_box(36, data).unwrap();
+        assert_eq!(edts_box.size, 36);
+
+        let elst_box = edts_box.elst.unwrap();
+        assert_eq!(elst_box.size, 28);
+        assert_eq!(elst_box.data.len(), 20);
     }
 
     #[test]
@@ -549,12 +1065,230 @@ mod tests {
         // Test that the enum variants exist and can be matched
         match video_stream {
             Streams::Video => assert!(true),
-            Streams::Audio => assert!(false),
+            Streams::Audio | Streams::Other => assert!(false),
         }
         
         match audio_stream {
             Streams::Audio => assert!(true),
-            Streams::Video => assert!(false),
+            Streams::Video | Streams::Other => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_get_stbl_box_avc1_dimensions() {
+        let mut avc1_entry = vec![
+            0x00, 0x00, 0x00, 0x56, // entry size = 86
+            0x61, 0x76, 0x63, 0x31, // "avc1"
+        ];
+        avc1_entry.extend_from_slice(&[0; 24]); // reserved and pre-defined fields
+        avc1_entry.extend_from_slice(&[0x05, 0x00, 0x02, 0xD0]); // 1280x720
+        avc1_entry.extend_from_slice(&[0; 50]); // rest of the visual sample entry
+
+        let mut data = vec![];
+        data.extend_from_slice(&((16 + avc1_entry.len()) as u32).to_be_bytes());
+        data.extend_from_slice(b"stsd");
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // version + flags
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]); // entry count = 1
+        data.extend_from_slice(&avc1_entry);
+
+        for title in [b"stts", b"stsc", b"stsz", b"stco"] {
+            data.extend_from_slice(&[0x00, 0x00, 0x00, 0x10]);
+            data.extend_from_slice(title);
+            data.extend_from_slice(&[0; 8]);
+        }
+
+        let stbl_box = get_stbl_box(data.len() as u32 + 8, data).unwrap();
+
+        assert_eq!(stbl_box.stsd.video_codec, Some(VideoCodec::H264));
+        assert_eq!(stbl_box.stsd.dimensions, Some((1280, 720)));
+        assert!(stbl_box.stsd.codec_config.is_none());
+    }
+
+    #[test]
+    fn test_get_stbl_box_hvc1_config() {
+        let mut hvc1_entry = vec![];
+        hvc1_entry.extend_from_slice(&[0; 24]); // reserved and pre-defined fields
+        hvc1_entry.extend_from_slice(&[0x07, 0x80, 0x04, 0x38]); // 1920x1080
+        hvc1_entry.extend_from_slice(&[0; 50]); // rest of the visual sample entry
+        hvc1_entry.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x70, 0x61, 0x73, 0x70, // "pasp"
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+            0x00, 0x00, 0x00, 0x0C, // size = 12
+            0x68, 0x76, 0x63, 0x43, // "hvcC"
+            0x01, 0x02, 0x03, 0x04,
+        ]);
+
+        let mut data = vec![];
+        data.extend_from_slice(&((24 + hvc1_entry.len()) as u32).to_be_bytes());
+        data.extend_from_slice(b"stsd");
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // version + flags
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]); // entry count = 1
+        data.extend_from_slice(&((8 + hvc1_entry.len()) as u32).to_be_bytes());
+        data.extend_from_slice(b"hev1");
+        data.extend_from_slice(&hvc1_entry);
+
+        for title in [b"stts", b"stsc", b"stsz", b"stco"] {
+            data.extend_from_slice(&[0x00, 0x00, 0x00, 0x10]);
+            data.extend_from_slice(title);
+            data.extend_from_slice(&[0; 8]);
+        }
+
+        let stbl_box = get_stbl_box(data.len() as u32 + 8, data).unwrap();
+
+        assert_eq!(stbl_box.stsd.video_codec, Some(VideoCodec::Hevc));
+        assert_eq!(stbl_box.stsd.dimensions, Some((1920, 1080)));
+        assert_eq!(stbl_box.stsd.codec_config, Some(vec![0x01, 0x02, 0x03, 0x04]));
+    }
+
+    #[test]
+    fn test_video_codec_from_sample_entry() {
+        assert_eq!(VideoCodec::from_sample_entry("avc1"), Some(VideoCodec::H264));
+        assert_eq!(VideoCodec::from_sample_entry("hvc1"), Some(VideoCodec::Hevc));
+        assert_eq!(VideoCodec::from_sample_entry("vp09"), Some(VideoCodec::Vp9));
+        assert_eq!(VideoCodec::from_sample_entry("av01"), Some(VideoCodec::Av1));
+        assert_eq!(VideoCodec::from_sample_entry("mp4a"), None);
+        assert_eq!(VideoCodec::Av1.config_box_title(), "av1C");
+    }
+
+    #[test]
+    fn test_get_stbl_box_mp4a_esds() {
+        let mut mp4a_entry = vec![];
+        mp4a_entry.extend_from_slice(&[0; 8]); // reserved and data reference index
+        mp4a_entry.extend_from_slice(&[0; 8]); // version, revision and vendor
+        mp4a_entry.extend_from_slice(&[0x00, 0x01, 0x00, 0x10]); // mono, 16 bits
+        mp4a_entry.extend_from_slice(&[0; 4]); // compression id and packet size
+        mp4a_entry.extend_from_slice(&[0xBB, 0x80, 0x00, 0x00]); // 48000 Hz
+        mp4a_entry.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x2A, // size = 42
+            0x65, 0x73, 0x64, 0x73, // "esds"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x03, 0x80, 0x80, 0x80, 0x19, // ES_Descriptor, length in 4 bytes
+            0x00, 0x01, 0x00, // ES_ID and flags
+            0x04, 0x11, // DecoderConfigDescriptor
+            0x40, 0x15, // MPEG-4 audio, audio stream
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x02, 0x11, 0x88, // AudioSpecificConfig
+            0x06, 0x01, 0x02, // SLConfigDescriptor
+        ]);
+
+        let mut data = vec![];
+        data.extend_from_slice(&((24 + mp4a_entry.len()) as u32).to_be_bytes());
+        data.extend_from_slice(b"stsd");
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // version + flags
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]); // entry count = 1
+        data.extend_from_slice(&((8 + mp4a_entry.len()) as u32).to_be_bytes());
+        data.extend_from_slice(b"mp4a");
+        data.extend_from_slice(&mp4a_entry);
+
+        for title in [b"stts", b"stsc", b"stsz", b"stco"] {
+            data.extend_from_slice(&[0x00, 0x00, 0x00, 0x10]);
+            data.extend_from_slice(title);
+            data.extend_from_slice(&[0; 8]);
+        }
+
+        let stbl_box = get_stbl_box(data.len() as u32 + 8, data).unwrap();
+
+        assert_eq!(stbl_box.stsd.video_codec, None);
+        assert_eq!(stbl_box.stsd.audio_codec, Some(AudioCodec::Aac));
+        assert_eq!(stbl_box.stsd.audio_format, Some((1, 48000)));
+        assert_eq!(stbl_box.stsd.codec_config, Some(vec![0x11, 0x88]));
+    }
+
+    #[test]
+    fn test_parse_esds_without_decoder_specific_info() {
+        let esds = [
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x03, 0x14, 0x00, 0x01, 0x00, // ES_Descriptor
+            0x04, 0x0D, 0x6B, 0x15, // MPEG-1 audio
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        let (object_type, config) = parse_esds(&esds).unwrap();
+        assert_eq!(AudioCodec::from_object_type(object_type), Some(AudioCodec::Mp3));
+        assert!(config.is_empty());
+
+        assert!(parse_esds(&[0x00, 0x00, 0x00, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_opus_head_from_dops() {
+        let dops = [
+            0x00, // version
+            0x02, // channels
+            0x01, 0x38, // pre-skip = 312
+            0x00, 0x00, 0xBB, 0x80, // input sample rate = 48000
+            0x00, 0x00, // output gain
+            0x00, // channel mapping family
+        ];
+
+        let opus_head = opus_head_from_dops(&dops).unwrap();
+
+        assert_eq!(&opus_head[..8], b"OpusHead");
+        assert_eq!(
+            &opus_head[8..],
+            &[0x01, 0x02, 0x38, 0x01, 0x80, 0xBB, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert!(opus_head_from_dops(&dops[..10]).is_err());
+    }
+
+    #[test]
+    fn test_get_stbl_box_with_stsd_after_other_boxes() {
+        let mut data = vec![];
+
+        for title in [b"stts", b"stsc", b"stsz", b"stco"] {
+            data.extend_from_slice(&[0x00, 0x00, 0x00, 0x10]);
+            data.extend_from_slice(title);
+            data.extend_from_slice(&[0; 8]);
+        }
+
+        data.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x73, 0x74, 0x73, 0x64, // "stsd"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x00, // entry count = 0
+        ]);
+
+        let stbl_box = get_stbl_box(data.len() as u32 + 8, data).unwrap();
+
+        assert_eq!(stbl_box.stsd.size, 16);
+        assert!(stbl_box.unknown_boxes.is_empty());
+    }
+
+    #[test]
+    fn test_get_minf_box_keeps_unknown_boxes() {
+        let mut stbl = vec![
+            0x00, 0x00, 0x00, 0x10, // size = 16
+            0x73, 0x74, 0x73, 0x64, // "stsd"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            0x00, 0x00, 0x00, 0x00, // entry count = 0
+        ];
+
+        for title in [b"stts", b"stsc", b"stsz", b"stco", b"sgpd"] {
+            stbl.extend_from_slice(&[0x00, 0x00, 0x00, 0x10]);
+            stbl.extend_from_slice(title);
+            stbl.extend_from_slice(&[0; 8]);
         }
+
+        let mut data = vec![
+            // nmhd box, for a track that is neither video nor audio
+            0x00, 0x00, 0x00, 0x0C, // size = 12
+            0x6E, 0x6D, 0x68, 0x64, // "nmhd"
+            0x00, 0x00, 0x00, 0x00, // version + flags
+            // dinf box
+            0x00, 0x00, 0x00, 0x08, // size = 8
+            0x64, 0x69, 0x6E, 0x66, // "dinf"
+        ];
+        data.extend_from_slice(&(stbl.len() as u32 + 8).to_be_bytes());
+        data.extend_from_slice(b"stbl");
+        data.extend_from_slice(&stbl);
+
+        let minf_box = get_minf_box(data.len() as u32 + 8, data).unwrap();
+
+        assert!(matches!(minf_box.header, Streams::Other));
+        assert_eq!(minf_box.unknown_boxes.len(), 1);
+        assert_eq!(minf_box.unknown_boxes[0].title, "nmhd");
+        assert_eq!(minf_box.stbl.unknown_boxes.len(), 1);
+        assert_eq!(minf_box.stbl.unknown_boxes[0].title, "sgpd");
     }
 }
@@ -1,20 +1,77 @@
-use std::{collections::VecDeque, error::Error, usize};
+use std::{collections::VecDeque, iter, usize};
 
-use super::get_moov_box::{MOOVBox, STCOBox, STSCBox, STSZBox, Streams};
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::Res;
+
+use super::get_moof_box::{MOOFBox, SIDXBox, TFDTBox, TFHDBox, TRUNBox};
+use super::get_moov_box::{
+    CO64Box, CTTSBox, ELSTBox, MDHDBox, MOOVBox, MVHDBox, STCOBox, STSCBox, STSSBox, STSZBox, STTSBox, Streams,
+    TKHDBox, TRAKBox, TREXBox,
+};
 
 pub type SampleMap = VecDeque<SampleData>;
 
+/*
+ * Samples a trun box without per-sample fields can list. The bytes of the box bound
+ * the count otherwise, but such a run is only its header, whatever its length
+ */
+const MAX_RUN_SAMPLES_WITHOUT_FIELDS: usize = 1 << 20;
+
 #[derive(Clone, Debug)]
 pub struct SampleData {
     pub size: u32,
     pub is_video: bool,
+    /// Absolute position of the sample in the file
+    pub offset: u64,
+    /// Duration of the sample, in the timescale of its track
+    pub duration: u32,
+    /// Decode time of the sample, in the timescale of its track
+    pub decode_time: u64,
+    /// Number of time units per second in the sample's track
+    pub timescale: u32,
+    /// Offset from the decode time to the presentation time, from ctts or trun and the edit list
+    pub composition_offset: i32,
+    /// Whether decoding can start at this sample
+    pub is_sync: bool,
+}
+
+impl SampleData {
+    pub fn decode_time_in_ms(&self) -> u64 {
+        if self.timescale == 0 {
+            return 0;
+        }
+
+        self.decode_time * 1000 / self.timescale as u64
+    }
+
+    /// Time the sample is shown at, negative for the samples an edit list cuts from the start
+    pub fn presentation_time(&self) -> i64 {
+        self.decode_time as i64 + self.composition_offset as i64
+    }
+
+    pub fn presentation_time_in_ms(&self) -> i64 {
+        if self.timescale == 0 {
+            return 0;
+        }
+
+        self.presentation_time() * 1000 / self.timescale as i64
+    }
+}
+
+/// Where a subsegment listed in a sidx box starts, in bytes and in time
+#[derive(Clone, Debug, PartialEq)]
+pub struct SegmentReference {
+    pub offset: u64,
+    pub time_in_ms: u64,
+    pub duration_in_ms: u64,
 }
 
 #[derive(Debug)]
 pub struct ChunkData {
     pub is_video: bool,
-    pub offset: u32,
+    pub offset: u64,
     pub sample_sizes: Vec<u32>,
+    pub sample_durations: Vec<u32>,
 }
 
 struct ChunkToSample {
@@ -22,57 +79,251 @@ struct ChunkToSample {
     pub sample_count: u32,
 }
 
-pub fn get_sample_map(moov_box: MOOVBox) -> Result<SampleMap, Box<dyn Error>> {
-    let mut chunk_data: VecDeque<ChunkData> = VecDeque::new();
-    for trak in moov_box.traks {
-        let chunk_offsets = parse_stco(&trak.media.minf.stbl.stco);
-        let chunk_offsets_with_sample_count = parse_stsc(&trak.media.minf.stbl.stsc, chunk_offsets);
+/// Sample defaults for a track, from its trex box or a tfhd box
+#[derive(Debug, Default, PartialEq)]
+struct TrackFragmentDefaults {
+    track_id: u32,
+    base_data_offset: Option<u64>,
+    default_base_is_moof: bool,
+    sample_duration: Option<u32>,
+    sample_size: Option<u32>,
+    sample_flags: Option<u32>,
+}
+
+#[derive(Debug, PartialEq)]
+struct TrackRunSample {
+    duration: Option<u32>,
+    size: Option<u32>,
+    flags: Option<u32>,
+    composition_offset: Option<i32>,
+}
+
+#[derive(Debug, PartialEq)]
+struct TrackRun {
+    data_offset: Option<i32>,
+    first_sample_flags: Option<u32>,
+    samples: Vec<TrackRunSample>,
+}
+
+pub fn get_sample_map(moov_box: &MOOVBox) -> Res<SampleMap> {
+    let mut samples: Vec<SampleData> = vec![];
+    let movie_timescale = parse_mvhd_timescale(&moov_box.mvhd);
+
+    for trak in &moov_box.traks {
+        if matches!(trak.media.minf.header, Streams::Other) {
+            continue;
+        }
+
+        let chunk_offsets = match trak.media.minf.stbl.co64 {
+            Some(ref co64) => parse_co64(co64)?,
+            None => trak.media.minf.stbl.stco.as_ref().map(parse_stco).transpose()?.unwrap_or_default(),
+        };
+        let chunk_offsets_with_sample_count = parse_stsc(&trak.media.minf.stbl.stsc, chunk_offsets)?;
+        let sample_count = parse_stsz_sample_count(&trak.media.minf.stbl.stsz)?;
+        let sample_durations = parse_stts(&trak.media.minf.stbl.stts, sample_count)?;
+        let is_video = matches!(trak.media.minf.header, Streams::Video);
+
+        let mut chunk_data = parse_stsz(
+            &trak.media.minf.stbl.stsz,
+            chunk_offsets_with_sample_count,
+            is_video,
+        )?;
+
+        set_sample_durations(&mut chunk_data, &sample_durations);
+
+        // Timing and sync samples are numbered per track, so set them before merging tracks
+        let mut track_samples = format_sample_data(chunk_data);
+
+        set_sample_timing(
+            &mut track_samples,
+            parse_mdhd_timescale(&trak.media.mdhd),
+            trak.media.minf.stbl.stss.as_ref().map(parse_stss).transpose()?,
+            &trak
+                .media
+                .minf
+                .stbl
+                .ctts
+                .as_ref()
+                .map(|ctts| parse_ctts(ctts, sample_count))
+                .transpose()?
+                .unwrap_or_default(),
+        );
+
+        let edit_offset = parse_edit_offset(trak, movie_timescale)?;
+        for sample in track_samples.iter_mut() {
+            sample.composition_offset += edit_offset;
+        }
+
+        samples.extend(track_samples);
+    }
+
+    samples.sort_by_key(|sample| sample.offset);
+
+    Ok(samples.into())
+}
+
+/*
+ * Keeps the samples needed to start playing at target_ms: video from the
+ * closest sync sample shown at or before the target, and audio from that sample's time on.
+ * Times are compared on the presentation timeline that playback timestamps use,
+ * while video is kept in decode order from the sync sample.
+ * Returns the remaining samples and the time playback resumes at.
+ */
+pub fn seek_sample_map(sample_map: SampleMap, target_ms: u64) -> (SampleMap, u64) {
+    let has_video = sample_map.iter().any(|sample| sample.is_video);
+
+    let keyframe = sample_map
+        .iter()
+        .filter(|sample| sample.is_video && sample.is_sync)
+        .filter(|sample| sample.presentation_time_in_ms() <= target_ms as i64)
+        .max_by_key(|sample| sample.decode_time);
+
+    let (keyframe_time, resume_ms) = match keyframe {
+        Some(keyframe) => (keyframe.decode_time, keyframe.presentation_time_in_ms().max(0) as u64),
+        // Without video, audio can start anywhere
+        None if !has_video => (0, target_ms),
+        None => (0, 0),
+    };
+
+    let sample_map = sample_map
+        .into_iter()
+        .filter(|sample| {
+            if sample.is_video {
+                sample.decode_time >= keyframe_time
+            } else {
+                sample.presentation_time_in_ms() >= resume_ms as i64
+            }
+        })
+        .collect();
+
+    (sample_map, resume_ms)
+}
+
+/*
+ * Builds the samples of a fragmented file's moof box.
+ * moof_offset is the absolute position of the moof box in the file,
+ * which the sample data offsets are relative to.
+ */
+pub fn get_fragment_sample_map(
+    moof_box: &MOOFBox,
+    moof_offset: u64,
+    moov_box: &MOOVBox,
+) -> Res<SampleMap> {
+    let mut samples: Vec<SampleData> = vec![];
+    let movie_timescale = parse_mvhd_timescale(&moov_box.mvhd);
+
+    // Without an explicit base, a traf's data follows the previous traf's data
+    let mut next_data_offset = moof_offset;
+
+    for traf in &moof_box.trafs {
+        let tfhd = parse_tfhd(&traf.tfhd)?;
 
-        match trak.media.minf.header {
-            Streams::Audio => {
-                let mut new_chunk_data = parse_stsz(
-                    &trak.media.minf.stbl.stsz,
-                    chunk_offsets_with_sample_count,
-                    false,
-                );
+        let trak = moov_box
+            .traks
+            .iter()
+            .find(|trak| parse_tkhd_track_id(&trak.tkhd).is_ok_and(|track_id| track_id == tfhd.track_id))
+            .ok_or_else(|| PlaybackError::Container(format!("No trak box found for track {}", tfhd.track_id)))?;
+
+        let is_video = matches!(trak.media.minf.header, Streams::Video);
+        let is_played = !matches!(trak.media.minf.header, Streams::Other);
+
+        let trex = moov_box
+            .mvex
+            .iter()
+            .flat_map(|mvex| mvex.trexs.iter())
+            .map(parse_trex)
+            .collect::<Res<Vec<_>>>()?
+            .into_iter()
+            .find(|trex| trex.track_id == tfhd.track_id)
+            .unwrap_or_default();
 
-                chunk_data.append(&mut new_chunk_data);
+        let default_duration = tfhd.sample_duration.or(trex.sample_duration).unwrap_or(0);
+        let default_size = tfhd.sample_size.or(trex.sample_size).unwrap_or(0);
+        let default_flags = tfhd.sample_flags.or(trex.sample_flags);
+
+        let timescale = parse_mdhd_timescale(&trak.media.mdhd);
+        let edit_offset = parse_edit_offset(trak, movie_timescale)?;
+        let mut decode_time = traf.tfdt.as_ref().map(parse_tfdt).transpose()?.unwrap_or(0);
+
+        let base_data_offset = tfhd.base_data_offset.unwrap_or(if tfhd.default_base_is_moof {
+            moof_offset
+        } else {
+            next_data_offset
+        });
+
+        let mut data_offset = base_data_offset;
+
+        for trun in &traf.truns {
+            let trun = parse_trun(trun)?;
+
+            if let Some(relative_offset) = trun.data_offset {
+                data_offset = (base_data_offset as i64 + relative_offset as i64) as u64;
             }
-            Streams::Video => {
-                let mut new_chunk_data = parse_stsz(
-                    &trak.media.minf.stbl.stsz,
-                    chunk_offsets_with_sample_count,
-                    true,
-                );
-
-                chunk_data.append(&mut new_chunk_data);
+
+            for (index, sample) in trun.samples.into_iter().enumerate() {
+                let size = sample.size.unwrap_or(default_size);
+                let duration = sample.duration.unwrap_or(default_duration);
+
+                let flags = match index {
+                    0 => sample.flags.or(trun.first_sample_flags),
+                    _ => sample.flags,
+                }
+                .or(default_flags);
+
+                // Samples of the tracks that are not played are skipped over
+                if is_played {
+                    samples.push(SampleData {
+                        size,
+                        is_video,
+                        offset: data_offset,
+                        duration,
+                        decode_time,
+                        timescale,
+                        composition_offset: sample.composition_offset.unwrap_or(0) + edit_offset,
+                        // sample_is_non_sync_sample flag
+                        is_sync: flags.is_none_or(|flags| flags & 0x00010000 == 0),
+                    });
+                }
+
+                data_offset += size as u64;
+                decode_time += duration as u64;
             }
         }
+
+        next_data_offset = data_offset;
     }
 
-    Ok(format_sample_data(chunk_data))
+    samples.sort_by_key(|sample| sample.offset);
+
+    Ok(samples.into())
 }
 
 fn format_sample_data(chunk_data: VecDeque<ChunkData>) -> VecDeque<SampleData> {
     let mut chunk_data_vec: Vec<ChunkData> = chunk_data.into();
     chunk_data_vec.sort_by(|a, b| a.offset.cmp(&b.offset));
-    assert!(chunk_data_vec[0].offset < chunk_data_vec[1].offset);
-
-    let mut sample_offsets_sum = 0;
 
     chunk_data_vec
         .iter()
         .flat_map(|chunk| {
+            let mut sample_offset = chunk.offset;
+
             chunk
                 .sample_sizes
                 .iter()
-                .map(|size| {
+                .enumerate()
+                .map(|(index, size)| {
                     let sample_data: SampleData = SampleData {
                         size: *size,
                         is_video: chunk.is_video,
+                        offset: sample_offset,
+                        duration: chunk.sample_durations.get(index).copied().unwrap_or(0),
+                        decode_time: 0,
+                        timescale: 0,
+                        composition_offset: 0,
+                        is_sync: true,
                     };
 
-                    sample_offsets_sum += *size;
+                    sample_offset += *size as u64;
                     sample_data
                 })
                 .collect::<VecDeque<SampleData>>()
@@ -80,7 +331,416 @@ fn format_sample_data(chunk_data: VecDeque<ChunkData>) -> VecDeque<SampleData> {
         .collect::<VecDeque<SampleData>>()
 }
 
-fn parse_stco(stco_box: &STCOBox) -> Vec<u32> {
+/*
+ * Expands the stts box into one duration per sample, for at most the sample_count
+ * samples of the stsz box, so that the counts in the file don't size the buffer
+ */
+fn parse_stts(stts: &STTSBox, sample_count: usize) -> Res<Vec<u32>> {
+    let data: Vec<u32> = stts
+        .data
+        .chunks_exact(4)
+        .map(|chunk| {
+            let bytes: [u8; 4] = chunk.try_into().unwrap();
+            u32::from_be_bytes(bytes)
+        })
+        .collect();
+
+    let entries = data
+        .get(2..)
+        .ok_or_else(|| PlaybackError::Container("stts box is too short".to_string()))?;
+
+    let mut durations = vec![];
+
+    for entry in entries.chunks_exact(2) {
+        let count = (entry[0] as usize).min(sample_count - durations.len());
+        durations.extend(iter::repeat_n(entry[1], count));
+    }
+
+    Ok(durations)
+}
+
+fn set_sample_durations(chunk_data: &mut VecDeque<ChunkData>, sample_durations: &[u32]) {
+    let mut current_index = 0;
+
+    for chunk in chunk_data.iter_mut() {
+        let sample_count = chunk.sample_sizes.len();
+
+        chunk.sample_durations = sample_durations
+            .iter()
+            .skip(current_index)
+            .take(sample_count)
+            .copied()
+            .collect();
+
+        current_index += sample_count;
+    }
+}
+
+/*
+ * Sets the decode time of each sample of a track from the durations before it,
+ * its composition offset from ctts, and marks the sync samples listed in stss
+ */
+fn set_sample_timing(
+    samples: &mut VecDeque<SampleData>,
+    timescale: u32,
+    sync_samples: Option<Vec<u32>>,
+    composition_offsets: &[i32],
+) {
+    let mut decode_time = 0;
+
+    for (index, sample) in samples.iter_mut().enumerate() {
+        sample.decode_time = decode_time;
+        sample.timescale = timescale;
+        sample.composition_offset = composition_offsets.get(index).copied().unwrap_or(0);
+
+        // stss uses 1-based sample numbers, and every sample is a sync sample without it
+        sample.is_sync = sync_samples
+            .as_ref()
+            .is_none_or(|sync_samples| sync_samples.binary_search(&(index as u32 + 1)).is_ok());
+
+        decode_time += sample.duration as u64;
+    }
+}
+
+/*
+ * Expands the ctts box into one composition offset per sample, for at most the sample_count
+ * samples of the stsz box. Version 0 offsets are unsigned, but encoders write negative ones
+ * there too, so both are read as signed
+ */
+fn parse_ctts(ctts: &CTTSBox, sample_count: usize) -> Res<Vec<i32>> {
+    let entry_count = read_u32(&ctts.data, 4)? as usize;
+    let mut offsets = vec![];
+
+    for index in 0..entry_count {
+        let count = read_u32(&ctts.data, 8 + index * 8)? as usize;
+        let offset = read_u32(&ctts.data, 12 + index * 8)? as i32;
+        offsets.extend(iter::repeat_n(offset, count.min(sample_count - offsets.len())));
+    }
+
+    Ok(offsets)
+}
+
+fn parse_stss(stss: &STSSBox) -> Res<Vec<u32>> {
+    let entry_count = read_u32(&stss.data, 4)? as usize;
+
+    (0..entry_count)
+        .map(|index| read_u32(&stss.data, 8 + index * 4))
+        .collect()
+}
+
+/*
+ * Timescale of a track, or 0 when the mdhd box is too short to hold it
+ */
+pub fn parse_mdhd_timescale(mdhd: &MDHDBox) -> u32 {
+    let timescale = match mdhd.data.first() {
+        Some(1) => read_u32(&mdhd.data, 20),
+        Some(0) => read_u32(&mdhd.data, 12),
+        _ => return 0,
+    };
+
+    timescale.unwrap_or(0)
+}
+
+/*
+ * Shift from the media time of a track to the presentation, in the track's timescale.
+ * Leading empty edits delay the track, and the first media edit cuts what comes before
+ * its media time, e.g. the priming samples of an AAC encoder. Later edits are ignored,
+ * they are rare outside of editing software
+ */
+fn parse_edit_offset(trak: &TRAKBox, movie_timescale: u32) -> Res<i32> {
+    let media_timescale = parse_mdhd_timescale(&trak.media.mdhd);
+
+    match trak.edts.as_ref().and_then(|edts| edts.elst.as_ref()) {
+        Some(elst) if movie_timescale > 0 => {
+            let (empty_duration, media_time) = parse_elst(elst)?;
+            let delay = empty_duration * media_timescale as u64 / movie_timescale as u64;
+
+            Ok((delay as i64 - media_time) as i32)
+        }
+        _ => Ok(0),
+    }
+}
+
+/*
+ * Total duration of the leading empty edits, in the movie timescale,
+ * and the media time the first media edit starts at
+ */
+fn parse_elst(elst: &ELSTBox) -> Res<(u64, i64)> {
+    let version = elst.data.first().copied().unwrap_or(0);
+    let entry_count = read_u32(&elst.data, 4)? as usize;
+    let entry_size = if version == 1 { 20 } else { 12 };
+
+    let mut empty_duration = 0;
+
+    for index in 0..entry_count {
+        let offset = 8 + index * entry_size;
+
+        let (segment_duration, media_time) = if version == 1 {
+            (read_u64(&elst.data, offset)?, read_u64(&elst.data, offset + 8)? as i64)
+        } else {
+            (read_u32(&elst.data, offset)? as u64, read_u32(&elst.data, offset + 4)? as i32 as i64)
+        };
+
+        // An empty edit shows nothing from this track for its duration
+        if media_time == -1 {
+            empty_duration += segment_duration;
+            continue;
+        }
+
+        return Ok((empty_duration, media_time));
+    }
+
+    Ok((empty_duration, 0))
+}
+
+fn parse_mvhd_timescale(mvhd: &MVHDBox) -> u32 {
+    let timescale = match mvhd.data.first() {
+        Some(1) => read_u32(&mvhd.data, 20),
+        Some(0) => read_u32(&mvhd.data, 12),
+        _ => return 0,
+    };
+
+    timescale.unwrap_or(0)
+}
+
+/*
+ * Duration of the presentation from the mvhd box, which is 0 in most fragmented files
+ */
+pub fn parse_mvhd_duration_in_ms(mvhd: &MVHDBox) -> u64 {
+    let (timescale, duration) = match mvhd.data.first() {
+        Some(1) => (read_u32(&mvhd.data, 20), read_u64(&mvhd.data, 24)),
+        Some(0) => (read_u32(&mvhd.data, 12), read_u32(&mvhd.data, 16).map(u64::from)),
+        _ => return 0,
+    };
+
+    match (timescale, duration) {
+        (Ok(timescale), Ok(duration)) if timescale > 0 => duration * 1000 / timescale as u64,
+        _ => 0,
+    }
+}
+
+fn parse_tfdt(tfdt: &TFDTBox) -> Res<u64> {
+    match tfdt.data.first() {
+        Some(1) => read_u64(&tfdt.data, 4),
+        _ => read_u32(&tfdt.data, 4).map(u64::from),
+    }
+}
+
+/*
+ * Lists where each subsegment of a sidx box starts.
+ * sidx_end is the absolute position of the first byte after the sidx box,
+ * which the subsegment offsets are relative to.
+ */
+pub fn parse_sidx(sidx: &SIDXBox, sidx_end: u64) -> Res<Vec<SegmentReference>> {
+    let timescale = read_u32(&sidx.data, 8)? as u64;
+
+    let (mut time, first_offset, mut offset) = match sidx.data.first() {
+        Some(1) => (read_u64(&sidx.data, 12)?, read_u64(&sidx.data, 20)?, 28),
+        _ => (
+            read_u32(&sidx.data, 12)? as u64,
+            read_u32(&sidx.data, 16)? as u64,
+            20,
+        ),
+    };
+
+    // The reference count takes the low 16 bits, after 2 reserved bytes
+    let reference_count = read_u32(&sidx.data, offset)? & 0xFFFF;
+    offset += 4;
+
+    let mut position = sidx_end + first_offset;
+    let mut references = Vec::with_capacity(reference_count as usize);
+
+    for _ in 0..reference_count {
+        if timescale == 0 || offset + 12 > sidx.data.len() {
+            break;
+        }
+
+        let referenced_size = read_u32(&sidx.data, offset)? & 0x7FFFFFFF;
+        let subsegment_duration = read_u32(&sidx.data, offset + 4)? as u64;
+
+        references.push(SegmentReference {
+            offset: position,
+            time_in_ms: time * 1000 / timescale,
+            duration_in_ms: subsegment_duration * 1000 / timescale,
+        });
+
+        position += referenced_size as u64;
+        time += subsegment_duration;
+        offset += 12;
+    }
+
+    Ok(references)
+}
+
+/*
+ * Reads big-endian numbers from the payload of a box, failing when it ends before them
+ */
+fn read_u32(data: &[u8], offset: usize) -> Res<u32> {
+    read_bytes(data, offset).map(u32::from_be_bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Res<u64> {
+    read_bytes(data, offset).map(u64::from_be_bytes)
+}
+
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> Res<[u8; N]> {
+    data.get(offset..offset + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| {
+            PlaybackError::Container(format!(
+                "Truncated box: needs {} bytes, {} left",
+                offset + N,
+                data.len()
+            ))
+        })
+}
+
+fn parse_tkhd_track_id(tkhd: &TKHDBox) -> Res<u32> {
+    match tkhd.data.first() {
+        Some(1) => read_u32(&tkhd.data, 20),
+        _ => read_u32(&tkhd.data, 12),
+    }
+}
+
+/*
+ * Width and height are stored as 16.16 fixed-point numbers at the end of the tkhd box
+ */
+pub fn parse_tkhd_dimensions(tkhd: &TKHDBox) -> (u32, u32) {
+    let dimensions_offset = match tkhd.data.first() {
+        Some(1) => 88,
+        _ => 76,
+    };
+
+    match (
+        read_u32(&tkhd.data, dimensions_offset),
+        read_u32(&tkhd.data, dimensions_offset + 4),
+    ) {
+        (Ok(width), Ok(height)) => (width >> 16, height >> 16),
+        _ => (0, 0),
+    }
+}
+
+fn parse_trex(trex: &TREXBox) -> Res<TrackFragmentDefaults> {
+    Ok(TrackFragmentDefaults {
+        track_id: read_u32(&trex.data, 4)?,
+        sample_duration: Some(read_u32(&trex.data, 12)?),
+        sample_size: Some(read_u32(&trex.data, 16)?),
+        sample_flags: Some(read_u32(&trex.data, 20)?),
+        ..Default::default()
+    })
+}
+
+fn parse_tfhd(tfhd: &TFHDBox) -> Res<TrackFragmentDefaults> {
+    let flags = read_u32(&tfhd.data, 0)? & 0x00FFFFFF;
+    let mut defaults = TrackFragmentDefaults {
+        track_id: read_u32(&tfhd.data, 4)?,
+        default_base_is_moof: flags & 0x020000 != 0,
+        ..Default::default()
+    };
+
+    let mut offset = 8;
+
+    if flags & 0x000001 != 0 {
+        defaults.base_data_offset = Some(read_u64(&tfhd.data, offset)?);
+        offset += 8;
+    }
+    if flags & 0x000002 != 0 {
+        // Sample description index
+        offset += 4;
+    }
+    if flags & 0x000008 != 0 {
+        defaults.sample_duration = Some(read_u32(&tfhd.data, offset)?);
+        offset += 4;
+    }
+    if flags & 0x000010 != 0 {
+        defaults.sample_size = Some(read_u32(&tfhd.data, offset)?);
+        offset += 4;
+    }
+    if flags & 0x000020 != 0 {
+        defaults.sample_flags = Some(read_u32(&tfhd.data, offset)?);
+    }
+
+    Ok(defaults)
+}
+
+fn parse_trun(trun: &TRUNBox) -> Res<TrackRun> {
+    let flags = read_u32(&trun.data, 0)? & 0x00FFFFFF;
+    let sample_count = read_u32(&trun.data, 4)?;
+    let mut offset = 8;
+
+    let data_offset = if flags & 0x000001 != 0 {
+        let value = read_u32(&trun.data, offset)? as i32;
+        offset += 4;
+        Some(value)
+    } else {
+        None
+    };
+
+    let first_sample_flags = if flags & 0x000004 != 0 {
+        let value = read_u32(&trun.data, offset)?;
+        offset += 4;
+        Some(value)
+    } else {
+        None
+    };
+
+    // The count is only trusted as far as the per-sample fields fit in the box
+    let sample_size = [0x000100, 0x000200, 0x000400, 0x000800]
+        .iter()
+        .filter(|flag| flags & **flag != 0)
+        .count()
+        * 4;
+    let fits = match sample_size {
+        0 => sample_count as usize <= MAX_RUN_SAMPLES_WITHOUT_FIELDS,
+        _ => sample_count as usize * sample_size <= trun.data.len().saturating_sub(offset),
+    };
+
+    if !fits {
+        return Err(PlaybackError::Container(format!(
+            "trun box lists {} samples, more than its {} bytes hold",
+            sample_count,
+            trun.data.len()
+        )));
+    }
+
+    let mut samples = Vec::with_capacity(sample_count as usize);
+
+    for _ in 0..sample_count {
+        let mut sample = TrackRunSample {
+            duration: None,
+            size: None,
+            flags: None,
+            composition_offset: None,
+        };
+
+        if flags & 0x000100 != 0 {
+            sample.duration = Some(read_u32(&trun.data, offset)?);
+            offset += 4;
+        }
+        if flags & 0x000200 != 0 {
+            sample.size = Some(read_u32(&trun.data, offset)?);
+            offset += 4;
+        }
+        if flags & 0x000400 != 0 {
+            sample.flags = Some(read_u32(&trun.data, offset)?);
+            offset += 4;
+        }
+        if flags & 0x000800 != 0 {
+            sample.composition_offset = Some(read_u32(&trun.data, offset)? as i32);
+            offset += 4;
+        }
+
+        samples.push(sample);
+    }
+
+    Ok(TrackRun {
+        data_offset,
+        first_sample_flags,
+        samples,
+    })
+}
+
+fn parse_stco(stco_box: &STCOBox) -> Res<Vec<u64>> {
     let data: Vec<u32> = stco_box
         .data
         .chunks_exact(4)
@@ -90,14 +750,34 @@ fn parse_stco(stco_box: &STCOBox) -> Vec<u32> {
         })
         .collect();
 
-    let size = data[1];
+    let (entry_count, offsets) = match data.get(1..) {
+        Some([entry_count, offsets @ ..]) => (*entry_count as usize, offsets),
+        _ => return Err(PlaybackError::Container("stco box is too short".to_string())),
+    };
 
-    assert_eq!(size as usize, data.len() - 2);
+    if entry_count != offsets.len() {
+        return Err(PlaybackError::Container(format!(
+            "stco box has {} offsets for {} entries",
+            offsets.len(),
+            entry_count
+        )));
+    }
+
+    Ok(offsets.iter().map(|offset| *offset as u64).collect())
+}
 
-    data[2..].to_vec()
+/*
+ * 64-bit version of stco, for files with chunks past 4 GiB
+ */
+fn parse_co64(co64_box: &CO64Box) -> Res<Vec<u64>> {
+    let entry_count = read_u32(&co64_box.data, 4)? as usize;
+
+    (0..entry_count)
+        .map(|index| read_u64(&co64_box.data, 8 + index * 8))
+        .collect()
 }
 
-fn parse_stsc(stsc: &STSCBox, chunk_offsets: Vec<u32>) -> Vec<(u32, u32)> {
+fn parse_stsc(stsc: &STSCBox, chunk_offsets: Vec<u64>) -> Res<Vec<(u64, u32)>> {
     let data: Vec<u32> = stsc
         .data
         .chunks_exact(4)
@@ -107,7 +787,9 @@ fn parse_stsc(stsc: &STSCBox, chunk_offsets: Vec<u32>) -> Vec<(u32, u32)> {
         })
         .collect();
 
-    let chunk_to_sample: Vec<ChunkToSample> = data[2..]
+    let chunk_to_sample: Vec<ChunkToSample> = data
+        .get(2..)
+        .ok_or_else(|| PlaybackError::Container("stsc box is too short".to_string()))?
         .chunks_exact(3)
         .map(|chunk| ChunkToSample {
             starting_chunk: chunk[0],
@@ -115,7 +797,7 @@ fn parse_stsc(stsc: &STSCBox, chunk_offsets: Vec<u32>) -> Vec<(u32, u32)> {
         })
         .collect();
 
-    let mut result: Vec<(u32, u32)> = vec![];
+    let mut result: Vec<(u64, u32)> = vec![];
     let mut current_sample_count_index = 0;
     let mut next_sample_count_index = 1;
 
@@ -126,22 +808,26 @@ fn parse_stsc(stsc: &STSCBox, chunk_offsets: Vec<u32>) -> Vec<(u32, u32)> {
                 next_sample_count_index += 1;
             }
         }
-        let tuple = (
-            chunk_offset.clone(),
-            chunk_to_sample[current_sample_count_index].sample_count,
-        );
+        let sample_count = chunk_to_sample
+            .get(current_sample_count_index)
+            .ok_or_else(|| PlaybackError::Container("stsc box has no entries".to_string()))?
+            .sample_count;
 
-        result.push(tuple);
+        result.push((chunk_offset.clone(), sample_count));
     }
 
-    result
+    Ok(result)
+}
+
+fn parse_stsz_sample_count(stsz: &STSZBox) -> Res<usize> {
+    Ok(read_u32(&stsz.data, 8)? as usize)
 }
 
 fn parse_stsz(
     stsz: &STSZBox,
-    chunk_offsets_with_sample_count: Vec<(u32, u32)>,
+    chunk_offsets_with_sample_count: Vec<(u64, u32)>,
     is_video: bool,
-) -> VecDeque<ChunkData> {
+) -> Res<VecDeque<ChunkData>> {
     let data: Vec<u32> = stsz
         .data
         .chunks_exact(4)
@@ -151,34 +837,59 @@ fn parse_stsz(
         })
         .collect();
 
-    let general_size = data[1];
+    let (general_size, sample_count) = match data.get(1..3) {
+        Some(&[general_size, sample_count]) => (general_size, sample_count as u64),
+        _ => return Err(PlaybackError::Container("stsz box is too short".to_string())),
+    };
+
+    // Sizes the buffers below, so it can't be more than the samples stsz lists
+    let chunk_sample_count: u64 = chunk_offsets_with_sample_count
+        .iter()
+        .map(|(_, sample_count)| *sample_count as u64)
+        .sum();
+
+    if chunk_sample_count > sample_count {
+        return Err(PlaybackError::Container(format!(
+            "stsc box describes {} samples, stsz only has {}",
+            chunk_sample_count, sample_count
+        )));
+    }
 
     if general_size != 0 {
-        return chunk_offsets_with_sample_count
+        return Ok(chunk_offsets_with_sample_count
             .iter()
             .map(|(offset, sample_count)| ChunkData {
                 is_video,
                 offset: offset.clone(),
                 sample_sizes: vec![general_size; sample_count.clone() as usize],
+                sample_durations: vec![],
             })
-            .collect();
+            .collect());
     }
 
-    let sizes: Vec<u32> = data[3..].to_vec();
+    let sizes = data.get(3..).unwrap_or_default();
     let mut current_index = 0;
 
     chunk_offsets_with_sample_count
         .iter()
         .map(|(offset, sample_count)| {
-            let chunk_data = ChunkData {
+            let chunk_end = current_index + *sample_count as usize;
+            let sample_sizes = sizes.get(current_index..chunk_end).ok_or_else(|| {
+                PlaybackError::Container(format!(
+                    "stsz box has {} sizes, the chunks need {}",
+                    sizes.len(),
+                    chunk_end
+                ))
+            })?;
+
+            current_index = chunk_end;
+
+            Ok(ChunkData {
                 is_video,
                 offset: offset.clone(),
-                sample_sizes: sizes[current_index..(current_index + *sample_count as usize)]
-                    .to_vec(),
-            };
-            current_index += *sample_count as usize;
-
-            chunk_data
+                sample_sizes: sample_sizes.to_vec(),
+                sample_durations: vec![],
+            })
         })
         .collect()
 }
@@ -186,6 +897,7 @@ fn parse_stsz(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::demux::get_moof_box::*;
     use crate::demux::get_moov_box::*;
 
     #[test]
@@ -193,10 +905,18 @@ mod tests {
         let sample = SampleData {
             size: 1024,
             is_video: true,
+            offset: 48,
+            duration: 512,
+            decode_time: 1024,
+            timescale: 512,
+            composition_offset: 0,
+            is_sync: true,
         };
         
         assert_eq!(sample.size, 1024);
         assert!(sample.is_video);
+        assert_eq!(sample.offset, 48);
+        assert_eq!(sample.decode_time_in_ms(), 2000);
     }
 
     #[test]
@@ -205,6 +925,7 @@ mod tests {
             is_video: false,
             offset: 2048,
             sample_sizes: vec![512, 1024, 768],
+            sample_durations: vec![],
         };
         
         assert!(!chunk.is_video);
@@ -225,12 +946,42 @@ mod tests {
             ],
         };
         
-        let offsets = parse_stco(&stco_box);
+        let offsets = parse_stco(&stco_box).unwrap();
         assert_eq!(offsets.len(), 2);
         assert_eq!(offsets[0], 4096);
         assert_eq!(offsets[1], 8192);
     }
 
+    #[test]
+    fn test_parse_stco_with_missing_offsets() {
+        let stco_box = STCOBox {
+            size: 20,
+            data: vec![
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x02, // entry count = 2
+                0x00, 0x00, 0x10, 0x00, // offset 1 = 4096
+            ],
+        };
+
+        assert!(matches!(parse_stco(&stco_box), Err(PlaybackError::Container(_))));
+        assert!(parse_stco(&STCOBox { size: 8, data: vec![] }).is_err());
+    }
+
+    #[test]
+    fn test_parse_co64() {
+        let co64_box = CO64Box {
+            size: 32,
+            data: vec![
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x02, // entry count = 2
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, // offset 1 = 4096
+                0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // offset 2 = 4 GiB
+            ],
+        };
+
+        assert_eq!(parse_co64(&co64_box).unwrap(), vec![4096, 1 << 32]);
+    }
+
     #[test]
     fn test_parse_stsc() {
         let stsc_box = STSCBox {
@@ -245,7 +996,7 @@ mod tests {
         };
         
         let chunk_offsets = vec![4096, 8192];
-        let result = parse_stsc(&stsc_box, chunk_offsets);
+        let result = parse_stsc(&stsc_box, chunk_offsets).unwrap();
         
         assert_eq!(result.len(), 2);
         assert_eq!(result[0], (4096, 2)); // offset, sample count
@@ -264,7 +1015,7 @@ mod tests {
         };
         
         let chunk_offsets = vec![(4096, 2)]; // offset, sample count
-        let result = parse_stsz(&stsz_box, chunk_offsets, true);
+        let result = parse_stsz(&stsz_box, chunk_offsets, true).unwrap();
         
         assert_eq!(result.len(), 1);
         let chunk = &result[0];
@@ -289,7 +1040,7 @@ mod tests {
         };
         
         let chunk_offsets = vec![(4096, 2)]; // offset, sample count
-        let result = parse_stsz(&stsz_box, chunk_offsets, false);
+        let result = parse_stsz(&stsz_box, chunk_offsets, false).unwrap();
         
         assert_eq!(result.len(), 1);
         let chunk = &result[0];
@@ -309,12 +1060,14 @@ mod tests {
             is_video: false,
             offset: 8192,
             sample_sizes: vec![256, 512],
+            sample_durations: vec![1024, 1024],
         });
         
         chunk_data.push_back(ChunkData {
             is_video: true,
             offset: 4096,
             sample_sizes: vec![1024],
+            sample_durations: vec![512],
         });
         
         let sample_data = format_sample_data(chunk_data);
@@ -324,11 +1077,617 @@ mod tests {
         // Should be sorted by offset, so video (4096) comes first
         assert!(sample_data[0].is_video);
         assert_eq!(sample_data[0].size, 1024);
+        assert_eq!(sample_data[0].offset, 4096);
+        assert_eq!(sample_data[0].duration, 512);
         
         // Then audio samples (8192)
         assert!(!sample_data[1].is_video);
         assert_eq!(sample_data[1].size, 256);
+        assert_eq!(sample_data[1].offset, 8192);
         assert!(!sample_data[2].is_video);
         assert_eq!(sample_data[2].size, 512);
+        assert_eq!(sample_data[2].offset, 8192 + 256);
+    }
+
+    #[test]
+    fn test_parse_stts() {
+        let stts_box = STTSBox {
+            size: 32,
+            data: vec![
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x02, // entry count = 2
+                0x00, 0x00, 0x00, 0x02, // sample count = 2
+                0x00, 0x00, 0x02, 0x00, // sample delta = 512
+                0x00, 0x00, 0x00, 0x01, // sample count = 1
+                0x00, 0x00, 0x04, 0x00, // sample delta = 1024
+            ],
+        };
+
+        assert_eq!(parse_stts(&stts_box, 3).unwrap(), vec![512, 512, 1024]);
+
+        // Samples past those stsz lists are dropped
+        assert_eq!(parse_stts(&stts_box, 2).unwrap(), vec![512, 512]);
+    }
+
+    #[test]
+    fn test_sample_counts_beyond_the_box_are_rejected() {
+        let trun_box = TRUNBox {
+            size: 20,
+            data: vec![
+                0x00, 0x00, 0x01, 0x00, // version + flags (duration)
+                0xFF, 0xFF, 0xFF, 0xFF, // sample count = 4294967295
+                0x00, 0x00, 0x02, 0x00, // sample 1 duration = 512
+            ],
+        };
+        let stts_box = STTSBox {
+            size: 24,
+            data: vec![
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x01, // entry count = 1
+                0xFF, 0xFF, 0xFF, 0xFF, // sample count = 4294967295
+                0x00, 0x00, 0x02, 0x00, // sample delta = 512
+            ],
+        };
+        let stsz_box = STSZBox {
+            size: 20,
+            data: vec![
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x04, 0x00, // general size = 1024
+                0x00, 0x00, 0x00, 0x02, // sample count = 2
+            ],
+        };
+
+        assert!(matches!(parse_trun(&trun_box), Err(PlaybackError::Container(_))));
+        assert_eq!(parse_stts(&stts_box, 2).unwrap().len(), 2);
+        assert!(parse_stsz(&stsz_box, vec![(4096, u32::MAX)], true).is_err());
+    }
+
+    #[test]
+    fn test_set_sample_durations() {
+        let mut chunk_data = VecDeque::from([
+            ChunkData {
+                is_video: true,
+                offset: 0,
+                sample_sizes: vec![10, 20],
+                sample_durations: vec![],
+            },
+            ChunkData {
+                is_video: true,
+                offset: 30,
+                sample_sizes: vec![30],
+                sample_durations: vec![],
+            },
+        ]);
+
+        set_sample_durations(&mut chunk_data, &[1, 2, 3]);
+
+        assert_eq!(chunk_data[0].sample_durations, vec![1, 2]);
+        assert_eq!(chunk_data[1].sample_durations, vec![3]);
+    }
+
+    #[test]
+    fn test_parse_tkhd_dimensions() {
+        let mut data = vec![0; 84];
+        data[76..80].copy_from_slice(&(1920u32 << 16).to_be_bytes());
+        data[80..84].copy_from_slice(&(1080u32 << 16).to_be_bytes());
+
+        let tkhd_box = TKHDBox { size: 92, data };
+
+        assert_eq!(parse_tkhd_dimensions(&tkhd_box), (1920, 1080));
+    }
+
+    #[test]
+    fn test_parse_tfhd() {
+        let tfhd_box = TFHDBox {
+            size: 24,
+            data: vec![
+                0x00, 0x02, 0x00, 0x18, // version + flags (default-base-is-moof, duration, size)
+                0x00, 0x00, 0x00, 0x02, // track id = 2
+                0x00, 0x00, 0x04, 0x00, // default sample duration = 1024
+                0x00, 0x00, 0x01, 0x00, // default sample size = 256
+            ],
+        };
+
+        let defaults = parse_tfhd(&tfhd_box).unwrap();
+
+        assert_eq!(defaults.track_id, 2);
+        assert!(defaults.default_base_is_moof);
+        assert_eq!(defaults.base_data_offset, None);
+        assert_eq!(defaults.sample_duration, Some(1024));
+        assert_eq!(defaults.sample_size, Some(256));
+    }
+
+    #[test]
+    fn test_truncated_fragment_boxes() {
+        let trun_box = TRUNBox {
+            size: 24,
+            data: vec![
+                0x00, 0x00, 0x03, 0x00, // version + flags (duration, size)
+                0x00, 0x00, 0x00, 0x02, // sample count = 2
+                0x00, 0x00, 0x02, 0x00, // sample 1 duration = 512
+                0x00, 0x00, 0x00, 0x10, // sample 1 size = 16, and no sample 2
+            ],
+        };
+        let tfdt_box = TFDTBox {
+            size: 16,
+            data: vec![
+                0x01, 0x00, 0x00, 0x00, // version 1, with a 64-bit decode time
+                0x00, 0x00, 0x00, 0x00, // half of it
+            ],
+        };
+
+        assert!(matches!(parse_trun(&trun_box), Err(PlaybackError::Container(_))));
+        assert!(matches!(parse_tfdt(&tfdt_box), Err(PlaybackError::Container(_))));
+        assert!(parse_tfhd(&TFHDBox { size: 8, data: vec![] }).is_err());
+        assert!(parse_sidx(&SIDXBox { size: 8, data: vec![0x00] }, 0).is_err());
+        assert!(parse_stss(&STSSBox { size: 8, data: vec![0x00, 0x00] }).is_err());
+    }
+
+    #[test]
+    fn test_parse_trun() {
+        let trun_box = TRUNBox {
+            size: 40,
+            data: vec![
+                0x00, 0x00, 0x03, 0x05, // version + flags (data offset, first sample flags, duration, size)
+                0x00, 0x00, 0x00, 0x02, // sample count = 2
+                0x00, 0x00, 0x00, 0x70, // data offset = 112
+                0x02, 0x00, 0x00, 0x00, // first sample flags
+                0x00, 0x00, 0x02, 0x00, // sample 1 duration = 512
+                0x00, 0x00, 0x00, 0x10, // sample 1 size = 16
+                0x00, 0x00, 0x02, 0x00, // sample 2 duration = 512
+                0x00, 0x00, 0x00, 0x20, // sample 2 size = 32
+            ],
+        };
+
+        let trun = parse_trun(&trun_box).unwrap();
+
+        assert_eq!(trun.data_offset, Some(112));
+        assert_eq!(trun.first_sample_flags, Some(0x02000000));
+        assert_eq!(
+            trun.samples,
+            vec![
+                TrackRunSample {
+                    duration: Some(512),
+                    size: Some(16),
+                    flags: None,
+                    composition_offset: None,
+                },
+                TrackRunSample {
+                    duration: Some(512),
+                    size: Some(32),
+                    flags: None,
+                    composition_offset: None,
+                },
+            ]
+        );
+    }
+
+    fn create_test_trak(track_id: u8, header: Streams) -> TRAKBox {
+        let empty_box = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        TRAKBox {
+            size: 0,
+            tkhd: TKHDBox {
+                size: 0,
+                data: vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, track_id],
+            },
+            edts: None,
+            media: MDIABox {
+                size: 0,
+                mdhd: MDHDBox {
+                    size: 0,
+                    data: vec![
+                        0x00, 0x00, 0x00, 0x00, // version + flags
+                        0x00, 0x00, 0x00, 0x00, // creation time
+                        0x00, 0x00, 0x00, 0x00, // modification time
+                        0x00, 0x00, 0x03, 0xE8, // timescale = 1000
+                        0x00, 0x00, 0x00, 0x00, // duration
+                    ],
+                },
+                hdlr: HDLRBox {
+                    size: 0,
+                    data: vec![],
+                },
+                minf: MINFBox {
+                    size: 0,
+                    header,
+                    dinf: DINFBox {
+                        size: 0,
+                        data: vec![],
+                    },
+                    stbl: STBLBbox {
+                        size: 0,
+                        stsd: STSDBox {
+                            size: 0,
+                            data: vec![],
+                            video_codec: None,
+                            audio_codec: None,
+                            codec_config: None,
+                            dimensions: None,
+                            audio_format: None,
+                        },
+                        ctts: None,
+                        stts: STTSBox {
+                            size: 0,
+                            data: empty_box.clone(),
+                        },
+                        stsz: STSZBox {
+                            size: 0,
+                            data: empty_box.clone(),
+                        },
+                        stco: Some(STCOBox {
+                            size: 0,
+                            data: empty_box.clone(),
+                        }),
+                        co64: None,
+                        stsc: STSCBox {
+                            size: 0,
+                            data: empty_box,
+                        },
+                        stss: None,
+                        unknown_boxes: vec![],
+                    },
+                    unknown_boxes: vec![],
+                },
+                unknown_boxes: vec![],
+            },
+            unknown_boxes: vec![],
+        }
+    }
+
+    #[test]
+    fn test_get_fragment_sample_map() {
+        let moov_box = MOOVBox {
+            size: 0,
+            mvhd: MVHDBox {
+                size: 0,
+                data: vec![],
+            },
+            traks: vec![
+                create_test_trak(1, Streams::Video),
+                create_test_trak(2, Streams::Audio),
+            ],
+            mvex: Some(MVEXBox {
+                size: 0,
+                trexs: vec![TREXBox {
+                    size: 32,
+                    data: vec![
+                        0x00, 0x00, 0x00, 0x00, // version + flags
+                        0x00, 0x00, 0x00, 0x02, // track id = 2
+                        0x00, 0x00, 0x00, 0x01, // default sample description index
+                        0x00, 0x00, 0x04, 0x00, // default sample duration = 1024
+                        0x00, 0x00, 0x00, 0x08, // default sample size = 8
+                        0x00, 0x00, 0x00, 0x00, // default sample flags
+                    ],
+                }],
+                unknown_boxes: vec![],
+            }),
+            unknown_boxes: vec![],
+        };
+
+        let moof_box = MOOFBox {
+            size: 0,
+            mfhd: MFHDBox {
+                size: 0,
+                data: vec![],
+            },
+            trafs: vec![
+                TRAFBox {
+                    size: 0,
+                    tfhd: TFHDBox {
+                        size: 0,
+                        data: vec![
+                            0x00, 0x02, 0x00, 0x08, // version + flags (default-base-is-moof, duration)
+                            0x00, 0x00, 0x00, 0x01, // track id = 1
+                            0x00, 0x00, 0x02, 0x00, // default sample duration = 512
+                        ],
+                    },
+                    tfdt: Some(TFDTBox {
+                        size: 0,
+                        data: vec![
+                            0x00, 0x00, 0x00, 0x00, // version + flags
+                            0x00, 0x00, 0x08, 0x00, // base media decode time = 2048
+                        ],
+                    }),
+                    truns: vec![TRUNBox {
+                        size: 0,
+                        data: vec![
+                            0x00, 0x00, 0x02, 0x01, // version + flags (data offset, size)
+                            0x00, 0x00, 0x00, 0x02, // sample count = 2
+                            0x00, 0x00, 0x00, 0x64, // data offset = 100
+                            0x00, 0x00, 0x00, 0x10, // sample 1 size = 16
+                            0x00, 0x00, 0x00, 0x20, // sample 2 size = 32
+                        ],
+                    }],
+                },
+                TRAFBox {
+                    size: 0,
+                    tfhd: TFHDBox {
+                        size: 0,
+                        data: vec![
+                            0x00, 0x00, 0x00, 0x00, // version + flags
+                            0x00, 0x00, 0x00, 0x02, // track id = 2
+                        ],
+                    },
+                    tfdt: None,
+                    truns: vec![TRUNBox {
+                        size: 0,
+                        data: vec![
+                            0x00, 0x00, 0x00, 0x00, // version + flags
+                            0x00, 0x00, 0x00, 0x02, // sample count = 2
+                        ],
+                    }],
+                },
+            ],
+        };
+
+        let sample_map = get_fragment_sample_map(&moof_box, 1000, &moov_box).unwrap();
+
+        assert_eq!(sample_map.len(), 4);
+
+        // Video samples start at the moof offset plus the trun data offset
+        assert!(sample_map[0].is_video);
+        assert_eq!(sample_map[0].offset, 1100);
+        assert_eq!(sample_map[0].size, 16);
+        assert_eq!(sample_map[0].duration, 512);
+        assert_eq!(sample_map[1].offset, 1116);
+        assert_eq!(sample_map[1].size, 32);
+
+        // Audio samples follow the video data and use the trex defaults
+        assert!(!sample_map[2].is_video);
+        assert_eq!(sample_map[2].offset, 1148);
+        assert_eq!(sample_map[2].size, 8);
+        assert_eq!(sample_map[2].duration, 1024);
+        assert_eq!(sample_map[3].offset, 1156);
+
+        // Decode times start at the tfdt base media decode time
+        assert_eq!(sample_map[0].decode_time, 2048);
+        assert_eq!(sample_map[1].decode_time, 2560);
+        assert_eq!(sample_map[1].decode_time_in_ms(), 2560);
+        assert_eq!(sample_map[3].decode_time, 1024);
+        assert!(sample_map.iter().all(|sample| sample.is_sync));
+    }
+
+    #[test]
+    fn test_set_sample_timing() {
+        let mut samples: VecDeque<SampleData> = (0..4)
+            .map(|index| SampleData {
+                size: 10,
+                is_video: true,
+                offset: index * 10,
+                duration: 500,
+                decode_time: 0,
+                timescale: 0,
+                composition_offset: 0,
+                is_sync: true,
+            })
+            .collect();
+
+        // I P B P order: the B-frame is shown before the P-frame decoded ahead of it
+        set_sample_timing(&mut samples, 1000, Some(vec![1, 3]), &[500, 1000, 0, 500]);
+
+        assert_eq!(samples[2].decode_time, 1000);
+        assert_eq!(samples[3].decode_time_in_ms(), 1500);
+        assert_eq!(samples[1].presentation_time(), 1500);
+        assert_eq!(samples[2].presentation_time(), 1000);
+        assert!(samples[0].is_sync);
+        assert!(!samples[1].is_sync);
+        assert!(samples[2].is_sync);
+        assert!(!samples[3].is_sync);
+    }
+
+    #[test]
+    fn test_parse_stss() {
+        let stss_box = STSSBox {
+            size: 24,
+            data: vec![
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x02, // entry count = 2
+                0x00, 0x00, 0x00, 0x01, // sample 1
+                0x00, 0x00, 0x00, 0x1E, // sample 30
+            ],
+        };
+
+        assert_eq!(parse_stss(&stss_box).unwrap(), vec![1, 30]);
+    }
+
+    #[test]
+    fn test_parse_ctts() {
+        let ctts_box = CTTSBox {
+            size: 32,
+            data: vec![
+                0x01, 0x00, 0x00, 0x00, // version 1 + flags
+                0x00, 0x00, 0x00, 0x02, // entry count = 2
+                0x00, 0x00, 0x00, 0x02, // sample count = 2
+                0x00, 0x00, 0x02, 0x00, // offset = 512
+                0x00, 0x00, 0x00, 0x01, // sample count = 1
+                0xFF, 0xFF, 0xFE, 0x00, // offset = -512
+            ],
+        };
+
+        assert_eq!(parse_ctts(&ctts_box, 3).unwrap(), vec![512, 512, -512]);
+        assert_eq!(parse_ctts(&ctts_box, 1).unwrap(), vec![512]);
+    }
+
+    #[test]
+    fn test_seek_sample_map() {
+        let sample = |is_video: bool, offset: u64, decode_time: u64, is_sync: bool| SampleData {
+            size: 10,
+            is_video,
+            offset,
+            duration: 1000,
+            decode_time,
+            timescale: 1000,
+            composition_offset: 0,
+            is_sync,
+        };
+
+        let sample_map = SampleMap::from([
+            sample(true, 0, 0, true),
+            sample(false, 10, 0, true),
+            sample(true, 20, 1000, false),
+            sample(false, 30, 1000, true),
+            sample(true, 40, 2000, true),
+            sample(false, 50, 2000, true),
+            sample(true, 60, 3000, false),
+            sample(false, 70, 3000, true),
+        ]);
+
+        let (sample_map, resume_ms) = seek_sample_map(sample_map, 3500);
+
+        // The closest sync sample before 3.5s is the one at 2s
+        assert_eq!(resume_ms, 2000);
+        assert_eq!(sample_map.len(), 4);
+        assert_eq!(sample_map[0].offset, 40);
+        assert!(sample_map.iter().all(|sample| sample.decode_time >= 2000));
+    }
+
+    #[test]
+    fn test_seek_sample_map_uses_presentation_time() {
+        let sample = |is_video: bool, offset: u64, decode_time: u64, composition_offset: i32| SampleData {
+            size: 10,
+            is_video,
+            offset,
+            duration: 1000,
+            decode_time,
+            timescale: 1000,
+            composition_offset,
+            is_sync: true,
+        };
+
+        // Video frames are shown 0.5s after they are decoded
+        let sample_map = SampleMap::from([
+            sample(true, 0, 0, 500),
+            sample(false, 10, 0, 0),
+            sample(false, 20, 1000, 0),
+            sample(true, 30, 2000, 500),
+            sample(false, 40, 2000, 0),
+            sample(false, 50, 3000, 0),
+        ]);
+
+        let (sample_map, resume_ms) = seek_sample_map(sample_map, 2300);
+
+        // The frame decoded at 2s is only shown at 2.5s, after the target
+        assert_eq!(resume_ms, 500);
+        assert_eq!(sample_map[0].offset, 0);
+        assert_eq!(sample_map.len(), 5);
+        assert_eq!(sample_map[1].offset, 20);
+    }
+
+    #[test]
+    fn test_seek_sample_map_audio_only() {
+        let sample_map: SampleMap = (0..4)
+            .map(|index| SampleData {
+                size: 10,
+                is_video: false,
+                offset: index * 10,
+                duration: 1024,
+                decode_time: index * 1024,
+                timescale: 1024,
+                composition_offset: 0,
+                is_sync: true,
+            })
+            .collect();
+
+        let (sample_map, resume_ms) = seek_sample_map(sample_map, 1500);
+
+        assert_eq!(resume_ms, 1500);
+        assert_eq!(sample_map.len(), 2);
+        assert_eq!(sample_map[0].offset, 20);
+    }
+
+    #[test]
+    fn test_parse_sidx() {
+        let sidx_box = SIDXBox {
+            size: 56,
+            data: vec![
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x01, // reference id
+                0x00, 0x00, 0x03, 0xE8, // timescale = 1000
+                0x00, 0x00, 0x00, 0x00, // earliest presentation time
+                0x00, 0x00, 0x00, 0x00, // first offset
+                0x00, 0x00, 0x00, 0x02, // reserved + reference count = 2
+                0x00, 0x00, 0x10, 0x00, // referenced size = 4096
+                0x00, 0x00, 0x13, 0x88, // subsegment duration = 5000
+                0x90, 0x00, 0x00, 0x00, // starts with SAP
+                0x00, 0x00, 0x20, 0x00, // referenced size = 8192
+                0x00, 0x00, 0x13, 0x88, // subsegment duration = 5000
+                0x90, 0x00, 0x00, 0x00, // starts with SAP
+            ],
+        };
+
+        let references = parse_sidx(&sidx_box, 800).unwrap();
+
+        assert_eq!(
+            references,
+            vec![
+                SegmentReference {
+                    offset: 800,
+                    time_in_ms: 0,
+                    duration_in_ms: 5000,
+                },
+                SegmentReference {
+                    offset: 4896,
+                    time_in_ms: 5000,
+                    duration_in_ms: 5000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mvhd_duration_in_ms() {
+        let mut data = vec![0; 20];
+        data[12..16].copy_from_slice(&600u32.to_be_bytes());
+        data[16..20].copy_from_slice(&6000u32.to_be_bytes());
+
+        assert_eq!(parse_mvhd_duration_in_ms(&MVHDBox { size: 28, data }), 10000);
+        assert_eq!(parse_mvhd_duration_in_ms(&MVHDBox { size: 8, data: vec![] }), 0);
+    }
+
+    #[test]
+    fn test_parse_elst() {
+        let elst_box = ELSTBox {
+            size: 40,
+            data: vec![
+                0x00, 0x00, 0x00, 0x00, // version + flags
+                0x00, 0x00, 0x00, 0x02, // entry count = 2
+                0x00, 0x00, 0x01, 0xF4, // segment duration = 500
+                0xFF, 0xFF, 0xFF, 0xFF, // media time = -1, empty edit
+                0x00, 0x01, 0x00, 0x00, // media rate = 1.0
+                0x00, 0x00, 0x27, 0x10, // segment duration = 10000
+                0x00, 0x00, 0x04, 0x00, // media time = 1024
+                0x00, 0x01, 0x00, 0x00, // media rate = 1.0
+            ],
+        };
+
+        assert_eq!(parse_elst(&elst_box).unwrap(), (500, 1024));
+    }
+
+    #[test]
+    fn test_parse_edit_offset() {
+        let mut trak = create_test_trak(1, Streams::Audio);
+        assert_eq!(parse_edit_offset(&trak, 600).unwrap(), 0);
+
+        // 300 movie units at 600 per second are 500 media units at 1000 per second
+        trak.edts = Some(EDTSBox {
+            size: 0,
+            elst: Some(ELSTBox {
+                size: 0,
+                data: vec![
+                    0x00, 0x00, 0x00, 0x00, // version + flags
+                    0x00, 0x00, 0x00, 0x02, // entry count = 2
+                    0x00, 0x00, 0x01, 0x2C, // segment duration = 300
+                    0xFF, 0xFF, 0xFF, 0xFF, // media time = -1, empty edit
+                    0x00, 0x01, 0x00, 0x00, // media rate = 1.0
+                    0x00, 0x00, 0x27, 0x10, // segment duration = 10000
+                    0x00, 0x00, 0x00, 0x64, // media time = 100
+                    0x00, 0x01, 0x00, 0x00, // media rate = 1.0
+                ],
+            }),
+            unknown_boxes: vec![],
+        });
+
+        assert_eq!(parse_edit_offset(&trak, 600).unwrap(), 400);
     }
 }
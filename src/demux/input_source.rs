@@ -0,0 +1,347 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::Res;
+
+/// yt-dlp format 18: H.264 at 640x360 with AAC-LC audio, in a single MP4 file
+pub const DEFAULT_FORMAT: &str = "18";
+
+/// Where the demultiplexer reads its MP4 or WebM bytes from.
+pub enum InputSource {
+    /// A URL (or `ytsearch:` query) resolved and downloaded by yt-dlp,
+    /// with the format selector passed to its `-f` option
+    YtDlp { url: String, format: String },
+    /// A direct media URL, fetched with curl so that byte ranges can be requested
+    Http(String),
+    /// A file on the local filesystem
+    File(PathBuf),
+    /// Bytes piped in on stdin
+    Stdin,
+    /// Any other byte stream, e.g. an in-memory test fixture.
+    /// It can only be opened once.
+    Reader(Box<dyn Read + Send>),
+}
+
+impl InputSource {
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Self {
+        InputSource::Reader(Box::new(reader))
+    }
+
+    /// Whether the source can be reopened at an arbitrary byte offset
+    pub fn is_seekable(&self) -> bool {
+        matches!(
+            self,
+            InputSource::YtDlp { .. } | InputSource::Http(_) | InputSource::File(_)
+        )
+    }
+
+    /// Opens the source and returns a reader over its bytes
+    pub fn open(&mut self) -> Res<OpenedInput> {
+        self.open_at(0)
+    }
+
+    /// Opens the source so that the first byte read is the one at `offset`
+    pub fn open_at(&mut self, offset: u64) -> Res<OpenedInput> {
+        if offset > 0 {
+            if let InputSource::YtDlp { url, format } = self {
+                // yt-dlp can only stream from the start, so ask it for the
+                // direct media URL and request byte ranges from there instead
+                *self = InputSource::Http(resolve_media_url(url, format)?);
+            }
+        }
+
+        match self {
+            InputSource::YtDlp { url, format } => {
+                /*
+                 * This starts the yt-dlp program for a given url and format selector
+                 * The selected format must be a single MP4 or WebM file
+                 * Video must be encoded in H264, HEVC, VP9 or AV1, audio in AAC, MP3 or Opus
+                 */
+                let mut process = Command::new("yt-dlp")
+                    .args(["-o", "-", "--no-part", "-f", format.as_str(), url.as_str()])
+                    .stderr(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| PlaybackError::Input(format!("Could not start yt-dlp process: {}", e)))?;
+
+                let stdout = process
+                    .stdout
+                    .take()
+                    .ok_or_else(|| PlaybackError::Input("yt-dlp has no stdout".to_string()))?;
+
+                Ok(OpenedInput {
+                    reader: Box::new(stdout),
+                    process: Some((process, "yt-dlp")),
+                })
+            }
+            InputSource::Http(url) => {
+                let range = format!("{}-", offset);
+
+                let mut process = Command::new("curl")
+                    .args(["-s", "-f", "-L", "-r", range.as_str(), url.as_str()])
+                    .stderr(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| PlaybackError::Input(format!("Could not start curl process: {}", e)))?;
+
+                let stdout = process
+                    .stdout
+                    .take()
+                    .ok_or_else(|| PlaybackError::Input("curl has no stdout".to_string()))?;
+
+                Ok(OpenedInput {
+                    reader: Box::new(stdout),
+                    process: Some((process, "curl")),
+                })
+            }
+            InputSource::File(path) => {
+                let mut file = File::open(&*path).map_err(|e| {
+                    PlaybackError::Input(format!("Could not open {}: {}", path.display(), e))
+                })?;
+
+                file.seek(SeekFrom::Start(offset)).map_err(|e| {
+                    PlaybackError::Input(format!("Could not seek in {}: {}", path.display(), e))
+                })?;
+
+                Ok(OpenedInput {
+                    reader: Box::new(file),
+                    process: None,
+                })
+            }
+            InputSource::Stdin | InputSource::Reader(_) if offset > 0 => {
+                Err(PlaybackError::Input("Input is not seekable".to_string()))
+            }
+            InputSource::Stdin => Ok(OpenedInput {
+                reader: Box::new(io::stdin()),
+                process: None,
+            }),
+            InputSource::Reader(reader) => Ok(OpenedInput {
+                reader: std::mem::replace(reader, Box::new(io::empty())),
+                process: None,
+            }),
+        }
+    }
+}
+
+/*
+ * Builds a yt-dlp format selector for the best video-only H.264 stream
+ * that is at most max_height pixels tall, then for the best HEVC, VP9 or AV1 one
+ * in an MP4 or WebM file, falling back to format 18
+ * HLS and DASH manifest formats are left out since they can't be range-requested
+ */
+pub fn video_format_selector(max_height: u32) -> String {
+    let filters = format!("[height<={}][protocol^=http][protocol!*=dash]", max_height);
+
+    format!(
+        "bestvideo[vcodec^=avc1]{}/bestvideo[vcodec~='^(hvc1|hev1|vp0?9|av01)']{}/{}",
+        filters, filters, DEFAULT_FORMAT
+    )
+}
+
+/*
+ * Builds a yt-dlp format selector for the best audio-only AAC stream,
+ * then for the best Opus one, falling back to the audio track of format 18
+ */
+pub fn audio_format_selector() -> String {
+    format!(
+        "bestaudio[acodec^=mp4a]{filters}/bestaudio[acodec=opus]{filters}/{}",
+        DEFAULT_FORMAT,
+        filters = "[protocol^=http][protocol!*=dash]"
+    )
+}
+
+/*
+ * Asks yt-dlp for the direct URL of the selected format without downloading it
+ */
+fn resolve_media_url(url: &str, format: &str) -> Res<String> {
+    let output = Command::new("yt-dlp")
+        .args(["-g", "-f", format, url])
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| PlaybackError::Input(format!("Could not start yt-dlp process: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(PlaybackError::Input(format!("yt-dlp could not resolve {}", url)));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .ok_or_else(|| PlaybackError::Input(format!("yt-dlp returned no media URL for {}", url)))
+}
+
+/// The yt-dlp formats to play: either one muxed format,
+/// or a video format and a separate audio format
+#[derive(Clone, Debug)]
+pub struct FormatSelection {
+    pub video: String,
+    pub audio: Option<String>,
+}
+
+impl FormatSelection {
+    /// Builds the main input and, if any, the separate audio input for a URL
+    pub fn inputs(&self, url: &str) -> (InputSource, Option<InputSource>) {
+        let input = InputSource::YtDlp {
+            url: url.to_string(),
+            format: self.video.clone(),
+        };
+
+        let audio_input = self.audio.as_ref().map(|format| InputSource::YtDlp {
+            url: url.to_string(),
+            format: format.clone(),
+        });
+
+        (input, audio_input)
+    }
+}
+
+/// An opened input. Dropping it stops the backing process, if any.
+pub struct OpenedInput {
+    reader: Box<dyn Read + Send>,
+    // The backing process, with the name of its program
+    process: Option<(Child, &'static str)>,
+}
+
+impl OpenedInput {
+    pub fn close(&mut self) {
+        if let Some((mut process, _)) = self.process.take() {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+    }
+
+    /// Once everything has been read, fails if the backing process exited
+    /// with an error, e.g. when yt-dlp could not download the video
+    pub fn check_exit_status(&mut self) -> Res<()> {
+        if let Some((ref mut process, program)) = self.process {
+            let status = process
+                .wait()
+                .map_err(|e| PlaybackError::Input(format!("Could not wait for {}: {}", program, e)))?;
+
+            if !status.success() {
+                return Err(PlaybackError::Input(format!("{} exited with {}", program, status)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for OpenedInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Drop for OpenedInput {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_reader_source() {
+        let mut source = InputSource::from_reader(Cursor::new(vec![1, 2, 3, 4]));
+
+        let mut data = vec![];
+        source.open().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![1, 2, 3, 4]);
+
+        // A reader can only be consumed once
+        let mut data = vec![];
+        source.open().unwrap().read_to_end(&mut data).unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_file_source() {
+        let path = std::env::temp_dir().join("yt-term-input-source-test.mp4");
+        std::fs::write(&path, [0x00, 0x00, 0x00, 0x08, 0x66, 0x72, 0x65, 0x65]).unwrap();
+
+        let mut source = InputSource::File(path.clone());
+        let mut data = vec![];
+        source.open().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 8);
+        assert_eq!(&data[4..], b"free");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_source_open_at() {
+        let path = std::env::temp_dir().join("yt-term-input-source-open-at-test.mp4");
+        std::fs::write(&path, [0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+
+        let mut source = InputSource::File(path.clone());
+        assert!(source.is_seekable());
+
+        let mut data = vec![];
+        source.open_at(5).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![5, 6, 7]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_streams_are_not_seekable() {
+        let mut source = InputSource::from_reader(Cursor::new(vec![0; 16]));
+        assert!(!source.is_seekable());
+        assert!(!InputSource::Stdin.is_seekable());
+
+        assert!(source.open_at(8).is_err());
+    }
+
+    #[test]
+    fn test_video_format_selector() {
+        let selector = video_format_selector(720);
+
+        assert!(selector.starts_with("bestvideo[vcodec^=avc1]"));
+        assert!(selector.contains("[height<=720]"));
+        assert!(selector.contains("/bestvideo[vcodec~='^(hvc1|hev1|vp0?9|av01)'][height<=720]"));
+        assert!(selector.ends_with("/18"));
+    }
+
+    #[test]
+    fn test_audio_format_selector() {
+        let selector = audio_format_selector();
+
+        assert!(selector.starts_with("bestaudio[acodec^=mp4a]"));
+        assert!(selector.contains("/bestaudio[acodec=opus][protocol^=http]"));
+        assert!(selector.ends_with("/18"));
+    }
+
+    #[test]
+    fn test_format_selection_inputs() {
+        let selection = FormatSelection {
+            video: "137".to_string(),
+            audio: Some("140".to_string()),
+        };
+
+        let (input, audio_input) = selection.inputs("https://example.com/video");
+
+        assert!(matches!(input, InputSource::YtDlp { ref format, .. } if format == "137"));
+        assert!(matches!(audio_input, Some(InputSource::YtDlp { ref format, .. }) if format == "140"));
+
+        let muxed = FormatSelection {
+            video: DEFAULT_FORMAT.to_string(),
+            audio: None,
+        };
+        assert!(muxed.inputs("https://example.com/video").1.is_none());
+    }
+
+    #[test]
+    fn test_missing_file_source() {
+        let mut source = InputSource::File(PathBuf::from("/this/file/does/not/exist.mp4"));
+        assert!(source.open().is_err());
+    }
+}
@@ -0,0 +1,687 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read};
+
+use crate::demux::ebml::{
+    check_ebml_header, parse_block, parse_block_group, parse_cues, parse_element_header,
+    parse_info, parse_seek_head, parse_tracks, read_uint, CuePoint, MatroskaBlock, MatroskaTrack,
+    TrackType, BLOCK_GROUP_ID, CLUSTER_ID, CUES_ID, EBML_HEADER_ID, INFO_ID, SEEK_HEAD_ID,
+    SEGMENT_ID, SIMPLE_BLOCK_ID, TIMESTAMP_ID, TRACKS_ID,
+};
+use crate::demux::get_sample_map::SampleData;
+use crate::demux::input_source::{InputSource, OpenedInput};
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::Res;
+
+/* Timescale of the samples handed out, which are in milliseconds */
+const SAMPLE_TIMESCALE: u32 = 1000;
+
+pub enum MatroskaEvent {
+    /// The Tracks element was parsed, and can be read with MatroskaReader::played_tracks
+    Tracks,
+    /// The Cues element was parsed, so the input can now seek
+    Cues,
+    /// The next frame of the played tracks, with its bytes
+    Frame(SampleData, Vec<u8>),
+}
+
+enum ElementStep {
+    /// More bytes are needed to parse the next element
+    NeedData,
+    /// An element was consumed, keep going
+    Continue,
+    Tracks,
+    Cues,
+}
+
+/// Walks the elements of one Matroska or WebM input and yields the frames
+/// of its first video and audio tracks, in file order.
+pub struct MatroskaReader {
+    source: InputSource,
+    input: Option<OpenedInput>,
+    buffer: Vec<u8>,
+    accumulated_data: Vec<u8>,
+    // Absolute offset in the input of the first byte of accumulated_data
+    position: u64,
+    // Absolute offset of the Segment data, which positions in the Segment are relative to
+    segment_data_offset: Option<u64>,
+    // Nanoseconds per timestamp unit
+    timestamp_scale: u64,
+    duration_in_ms: u64,
+    tracks: Option<Vec<MatroskaTrack>>,
+    video_track: Option<MatroskaTrack>,
+    audio_track: Option<MatroskaTrack>,
+    cue_points: Option<Vec<CuePoint>>,
+    // Position of the Cues element from the SeekHead, relative to the Segment data
+    cues_position: Option<u64>,
+    // Set while reading Cues that come after the clusters, to the offset of the first cluster
+    first_cluster_offset: Option<u64>,
+    cluster_timestamp: u64,
+    // Bytes of a skipped element that haven't been read yet
+    skip_remaining: u64,
+    pending_frames: VecDeque<(SampleData, Vec<u8>)>,
+    // After seeking, audio frames before this time are dropped
+    seek_floor_ms: Option<u64>,
+    // After seeking, video frames are dropped until the next keyframe
+    waiting_for_keyframe: bool,
+    finished: bool,
+}
+
+impl MatroskaReader {
+    pub fn new(source: InputSource) -> Self {
+        Self {
+            source,
+            input: None,
+            buffer: vec![0; 1000000],
+            accumulated_data: vec![],
+            position: 0,
+            segment_data_offset: None,
+            timestamp_scale: 1_000_000,
+            duration_in_ms: 0,
+            tracks: None,
+            video_track: None,
+            audio_track: None,
+            cue_points: None,
+            cues_position: None,
+            first_cluster_offset: None,
+            cluster_timestamp: 0,
+            skip_remaining: 0,
+            pending_frames: VecDeque::new(),
+            seek_floor_ms: None,
+            waiting_for_keyframe: false,
+            finished: false,
+        }
+    }
+
+    /// Continues from an input that was already opened, and the bytes already read from it
+    pub fn from_opened(source: InputSource, input: OpenedInput, read_data: Vec<u8>) -> Self {
+        let mut reader = Self::new(source);
+        reader.input = Some(input);
+        reader.accumulated_data = read_data;
+        reader
+    }
+
+    /// The first video and audio tracks with a codec the player can decode
+    pub fn played_tracks(&self) -> impl Iterator<Item = &MatroskaTrack> {
+        self.video_track.iter().chain(self.audio_track.iter())
+    }
+
+    pub fn duration_in_ms(&self) -> u64 {
+        self.duration_in_ms
+    }
+
+    /// Whether seek can be called: the input must be seekable, with a Cues element
+    pub fn is_seekable(&self) -> bool {
+        self.source.is_seekable()
+            && self.tracks.is_some()
+            && self.segment_data_offset.is_some()
+            && self
+                .cue_points
+                .as_ref()
+                .is_some_and(|cue_points| !cue_points.is_empty())
+    }
+
+    /*
+     * Restarts the input at the cluster of the closest cue point at or before target_ms.
+     * Returns the time playback resumes at.
+     */
+    pub fn seek(&mut self, target_ms: u64) -> Res<u64> {
+        if !self.is_seekable() {
+            return Err(PlaybackError::Input("Input is not seekable".to_string()));
+        }
+
+        let segment_data_offset = self.segment_data_offset.unwrap_or_default();
+        let cue_points = self.cue_points.as_deref().unwrap_or_default();
+
+        // Cue points of the video track land on its keyframes
+        let video_track_number = self.video_track.as_ref().map(|track| track.number);
+        let video_cue_points: Vec<&CuePoint> = cue_points
+            .iter()
+            .filter(|cue_point| Some(cue_point.track) == video_track_number)
+            .collect();
+        let cue_points: Vec<&CuePoint> = if video_cue_points.is_empty() {
+            cue_points.iter().collect()
+        } else {
+            video_cue_points
+        };
+
+        let cue_point = cue_points
+            .iter()
+            .rev()
+            .find(|cue_point| self.units_to_ms(cue_point.time) <= target_ms)
+            .or(cue_points.first())
+            .ok_or_else(|| {
+                PlaybackError::Container("Cues element has no cue points".to_string())
+            })?;
+
+        let has_video = self.video_track.is_some();
+        let resume_ms = if has_video {
+            self.units_to_ms(cue_point.time)
+        } else {
+            target_ms
+        };
+
+        self.position = segment_data_offset + cue_point.cluster_position;
+        self.pending_frames.clear();
+        self.skip_remaining = 0;
+        self.seek_floor_ms = Some(resume_ms);
+        self.waiting_for_keyframe = has_video;
+        self.finished = false;
+        self.reopen()?;
+
+        Ok(resume_ms)
+    }
+
+    /// Stops the process backing the input, if any
+    pub fn close(&mut self) {
+        if let Some(mut input) = self.input.take() {
+            input.close();
+        }
+    }
+
+    /*
+     * Returns the next Tracks or Cues element or frame of the input,
+     * or None once every frame has been read
+     */
+    pub fn next_event(&mut self) -> Res<Option<MatroskaEvent>> {
+        if self.input.is_none() && !self.finished {
+            self.input = Some(self.source.open()?);
+        }
+
+        loop {
+            if let Some((sample_data, frame)) = self.pending_frames.pop_front() {
+                return Ok(Some(MatroskaEvent::Frame(sample_data, frame)));
+            }
+
+            if self.finished {
+                return Ok(None);
+            }
+
+            match self.parse_element()? {
+                ElementStep::Continue => continue,
+                ElementStep::Tracks => return Ok(Some(MatroskaEvent::Tracks)),
+                ElementStep::Cues => return Ok(Some(MatroskaEvent::Cues)),
+                ElementStep::NeedData => {}
+            }
+
+            let input = self
+                .input
+                .as_mut()
+                .ok_or_else(|| PlaybackError::Input("Input is closed".to_string()))?;
+
+            match input.read(&mut self.buffer) {
+                Ok(0) => {
+                    // Segments and clusters of unknown size simply end with the input
+                    self.finished = true;
+                    input.check_exit_status()?;
+
+                    if self.tracks.is_none() {
+                        return Err(PlaybackError::Container(
+                            "Input ended before a Tracks element was found".to_string(),
+                        ));
+                    }
+                }
+                Ok(bytes_read) => {
+                    self.accumulated_data
+                        .extend_from_slice(&self.buffer[..bytes_read]);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(PlaybackError::Input(format!("Could not read input: {}", e)));
+                }
+            }
+        }
+    }
+
+    /*
+     * Parses the element at the start of the accumulated data. Segment and Cluster
+     * elements are entered rather than read whole, since they hold the whole file
+     * and are often streamed with an unknown size.
+     */
+    fn parse_element(&mut self) -> Res<ElementStep> {
+        if self.skip_remaining > 0 {
+            let skipped = self.skip_remaining.min(self.accumulated_data.len() as u64);
+            self.accumulated_data.drain(..skipped as usize);
+            self.position += skipped;
+            self.skip_remaining -= skipped;
+
+            if self.skip_remaining > 0 {
+                return Ok(ElementStep::NeedData);
+            }
+        }
+
+        let Some(header) = parse_element_header(&self.accumulated_data)? else {
+            return Ok(ElementStep::NeedData);
+        };
+
+        if header.id != CUES_ID {
+            if let Some(first_cluster_offset) = self.first_cluster_offset.take() {
+                // The SeekHead was wrong, play without seeking
+                self.cues_position = None;
+                self.position = first_cluster_offset;
+                self.reopen()?;
+                return Ok(ElementStep::NeedData);
+            }
+        }
+
+        match header.id {
+            SEGMENT_ID => {
+                self.consume(header.header_size);
+                self.segment_data_offset = Some(self.position);
+                return Ok(ElementStep::Continue);
+            }
+            CLUSTER_ID => {
+                if let (Some(segment_data_offset), Some(cues_position), None) = (
+                    self.segment_data_offset,
+                    self.cues_position,
+                    &self.cue_points,
+                ) {
+                    if self.source.is_seekable() && self.first_cluster_offset.is_none() {
+                        // The Cues come after the clusters: read them first, then come back
+                        self.first_cluster_offset = Some(self.position);
+                        self.position = segment_data_offset + cues_position;
+                        self.reopen()?;
+                        return Ok(ElementStep::NeedData);
+                    }
+                }
+
+                self.consume(header.header_size);
+                self.cluster_timestamp = 0;
+                return Ok(ElementStep::Continue);
+            }
+            EBML_HEADER_ID | SEEK_HEAD_ID | INFO_ID | TRACKS_ID | CUES_ID | TIMESTAMP_ID
+            | SIMPLE_BLOCK_ID | BLOCK_GROUP_ID => {}
+            _ => {
+                // e.g. Void, Tags, Chapters or Attachments, none of which are needed to play the frames
+                let size = header.size.ok_or_else(|| {
+                    PlaybackError::Container(format!(
+                        "Element {:#x} has an unknown size",
+                        header.id
+                    ))
+                })?;
+
+                self.consume(header.header_size);
+                self.skip_remaining = size;
+                return Ok(ElementStep::Continue);
+            }
+        }
+
+        let size = header.size.ok_or_else(|| {
+            PlaybackError::Container(format!("Element {:#x} has an unknown size", header.id))
+        })?;
+
+        if (self.accumulated_data.len() as u64) < header.header_size as u64 + size {
+            return Ok(ElementStep::NeedData);
+        }
+
+        let element_offset = self.position;
+        self.consume(header.header_size);
+        let data: Vec<u8> = self.accumulated_data.drain(..size as usize).collect();
+        self.position += size;
+
+        match header.id {
+            EBML_HEADER_ID => check_ebml_header(&data)?,
+            SEEK_HEAD_ID => {
+                // Later SeekHeads are only listed by the first one
+                if self.cues_position.is_none() {
+                    self.cues_position = parse_seek_head(&data)?
+                        .into_iter()
+                        .find(|(id, _)| *id == CUES_ID)
+                        .map(|(_, position)| position);
+                }
+            }
+            INFO_ID => {
+                let (timestamp_scale, duration) = parse_info(&data)?;
+                self.timestamp_scale = timestamp_scale;
+                self.duration_in_ms = duration
+                    .map(|duration| (duration * timestamp_scale as f64 / 1_000_000.0) as u64)
+                    .unwrap_or(0);
+            }
+            TRACKS_ID => {
+                let tracks = parse_tracks(&data)?;
+
+                self.video_track = tracks
+                    .iter()
+                    .find(|track| {
+                        track.track_type == TrackType::Video && track.video_codec().is_some()
+                    })
+                    .cloned();
+                self.audio_track = tracks
+                    .iter()
+                    .find(|track| {
+                        track.track_type == TrackType::Audio && track.audio_codec().is_some()
+                    })
+                    .cloned();
+                self.tracks = Some(tracks);
+
+                return Ok(ElementStep::Tracks);
+            }
+            CUES_ID => {
+                self.cue_points = Some(parse_cues(&data)?);
+
+                if let Some(first_cluster_offset) = self.first_cluster_offset.take() {
+                    self.position = first_cluster_offset;
+                    self.reopen()?;
+                }
+
+                return Ok(ElementStep::Cues);
+            }
+            TIMESTAMP_ID => self.cluster_timestamp = read_uint(&data),
+            SIMPLE_BLOCK_ID => {
+                let block = parse_block(&data, true)?;
+                self.queue_frames(block, None, element_offset);
+            }
+            BLOCK_GROUP_ID => {
+                let (block, duration) = parse_block_group(&data)?;
+                self.queue_frames(block, duration, element_offset);
+            }
+            _ => {}
+        }
+
+        Ok(ElementStep::Continue)
+    }
+
+    /*
+     * Turns the frames of a block into samples, if it belongs to a played track
+     */
+    fn queue_frames(&mut self, block: MatroskaBlock, duration: Option<u64>, offset: u64) {
+        let (track, is_video) = match (&self.video_track, &self.audio_track) {
+            (Some(track), _) if track.number == block.track_number => (track, true),
+            (_, Some(track)) if track.number == block.track_number => (track, false),
+            _ => return,
+        };
+
+        if is_video && self.waiting_for_keyframe {
+            if !block.keyframe {
+                return;
+            }
+            self.waiting_for_keyframe = false;
+        }
+
+        let block_time = self.cluster_timestamp as i64 + block.relative_timestamp as i64;
+        let block_time_ms = (block_time.max(0) as u64 * self.timestamp_scale / 1_000_000) as i64;
+
+        let frame_duration_ms = match (duration, track.default_duration_ns) {
+            (Some(duration), _) => self.units_to_ms(duration) / block.frames.len() as u64,
+            (None, Some(default_duration_ns)) => default_duration_ns / 1_000_000,
+            (None, None) => 0,
+        };
+
+        // Opus and AAC start with priming samples, which are cut like an MP4 edit list would
+        let codec_delay_ms = if is_video {
+            0
+        } else {
+            (track.codec_delay_ns / 1_000_000) as i32
+        };
+
+        for (index, frame) in block.frames.into_iter().enumerate() {
+            let decode_time = block_time_ms as u64 + index as u64 * frame_duration_ms;
+
+            if !is_video && self.seek_floor_ms.is_some_and(|floor| decode_time < floor) {
+                continue;
+            }
+
+            self.pending_frames.push_back((
+                SampleData {
+                    size: frame.len() as u32,
+                    is_video,
+                    offset,
+                    duration: frame_duration_ms as u32,
+                    decode_time,
+                    timescale: SAMPLE_TIMESCALE,
+                    composition_offset: -codec_delay_ms,
+                    is_sync: block.keyframe,
+                },
+                frame,
+            ));
+        }
+    }
+
+    fn units_to_ms(&self, units: u64) -> u64 {
+        units * self.timestamp_scale / 1_000_000
+    }
+
+    fn consume(&mut self, size: usize) {
+        self.accumulated_data.drain(..size);
+        self.position += size as u64;
+    }
+
+    /*
+     * Restarts the input at the current position, dropping any buffered bytes
+     */
+    fn reopen(&mut self) -> Res<()> {
+        self.close();
+        self.accumulated_data.clear();
+        self.input = Some(self.source.open_at(self.position)?);
+        Ok(())
+    }
+}
+
+impl Drop for MatroskaReader {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn element(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut element = id.to_vec();
+        // An 8-byte size, long enough for any test element
+        element.push(0x01);
+        element.extend_from_slice(&(data.len() as u64).to_be_bytes()[1..]);
+        element.extend_from_slice(data);
+        element
+    }
+
+    fn webm_file(cues_first: bool) -> Vec<u8> {
+        let ebml_header = element(&[0x1A, 0x45, 0xDF, 0xA3], &element(&[0x42, 0x82], b"webm"));
+
+        let info = element(
+            &[0x15, 0x49, 0xA9, 0x66],
+            &element(&[0x44, 0x89], &2000.0f64.to_be_bytes()),
+        );
+
+        let video_track = [
+            element(&[0xD7], &[1]),
+            element(&[0x83], &[1]),
+            element(&[0x86], b"V_VP9"),
+            element(
+                &[0xE0],
+                &[element(&[0xB0], &[0x40]), element(&[0xBA], &[0x30])].concat(),
+            ),
+        ]
+        .concat();
+        let audio_track = [
+            element(&[0xD7], &[2]),
+            element(&[0x83], &[2]),
+            element(&[0x86], b"A_OPUS"),
+        ]
+        .concat();
+        let tracks = element(
+            &[0x16, 0x54, 0xAE, 0x6B],
+            &[
+                element(&[0xAE], &video_track),
+                element(&[0xAE], &audio_track),
+            ]
+            .concat(),
+        );
+
+        let cluster = |timestamp: u16| {
+            let video_block = [
+                element(&[0xA1], &[0x81, 0x00, 0x21, 0x00, 0xDD]),
+                element(&[0xFB], &[0xDF]),
+            ];
+
+            [
+                element(&[0xE7], &timestamp.to_be_bytes()),
+                // Keyframe of the video track
+                element(&[0xA3], &[0x81, 0x00, 0x00, 0x80, 0xAA]),
+                // Two audio frames, in a block with Xiph lacing
+                element(&[0xA3], &[0x82, 0x00, 0x0A, 0x82, 0x01, 0x01, 0xBB, 0xCC]),
+                // Video frame that depends on the keyframe
+                element(&[0xA0], &video_block.concat()),
+            ]
+            .concat()
+        };
+
+        // Clusters of unknown size, as when streamed
+        let clusters = [
+            vec![0x1F, 0x43, 0xB6, 0x75, 0xFF],
+            cluster(0),
+            vec![0x1F, 0x43, 0xB6, 0x75, 0xFF],
+            cluster(1000),
+        ]
+        .concat();
+        let second_cluster_position = (5 + cluster(0).len()) as u64;
+
+        // Positions are written on 8 bytes, so the size of these doesn't depend on them
+        let cues = |first_cluster: u64| {
+            let cue_point = |time: u16, cluster_position: u64| {
+                let track_positions = [
+                    element(&[0xF7], &[1]),
+                    element(&[0xF1], &cluster_position.to_be_bytes()),
+                ];
+
+                element(
+                    &[0xBB],
+                    &[
+                        element(&[0xB3], &time.to_be_bytes()),
+                        element(&[0xB7], &track_positions.concat()),
+                    ]
+                    .concat(),
+                )
+            };
+
+            element(
+                &[0x1C, 0x53, 0xBB, 0x6B],
+                &[
+                    cue_point(0, first_cluster),
+                    cue_point(1000, first_cluster + second_cluster_position),
+                ]
+                .concat(),
+            )
+        };
+        let seek_head = |cues_position: u64| {
+            let seek = [
+                element(&[0x53, 0xAB], &[0x1C, 0x53, 0xBB, 0x6B]),
+                element(&[0x53, 0xAC], &cues_position.to_be_bytes()),
+            ];
+
+            element(
+                &[0x11, 0x4D, 0x9B, 0x74],
+                &element(&[0x4D, 0xBB], &seek.concat()),
+            )
+        };
+
+        let headers_size = (info.len() + tracks.len()) as u64;
+        let segment = if cues_first {
+            let first_cluster = headers_size + cues(0).len() as u64;
+            [info, tracks, cues(first_cluster), clusters].concat()
+        } else {
+            let first_cluster = seek_head(0).len() as u64 + headers_size;
+            let cues_position = first_cluster + clusters.len() as u64;
+            [
+                seek_head(cues_position),
+                info,
+                tracks,
+                clusters,
+                cues(first_cluster),
+            ]
+            .concat()
+        };
+
+        [
+            ebml_header,
+            vec![
+                0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            ],
+            segment,
+        ]
+        .concat()
+    }
+
+    fn read_frames(reader: &mut MatroskaReader) -> Vec<(bool, u64, Vec<u8>)> {
+        let mut frames = vec![];
+
+        while let Some(event) = reader.next_event().unwrap() {
+            if let MatroskaEvent::Frame(sample_data, frame) = event {
+                frames.push((sample_data.is_video, sample_data.decode_time, frame));
+            }
+        }
+
+        frames
+    }
+
+    #[test]
+    fn test_read_frames_from_stream() {
+        let mut reader =
+            MatroskaReader::new(InputSource::from_reader(Cursor::new(webm_file(true))));
+
+        assert!(matches!(
+            reader.next_event().unwrap(),
+            Some(MatroskaEvent::Tracks)
+        ));
+        assert_eq!(reader.duration_in_ms(), 2000);
+
+        let codecs: Vec<&str> = reader
+            .played_tracks()
+            .map(|track| track.codec_id.as_str())
+            .collect();
+        assert_eq!(codecs, vec!["V_VP9", "A_OPUS"]);
+        assert_eq!(
+            reader.video_track.as_ref().unwrap().dimensions,
+            Some((0x40, 0x30))
+        );
+
+        assert!(matches!(
+            reader.next_event().unwrap(),
+            Some(MatroskaEvent::Cues)
+        ));
+        // Streams can't be reopened at a cluster
+        assert!(!reader.is_seekable());
+
+        let frames = read_frames(&mut reader);
+        assert_eq!(
+            frames,
+            vec![
+                (true, 0, vec![0xAA]),
+                (false, 10, vec![0xBB]),
+                (false, 10, vec![0xCC]),
+                (true, 33, vec![0xDD]),
+                (true, 1000, vec![0xAA]),
+                (false, 1010, vec![0xBB]),
+                (false, 1010, vec![0xCC]),
+                (true, 1033, vec![0xDD]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_seek_with_cues_after_clusters() {
+        let path = std::env::temp_dir().join(format!("yt-term-test-{}.webm", std::process::id()));
+        std::fs::write(&path, webm_file(false)).unwrap();
+
+        let mut reader = MatroskaReader::new(InputSource::File(path.clone()));
+
+        assert!(matches!(
+            reader.next_event().unwrap(),
+            Some(MatroskaEvent::Tracks)
+        ));
+        // The Cues at the end are read before the first cluster
+        assert!(matches!(
+            reader.next_event().unwrap(),
+            Some(MatroskaEvent::Cues)
+        ));
+        assert!(reader.is_seekable());
+
+        assert_eq!(reader.seek(1500).unwrap(), 1000);
+        let frames = read_frames(&mut reader);
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[0], (true, 1000, vec![0xAA]));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,5 +1,11 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 
+use crate::audio::output::AudioOutputKind;
+use crate::video::renderer::RendererKind;
+use crate::video::transmission::TransmissionChoice;
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 pub struct Args {
@@ -8,6 +14,61 @@ pub struct Args {
     
     #[clap(short, long, group = "input")]
     pub search: Option<String>,
+
+    /// Play a local MP4 or WebM file, or `-` to read from stdin
+    #[clap(long, group = "input")]
+    pub file: Option<PathBuf>,
+
+    /// Maximum video height in pixels (defaults to what fits the terminal)
+    #[clap(short, long)]
+    pub quality: Option<u32>,
+
+    /// yt-dlp format selector to use instead of --quality
+    #[clap(short, long)]
+    pub format: Option<String>,
+
+    /// yt-dlp format selector for a separate audio stream, used with --format
+    #[clap(long, requires = "format")]
+    pub audio_format: Option<String>,
+
+    /// Volume in percent, from 0 to 200
+    #[clap(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(0..=200))]
+    pub volume: u32,
+
+    /// Play every video at a similar loudness, measured as it plays (EBU R128)
+    #[clap(long)]
+    pub normalize: bool,
+
+    /// Where to play the audio: device, null, wav:FILE, pcm for stdout or pcm:FILE
+    #[clap(long, default_value = "device")]
+    pub audio_out: AudioOutputKind,
+
+    /// Name of the output device to play on, from --list-audio-devices
+    #[clap(long, conflicts_with = "audio_out")]
+    pub audio_device: Option<String>,
+
+    /// zlib level from 1 to 9 for the frames sent to the terminal, which cuts the
+    /// bandwidth over SSH at the cost of CPU time, or 0 to send them uncompressed
+    #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=9))]
+    pub compression: u32,
+
+    /// How frames reach the terminal: auto, direct through the pty, or shm and file when
+    /// the terminal runs on the same machine. Falls back to direct if the terminal can't read them
+    #[clap(long, default_value = "auto")]
+    pub transmission: TransmissionChoice,
+
+    /// Graphics protocol to draw the video with: kitty, or sixel for terminals such as
+    /// foot, xterm and mlterm
+    #[clap(long, default_value = "kitty")]
+    pub renderer: RendererKind,
+
+    /// Dither the colors of sixel frames, smoother gradients for more bytes per frame
+    #[clap(long)]
+    pub dither: bool,
+
+    /// Print the names of the output devices and exit
+    #[clap(long)]
+    pub list_audio_devices: bool,
 }
 
 pub fn parse_args() -> Args {
@@ -0,0 +1,110 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+struct ClockState {
+    start_ms: usize,
+    sample_rate: usize,
+    frames_played: usize,
+    last_frames: usize,
+    advanced_at: Option<Instant>,
+}
+
+/// Playback position driven by the audio output.
+///
+/// The output callback reports how many frames it actually took from the buffer,
+/// so the clock follows the sound card rather than the timestamps of the audio
+/// packets, and stops when the output is paused or starved. The video adapter
+/// shows its frames against it whenever audio is playing.
+#[derive(Default)]
+pub struct PlaybackClock {
+    state: Mutex<Option<ClockState>>,
+}
+
+impl PlaybackClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts counting from the timestamp of the first sample handed to the output
+    pub fn start(&self, start_ms: usize, sample_rate: u32) {
+        *self.state.lock().unwrap() = Some(ClockState {
+            start_ms,
+            sample_rate: sample_rate as usize,
+            frames_played: 0,
+            last_frames: 0,
+            advanced_at: None,
+        });
+    }
+
+    /// Stops driving playback, e.g. after a seek or once all the audio has been played
+    pub fn stop(&self) {
+        *self.state.lock().unwrap() = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.lock().unwrap().is_some()
+    }
+
+    /// Called from the output callback with the number of frames it just took
+    pub fn advance(&self, frames: usize) {
+        if let Some(ref mut state) = *self.state.lock().unwrap() {
+            state.frames_played += frames;
+            state.last_frames = frames;
+            state.advanced_at = Some(Instant::now());
+        }
+    }
+
+    /// Position of the sample being played, or None when audio isn't driving playback
+    pub fn position_ms(&self) -> Option<usize> {
+        self.state.lock().unwrap().as_ref().map(|state| {
+            let played_ms = (state.frames_played - state.last_frames) * 1000 / state.sample_rate;
+
+            // The output plays the last frames it took between two callbacks
+            let last_ms = state.last_frames * 1000 / state.sample_rate;
+            let into_last_ms = state
+                .advanced_at
+                .map(|advanced_at| (advanced_at.elapsed().as_millis() as usize).min(last_ms))
+                .unwrap_or(0);
+
+            state.start_ms + played_ms + into_last_ms
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_follows_played_frames() {
+        let clock = PlaybackClock::new();
+        assert_eq!(clock.position_ms(), None);
+
+        clock.start(1000, 44100);
+        assert_eq!(clock.position_ms(), Some(1000));
+
+        clock.advance(44100);
+        let position_ms = clock.position_ms().unwrap();
+        assert!((1000..=2000).contains(&position_ms));
+
+        clock.advance(4410);
+        let position_ms = clock.position_ms().unwrap();
+        assert!((2000..=2100).contains(&position_ms));
+    }
+
+    #[test]
+    fn test_clock_restarts_after_stop() {
+        let clock = PlaybackClock::new();
+        clock.start(0, 44100);
+        clock.advance(44100);
+
+        clock.stop();
+        assert!(!clock.is_running());
+        assert_eq!(clock.position_ms(), None);
+
+        // Frames played between a seek and the first new sample don't count
+        clock.advance(44100);
+        clock.start(5000, 44100);
+        assert_eq!(clock.position_ms(), Some(5000));
+    }
+}
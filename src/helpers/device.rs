@@ -0,0 +1,56 @@
+use std::sync::Mutex;
+
+/// Shared between the key handlers and the audio adapter to move playback
+/// to another output device while it plays.
+///
+/// The adapter takes the request between two messages, or while it waits
+/// for the output to take its samples, so the samples it has buffered play
+/// on the new device.
+#[derive(Default)]
+pub struct DeviceControl {
+    requested: Mutex<Option<String>>,
+    /// Why the last switch failed, playback then stays on the previous device
+    error: Mutex<Option<String>>,
+}
+
+impl DeviceControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests a switch to the output device with this name, replacing any pending request
+    pub fn switch_to(&self, name: String) {
+        *self.requested.lock().unwrap() = Some(name);
+    }
+
+    /// Takes the pending switch, if any
+    pub fn take_request(&self) -> Option<String> {
+        self.requested.lock().unwrap().take()
+    }
+
+    pub fn report_error(&self, error: String) {
+        *self.error.lock().unwrap() = Some(error);
+    }
+
+    pub fn take_error(&self) -> Option<String> {
+        self.error.lock().unwrap().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_request_wins() {
+        let device_control = DeviceControl::new();
+
+        device_control.switch_to("Speakers".to_string());
+        device_control.switch_to("Headphones".to_string());
+        assert_eq!(
+            device_control.take_request(),
+            Some("Headphones".to_string())
+        );
+        assert_eq!(device_control.take_request(), None);
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+
+/// Why playback failed, by the part of the pipeline that failed.
+///
+/// Every stage returns it instead of printing or panicking, so that it reaches
+/// PlaybackHandle::join and can be shown without breaking the screen.
+#[derive(Debug)]
+pub enum PlaybackError {
+    /// The input could not be opened or read: yt-dlp, curl, a file or stdin
+    Input(String),
+    /// The MP4 boxes or Matroska elements are missing or malformed
+    Container(String),
+    /// A decoder could not be created
+    Codec(String),
+    /// The audio output device could not be opened or failed while playing
+    AudioDevice(String),
+    /// The terminal could not be set up or written to
+    Terminal(String),
+    /// A playback thread panicked
+    Internal(String),
+}
+
+impl PlaybackError {
+    pub fn missing_box(title: &str) -> Self {
+        PlaybackError::Container(format!("No {} box found", title))
+    }
+
+    /// Status the CLI exits with, one per kind of failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PlaybackError::Input(_) => 2,
+            PlaybackError::Container(_) => 3,
+            PlaybackError::Codec(_) => 4,
+            PlaybackError::AudioDevice(_) => 5,
+            PlaybackError::Terminal(_) => 6,
+            PlaybackError::Internal(_) => 70,
+        }
+    }
+}
+
+impl fmt::Display for PlaybackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaybackError::Input(message) => write!(f, "Input error: {}", message),
+            PlaybackError::Container(message) => write!(f, "Invalid container: {}", message),
+            PlaybackError::Codec(message) => write!(f, "Decoder error: {}", message),
+            PlaybackError::AudioDevice(message) => write!(f, "Audio device error: {}", message),
+            PlaybackError::Terminal(message) => write!(f, "Terminal error: {}", message),
+            PlaybackError::Internal(message) => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for PlaybackError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_and_exit_code() {
+        let error = PlaybackError::Container("stco or co64 not found".to_string());
+
+        assert_eq!(error.to_string(), "Invalid container: stco or co64 not found");
+        assert_eq!(error.exit_code(), 3);
+        assert_ne!(error.exit_code(), PlaybackError::Input(String::new()).exit_code());
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Shared between the playback threads to pause and resume the output.
+///
+/// Only the adapters look at it: they stop their clock while paused, so the
+/// frames and samples queued upstream are played on time once resumed.
+#[derive(Default)]
+pub struct PauseControl {
+    paused: AtomicBool,
+}
+
+impl PauseControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Pauses when playing and resumes when paused, returns whether it is now paused
+    pub fn toggle(&self) -> bool {
+        !self.paused.fetch_xor(true, Ordering::SeqCst)
+    }
+
+    /// Blocks until playback resumes or is cancelled, returns for how long it was paused
+    pub fn wait_while_paused(&self, cancel_flag: Option<&AtomicBool>) -> Duration {
+        let paused_at = Instant::now();
+
+        while self.is_paused() && !cancel_flag.is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+            thread::sleep(Duration::from_millis(16));
+        }
+
+        paused_at.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_toggle() {
+        let pause_control = PauseControl::new();
+
+        assert!(pause_control.toggle());
+        assert!(pause_control.is_paused());
+        assert!(!pause_control.toggle());
+        assert!(!pause_control.is_paused());
+    }
+
+    #[test]
+    fn test_wait_while_paused_until_resumed() {
+        let pause_control = Arc::new(PauseControl::new());
+        pause_control.toggle();
+
+        let resume = pause_control.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            resume.toggle();
+        });
+
+        assert!(pause_control.wait_while_paused(None) >= Duration::from_millis(50));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_wait_while_paused_stops_on_cancel() {
+        let pause_control = PauseControl::new();
+        pause_control.toggle();
+
+        let cancel_flag = AtomicBool::new(true);
+        pause_control.wait_while_paused(Some(&cancel_flag));
+        assert!(pause_control.is_paused());
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Shared between the playback threads to request seeks and drop stale data.
+///
+/// Each seek bumps the generation. Every stage drops what it receives until the
+/// flush marker of the current generation, which the demultiplexer sends once it
+/// has restarted the input at the new position.
+#[derive(Default)]
+pub struct SeekControl {
+    generation: AtomicUsize,
+    target_ms: Mutex<Option<usize>>,
+    position_ms: AtomicUsize,
+    duration_ms: AtomicUsize,
+    seekable: AtomicBool,
+    finished: AtomicBool,
+}
+
+impl SeekControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests a seek to an absolute position, ignored when the input can't seek
+    pub fn seek_to(&self, target_ms: usize) {
+        if !self.is_seekable() {
+            return;
+        }
+
+        let duration_ms = self.duration_ms();
+        let target_ms = if duration_ms > 0 {
+            target_ms.min(duration_ms)
+        } else {
+            target_ms
+        };
+
+        let mut target = self.target_ms.lock().unwrap();
+        *target = Some(target_ms);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        // So that repeated relative seeks add up before the next frame is shown
+        self.set_position_ms(target_ms);
+    }
+
+    /// Requests a seek relative to the position currently on screen
+    pub fn seek_by(&self, delta_ms: i64) {
+        let target_ms = (self.position_ms() as i64 + delta_ms).max(0);
+        self.seek_to(target_ms as usize);
+    }
+
+    /// Requests a seek to a percentage of the duration, when it is known
+    pub fn seek_to_percent(&self, percent: usize) {
+        let duration_ms = self.duration_ms();
+
+        if duration_ms > 0 {
+            self.seek_to(duration_ms * percent.min(100) / 100);
+        }
+    }
+
+    /// Takes the pending seek, with the generation its flush marker must carry
+    pub fn take_request(&self) -> Option<(usize, usize)> {
+        let mut target = self.target_ms.lock().unwrap();
+        target
+            .take()
+            .map(|target_ms| (target_ms, self.generation.load(Ordering::SeqCst)))
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Whether a stage that last saw the flush marker of `generation` should drop what it receives
+    pub fn is_stale(&self, generation: usize) -> bool {
+        self.generation() != generation
+    }
+
+    pub fn position_ms(&self) -> usize {
+        self.position_ms.load(Ordering::SeqCst)
+    }
+
+    pub fn set_position_ms(&self, position_ms: usize) {
+        self.position_ms.store(position_ms, Ordering::SeqCst);
+    }
+
+    pub fn duration_ms(&self) -> usize {
+        self.duration_ms.load(Ordering::SeqCst)
+    }
+
+    pub fn set_duration_ms(&self, duration_ms: usize) {
+        self.duration_ms.store(duration_ms, Ordering::SeqCst);
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        self.seekable.load(Ordering::SeqCst)
+    }
+
+    pub fn set_seekable(&self, seekable: bool) {
+        self.seekable.store(seekable, Ordering::SeqCst);
+    }
+
+    /// Whether the last frame has been shown, after which seeking is pointless
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    pub fn set_finished(&self) {
+        self.finished.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seek_needs_seekable_input() {
+        let seek_control = SeekControl::new();
+
+        seek_control.seek_to(1000);
+        assert_eq!(seek_control.generation(), 0);
+        assert!(seek_control.take_request().is_none());
+    }
+
+    #[test]
+    fn test_seek_by_is_relative_and_clamped() {
+        let seek_control = SeekControl::new();
+        seek_control.set_seekable(true);
+        seek_control.set_duration_ms(60000);
+        seek_control.set_position_ms(10000);
+
+        seek_control.seek_by(-30000);
+        assert_eq!(seek_control.take_request(), Some((0, 1)));
+
+        seek_control.seek_by(5000);
+        assert_eq!(seek_control.take_request(), Some((5000, 2)));
+
+        seek_control.seek_by(120000);
+        assert_eq!(seek_control.take_request(), Some((60000, 3)));
+    }
+
+    #[test]
+    fn test_seek_to_percent() {
+        let seek_control = SeekControl::new();
+        seek_control.set_seekable(true);
+
+        // Nothing to do until the duration is known
+        seek_control.seek_to_percent(50);
+        assert!(seek_control.take_request().is_none());
+
+        seek_control.set_duration_ms(200000);
+        seek_control.seek_to_percent(30);
+        assert_eq!(seek_control.take_request(), Some((60000, 1)));
+    }
+
+    #[test]
+    fn test_only_the_latest_seek_is_kept() {
+        let seek_control = SeekControl::new();
+        seek_control.set_seekable(true);
+
+        seek_control.seek_to(1000);
+        seek_control.seek_to(2000);
+
+        assert_eq!(seek_control.take_request(), Some((2000, 2)));
+        assert!(seek_control.take_request().is_none());
+    }
+}
@@ -1,5 +1,7 @@
 use std::io::Write;
 
+use super::error::PlaybackError;
+use crate::video::kitty::delete_image_escape;
 use super::types::Res;
 
 pub struct ScreenGuard {}
@@ -9,8 +11,11 @@ impl ScreenGuard {
         let mut stdout = std::io::stdout();
         let alternate_screen = b"\x1B[?1049h";
 
-        stdout.write_all(alternate_screen)?;
-        stdout.flush()?;
+        stdout
+            .write_all(alternate_screen)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| PlaybackError::Terminal(format!("Could not enter the alternate screen: {}", e)))?;
+
         Ok(ScreenGuard {})
     }
 }
@@ -19,7 +24,8 @@ impl Drop for ScreenGuard {
     fn drop(&mut self) {
         let mut stdout = std::io::stdout();
 
-        let mut buffer = vec![];
+        // The video is cleared even when playback didn't stop on its own
+        let mut buffer = delete_image_escape();
         let reset = b"\x1B[?1049l";
         let clear = b"\x1b[2J";
         let cursor = b"\x1b[H";
@@ -1,7 +1,40 @@
-pub type Res<T> = Result<T, Box<dyn std::error::Error>>;
+use super::error::PlaybackError;
+
+pub type Res<T> = Result<T, PlaybackError>;
 
 #[derive(Clone, Debug)]
 pub struct BytesWithTimestamp {
     pub data: Vec<u8>,
     pub timestamp_in_ms: usize,
 }
+
+/// How the decoder stores each audio sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    U8,
+    I16,
+    I32,
+    F32,
+    F64,
+}
+
+impl SampleFormat {
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            SampleFormat::U8 => 1,
+            SampleFormat::I16 => 2,
+            SampleFormat::I32 | SampleFormat::F32 => 4,
+            SampleFormat::F64 => 8,
+        }
+    }
+}
+
+/// Layout of the decoded audio sent downstream, as the decoder produced it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+    /// Whether each channel comes in its own block, one after the other, instead of interleaved
+    pub planar: bool,
+}
@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/* Volume change of one key press, in percent */
+const VOLUME_STEP: u32 = 5;
+
+pub const MAX_VOLUME: u32 = 200;
+
+/// Shared between the key handlers and the audio output to set the volume.
+///
+/// It outlives a single playback in the TUI, so that the next video
+/// plays at the volume the previous one was left at.
+pub struct VolumeControl {
+    /// In percent of the decoded level
+    level: AtomicU32,
+    muted: AtomicBool,
+    /// Whether the audio adapter brings the measured loudness to a common level
+    normalize: bool,
+}
+
+impl VolumeControl {
+    pub fn new(level: u32, normalize: bool) -> Self {
+        VolumeControl {
+            level: AtomicU32::new(level.min(MAX_VOLUME)),
+            muted: AtomicBool::new(false),
+            normalize,
+        }
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level.load(Ordering::SeqCst)
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::SeqCst)
+    }
+
+    pub fn is_normalizing(&self) -> bool {
+        self.normalize
+    }
+
+    /// Raises the volume by one step and unmutes, returns the new level
+    pub fn volume_up(&self) -> u32 {
+        self.muted.store(false, Ordering::SeqCst);
+        self.change_level(|level| (level + VOLUME_STEP).min(MAX_VOLUME))
+    }
+
+    /// Lowers the volume by one step, returns the new level
+    pub fn volume_down(&self) -> u32 {
+        self.change_level(|level| level.saturating_sub(VOLUME_STEP))
+    }
+
+    /// Mutes when audible and unmutes when muted, returns whether it is now muted
+    pub fn toggle_mute(&self) -> bool {
+        !self.muted.fetch_xor(true, Ordering::SeqCst)
+    }
+
+    /// Linear gain the output applies to every sample
+    pub fn gain(&self) -> f32 {
+        if self.is_muted() {
+            0.0
+        } else {
+            self.level() as f32 / 100.0
+        }
+    }
+
+    fn change_level(&self, change: impl Fn(u32) -> u32) -> u32 {
+        let previous = self
+            .level
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |level| {
+                Some(change(level))
+            })
+            .unwrap();
+        change(previous)
+    }
+}
+
+impl Default for VolumeControl {
+    fn default() -> Self {
+        VolumeControl::new(100, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_volume_steps_and_mute() {
+        let volume_control = VolumeControl::new(MAX_VOLUME - 2, false);
+
+        assert_eq!(volume_control.volume_up(), MAX_VOLUME);
+        assert_eq!(volume_control.volume_down(), MAX_VOLUME - VOLUME_STEP);
+
+        assert!(volume_control.toggle_mute());
+        assert_eq!(volume_control.gain(), 0.0);
+
+        // Turning the volume up also unmutes
+        volume_control.volume_up();
+        assert!(!volume_control.is_muted());
+        assert_eq!(volume_control.gain(), 2.0);
+
+        let volume_control = VolumeControl::new(3, false);
+        assert_eq!(volume_control.volume_down(), 0);
+    }
+}
@@ -1,45 +1,128 @@
 mod helpers {
     pub mod args;
+    pub mod clock;
+    pub mod device;
+    pub mod error;
+    pub mod pause;
+    pub mod seek;
     pub mod structs;
     pub mod types;
+    pub mod volume;
 }
 
 mod video {
     pub mod adapter;
     pub mod encoder;
+    pub mod kitty;
+    pub mod renderer;
+    pub mod sixel;
+    pub mod transmission;
 }
 
 mod audio {
     pub mod adapter;
+    pub mod cpal_output;
+    mod file_output;
+    mod loudness;
+    pub mod output;
+    mod resampler;
 }
 
 mod demux {
+    mod box_reader;
     pub mod codec_context;
+    mod container_reader;
     pub mod demultiplexer;
+    mod ebml;
+    mod get_moof_box;
     mod get_moov_box;
+    pub mod input_source;
     mod get_sample_map;
+    mod matroska_reader;
 }
 
 mod tui;
 
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::{sync::mpsc::channel, thread};
+use std::time::Duration;
+use std::{
+    sync::mpsc::{channel, sync_channel},
+    thread,
+};
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
 
 use audio::adapter::AudioAdapter;
+use audio::cpal_output::output_device_names;
+use audio::output::AudioOutputKind;
 use demux::demultiplexer::{Demultiplexer, RawAudioMessage, RawVideoMessage};
-use helpers::{args::parse_args, structs::ScreenGuard};
+use demux::input_source::{
+    audio_format_selector, video_format_selector, FormatSelection, InputSource, DEFAULT_FORMAT,
+};
+use helpers::{
+    args::parse_args, clock::PlaybackClock, device::DeviceControl, error::PlaybackError, pause::PauseControl,
+    seek::SeekControl, structs::ScreenGuard, types::Res, volume::VolumeControl,
+};
 use video::{
     adapter::TerminalAdapter,
     encoder::{EncodedVideoMessage, Encoder},
+    kitty::KittyRenderer,
+    renderer::{FrameRenderer, RendererKind},
+    sixel::SixelRenderer,
+    transmission::TransmissionMedium,
 };
 
+/* Decoded frames queued ahead of the encoder, each up to 6 MB of RGB at 1080p */
+const DECODED_FRAMES_AHEAD: usize = 16;
+
+/* Encoded frames queued ahead of the terminal adapter */
+const ENCODED_FRAMES_AHEAD: usize = 8;
+
+/// Settings from the command line that every playback starts with
+#[derive(Clone)]
+pub struct PlaybackOptions {
+    /// Shared by every playback, so that the volume carries over to the next video
+    pub volume_control: Arc<VolumeControl>,
+    pub audio_out: AudioOutputKind,
+    /// zlib level of the frames sent to the terminal, 0 to send them uncompressed
+    pub compression_level: u32,
+    /// How frames reach the terminal, as acknowledged on startup
+    pub transmission_medium: TransmissionMedium,
+    pub renderer: RendererKind,
+    /// Whether sixel frames are dithered
+    pub dither: bool,
+}
+
+impl PlaybackOptions {
+    /* Created for every playback, since renderers keep the layout of the frames */
+    fn create_renderer(&self) -> Box<dyn FrameRenderer> {
+        match self.renderer {
+            RendererKind::Kitty => {
+                let mut renderer = KittyRenderer::new();
+                renderer.set_compression_level(self.compression_level);
+                renderer.set_transmission_medium(self.transmission_medium);
+                Box::new(renderer)
+            }
+            RendererKind::Sixel => Box::new(SixelRenderer::new(self.dither)),
+        }
+    }
+}
+
 pub struct PlaybackHandle {
     cancel_flag: Arc<AtomicBool>,
-    demux_handle: thread::JoinHandle<()>,
-    encode_handle: thread::JoinHandle<()>,
-    audio_handle: thread::JoinHandle<()>,
-    video_handle: thread::JoinHandle<()>,
+    seek_control: Arc<SeekControl>,
+    pause_control: Arc<PauseControl>,
+    volume_control: Arc<VolumeControl>,
+    device_control: Arc<DeviceControl>,
+    demux_handle: thread::JoinHandle<Res<()>>,
+    encode_handle: thread::JoinHandle<Res<()>>,
+    audio_handle: thread::JoinHandle<Res<()>>,
+    video_handle: thread::JoinHandle<Res<()>>,
 }
 
 impl PlaybackHandle {
@@ -47,6 +130,38 @@ impl PlaybackHandle {
         self.cancel_flag.store(true, Ordering::SeqCst);
     }
 
+    /// Moves playback forward or backward from the position on screen
+    pub fn seek_by(&self, delta_ms: i64) {
+        self.seek_control.seek_by(delta_ms);
+    }
+
+    pub fn seek_to_percent(&self, percent: usize) {
+        self.seek_control.seek_to_percent(percent);
+    }
+
+    /// Pauses or resumes playback, returns whether it is now paused
+    pub fn toggle_pause(&self) -> bool {
+        self.pause_control.toggle()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause_control.is_paused()
+    }
+
+    pub fn volume_control(&self) -> &VolumeControl {
+        &self.volume_control
+    }
+
+    /// Moves the audio to another output device, without stopping playback
+    pub fn switch_audio_device(&self, name: String) {
+        self.device_control.switch_to(name);
+    }
+
+    /// Why the last device switch failed, taken so that it is reported once
+    pub fn take_audio_device_error(&self) -> Option<String> {
+        self.device_control.take_error()
+    }
+
     pub fn is_finished(&self) -> bool {
         self.demux_handle.is_finished()
             && self.encode_handle.is_finished()
@@ -54,123 +169,301 @@ impl PlaybackHandle {
             && self.video_handle.is_finished()
     }
 
-    pub fn join(self) {
-        let _ = self.demux_handle.join();
-        let _ = self.encode_handle.join();
-        let _ = self.audio_handle.join();
-        let _ = self.video_handle.join();
+    /// Waits for every stage, returns why playback failed if one of them did
+    pub fn join(self) -> Res<()> {
+        let results = [
+            self.demux_handle.join(),
+            self.encode_handle.join(),
+            self.audio_handle.join(),
+            self.video_handle.join(),
+        ];
+
+        // The other stages only stop because of the first failure, which is in the first stage upstream
+        results.into_iter().try_for_each(|result| {
+            result.unwrap_or_else(|payload| Err(PlaybackError::Internal(panic_message(payload))))
+        })
     }
 }
 
+/*
+ * Runs a stage of the pipeline on its own thread.
+ * If it fails or panics, the other stages are cancelled and the error is returned on join.
+ */
+fn spawn_stage<F>(cancel_flag: Arc<AtomicBool>, stage: F) -> thread::JoinHandle<Res<()>>
+where
+    F: FnOnce() -> Res<()> + Send + 'static,
+{
+    thread::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(stage))
+            .unwrap_or_else(|payload| Err(PlaybackError::Internal(panic_message(payload))));
+
+        if result.is_err() {
+            cancel_flag.store(true, Ordering::SeqCst);
+        }
+
+        result
+    })
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "a playback thread panicked".to_string())
+}
+
 fn main() {
-    ffmpeg_next::init().unwrap();
+    // Printed once the screen has been restored, so that the message stays visible
+    if let Err(error) = run() {
+        eprintln!("{}", error);
+        process::exit(error.exit_code());
+    }
+}
+
+fn run() -> Res<()> {
+    ffmpeg_next::init()
+        .map_err(|e| PlaybackError::Codec(format!("Could not initialize ffmpeg: {}", e)))?;
 
     let args = parse_args();
 
-    if args.url.is_some() || args.search.is_some() {
-        let input = if let Some(url) = args.url {
-            url
+    if args.list_audio_devices {
+        return list_audio_devices();
+    }
+
+    let options = PlaybackOptions {
+        volume_control: Arc::new(VolumeControl::new(args.volume, args.normalize)),
+        audio_out: match args.audio_device {
+            Some(name) => AudioOutputKind::Device(Some(name)),
+            None => args.audio_out,
+        },
+        compression_level: args.compression,
+        // Asked before the TUI reads from stdin, since the terminal answers there.
+        // Sixel frames always go through the pty
+        transmission_medium: match args.renderer {
+            RendererKind::Kitty => args.transmission.detect(),
+            RendererKind::Sixel => TransmissionMedium::Direct,
+        },
+        renderer: args.renderer,
+        dither: args.dither,
+    };
+
+    if args.url.is_some() || args.search.is_some() || args.file.is_some() {
+        let formats = select_formats(
+            args.format.clone(),
+            args.audio_format.clone(),
+            args.quality,
+            None,
+        );
+
+        let (input, audio_input) = if let Some(url) = args.url {
+            formats.inputs(&url)
         } else if let Some(search) = args.search {
-            format!("ytsearch:{}", search)
+            formats.inputs(&format!("ytsearch:{}", search))
+        } else if let Some(path) = args.file {
+            if path.as_os_str() == "-" {
+                (InputSource::Stdin, None)
+            } else {
+                (InputSource::File(path), None)
+            }
         } else {
             unreachable!()
         };
-        run_direct_playback(&input, true, true);
+        run_direct_playback(input, audio_input, true, true, options)
     } else {
-        tui::run().expect("TUI error");
+        tui::run(args.quality, args.format, args.audio_format, options)
     }
 }
 
-fn run_direct_playback(input: &str, use_screen_guard: bool, center_video: bool) {
-    let (demultiplexer_audio_tx, demultiplexer_audio_rx) = channel::<RawAudioMessage>();
-    let (demultiplexer_video_tx, demultiplexer_video_rx) = channel::<RawVideoMessage>();
-    let (video_encoding_tx, video_encoding_rx) = channel::<EncodedVideoMessage>();
+fn list_audio_devices() -> Res<()> {
+    let (names, default_name) = output_device_names()?;
 
+    for name in names {
+        if default_name.as_ref() == Some(&name) {
+            println!("{} (default)", name);
+        } else {
+            println!("{}", name);
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Picks the yt-dlp formats: an explicit --format wins, then --quality,
+ * then the tallest video stream that fits the video area of the terminal,
+ * played along with the best separate audio stream
+ */
+pub fn select_formats(
+    format: Option<String>,
+    audio_format: Option<String>,
+    quality: Option<u32>,
+    video_rows: Option<u16>,
+) -> FormatSelection {
+    if let Some(format) = format {
+        return FormatSelection {
+            video: format,
+            audio: audio_format,
+        };
+    }
+
+    match quality.or_else(|| Encoder::available_pixel_height(video_rows)) {
+        Some(max_height) => FormatSelection {
+            video: video_format_selector(max_height),
+            audio: Some(audio_format_selector()),
+        },
+        None => FormatSelection {
+            video: DEFAULT_FORMAT.to_string(),
+            audio: None,
+        },
+    }
+}
+
+fn run_direct_playback(
+    input: InputSource,
+    audio_input: Option<InputSource>,
+    use_screen_guard: bool,
+    center_video: bool,
+    options: PlaybackOptions,
+) -> Res<()> {
     let _screen_guard = if use_screen_guard {
-        Some(ScreenGuard::new().expect("Failed to initialize screen guard"))
+        Some(ScreenGuard::new()?)
     } else {
         None
     };
 
-    let y_offset = if center_video { None } else { Some(0) };
+    let handle = start_playback_async(input, audio_input, center_video, None, &options);
 
-    let mut demux =
-        Demultiplexer::new(demultiplexer_video_tx, demultiplexer_audio_tx, input.to_string());
+    // Read keys as they are pressed, Ctrl+C then comes in as a key too
+    let raw_mode = enable_raw_mode().is_ok();
 
-    let demux_handle = thread::spawn(move || {
-        demux.demux().expect("Failed to start demultiplexer");
-    });
-
-    let mut encoder =
-        Encoder::new(demultiplexer_video_rx, video_encoding_tx, y_offset, None).expect("Failed to create encoder");
-
-    let encode_handle = thread::spawn(move || {
-        encoder.encode().expect("Failed to start encoding");
-    });
+    while !handle.is_finished() {
+        if !raw_mode {
+            thread::sleep(Duration::from_millis(16));
+            continue;
+        }
 
-    let mut audio_adapter =
-        AudioAdapter::new(demultiplexer_audio_rx).expect("Failed to create audio adapter");
+        if let Ok(true) = event::poll(Duration::from_millis(16)) {
+            if let Ok(Event::Key(key)) = event::read() {
+                handle_direct_playback_key(&handle, key);
+            }
+        }
+    }
 
-    let audio_handle = thread::spawn(move || {
-        audio_adapter.run().expect("Failed to start audio playback");
-    });
+    if raw_mode {
+        let _ = disable_raw_mode();
+    }
 
-    let mut video_adapter =
-        TerminalAdapter::new(video_encoding_rx).expect("Failed to create video adapter");
+    handle.join()
+}
 
-    let video_handle = thread::spawn(move || {
-        video_adapter.run().expect("Failed to start video display");
-    });
+fn handle_direct_playback_key(handle: &PlaybackHandle, key: KeyEvent) {
+    if key.kind != KeyEventKind::Press {
+        return;
+    }
 
-    let _ = demux_handle.join();
-    let _ = encode_handle.join();
-    let _ = audio_handle.join();
-    let _ = video_handle.join();
+    match key.code {
+        KeyCode::Char(' ') => {
+            handle.toggle_pause();
+        }
+        KeyCode::Char('+') | KeyCode::Char('=') => {
+            handle.volume_control().volume_up();
+        }
+        KeyCode::Char('-') => {
+            handle.volume_control().volume_down();
+        }
+        KeyCode::Char('m') => {
+            handle.volume_control().toggle_mute();
+        }
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => handle.cancel(),
+        KeyCode::Char('q') | KeyCode::Esc => handle.cancel(),
+        _ => {}
+    }
 }
 
-pub fn start_playback_async(input: &str, center_video: bool, video_rows: Option<u16>) -> PlaybackHandle {
+pub fn start_playback_async(
+    input: InputSource,
+    audio_input: Option<InputSource>,
+    center_video: bool,
+    video_rows: Option<u16>,
+    options: &PlaybackOptions,
+) -> PlaybackHandle {
     let cancel_flag = Arc::new(AtomicBool::new(false));
+    let seek_control = Arc::new(SeekControl::new());
+    let pause_control = Arc::new(PauseControl::new());
+    let device_control = Arc::new(DeviceControl::new());
+    let playback_clock = Arc::new(PlaybackClock::new());
 
     let (demultiplexer_audio_tx, demultiplexer_audio_rx) = channel::<RawAudioMessage>();
-    let (demultiplexer_video_tx, demultiplexer_video_rx) = channel::<RawVideoMessage>();
-    let (video_encoding_tx, video_encoding_rx) = channel::<EncodedVideoMessage>();
+    let (demultiplexer_video_tx, demultiplexer_video_rx) =
+        sync_channel::<RawVideoMessage>(DECODED_FRAMES_AHEAD);
+    // Frames sent through shared memory or temp files take room there until they are shown
+    let (video_encoding_tx, video_encoding_rx) =
+        sync_channel::<EncodedVideoMessage>(ENCODED_FRAMES_AHEAD);
 
     let y_offset = if center_video { None } else { Some(0) };
 
     let cancel = cancel_flag.clone();
-    let url = input.to_string();
-    let demux_handle = thread::spawn(move || {
-        let mut demux = Demultiplexer::new(demultiplexer_video_tx, demultiplexer_audio_tx, url);
+    let seek = seek_control.clone();
+    let demux_handle = spawn_stage(cancel_flag.clone(), move || {
+        let mut demux = Demultiplexer::new(demultiplexer_video_tx, demultiplexer_audio_tx, input);
         demux.set_cancel_flag(cancel);
-        let _ = demux.demux();
+        demux.set_seek_control(seek);
+        if let Some(audio_input) = audio_input {
+            demux.set_audio_input(audio_input);
+        }
+        demux.demux()
     });
 
     let cancel = cancel_flag.clone();
-    let encode_handle = thread::spawn(move || {
-        let mut encoder = Encoder::new(demultiplexer_video_rx, video_encoding_tx, y_offset, video_rows)
-            .expect("Failed to create encoder");
+    let seek = seek_control.clone();
+    let renderer = options.create_renderer();
+    let encode_handle = spawn_stage(cancel_flag.clone(), move || {
+        let mut encoder =
+            Encoder::new(demultiplexer_video_rx, video_encoding_tx, y_offset, video_rows)?;
         encoder.set_cancel_flag(cancel);
-        let _ = encoder.encode();
+        encoder.set_seek_control(seek);
+        encoder.set_renderer(renderer);
+        encoder.encode()
     });
 
     let cancel = cancel_flag.clone();
-    let audio_handle = thread::spawn(move || {
-        let mut audio_adapter =
-            AudioAdapter::new(demultiplexer_audio_rx).expect("Failed to create audio adapter");
+    let seek = seek_control.clone();
+    let pause = pause_control.clone();
+    let clock = playback_clock.clone();
+    let volume = options.volume_control.clone();
+    let audio_out = options.audio_out.clone();
+    let device = device_control.clone();
+    let audio_handle = spawn_stage(cancel_flag.clone(), move || {
+        let mut audio_adapter = AudioAdapter::new(demultiplexer_audio_rx, audio_out.create())?;
         audio_adapter.set_cancel_flag(cancel);
-        let _ = audio_adapter.run();
+        audio_adapter.set_seek_control(seek);
+        audio_adapter.set_pause_control(pause);
+        audio_adapter.set_playback_clock(clock);
+        audio_adapter.set_volume_control(volume);
+        audio_adapter.set_device_control(device);
+        audio_adapter.run()
     });
 
     let cancel = cancel_flag.clone();
-    let video_handle = thread::spawn(move || {
-        let mut video_adapter =
-            TerminalAdapter::new(video_encoding_rx).expect("Failed to create video adapter");
+    let seek = seek_control.clone();
+    let pause = pause_control.clone();
+    let clock = playback_clock;
+    let video_handle = spawn_stage(cancel_flag.clone(), move || {
+        let mut video_adapter = TerminalAdapter::new(video_encoding_rx)?;
         video_adapter.set_cancel_flag(cancel);
-        let _ = video_adapter.run();
+        video_adapter.set_seek_control(seek);
+        video_adapter.set_pause_control(pause);
+        video_adapter.set_playback_clock(clock);
+        video_adapter.run()
     });
 
     PlaybackHandle {
         cancel_flag,
+        seek_control,
+        pause_control,
+        volume_control: options.volume_control.clone(),
+        device_control,
         demux_handle,
         encode_handle,
         audio_handle,
@@ -1,5 +1,26 @@
 use crate::tui::search::SearchResult;
 
+/// Output devices the audio can be moved to while a video plays
+pub struct DevicePicker {
+    pub devices: Vec<String>,
+    pub default_device: Option<String>,
+    pub selected_index: usize,
+}
+
+impl DevicePicker {
+    pub fn select_next(&mut self) {
+        if !self.devices.is_empty() {
+            self.selected_index = (self.selected_index + 1) % self.devices.len();
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        if !self.devices.is_empty() {
+            self.selected_index = self.selected_index.checked_sub(1).unwrap_or(self.devices.len() - 1);
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum AppMode {
     Search,
@@ -15,6 +36,19 @@ pub struct App {
     pub should_quit: bool,
     pub playing_title: Option<String>,
     pub playing_url: Option<String>,
+    pub paused: bool,
+    /// Volume in percent, as last set from the keys
+    pub volume: u32,
+    pub muted: bool,
+    /// Whether the loudness of each video is brought to a common level
+    pub normalizing: bool,
+    /// Output device the audio plays on, None for the default one
+    pub audio_device: Option<String>,
+    /// Device the audio played on before the last switch, restored if the switch fails
+    pub previous_audio_device: Option<String>,
+    pub device_picker: Option<DevicePicker>,
+    /// Why the last search or playback failed
+    pub error: Option<String>,
 }
 
 impl App {
@@ -27,6 +61,14 @@ impl App {
             should_quit: false,
             playing_title: None,
             playing_url: None,
+            paused: false,
+            volume: 100,
+            muted: false,
+            normalizing: false,
+            audio_device: None,
+            previous_audio_device: None,
+            device_picker: None,
+            error: None,
         }
     }
 
@@ -42,6 +84,11 @@ impl App {
         }
     }
 
+    /// Whether this is the device the audio plays on
+    pub fn is_playing_on(&self, device: &str, default_device: Option<&str>) -> bool {
+        self.audio_device.as_deref().or(default_device) == Some(device)
+    }
+
     pub fn get_selected_result(&self) -> Option<&SearchResult> {
         self.results.get(self.selected_index)
     }
@@ -4,47 +4,76 @@ pub mod terminal;
 pub mod ui;
 
 use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use std::io;
 use std::time::Duration;
 
-use crate::PlaybackHandle;
-use app::{App, AppMode};
+use crate::audio::cpal_output::output_device_names;
+use crate::audio::output::AudioOutputKind;
+use crate::demux::input_source::FormatSelection;
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::Res;
+use crate::{PlaybackHandle, PlaybackOptions};
+use app::{App, AppMode, DevicePicker};
 use search::search_youtube;
 
-pub fn run() -> Result<(), Box<dyn std::error::Error>> {
-    let mut terminal = terminal::init()?;
+pub fn run(
+    quality: Option<u32>,
+    format: Option<String>,
+    audio_format: Option<String>,
+    options: PlaybackOptions,
+) -> Res<()> {
+    let mut terminal = terminal::init().map_err(terminal_error)?;
     let mut app = App::new();
+    app.volume = options.volume_control.level();
+    app.normalizing = options.volume_control.is_normalizing();
+    if let AudioOutputKind::Device(ref name) = options.audio_out {
+        app.audio_device = name.clone();
+    }
     let mut playback: Option<PlaybackHandle> = None;
 
-    let result = run_app(&mut terminal, &mut app, &mut playback);
+    // Resolved once, since the video area of the TUI doesn't change
+    let formats = crate::select_formats(format, audio_format, quality, Some(ui::VIDEO_ROWS));
+
+    let result = run_app(&mut terminal, &mut app, &mut playback, &formats, &options);
 
     // Clean up any running playback
     if let Some(handle) = playback {
         handle.cancel();
-        handle.join();
+        let _ = handle.join();
     }
 
-    terminal::restore()?;
+    terminal::restore().map_err(terminal_error)?;
     result
 }
 
+fn terminal_error(error: io::Error) -> PlaybackError {
+    PlaybackError::Terminal(error.to_string())
+}
+
 fn run_app(
     terminal: &mut terminal::Tui,
     app: &mut App,
     playback: &mut Option<PlaybackHandle>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    formats: &FormatSelection,
+    options: &PlaybackOptions,
+) -> Res<()> {
     while !app.should_quit {
-        terminal.draw(|f| ui::render(f, app))?;
+        terminal.draw(|f| ui::render(f, app)).map_err(terminal_error)?;
 
-        // Check if playback finished naturally
+        // Check if playback finished naturally, or failed
         if let Some(ref handle) = playback {
             if handle.is_finished() {
-                playback.take().unwrap().join();
+                app.error = playback.take().unwrap().join().err().map(|e| e.to_string());
                 app.mode = AppMode::Results;
+            } else if let Some(error) = handle.take_audio_device_error() {
+                // Playback went on with the previous device
+                app.audio_device = app.previous_audio_device.take();
+                app.error = Some(error);
             }
         }
 
-        if event::poll(Duration::from_millis(16))? {
-            if let Event::Key(key) = event::read()? {
+        if event::poll(Duration::from_millis(16)).map_err(terminal_error)? {
+            if let Event::Key(key) = event::read().map_err(terminal_error)? {
                 if key.kind != KeyEventKind::Press {
                     continue;
                 }
@@ -61,8 +90,13 @@ fn run_app(
 
                 match &app.mode {
                     AppMode::Search => handle_search_mode(app, key.code),
-                    AppMode::Results => handle_results_mode(app, key.code, playback),
-                    AppMode::Playing => handle_playing_mode(app, key.code, playback),
+                    AppMode::Results => {
+                        handle_results_mode(app, key.code, playback, formats, options)
+                    }
+                    AppMode::Playing if app.device_picker.is_some() => {
+                        handle_device_picker(app, key.code, playback)
+                    }
+                    AppMode::Playing => handle_playing_mode(app, key.code, playback, options),
                 }
             }
         }
@@ -72,9 +106,11 @@ fn run_app(
 }
 
 fn stop_playback(app: &mut App, playback: &mut Option<PlaybackHandle>) {
+    app.device_picker = None;
     if let Some(handle) = playback.take() {
         handle.cancel();
-        handle.join();
+        // Playback may have failed before it was stopped
+        app.error = handle.join().err().map(|e| e.to_string());
     }
     app.mode = AppMode::Results;
 }
@@ -95,12 +131,16 @@ fn handle_search_mode(app: &mut App, key: KeyCode) {
         }
         KeyCode::Enter => {
             if !app.search_input.is_empty() {
-                if let Ok(results) = search_youtube(&app.search_input, 10) {
-                    app.results = results;
-                    app.selected_index = 0;
-                    if !app.results.is_empty() {
-                        app.mode = AppMode::Results;
+                match search_youtube(&app.search_input, 10) {
+                    Ok(results) => {
+                        app.error = None;
+                        app.results = results;
+                        app.selected_index = 0;
+                        if !app.results.is_empty() {
+                            app.mode = AppMode::Results;
+                        }
                     }
+                    Err(error) => app.error = Some(error.to_string()),
                 }
             }
         }
@@ -108,7 +148,13 @@ fn handle_search_mode(app: &mut App, key: KeyCode) {
     }
 }
 
-fn handle_results_mode(app: &mut App, key: KeyCode, playback: &mut Option<PlaybackHandle>) {
+fn handle_results_mode(
+    app: &mut App,
+    key: KeyCode,
+    playback: &mut Option<PlaybackHandle>,
+    formats: &FormatSelection,
+    options: &PlaybackOptions,
+) {
     match key {
         KeyCode::Char('q') => {
             app.should_quit = true;
@@ -126,21 +172,119 @@ fn handle_results_mode(app: &mut App, key: KeyCode, playback: &mut Option<Playba
             if let Some(result) = app.get_selected_result().cloned() {
                 app.playing_title = Some(result.title.clone());
                 app.playing_url = Some(result.url.clone());
+                app.paused = false;
+                app.error = None;
                 app.mode = AppMode::Playing;
 
+                let (input, audio_input) = formats.inputs(&result.url);
+
                 // Start playback asynchronously with video area constraints
-                *playback = Some(crate::start_playback_async(&result.url, false, Some(ui::VIDEO_ROWS)));
+                *playback = Some(crate::start_playback_async(
+                    input,
+                    audio_input,
+                    false,
+                    Some(ui::VIDEO_ROWS),
+                    options,
+                ));
             }
         }
         _ => {}
     }
 }
 
-fn handle_playing_mode(app: &mut App, key: KeyCode, playback: &mut Option<PlaybackHandle>) {
+fn handle_playing_mode(
+    app: &mut App,
+    key: KeyCode,
+    playback: &mut Option<PlaybackHandle>,
+    options: &PlaybackOptions,
+) {
     match key {
         KeyCode::Esc | KeyCode::Char('q') => {
             stop_playback(app, playback);
         }
+        KeyCode::Char(' ') => {
+            if let Some(ref handle) = playback {
+                app.paused = handle.toggle_pause();
+            }
+        }
+        KeyCode::Char('+') | KeyCode::Char('=') => {
+            if let Some(ref handle) = playback {
+                app.volume = handle.volume_control().volume_up();
+                app.muted = false;
+            }
+        }
+        KeyCode::Char('-') => {
+            if let Some(ref handle) = playback {
+                app.volume = handle.volume_control().volume_down();
+            }
+        }
+        KeyCode::Char('m') => {
+            if let Some(ref handle) = playback {
+                app.muted = handle.volume_control().toggle_mute();
+            }
+        }
+        // Only the device output can switch, the others write wherever --audio-out said
+        KeyCode::Char('d') if !matches!(options.audio_out, AudioOutputKind::Device(_)) => {
+            app.error = Some("device switching needs --audio-out device".to_string());
+        }
+        KeyCode::Char('d') => match output_device_names() {
+            Ok((devices, default_device)) => {
+                // Starts on the device the audio plays on
+                let selected_index = devices
+                    .iter()
+                    .position(|device| app.is_playing_on(device, default_device.as_deref()))
+                    .unwrap_or(0);
+
+                app.error = None;
+                app.device_picker = Some(DevicePicker {
+                    devices,
+                    default_device,
+                    selected_index,
+                });
+            }
+            Err(error) => app.error = Some(error.to_string()),
+        },
+        KeyCode::Left => seek_by(playback, -5000),
+        KeyCode::Right => seek_by(playback, 5000),
+        KeyCode::Down => seek_by(playback, -30000),
+        KeyCode::Up => seek_by(playback, 30000),
+        KeyCode::Char(digit @ '0'..='9') => {
+            // 0 to 9 jump to 0% to 90% of the video
+            if let Some(ref handle) = playback {
+                let percent = digit.to_digit(10).unwrap() as usize * 10;
+                handle.seek_to_percent(percent);
+            }
+        }
         _ => {}
     }
 }
+
+fn handle_device_picker(app: &mut App, key: KeyCode, playback: &mut Option<PlaybackHandle>) {
+    let Some(ref mut picker) = app.device_picker else {
+        return;
+    };
+
+    match key {
+        KeyCode::Esc | KeyCode::Char('d') => {
+            app.device_picker = None;
+        }
+        KeyCode::Char('j') | KeyCode::Down => picker.select_next(),
+        KeyCode::Char('k') | KeyCode::Up => picker.select_previous(),
+        KeyCode::Enter => {
+            let device = picker.devices.get(picker.selected_index).cloned();
+            app.device_picker = None;
+
+            if let (Some(device), Some(ref handle)) = (device, playback) {
+                app.previous_audio_device = app.audio_device.replace(device.clone());
+                handle.switch_audio_device(device);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn seek_by(playback: &Option<PlaybackHandle>, delta_ms: i64) {
+    if let Some(ref handle) = playback {
+        handle.seek_by(delta_ms);
+    }
+}
@@ -1,6 +1,9 @@
 use serde::Deserialize;
 use std::process::Command;
 
+use crate::helpers::error::PlaybackError;
+use crate::helpers::types::Res;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct SearchResult {
     pub id: String,
@@ -24,19 +27,21 @@ struct YtDlpEntry {
     url: String,
 }
 
-pub fn search_youtube(query: &str, max_results: usize) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+pub fn search_youtube(query: &str, max_results: usize) -> Res<Vec<SearchResult>> {
     let search_term = format!("ytsearch{}:{}", max_results, query);
 
     let output = Command::new("yt-dlp")
         .args(["--flat-playlist", "-J", &search_term])
-        .output()?;
+        .output()
+        .map_err(|e| PlaybackError::Input(format!("Could not start yt-dlp process: {}", e)))?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("yt-dlp search failed: {}", stderr).into());
+        return Err(PlaybackError::Input(format!("yt-dlp search failed: {}", stderr.trim())));
     }
 
-    let playlist: YtDlpPlaylist = serde_json::from_slice(&output.stdout)?;
+    let playlist: YtDlpPlaylist = serde_json::from_slice(&output.stdout)
+        .map_err(|e| PlaybackError::Input(format!("Unexpected yt-dlp search output: {}", e)))?;
 
     Ok(playlist
         .entries
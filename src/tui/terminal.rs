@@ -4,7 +4,9 @@ use crossterm::{
 };
 use ratatui::backend::CrosstermBackend;
 use ratatui::Terminal;
-use std::io::{self, Stdout};
+use std::io::{self, Stdout, Write};
+
+use crate::video::kitty::delete_image_escape;
 
 pub type Tui = Terminal<CrosstermBackend<Stdout>>;
 
@@ -16,6 +18,12 @@ pub fn init() -> io::Result<Tui> {
 
 pub fn restore() -> io::Result<()> {
     disable_raw_mode()?;
+
+    // Images stay on the alternate screen otherwise, where the next program to use it finds them
+    let mut stdout = io::stdout();
+    stdout.write_all(&delete_image_escape())?;
+    stdout.flush()?;
+
     execute!(io::stdout(), LeaveAlternateScreen)?;
     Ok(())
 }
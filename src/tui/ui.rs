@@ -63,6 +63,20 @@ fn render_search_bar(f: &mut Frame, app: &App, area: Rect) {
 }
 
 fn render_content_area(f: &mut Frame, app: &App, area: Rect) {
+    let area = match app.error {
+        // While playing, only a failed device switch is reported
+        Some(ref error) => {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(1), Constraint::Length(3)])
+                .split(area);
+
+            render_error(f, error, chunks[1]);
+            chunks[0]
+        }
+        _ => area,
+    };
+
     match &app.mode {
         AppMode::Search => {
             let help = Paragraph::new("Type to search, Enter to submit, q to quit")
@@ -94,15 +108,71 @@ fn render_content_area(f: &mut Frame, app: &App, area: Rect) {
             );
             f.render_widget(list, area);
         }
+        AppMode::Playing if app.device_picker.is_some() => render_device_picker(f, app, area),
         AppMode::Playing => {
             let title = app.playing_title.as_deref().unwrap_or("Unknown");
-            let status = Paragraph::new(format!("Playing: {}\n\nPress Esc to stop", title))
+            let state = if app.paused { "Paused" } else { "Playing" };
+            let volume = if app.muted {
+                "Muted".to_string()
+            } else {
+                format!("Volume {}%", app.volume)
+            };
+            let normalizing = if app.normalizing { ", normalized" } else { "" };
+            let status = Paragraph::new(format!(
+                "{}: {}\n{}{}\n\nSpace to pause, ←/→ seek 5s, ↓/↑ seek 30s, 0-9 jump to 0-90%, +/- volume, m to mute, d output device, Esc to stop",
+                state, title, volume, normalizing
+            ))
                 .block(Block::default().borders(Borders::ALL).title("Now Playing"));
             f.render_widget(status, area);
         }
     }
 }
 
+fn render_device_picker(f: &mut Frame, app: &App, area: Rect) {
+    let Some(ref picker) = app.device_picker else {
+        return;
+    };
+
+    let items: Vec<ListItem> = picker
+        .devices
+        .iter()
+        .enumerate()
+        .map(|(i, device)| {
+            let style = if i == picker.selected_index {
+                Style::default()
+                    .bg(Color::DarkGray)
+                    .add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+
+            let mut label = device.clone();
+            if picker.default_device.as_ref() == Some(device) {
+                label.push_str(" (default)");
+            }
+            if app.is_playing_on(device, picker.default_device.as_deref()) {
+                label.push_str(" (playing)");
+            }
+
+            ListItem::new(label).style(style)
+        })
+        .collect();
+
+    let list = List::new(items).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title("Output device (Enter to switch, Esc to close)"),
+    );
+    f.render_widget(list, area);
+}
+
+fn render_error(f: &mut Frame, error: &str, area: Rect) {
+    let error = Paragraph::new(error)
+        .style(Style::default().fg(Color::Red))
+        .block(Block::default().borders(Borders::ALL).title("Error"));
+    f.render_widget(error, area);
+}
+
 fn format_result(result: &SearchResult) -> String {
     let duration = result
         .duration
@@ -5,6 +5,10 @@ use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
+use crate::helpers::clock::PlaybackClock;
+use crate::helpers::error::PlaybackError;
+use crate::helpers::pause::PauseControl;
+use crate::helpers::seek::SeekControl;
 use crate::helpers::types::{BytesWithTimestamp, Res};
 
 use super::encoder::EncodedVideoMessage;
@@ -12,6 +16,12 @@ use super::encoder::EncodedVideoMessage;
 pub struct TerminalAdapter {
     producer_rx: Receiver<EncodedVideoMessage>,
     cancel_flag: Option<Arc<AtomicBool>>,
+    seek_control: Option<Arc<SeekControl>>,
+    pause_control: Option<Arc<PauseControl>>,
+    playback_clock: Option<Arc<PlaybackClock>>,
+    generation: usize,
+    /// Escape removing what the frames drew, from the encoder
+    clear_escape: Vec<u8>,
 }
 
 impl TerminalAdapter {
@@ -19,6 +29,11 @@ impl TerminalAdapter {
         Ok(TerminalAdapter {
             producer_rx,
             cancel_flag: None,
+            seek_control: None,
+            pause_control: None,
+            playback_clock: None,
+            generation: 0,
+            clear_escape: vec![],
         })
     }
 
@@ -26,6 +41,80 @@ impl TerminalAdapter {
         self.cancel_flag = Some(flag);
     }
 
+    pub fn set_seek_control(&mut self, seek_control: Arc<SeekControl>) {
+        self.seek_control = Some(seek_control);
+    }
+
+    pub fn set_pause_control(&mut self, pause_control: Arc<PauseControl>) {
+        self.pause_control = Some(pause_control);
+    }
+
+    /// Shows frames against the audio output instead of a timer of its own, while there is audio
+    pub fn set_playback_clock(&mut self, playback_clock: Arc<PlaybackClock>) {
+        self.playback_clock = Some(playback_clock);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel_flag
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::SeqCst))
+    }
+
+    /* Whether frames received now were decoded before the latest seek */
+    fn is_flushing(&self) -> bool {
+        self.seek_control
+            .as_ref()
+            .is_some_and(|seek_control| seek_control.is_stale(self.generation))
+    }
+
+    /*
+     * Current playback position, from the audio clock when it runs.
+     * The timer follows it, so that playback carries on smoothly
+     * on the timer alone once the audio is over
+     */
+    fn playback_position_ms(&self, start_time: &mut Instant) -> usize {
+        match self
+            .playback_clock
+            .as_ref()
+            .and_then(|playback_clock| playback_clock.position_ms())
+        {
+            Some(position_ms) => {
+                *start_time = Instant::now()
+                    .checked_sub(Duration::from_millis(position_ms as u64))
+                    .unwrap_or_else(Instant::now);
+                position_ms
+            }
+            None => start_time.elapsed().as_millis() as usize,
+        }
+    }
+
+    /*
+     * Keeps the previous frame on screen until the clock reaches the next one,
+     * returns false if playback was cancelled or seeked in the meantime
+     */
+    fn wait_for_frame(&self, timestamp_in_ms: usize, start_time: &mut Instant) -> bool {
+        loop {
+            if self.is_cancelled() || self.is_flushing() {
+                return false;
+            }
+
+            if let Some(ref pause_control) = self.pause_control {
+                if pause_control.is_paused() {
+                    *start_time += pause_control.wait_while_paused(self.cancel_flag.as_deref());
+                    continue;
+                }
+            }
+
+            let position_ms = self.playback_position_ms(start_time);
+            if timestamp_in_ms <= position_ms {
+                return true;
+            }
+
+            // Short naps, since the audio clock stalls when the output is starved
+            thread::sleep(Duration::from_millis((timestamp_in_ms - position_ms).min(10) as u64));
+        }
+    }
+
     fn process_element(&self, frame: BytesWithTimestamp) -> Res<()> {
         let mut stdout = io::stdout();
 
@@ -35,49 +124,107 @@ impl TerminalAdapter {
         buffer.extend_from_slice(reset_cursor);
         buffer.extend_from_slice(&frame.data);
 
-        stdout.write_all(&buffer)?;
-        stdout.flush()?;
-        Ok(())
+        stdout
+            .write_all(&buffer)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| PlaybackError::Terminal(format!("Could not write a frame: {}", e)))
     }
 
+    /* Removes what the frames drew, once playback stops or is cancelled */
+    fn clear_image(&self) -> Res<()> {
+        if self.clear_escape.is_empty() {
+            return Ok(());
+        }
+
+        let mut stdout = io::stdout();
+
+        stdout
+            .write_all(&self.clear_escape)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| PlaybackError::Terminal(format!("Could not clear the video: {}", e)))
+    }
+
+    /*
+     * Shows the frames on time until the last one, then tells the seek control that playback
+     * is finished, so that the other stages stop waiting for seeks after the end
+     */
     pub fn run(&mut self) -> Res<()> {
         let mut start_time = Instant::now();
         let mut started_playing = false;
 
         loop {
-            if let Some(ref flag) = self.cancel_flag {
-                if flag.load(Ordering::SeqCst) {
-                    return Ok(());
+            if self.is_cancelled() {
+                return self.clear_image();
+            }
+
+            if let Some(ref pause_control) = self.pause_control {
+                if pause_control.is_paused() {
+                    // Move the clock forward so that nothing is late when playback resumes
+                    start_time += pause_control.wait_while_paused(self.cancel_flag.as_deref());
+                    continue;
                 }
             }
 
             match self.producer_rx.recv_timeout(Duration::from_millis(16)) {
                 Ok(message) => match message {
-                    EncodedVideoMessage::EncodedVideoMessage(frame) => {
+                    EncodedVideoMessage::EncodedVideoMessage(frame, resource) => {
+                        if self.is_flushing() {
+                            continue;
+                        }
+
+                        // The first frame may not be at 0 after a seek
                         if !started_playing {
                             started_playing = true;
-                            start_time = Instant::now();
+                            start_time = Instant::now()
+                                .checked_sub(Duration::from_millis(frame.timestamp_in_ms as u64))
+                                .unwrap_or_else(Instant::now);
+                        }
+
+                        if frame.timestamp_in_ms + 10 < self.playback_position_ms(&mut start_time) {
+                            continue;
                         }
 
-                        if frame.timestamp_in_ms + 10 < start_time.elapsed().as_millis() as usize {
+                        if !self.wait_for_frame(frame.timestamp_in_ms, &mut start_time) {
                             continue;
                         }
 
-                        if frame.timestamp_in_ms > start_time.elapsed().as_millis() as usize {
-                            thread::sleep(Duration::from_millis(
-                                (frame.timestamp_in_ms - start_time.elapsed().as_millis() as usize)
-                                    as u64,
-                            ));
+                        let timestamp_in_ms = frame.timestamp_in_ms;
+                        self.process_element(frame)?;
+
+                        // Frames skipped above release theirs when dropped
+                        if let Some(resource) = resource {
+                            resource.mark_written();
                         }
 
-                        self.process_element(frame).unwrap();
+                        if let Some(ref seek_control) = self.seek_control {
+                            seek_control.set_position_ms(timestamp_in_ms);
+                        }
+                    }
+                    EncodedVideoMessage::Clear(clear_escape) => {
+                        self.clear_escape = clear_escape;
+                    }
+                    EncodedVideoMessage::Flush(generation) => {
+                        self.generation = generation;
+                        started_playing = false;
                     }
                     EncodedVideoMessage::Done => {
-                        return Ok(());
+                        if self.is_flushing() {
+                            continue;
+                        }
+
+                        if let Some(ref seek_control) = self.seek_control {
+                            seek_control.set_finished();
+                        }
+                        return self.clear_image();
                     }
                 },
                 Err(RecvTimeoutError::Timeout) => continue,
-                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    if let Some(ref seek_control) = self.seek_control {
+                        seek_control.set_finished();
+                    }
+                    return self.clear_image();
+                }
             }
         }
     }
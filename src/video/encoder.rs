@@ -1,13 +1,21 @@
 use crate::demux::demultiplexer::RawVideoMessage;
+use crate::helpers::error::PlaybackError;
+use crate::helpers::seek::SeekControl;
 use crate::helpers::types::{BytesWithTimestamp, Res};
-use base64::{engine::general_purpose, Engine as _};
+use crate::video::kitty::KittyRenderer;
+use crate::video::renderer::{FrameRenderer, FrameResource, Layout};
 use std::mem;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::{collections::HashMap, sync::mpsc, time::Duration};
+use std::{sync::mpsc, time::Duration};
 
 pub enum EncodedVideoMessage {
-    EncodedVideoMessage(BytesWithTimestamp),
+    /// A frame, with what its escapes refer to if anything
+    EncodedVideoMessage(BytesWithTimestamp, Option<Box<dyn FrameResource>>),
+    /// Everything sent before this belongs to the position before a seek
+    Flush(usize),
+    /// Escape removing what the frames sent after this draw, written once playback stops
+    Clear(Vec<u8>),
     Done,
 }
 
@@ -19,16 +27,19 @@ pub struct Encoder {
     term_cols: u16,
     term_rows: u16,
     producer_rx: mpsc::Receiver<RawVideoMessage>,
-    producer_tx: mpsc::Sender<EncodedVideoMessage>,
+    producer_tx: mpsc::SyncSender<EncodedVideoMessage>,
     force_y_offset: Option<usize>,
     video_rows: Option<u16>,
     cancel_flag: Option<Arc<AtomicBool>>,
+    seek_control: Option<Arc<SeekControl>>,
+    generation: usize,
+    renderer: Box<dyn FrameRenderer>,
 }
 
 impl Encoder {
     pub fn new(
         producer_rx: mpsc::Receiver<RawVideoMessage>,
-        producer_tx: mpsc::Sender<EncodedVideoMessage>,
+        producer_tx: mpsc::SyncSender<EncodedVideoMessage>,
         force_y_offset: Option<usize>,
         video_rows: Option<u16>,
     ) -> Res<Self> {
@@ -36,7 +47,7 @@ impl Encoder {
             Self::get_terminal_size().unwrap_or((1280, 720, 80, 24));
 
         if term_width == 0 || term_height == 0 || term_cols == 0 || term_rows == 0 {
-            return Err("Invalid terminal size".into());
+            return Err(PlaybackError::Terminal("Invalid terminal size".to_string()));
         }
 
         Ok(Encoder {
@@ -51,6 +62,9 @@ impl Encoder {
             force_y_offset,
             video_rows,
             cancel_flag: None,
+            seek_control: None,
+            generation: 0,
+            renderer: Box::new(KittyRenderer::new()),
         })
     }
 
@@ -58,6 +72,43 @@ impl Encoder {
         self.cancel_flag = Some(flag);
     }
 
+    pub fn set_seek_control(&mut self, seek_control: Arc<SeekControl>) {
+        self.seek_control = Some(seek_control);
+    }
+
+    /// Draws the frames with this renderer instead of the Kitty graphics protocol
+    pub fn set_renderer(&mut self, renderer: Box<dyn FrameRenderer>) {
+        self.renderer = renderer;
+    }
+
+    /* Whether the terminal adapter has shown the last frame */
+    fn is_finished(&self) -> bool {
+        self.seek_control
+            .as_ref()
+            .is_none_or(|seek_control| seek_control.is_finished())
+    }
+
+    /* Whether frames received now were decoded before the latest seek */
+    fn is_flushing(&self) -> bool {
+        self.seek_control
+            .as_ref()
+            .is_some_and(|seek_control| seek_control.is_stale(self.generation))
+    }
+
+    /// Height in pixels of the area the video is drawn in, used to pick a
+    /// stream resolution that fits it. Returns None when the terminal does
+    /// not report its pixel size.
+    pub fn available_pixel_height(video_rows: Option<u16>) -> Option<u32> {
+        let (_, term_height, _, term_rows) = Self::get_terminal_size().ok()?;
+
+        if term_height == 0 || term_rows == 0 {
+            return None;
+        }
+
+        let rows = video_rows.unwrap_or(term_rows).min(term_rows);
+        Some(term_height as u32 * rows as u32 / term_rows as u32)
+    }
+
     /// Returns pixel dimensions per cell (width, height).
     fn cell_pixel_dimensions(&self) -> (f64, f64) {
         (
@@ -66,13 +117,22 @@ impl Encoder {
         )
     }
 
+    /* Rows the video can take, short of the last terminal row when drawing there scrolls */
+    fn available_rows(&self) -> u16 {
+        let rows = self.video_rows.unwrap_or(self.term_rows);
+        if rows >= self.term_rows && self.renderer.scrolls_on_last_row() {
+            return rows.min(self.term_rows).saturating_sub(1).max(1);
+        }
+        rows
+    }
+
     /// Calculate display dimensions (columns, rows) for Kitty protocol scaling.
     /// Maintains aspect ratio while fitting within available space.
     fn calculate_display_dimensions(&self) -> (u16, u16) {
         let (cell_width_px, cell_height_px) = self.cell_pixel_dimensions();
 
         // Determine available rows
-        let available_rows = self.video_rows.unwrap_or(self.term_rows);
+        let available_rows = self.available_rows();
         let available_cols = self.term_cols;
 
         // Calculate target rows (video height in cells)
@@ -98,33 +158,9 @@ impl Encoder {
         (target_cols, target_rows)
     }
 
-    // Convert a frame to the Kitty Graphics Protocol format
-    fn encode_frame(
-        &self,
-        encoded_control_data: &Vec<u8>,
-        frame: BytesWithTimestamp,
-    ) -> BytesWithTimestamp {
-        // Base64 encode the frame data
-        let encoded_payload = self.encode_rgb(frame.data);
-        let prefix = b"\x1b_G";
-        let suffix = b"\x1b\\";
-        let delimiter = b";";
-        let mut buffer = vec![];
-
-        buffer.extend_from_slice(prefix);
-        buffer.extend_from_slice(&encoded_control_data);
-        buffer.extend_from_slice(delimiter);
-        buffer.extend_from_slice(&encoded_payload);
-        buffer.extend_from_slice(suffix);
-
-        BytesWithTimestamp {
-            data: buffer,
-            timestamp_in_ms: frame.timestamp_in_ms,
-        }
-    }
-
-    pub fn encode(&mut self) -> Res<()> {
-        // Calculate display dimensions for Kitty scaling
+    /* Where the frame goes and how big, for the current frame and terminal sizes */
+    fn build_layout(&self) -> Layout {
+        // Calculate display dimensions for scaling
         let (display_cols, display_rows) = self.calculate_display_dimensions();
         let (cell_width_px, cell_height_px) = self.cell_pixel_dimensions();
 
@@ -138,22 +174,58 @@ impl Encoder {
 
         // Calculate y offset: use force_y_offset if set, otherwise center within available space
         let y_offset = self.force_y_offset.unwrap_or_else(|| {
-            let available_rows = self.video_rows.unwrap_or(self.term_rows);
+            let available_rows = self.available_rows();
             let available_height_px = available_rows as f64 * cell_height_px;
             ((available_height_px - scaled_height_px) / 2.0).max(0.0) as usize
         });
 
-        let encoded_control_data = self.encode_control_data(HashMap::from([
-            ("f".into(), "24".into()),
-            ("s".into(), format!("{}", self.width)),
-            ("v".into(), format!("{}", self.height)),
-            ("c".into(), format!("{}", display_cols)),
-            ("r".into(), format!("{}", display_rows)),
-            ("t".into(), "d".into()),
-            ("a".into(), "T".into()),
-            ("X".into(), format!("{}", x_offset)),
-            ("Y".into(), format!("{}", y_offset)),
-        ]));
+        Layout {
+            frame_width: self.width,
+            frame_height: self.height,
+            cols: display_cols,
+            rows: display_rows,
+            x_offset,
+            y_offset,
+            cell_width: cell_width_px,
+            cell_height: cell_height_px,
+        }
+    }
+
+    /* Hands the layout to the renderer, and tells the terminal adapter how to clear what it draws */
+    fn update_layout(&mut self) -> bool {
+        let layout = self.build_layout();
+        self.renderer.set_layout(layout);
+
+        self.producer_tx
+            .send(EncodedVideoMessage::Clear(self.renderer.clear_escape()))
+            .is_ok()
+    }
+
+    fn encode_frame(
+        &mut self,
+        frame: BytesWithTimestamp,
+    ) -> (BytesWithTimestamp, Option<Box<dyn FrameResource>>) {
+        let rendered = self.renderer.render(frame.data);
+
+        let encoded_frame = BytesWithTimestamp {
+            data: rendered.escapes,
+            timestamp_in_ms: frame.timestamp_in_ms,
+        };
+
+        (encoded_frame, rendered.resource)
+    }
+
+    /*
+     * Encodes the frames until the last one has been shown. The demultiplexer reaches
+     * the end long before that, and a seek can still bring more frames after it.
+     * A failed send means the terminal adapter stopped, and it reports why if it failed.
+     */
+    pub fn encode(&mut self) -> Res<()> {
+        if !self.update_layout() {
+            return Ok(());
+        }
+
+        let mut ended = false;
 
         loop {
             if let Some(ref flag) = self.cancel_flag {
@@ -163,18 +235,59 @@ impl Encoder {
                 }
             }
 
+            if ended && self.is_finished() {
+                return Ok(());
+            }
+
             match self.producer_rx.recv_timeout(Duration::from_millis(16)) {
                 Ok(message) => match message {
-                    RawVideoMessage::VideoMessage(frame) => {
-                        let encoded_frame = self.encode_frame(&encoded_control_data, frame);
-
-                        self.producer_tx
-                            .send(EncodedVideoMessage::EncodedVideoMessage(encoded_frame))
-                            .unwrap();
+                    RawVideoMessage::VideoMessage {
+                        frame,
+                        width,
+                        height,
+                    } => {
+                        if self.is_flushing() {
+                            continue;
+                        }
+
+                        // Scaling and offsets depend on the frame size
+                        if width != self.width || height != self.height {
+                            self.width = width;
+                            self.height = height;
+                            if !self.update_layout() {
+                                return Ok(());
+                            }
+                        }
+
+                        let (encoded_frame, resource) = self.encode_frame(frame);
+
+                        if self
+                            .producer_tx
+                            .send(EncodedVideoMessage::EncodedVideoMessage(encoded_frame, resource))
+                            .is_err()
+                        {
+                            return Ok(());
+                        }
+                    }
+                    RawVideoMessage::Flush(generation) => {
+                        self.generation = generation;
+                        if self
+                            .producer_tx
+                            .send(EncodedVideoMessage::Flush(generation))
+                            .is_err()
+                        {
+                            return Ok(());
+                        }
                     }
                     RawVideoMessage::Done => {
-                        self.producer_tx.send(EncodedVideoMessage::Done).unwrap();
-                        return Ok(());
+                        if self.is_flushing() {
+                            continue;
+                        }
+
+                        if self.producer_tx.send(EncodedVideoMessage::Done).is_err() {
+                            return Ok(());
+                        }
+                        ended = true;
                     }
                 },
                 Err(mpsc::RecvTimeoutError::Timeout) => continue,
@@ -183,20 +296,6 @@ impl Encoder {
         }
     }
 
-    fn encode_control_data(&self, control_data: HashMap<String, String>) -> Vec<u8> {
-        let mut encoded_data = Vec::new();
-        for (key, value) in control_data {
-            encoded_data.push(format!("{}={}", key, value));
-        }
-
-        encoded_data.join(",").as_bytes().to_vec()
-    }
-
-    fn encode_rgb(&self, rgb: Vec<u8>) -> Vec<u8> {
-        let encoded = general_purpose::STANDARD.encode(&rgb);
-        encoded.as_bytes().to_vec()
-    }
-
     fn get_terminal_size() -> std::io::Result<(u16, u16, u16, u16)> {
         let mut winsize: libc::winsize = unsafe { mem::zeroed() };
 
@@ -213,12 +312,14 @@ impl Encoder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::video::sixel::SixelRenderer;
     use std::sync::mpsc;
+    use std::thread;
 
     #[test]
     fn test_new_encoder() {
         let (_streaming_done_tx, producer_rx) = mpsc::channel();
-        let (producer_tx, _encoding_done_rx) = mpsc::channel();
+        let (producer_tx, _encoding_done_rx) = mpsc::sync_channel(16);
 
         let encoder = Encoder::new(producer_rx, producer_tx, None, None).unwrap();
 
@@ -227,32 +328,169 @@ mod tests {
     }
 
     #[test]
-    fn test_encode_control_data() {
-        let encoder = Encoder::new(mpsc::channel().1, mpsc::channel().0, None, None).unwrap();
-
-        let control_data = HashMap::from([
-            ("f".into(), "24".into()),
-            ("s".into(), "640".into()),
-            ("v".into(), "360".into()),
-        ]);
-
-        let encoded_data = encoder.encode_control_data(control_data);
-        assert!(String::from_utf8(encoded_data.clone()).is_ok());
-        assert!(String::from_utf8(encoded_data.clone())
-            .unwrap()
-            .contains("f=24"));
-        assert!(String::from_utf8(encoded_data.clone())
-            .unwrap()
-            .contains("s=640"));
-        assert!(String::from_utf8(encoded_data.clone())
-            .unwrap()
-            .contains("v=360"));
+    fn test_control_data_follows_frame_size() {
+        let (raw_tx, producer_rx) = mpsc::channel();
+        let (producer_tx, encoded_rx) = mpsc::sync_channel(16);
+
+        let mut encoder = Encoder::new(producer_rx, producer_tx, None, None).unwrap();
+
+        raw_tx
+            .send(RawVideoMessage::VideoMessage {
+                frame: BytesWithTimestamp {
+                    data: vec![0; 4 * 2 * 3],
+                    timestamp_in_ms: 0,
+                },
+                width: 4,
+                height: 2,
+            })
+            .unwrap();
+        raw_tx.send(RawVideoMessage::Done).unwrap();
+
+        encoder.encode().unwrap();
+
+        assert_eq!(encoder.width, 4);
+        assert_eq!(encoder.height, 2);
+
+        let messages: Vec<EncodedVideoMessage> = encoded_rx.try_iter().collect();
+
+        // The adapter learns how to clear the frames before the first one, and again once they change size
+        assert_eq!(messages.len(), 4);
+        assert!(matches!(messages[0], EncodedVideoMessage::Clear(_)));
+        assert!(matches!(messages[1], EncodedVideoMessage::Clear(_)));
+        match messages[2] {
+            EncodedVideoMessage::EncodedVideoMessage(ref frame, _) => {
+                let escape = String::from_utf8(frame.data.clone()).unwrap();
+                assert!(escape.contains("s=4"));
+                assert!(escape.contains("v=2"));
+            }
+            _ => panic!("Expected an encoded frame"),
+        }
+        assert!(matches!(messages[3], EncodedVideoMessage::Done));
+    }
+
+    #[test]
+    fn test_sixel_frames_leave_the_last_row_free() {
+        let (_raw_tx, producer_rx) = mpsc::channel();
+        let (producer_tx, _encoded_rx) = mpsc::sync_channel(16);
+
+        let mut encoder = Encoder::new(producer_rx, producer_tx, None, None).unwrap();
+        (encoder.term_width, encoder.term_height, encoder.term_cols, encoder.term_rows) =
+            (800, 480, 80, 24);
+        (encoder.width, encoder.height) = (100, 1000);
+        assert_eq!(encoder.build_layout().rows, 24);
+
+        encoder.set_renderer(Box::new(SixelRenderer::new(false)));
+        assert_eq!(encoder.build_layout().rows, 23);
+
+        // Below a status bar the frames never reach the last row
+        encoder.video_rows = Some(20);
+        assert_eq!(encoder.build_layout().rows, 20);
+    }
+
+    #[test]
+    fn test_frames_before_a_seek_are_dropped() {
+        let (raw_tx, producer_rx) = mpsc::channel();
+        let (producer_tx, encoded_rx) = mpsc::sync_channel(16);
+
+        let seek_control = Arc::new(SeekControl::new());
+        seek_control.set_seekable(true);
+
+        let mut encoder = Encoder::new(producer_rx, producer_tx, None, None).unwrap();
+        encoder.set_seek_control(seek_control.clone());
+
+        let frame = |timestamp_in_ms| RawVideoMessage::VideoMessage {
+            frame: BytesWithTimestamp {
+                data: vec![0; 3],
+                timestamp_in_ms,
+            },
+            width: 1,
+            height: 1,
+        };
+
+        seek_control.seek_to(5000);
+
+        raw_tx.send(frame(0)).unwrap();
+        raw_tx.send(RawVideoMessage::Done).unwrap();
+        raw_tx.send(RawVideoMessage::Flush(1)).unwrap();
+        raw_tx.send(frame(5000)).unwrap();
+        raw_tx.send(RawVideoMessage::Done).unwrap();
+        // Otherwise the encoder waits for seeks until the last frame is shown
+        drop(raw_tx);
+
+        encoder.encode().unwrap();
+
+        let messages: Vec<EncodedVideoMessage> = encoded_rx
+            .try_iter()
+            .filter(|message| !matches!(message, EncodedVideoMessage::Clear(_)))
+            .collect();
+
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(messages[0], EncodedVideoMessage::Flush(1)));
+        assert!(matches!(
+            messages[1],
+            EncodedVideoMessage::EncodedVideoMessage(ref frame, _) if frame.timestamp_in_ms == 5000
+        ));
+        assert!(matches!(messages[2], EncodedVideoMessage::Done));
+    }
+
+    #[test]
+    fn test_seek_after_the_end_was_encoded() {
+        let (raw_tx, producer_rx) = mpsc::channel();
+        let (producer_tx, encoded_rx) = mpsc::sync_channel(16);
+
+        let seek_control = Arc::new(SeekControl::new());
+        seek_control.set_seekable(true);
+
+        let mut encoder = Encoder::new(producer_rx, producer_tx, None, None).unwrap();
+        encoder.set_seek_control(seek_control.clone());
+
+        let frame = |timestamp_in_ms| RawVideoMessage::VideoMessage {
+            frame: BytesWithTimestamp {
+                data: vec![0; 3],
+                timestamp_in_ms,
+            },
+            width: 1,
+            height: 1,
+        };
+
+        raw_tx.send(frame(9000)).unwrap();
+        raw_tx.send(RawVideoMessage::Done).unwrap();
+
+        let handle = thread::spawn(move || encoder.encode());
+
+        let next_message = || loop {
+            match encoded_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                EncodedVideoMessage::Clear(_) => continue,
+                message => return message,
+            }
+        };
+
+        assert!(matches!(next_message(), EncodedVideoMessage::EncodedVideoMessage(..)));
+        assert!(matches!(next_message(), EncodedVideoMessage::Done));
+
+        // The end went through, but the frames before it are still being shown
+        seek_control.seek_to(1000);
+        raw_tx.send(RawVideoMessage::Flush(1)).unwrap();
+        raw_tx.send(frame(1000)).unwrap();
+        raw_tx.send(RawVideoMessage::Done).unwrap();
+
+        assert!(matches!(next_message(), EncodedVideoMessage::Flush(1)));
+        assert!(matches!(
+            next_message(),
+            EncodedVideoMessage::EncodedVideoMessage(ref frame, _) if frame.timestamp_in_ms == 1000
+        ));
+        assert!(matches!(next_message(), EncodedVideoMessage::Done));
+        assert!(!handle.is_finished());
+
+        // Stops once the terminal adapter has shown the last frame
+        seek_control.set_finished();
+        handle.join().unwrap().unwrap();
     }
 
     #[test]
     fn test_get_terminal_size() {
         let (_streaming_done_tx, producer_rx) = mpsc::channel();
-        let (producer_tx, _encoding_done_rx) = mpsc::channel();
+        let (producer_tx, _encoding_done_rx) = mpsc::sync_channel(16);
 
         let encoder = Encoder::new(producer_rx, producer_tx, None, None).unwrap();
 
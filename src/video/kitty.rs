@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use base64::{engine::general_purpose, Engine as _};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::video::renderer::{FrameRenderer, FrameResource, Layout, RenderedFrame};
+use crate::video::transmission::{FrameFile, TransmissionMedium};
+
+/* Largest payload of a single escape, the graphics protocol splits bigger ones into chunks */
+const CHUNK_SIZE: usize = 4096;
+
+/*
+ * Every frame replaces the data of this image, and its one placement, in place.
+ * The id is out of the way of the small ones other programs tend to pick
+ */
+const IMAGE_ID: u32 = 30000;
+const PLACEMENT_ID: u32 = 1;
+
+/// Escape that deletes the image the frames were drawn with, and frees its data
+pub fn delete_image_escape() -> Vec<u8> {
+    format!("\x1b_Ga=d,d=I,i={},q=2\x1b\\", IMAGE_ID).into_bytes()
+}
+
+/// Draws the frames with the Kitty graphics protocol, which scales them itself
+pub struct KittyRenderer {
+    control_data: Vec<u8>,
+    /// zlib level of the payloads, which are sent uncompressed when None
+    compression: Option<Compression>,
+    medium: TransmissionMedium,
+}
+
+impl KittyRenderer {
+    pub fn new() -> Self {
+        KittyRenderer {
+            control_data: vec![],
+            compression: None,
+            medium: TransmissionMedium::Direct,
+        }
+    }
+
+    /// Compresses the payloads with zlib at this level, from 1 to 9, or sends them as they are with 0
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.compression = (level > 0).then(|| Compression::new(level.min(9)));
+    }
+
+    /// Sends the frames through this medium, which the terminal acknowledged
+    pub fn set_transmission_medium(&mut self, medium: TransmissionMedium) {
+        self.medium = medium;
+    }
+
+    // Build the Kitty control data for the layout
+    fn build_control_data(&self, layout: &Layout) -> Vec<u8> {
+        let mut control_data = HashMap::from([
+            ("f".into(), "24".into()),
+            ("s".into(), format!("{}", layout.frame_width)),
+            ("v".into(), format!("{}", layout.frame_height)),
+            ("c".into(), format!("{}", layout.cols)),
+            ("r".into(), format!("{}", layout.rows)),
+            ("a".into(), "T".into()),
+            ("i".into(), format!("{}", IMAGE_ID)),
+            ("p".into(), format!("{}", PLACEMENT_ID)),
+            // The terminal answers commands with an id, which would come back as key presses
+            ("q".into(), "2".into()),
+            ("X".into(), format!("{}", layout.x_offset)),
+            ("Y".into(), format!("{}", layout.y_offset)),
+        ]);
+
+        if self.compression.is_some() {
+            control_data.insert("o".into(), "z".into());
+        }
+
+        self.encode_control_data(control_data)
+    }
+
+    fn encode_control_data(&self, control_data: HashMap<String, String>) -> Vec<u8> {
+        let mut encoded_data = Vec::new();
+        for (key, value) in control_data {
+            encoded_data.push(format!("{}={}", key, value));
+        }
+
+        encoded_data.join(",").as_bytes().to_vec()
+    }
+
+    /* The pixels as the terminal reads them, compressed when asked to */
+    fn pixel_data(&self, rgb: Vec<u8>) -> Vec<u8> {
+        match self.compression {
+            Some(compression) => Self::compress(&rgb, compression),
+            None => rgb,
+        }
+    }
+
+    fn encode_rgb(&self, data: &[u8]) -> Vec<u8> {
+        let encoded = general_purpose::STANDARD.encode(data);
+        encoded.as_bytes().to_vec()
+    }
+
+    fn compress(rgb: &[u8], compression: Compression) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::with_capacity(rgb.len() / 4), compression);
+
+        // Writing to a Vec can't fail
+        encoder.write_all(rgb).unwrap();
+        encoder.finish().unwrap()
+    }
+}
+
+impl Default for KittyRenderer {
+    fn default() -> Self {
+        KittyRenderer::new()
+    }
+}
+
+/* The terminal removes the file once it has read it, from the escape naming it */
+impl FrameResource for FrameFile {
+    fn mark_written(self: Box<Self>) {
+        FrameFile::mark_written(*self);
+    }
+}
+
+impl FrameRenderer for KittyRenderer {
+    fn set_layout(&mut self, layout: Layout) {
+        self.control_data = self.build_control_data(&layout);
+    }
+
+    // Convert a frame to the Kitty Graphics Protocol format
+    fn render(&mut self, rgb: Vec<u8>) -> RenderedFrame {
+        let pixel_data = self.pixel_data(rgb);
+        let prefix = b"\x1b_G";
+        let suffix = b"\x1b\\";
+        let delimiter = b";";
+        let mut buffer = vec![];
+
+        // A frame that can't be written to a file goes through the pty instead
+        let frame_file = match self.medium {
+            TransmissionMedium::Direct => None,
+            medium => FrameFile::create(medium, &pixel_data).ok(),
+        };
+
+        match frame_file {
+            Some(ref frame_file) => {
+                buffer.extend_from_slice(prefix);
+                buffer.extend_from_slice(&self.control_data);
+                buffer.extend_from_slice(
+                    format!(",t={},S={}", self.medium.key(), pixel_data.len()).as_bytes(),
+                );
+                buffer.extend_from_slice(delimiter);
+                buffer.extend_from_slice(&frame_file.encoded_name());
+                buffer.extend_from_slice(suffix);
+            }
+            None => {
+                // Base64 encode the frame data
+                let encoded_payload = self.encode_rgb(&pixel_data);
+
+                // The control data goes with the first chunk, the others only say whether more follow
+                let chunk_count = encoded_payload.len().div_ceil(CHUNK_SIZE).max(1);
+                for index in 0..chunk_count {
+                    let start = index * CHUNK_SIZE;
+                    let end = (start + CHUNK_SIZE).min(encoded_payload.len());
+                    let more = if index + 1 < chunk_count {
+                        b"m=1"
+                    } else {
+                        b"m=0"
+                    };
+
+                    buffer.extend_from_slice(prefix);
+                    if index == 0 {
+                        buffer.extend_from_slice(&self.control_data);
+                        buffer.extend_from_slice(b",t=d,");
+                    }
+                    buffer.extend_from_slice(more);
+                    buffer.extend_from_slice(delimiter);
+                    buffer.extend_from_slice(&encoded_payload[start..end]);
+                    buffer.extend_from_slice(suffix);
+                }
+            }
+        }
+
+        RenderedFrame {
+            escapes: buffer,
+            resource: frame_file.map(|frame_file| Box::new(frame_file) as Box<dyn FrameResource>),
+        }
+    }
+
+    fn clear_escape(&self) -> Vec<u8> {
+        delete_image_escape()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn layout(frame_width: usize, frame_height: usize) -> Layout {
+        Layout {
+            frame_width,
+            frame_height,
+            cols: 80,
+            rows: 24,
+            x_offset: 0,
+            y_offset: 0,
+            cell_width: 16.0,
+            cell_height: 30.0,
+        }
+    }
+
+    #[test]
+    fn test_encode_control_data() {
+        let renderer = KittyRenderer::new();
+
+        let control_data = HashMap::from([
+            ("f".into(), "24".into()),
+            ("s".into(), "640".into()),
+            ("v".into(), "360".into()),
+        ]);
+
+        let encoded_data = renderer.encode_control_data(control_data);
+        assert!(String::from_utf8(encoded_data.clone()).is_ok());
+        assert!(String::from_utf8(encoded_data.clone())
+            .unwrap()
+            .contains("f=24"));
+        assert!(String::from_utf8(encoded_data.clone())
+            .unwrap()
+            .contains("s=640"));
+        assert!(String::from_utf8(encoded_data.clone())
+            .unwrap()
+            .contains("v=360"));
+    }
+
+    /*
+     * Reads the escapes of a frame back, checking that they follow the chunking rules,
+     * and returns the control data of the first one with the decoded payload
+     */
+    fn parse_chunked_escapes(data: &[u8]) -> (String, Vec<u8>) {
+        let text = String::from_utf8(data.to_vec()).unwrap();
+        let escapes: Vec<&str> = text
+            .strip_suffix("\x1b\\")
+            .unwrap()
+            .split("\x1b\\")
+            .map(|escape| escape.strip_prefix("\x1b_G").unwrap())
+            .collect();
+
+        let mut control_data = String::new();
+        let mut payload = String::new();
+
+        for (index, escape) in escapes.iter().enumerate() {
+            let (control, chunk) = escape.split_once(';').unwrap();
+            let keys: Vec<&str> = control.split(',').collect();
+            let last = index + 1 == escapes.len();
+
+            assert!(chunk.len() <= CHUNK_SIZE);
+            assert!(last || chunk.len() % 4 == 0);
+            assert_eq!(keys.last(), Some(&if last { "m=0" } else { "m=1" }));
+
+            if index == 0 {
+                control_data = keys[..keys.len() - 1].join(",");
+            } else {
+                assert_eq!(keys.len(), 1);
+            }
+
+            payload.push_str(chunk);
+        }
+
+        (
+            control_data,
+            general_purpose::STANDARD.decode(payload).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_frames_are_sent_in_chunks() {
+        let mut renderer = KittyRenderer::new();
+        renderer.set_layout(layout(640, 360));
+
+        for size in [0, 3, 3072, 3073, 640 * 360 * 3] {
+            let rgb: Vec<u8> = (0..size).map(|index| (index % 251) as u8).collect();
+
+            let frame = renderer.render(rgb.clone());
+            let (parsed_control_data, payload) = parse_chunked_escapes(&frame.escapes);
+
+            assert!(frame.resource.is_none());
+            assert_eq!(
+                parsed_control_data,
+                format!(
+                    "{},t=d",
+                    String::from_utf8(renderer.control_data.clone()).unwrap()
+                )
+            );
+            assert_eq!(payload, rgb);
+        }
+    }
+
+    /* A frame that compresses about as well as video does: smooth gradients, with some noise */
+    fn synthetic_frame(width: usize, height: usize, seed: usize) -> Vec<u8> {
+        let mut noise = seed as u32 | 1;
+        (0..width * height)
+            .flat_map(|index| {
+                noise ^= noise << 13;
+                noise ^= noise >> 17;
+                noise ^= noise << 5;
+                let (x, y) = (index % width, index / width);
+                // Sparse noise, as in the flat areas of a decoded frame
+                let jitter = if noise.is_multiple_of(4) {
+                    (noise >> 8) as usize % 6
+                } else {
+                    0
+                };
+                [
+                    ((x * 255 / width + jitter + seed) % 256) as u8,
+                    ((y * 255 / height + jitter) % 256) as u8,
+                    (((x + y) * 128 / (width + height) + jitter) % 256) as u8,
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compressed_frames() {
+        let mut renderer = KittyRenderer::new();
+        renderer.set_compression_level(6);
+        renderer.set_layout(layout(64, 36));
+        assert!(String::from_utf8(renderer.control_data.clone())
+            .unwrap()
+            .contains("o=z"));
+
+        let rgb = synthetic_frame(64, 36, 1);
+
+        let escapes = renderer.render(rgb.clone()).escapes;
+        let (_, payload) = parse_chunked_escapes(&escapes);
+        assert!(payload.len() < rgb.len());
+
+        let mut decompressed = vec![];
+        flate2::read::ZlibDecoder::new(payload.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, rgb);
+
+        renderer.set_compression_level(0);
+        renderer.set_layout(layout(64, 36));
+        assert!(!String::from_utf8(renderer.control_data.clone())
+            .unwrap()
+            .contains("o="));
+    }
+
+    #[test]
+    fn test_frames_sent_through_temp_files() {
+        let mut renderer = KittyRenderer::new();
+        renderer.set_transmission_medium(TransmissionMedium::TempFile);
+        renderer.set_layout(layout(16, 9));
+
+        let rgb = synthetic_frame(16, 9, 2);
+
+        let frame = renderer.render(rgb.clone());
+        let escape = String::from_utf8(frame.escapes).unwrap();
+        let (control, payload) = escape
+            .strip_prefix("\x1b_G")
+            .and_then(|escape| escape.strip_suffix("\x1b\\"))
+            .and_then(|escape| escape.split_once(';'))
+            .unwrap();
+
+        assert!(control.ends_with(&format!(",t=t,S={}", rgb.len())));
+        let path = String::from_utf8(general_purpose::STANDARD.decode(payload).unwrap()).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), rgb);
+
+        // Removed when the frame is skipped instead of written out
+        drop(frame.resource);
+        assert!(std::fs::metadata(&path).is_err());
+    }
+
+    #[test]
+    fn test_frames_replace_the_same_image() {
+        let mut renderer = KittyRenderer::new();
+        renderer.set_layout(layout(4, 2));
+
+        for _ in 0..2 {
+            let escapes = renderer.render(vec![0; 4 * 2 * 3]).escapes;
+            let escape = String::from_utf8(escapes).unwrap();
+            assert!(escape.contains(&format!("i={}", IMAGE_ID)));
+            assert!(escape.contains(&format!("p={}", PLACEMENT_ID)));
+            assert!(escape.contains("q=2"));
+        }
+
+        let delete = String::from_utf8(renderer.clear_escape()).unwrap();
+        assert!(delete.contains(&format!("a=d,d=I,i={}", IMAGE_ID)));
+    }
+
+    /*
+     * Compares how fast raw and compressed frames are encoded, and how many bytes
+     * go to the terminal. Run with `cargo test --release -- --ignored --nocapture`
+     */
+    #[test]
+    #[ignore]
+    fn bench_compression_throughput() {
+        let (width, height) = (640, 360);
+        let frames: Vec<Vec<u8>> = (0..30)
+            .map(|seed| synthetic_frame(width, height, seed))
+            .collect();
+
+        let mut renderer = KittyRenderer::new();
+
+        for level in [0, 1, 3, 6, 9] {
+            renderer.set_compression_level(level);
+            renderer.set_layout(layout(width, height));
+
+            let started_at = std::time::Instant::now();
+            let sent_bytes: usize = frames
+                .iter()
+                .map(|rgb| renderer.render(rgb.clone()).escapes.len())
+                .sum();
+            let elapsed = started_at.elapsed();
+
+            println!(
+                "level {}: {:.1} fps, {:.1} KB per frame, {:.1} MB/s at 30 fps",
+                level,
+                frames.len() as f64 / elapsed.as_secs_f64(),
+                sent_bytes as f64 / frames.len() as f64 / 1024.0,
+                sent_bytes as f64 / frames.len() as f64 * 30.0 / 1024.0 / 1024.0,
+            );
+        }
+    }
+}
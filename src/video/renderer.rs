@@ -0,0 +1,82 @@
+use std::str::FromStr;
+
+/// Where a frame is drawn and how big, worked out by the encoder from the
+/// terminal size and the frame size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Layout {
+    /// Size of the decoded frames, in pixels
+    pub frame_width: usize,
+    pub frame_height: usize,
+    /// Cells the frame is scaled to, keeping its aspect ratio
+    pub cols: u16,
+    pub rows: u16,
+    /// Offset of the frame from the top left corner, in pixels
+    pub x_offset: usize,
+    pub y_offset: usize,
+    /// Size of a cell, in pixels
+    pub cell_width: f64,
+    pub cell_height: f64,
+}
+
+/// A frame as a renderer draws it
+pub struct RenderedFrame {
+    pub escapes: Vec<u8>,
+    /// What the escapes refer to, released when dropped before they are written
+    pub resource: Option<Box<dyn FrameResource>>,
+}
+
+impl RenderedFrame {
+    /// A frame drawn by its escapes alone
+    pub fn new(escapes: Vec<u8>) -> Self {
+        RenderedFrame {
+            escapes,
+            resource: None,
+        }
+    }
+}
+
+/// Something the escapes of a frame refer to, like a file the terminal reads the pixels from
+pub trait FrameResource: Send {
+    /// Called once the escapes reached the terminal, which takes the resource over
+    fn mark_written(self: Box<Self>);
+}
+
+/// Turns decoded frames into the escapes that draw them, for one graphics protocol
+pub trait FrameRenderer: Send {
+    /// Called before the first frame, and whenever the layout changes
+    fn set_layout(&mut self, layout: Layout);
+
+    /// Escapes drawing a frame of packed RGB
+    fn render(&mut self, rgb: Vec<u8>) -> RenderedFrame;
+
+    /// Escape removing what the frames drew, for the current layout
+    fn clear_escape(&self) -> Vec<u8>;
+
+    /// Whether a frame reaching the last row of the terminal scrolls it, in
+    /// which case the layout leaves that row free
+    fn scrolls_on_last_row(&self) -> bool {
+        false
+    }
+}
+
+/// Graphics protocol the frames are drawn with, picked with --renderer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RendererKind {
+    Kitty,
+    Sixel,
+}
+
+impl FromStr for RendererKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "kitty" => Ok(RendererKind::Kitty),
+            "sixel" => Ok(RendererKind::Sixel),
+            _ => Err(format!(
+                "unknown renderer '{}', expected kitty or sixel",
+                value
+            )),
+        }
+    }
+}
@@ -0,0 +1,482 @@
+use std::mem;
+use std::ops::Range;
+
+use crate::video::renderer::{FrameRenderer, Layout, RenderedFrame};
+
+/* Color registers of a sixel image in most terminals */
+const PALETTE_SIZE: usize = 256;
+
+/* Pixels the palette is picked from, spread over the frame */
+const PALETTE_SAMPLES: usize = 16384;
+
+/* Colors are looked up in the palette by their top 5 bits per channel */
+const LOOKUP_SIZE: usize = 1 << 15;
+
+/// Draws the frames as sixel images, for terminals without the Kitty graphics
+/// protocol. Sixel has no scaling, so the frames are scaled to the cell area here,
+/// and its palette is picked for each frame with median cut.
+pub struct SixelRenderer {
+    /// Spreads the quantization error over the neighbouring pixels (Floyd-Steinberg)
+    dither: bool,
+    layout: Option<Layout>,
+    /// Size of the image drawn, in pixels
+    width: usize,
+    height: usize,
+    /// Cell the image starts in, from 1
+    row: usize,
+    col: usize,
+}
+
+impl SixelRenderer {
+    pub fn new(dither: bool) -> Self {
+        SixelRenderer {
+            dither,
+            layout: None,
+            width: 0,
+            height: 0,
+            row: 1,
+            col: 1,
+        }
+    }
+
+    /* Nearest neighbour scaling of the frame to the size of the image */
+    fn scale(&self, rgb: &[u8], layout: &Layout) -> Vec<[u8; 3]> {
+        let source_columns: Vec<usize> = (0..self.width)
+            .map(|x| x * layout.frame_width / self.width)
+            .collect();
+
+        (0..self.height)
+            .flat_map(|y| {
+                let source_row = y * layout.frame_height / self.height * layout.frame_width;
+                source_columns.iter().map(move |x| {
+                    let index = (source_row + x) * 3;
+                    [rgb[index], rgb[index + 1], rgb[index + 2]]
+                })
+            })
+            .collect()
+    }
+}
+
+impl FrameRenderer for SixelRenderer {
+    fn set_layout(&mut self, layout: Layout) {
+        self.width = ((layout.cols as f64 * layout.cell_width).round() as usize).max(1);
+        self.height = ((layout.rows as f64 * layout.cell_height).round() as usize).max(1);
+        self.row = (layout.y_offset as f64 / layout.cell_height).round() as usize + 1;
+        self.col = (layout.x_offset as f64 / layout.cell_width).round() as usize + 1;
+        self.layout = Some(layout);
+    }
+
+    fn render(&mut self, rgb: Vec<u8>) -> RenderedFrame {
+        let Some(layout) = self.layout else {
+            return RenderedFrame::new(vec![]);
+        };
+
+        // A frame that doesn't match the layout would be read out of bounds
+        if layout.frame_width == 0
+            || layout.frame_height == 0
+            || rgb.len() < layout.frame_width * layout.frame_height * 3
+        {
+            return RenderedFrame::new(vec![]);
+        }
+
+        let pixels = self.scale(&rgb, &layout);
+        let palette = median_cut(&pixels, PALETTE_SIZE);
+        let indices = quantize(&pixels, self.width, &palette, self.dither);
+
+        let mut buffer = format!("\x1b[{};{}H", self.row, self.col).into_bytes();
+        buffer.extend_from_slice(&encode_sixel(&indices, self.width, self.height, &palette));
+
+        RenderedFrame::new(buffer)
+    }
+
+    /* Erasing the cells also removes the sixel pixels drawn over them */
+    fn clear_escape(&self) -> Vec<u8> {
+        let Some(layout) = self.layout else {
+            return vec![];
+        };
+
+        (0..layout.rows as usize)
+            .flat_map(|row| {
+                format!("\x1b[{};{}H\x1b[{}X", self.row + row, self.col, layout.cols).into_bytes()
+            })
+            .collect()
+    }
+
+    /* The cursor ends up below the image, which scrolls the screen from the last row */
+    fn scrolls_on_last_row(&self) -> bool {
+        true
+    }
+}
+
+/* A box of median cut: samples, the channel they spread the most along, and how far */
+struct ColorBox {
+    samples: Range<usize>,
+    channel: usize,
+    extent: u8,
+}
+
+impl ColorBox {
+    fn new(samples: &[[u8; 3]], range: Range<usize>) -> Self {
+        let (channel, extent) = (0..3)
+            .map(|channel| {
+                let (min, max) = samples[range.clone()]
+                    .iter()
+                    .fold((u8::MAX, u8::MIN), |(min, max), sample| {
+                        (min.min(sample[channel]), max.max(sample[channel]))
+                    });
+                (channel, max.saturating_sub(min))
+            })
+            .max_by_key(|(_, extent)| *extent)
+            .unwrap();
+
+        ColorBox {
+            samples: range,
+            channel,
+            extent,
+        }
+    }
+}
+
+/*
+ * Picks a palette of at most `size` colors, by splitting the box of colors
+ * that spreads the most at its median until there are enough of them
+ */
+fn median_cut(pixels: &[[u8; 3]], size: usize) -> Vec<[u8; 3]> {
+    let step = (pixels.len() / PALETTE_SAMPLES).max(1);
+    let mut samples: Vec<[u8; 3]> = pixels.iter().step_by(step).copied().collect();
+
+    if samples.is_empty() {
+        return vec![[0, 0, 0]];
+    }
+
+    let mut boxes = vec![ColorBox::new(&samples, 0..samples.len())];
+
+    while boxes.len() < size {
+        let Some((index, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, color_box)| color_box.extent > 0)
+            .max_by_key(|(_, color_box)| color_box.extent)
+        else {
+            break;
+        };
+
+        let color_box = boxes.swap_remove(index);
+        let range = color_box.samples;
+        let channel = color_box.channel;
+        samples[range.clone()].sort_unstable_by_key(|sample| sample[channel]);
+
+        let middle = range.start + range.len() / 2;
+        boxes.push(ColorBox::new(&samples, range.start..middle));
+        boxes.push(ColorBox::new(&samples, middle..range.end));
+    }
+
+    boxes
+        .iter()
+        .map(|color_box| {
+            let samples = &samples[color_box.samples.clone()];
+            let mut sums = [0usize; 3];
+            for sample in samples {
+                for channel in 0..3 {
+                    sums[channel] += sample[channel] as usize;
+                }
+            }
+            sums.map(|sum| (sum / samples.len()) as u8)
+        })
+        .collect()
+}
+
+fn nearest_color(palette: &[[u8; 3]], color: [i32; 3]) -> usize {
+    palette
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, entry)| {
+            (0..3)
+                .map(|channel| {
+                    let difference = entry[channel] as i32 - color[channel];
+                    difference * difference
+                })
+                .sum::<i32>()
+        })
+        .map_or(0, |(index, _)| index)
+}
+
+/* Index in the palette of every pixel, with the error carried over to the next ones when dithering */
+fn quantize(pixels: &[[u8; 3]], width: usize, palette: &[[u8; 3]], dither: bool) -> Vec<u8> {
+    let mut lookup = vec![u16::MAX; LOOKUP_SIZE];
+    let mut find = |color: [i32; 3]| {
+        let key = ((color[0] as usize >> 3) << 10)
+            | ((color[1] as usize >> 3) << 5)
+            | (color[2] as usize >> 3);
+        if lookup[key] == u16::MAX {
+            lookup[key] = nearest_color(palette, color) as u16;
+        }
+        lookup[key] as usize
+    };
+
+    // Errors for the current and the next row, with a pixel of margin on both sides
+    let mut errors = vec![[0i32; 3]; width + 2];
+    let mut next_errors = vec![[0i32; 3]; width + 2];
+    let mut indices = Vec::with_capacity(pixels.len());
+
+    for row in pixels.chunks(width) {
+        for (x, pixel) in row.iter().enumerate() {
+            if !dither {
+                indices.push(find(pixel.map(|value| value as i32)) as u8);
+                continue;
+            }
+
+            let mut color = [0; 3];
+            for channel in 0..3 {
+                color[channel] = (pixel[channel] as i32 + errors[x + 1][channel]).clamp(0, 255);
+            }
+
+            let index = find(color);
+            indices.push(index as u8);
+
+            for channel in 0..3 {
+                let error = color[channel] - palette[index][channel] as i32;
+                errors[x + 2][channel] += error * 7 / 16;
+                next_errors[x][channel] += error * 3 / 16;
+                next_errors[x + 1][channel] += error * 5 / 16;
+                next_errors[x + 2][channel] += error / 16;
+            }
+        }
+
+        mem::swap(&mut errors, &mut next_errors);
+        next_errors.fill([0; 3]);
+    }
+
+    indices
+}
+
+/*
+ * The sixel image, a device control string: the palette in percent, then
+ * bands of 6 rows, each drawn once per color it uses. Every pixel gets a
+ * color, so the background is left as it is (P2 = 1).
+ */
+fn encode_sixel(indices: &[u8], width: usize, height: usize, palette: &[[u8; 3]]) -> Vec<u8> {
+    let mut buffer = format!("\x1bP0;1;0q\"1;1;{};{}", width, height).into_bytes();
+
+    for (index, color) in palette.iter().enumerate() {
+        let [red, green, blue] = color.map(|value| (value as u32 * 100 + 127) / 255);
+        buffer.extend_from_slice(format!("#{};2;{};{};{}", index, red, green, blue).as_bytes());
+    }
+
+    // Bits of every color in the band, one byte per column
+    let mut masks = vec![0u8; palette.len() * width];
+    let mut last_column = vec![0usize; palette.len()];
+    let mut used = vec![false; palette.len()];
+    let mut colors = Vec::with_capacity(palette.len());
+
+    for band_top in (0..height).step_by(6) {
+        // Down to the next band; none after the last, which would move the cursor past it
+        if band_top > 0 {
+            buffer.push(b'-');
+        }
+
+        for y in 0..6.min(height - band_top) {
+            let row = &indices[(band_top + y) * width..(band_top + y + 1) * width];
+            for (x, &index) in row.iter().enumerate() {
+                let index = index as usize;
+                if !used[index] {
+                    used[index] = true;
+                    colors.push(index);
+                }
+                masks[index * width + x] |= 1 << y;
+                last_column[index] = last_column[index].max(x);
+            }
+        }
+
+        for (position, &index) in colors.iter().enumerate() {
+            // Back to the start of the band for every color after the first
+            if position > 0 {
+                buffer.push(b'$');
+            }
+            buffer.extend_from_slice(format!("#{}", index).as_bytes());
+
+            let row = &mut masks[index * width..index * width + last_column[index] + 1];
+            push_runs(&mut buffer, row);
+            row.fill(0);
+
+            used[index] = false;
+            last_column[index] = 0;
+        }
+        colors.clear();
+    }
+
+    buffer.extend_from_slice(b"\x1b\\");
+    buffer
+}
+
+/* Sixel characters of a row of masks, with runs of more than 3 repeated as !count */
+fn push_runs(buffer: &mut Vec<u8>, masks: &[u8]) {
+    let mut start = 0;
+
+    while start < masks.len() {
+        let mask = masks[start];
+        let length = masks[start..]
+            .iter()
+            .take_while(|&&other| other == mask)
+            .count();
+        let character = b'?' + mask;
+
+        if length > 3 {
+            buffer.extend_from_slice(format!("!{}", length).as_bytes());
+            buffer.push(character);
+        } else {
+            buffer.extend(std::iter::repeat_n(character, length));
+        }
+
+        start += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter::Peekable;
+    use std::str::Chars;
+
+    fn number(characters: &mut Peekable<Chars>) -> usize {
+        let mut digits = String::new();
+        while let Some(digit) = characters.next_if(|c| c.is_ascii_digit()) {
+            digits.push(digit);
+        }
+        digits.parse().unwrap()
+    }
+
+    /* Reads a sixel image back into the palette index of every pixel */
+    fn decode_sixel(data: &[u8], width: usize, height: usize) -> Vec<u8> {
+        let text = String::from_utf8(data.to_vec()).unwrap();
+        let body = text
+            .strip_prefix(&format!("\x1bP0;1;0q\"1;1;{};{}", width, height))
+            .and_then(|body| body.strip_suffix("\x1b\\"))
+            .unwrap();
+
+        let mut pixels = vec![u8::MAX; width * height];
+        let (mut x, mut band_top, mut color) = (0, 0, 0);
+        let mut characters = body.chars().peekable();
+
+        while let Some(character) = characters.next() {
+            match character {
+                '#' => {
+                    color = number(&mut characters);
+                    // A color definition rather than a selection
+                    while characters.next_if_eq(&';').is_some() {
+                        number(&mut characters);
+                    }
+                }
+                '$' => x = 0,
+                '-' => {
+                    x = 0;
+                    band_top += 6;
+                }
+                '!' | '?'..='~' => {
+                    let count = if character == '!' {
+                        number(&mut characters)
+                    } else {
+                        1
+                    };
+                    let sixel = if character == '!' {
+                        characters.next().unwrap()
+                    } else {
+                        character
+                    };
+                    let bits = sixel as u8 - b'?';
+
+                    for _ in 0..count {
+                        for y in 0..6 {
+                            if bits & (1 << y) != 0 && band_top + y < height {
+                                pixels[(band_top + y) * width + x] = color as u8;
+                            }
+                        }
+                        x += 1;
+                    }
+                }
+                _ => panic!("Unexpected {:?}", character),
+            }
+        }
+
+        pixels
+    }
+
+    #[test]
+    fn test_few_colors_are_kept_as_they_are() {
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [40, 40, 40]];
+        let pixels: Vec<[u8; 3]> = (0..100).map(|index| colors[index % 4]).collect();
+
+        let mut palette = median_cut(&pixels, PALETTE_SIZE);
+        palette.sort();
+        let mut expected = colors.to_vec();
+        expected.sort();
+        assert_eq!(palette, expected);
+
+        // Split down to two colors, each the average of the ones it stands for
+        assert_eq!(median_cut(&pixels, 2).len(), 2);
+    }
+
+    #[test]
+    fn test_sixel_image_round_trip() {
+        let (width, height) = (13, 8);
+        let palette = [[0, 0, 0], [255, 255, 255], [255, 0, 0]];
+        let indices: Vec<u8> = (0..width * height)
+            .map(|index| ((index / 5 + index % 3) % 3) as u8)
+            .collect();
+
+        let data = encode_sixel(&indices, width, height, &palette);
+        assert_eq!(decode_sixel(&data, width, height), indices);
+
+        // A graphics newline between the two bands, none after the last
+        assert_eq!(data.iter().filter(|&&byte| byte == b'-').count(), 1);
+        assert!(data.ends_with(b"\x1b\\") && !data.ends_with(b"-\x1b\\"));
+    }
+
+    #[test]
+    fn test_dithering_mixes_colors() {
+        let pixels = vec![[128, 128, 128]; 16 * 16];
+        let palette = [[0, 0, 0], [255, 255, 255]];
+
+        let flat = quantize(&pixels, 16, &palette, false);
+        assert!(flat.iter().all(|&index| index == flat[0]));
+
+        let dithered = quantize(&pixels, 16, &palette, true);
+        let white = dithered.iter().filter(|&&index| index == 1).count();
+        assert!((100..156).contains(&white), "{}", white);
+    }
+
+    #[test]
+    fn test_frames_are_scaled_to_the_cell_area() {
+        let mut renderer = SixelRenderer::new(false);
+        renderer.set_layout(Layout {
+            frame_width: 2,
+            frame_height: 1,
+            cols: 4,
+            rows: 1,
+            x_offset: 20,
+            y_offset: 0,
+            cell_width: 10.0,
+            cell_height: 12.0,
+        });
+
+        let frame = renderer.render(vec![255, 0, 0, 0, 0, 255]);
+        assert!(frame.resource.is_none());
+
+        let escapes = frame.escapes.strip_prefix(b"\x1b[1;3H".as_slice()).unwrap();
+        let pixels = decode_sixel(escapes, 40, 12);
+        let palette = median_cut(
+            &renderer.scale(&[255, 0, 0, 0, 0, 255], &renderer.layout.unwrap()),
+            PALETTE_SIZE,
+        );
+
+        // The left half is red and the right half blue
+        for row in pixels.chunks(40) {
+            assert_eq!(palette[row[0] as usize], [255, 0, 0]);
+            assert!(row[..20].iter().all(|&index| index == row[0]));
+            assert_eq!(palette[row[39] as usize], [0, 0, 255]);
+            assert!(row[20..].iter().all(|&index| index == row[39]));
+        }
+
+        assert_eq!(renderer.clear_escape(), b"\x1b[1;3H\x1b[4X".to_vec());
+    }
+}
@@ -0,0 +1,423 @@
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose, Engine as _};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, is_raw_mode_enabled};
+
+/* Image ids of the probes, out of the way of the ids the terminal assigns */
+const PROBE_ID_BASE: u32 = 31000;
+
+/* How long the terminal has to answer the probes */
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/* Kitty only reads temp files with this in their path */
+const TEMP_FILE_MARKER: &str = "tty-graphics-protocol";
+
+/* Frame files created so far by this process, which number their names */
+static FRAME_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// How the frames reach the terminal, the `t` key of the graphics protocol
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransmissionMedium {
+    /// Base64 in the escapes, through the pty
+    Direct,
+    /// A POSIX shared memory object, named in the escape
+    SharedMemory,
+    /// A file in the temp directory, named in the escape
+    TempFile,
+}
+
+impl TransmissionMedium {
+    pub fn key(&self) -> &'static str {
+        match self {
+            TransmissionMedium::Direct => "d",
+            TransmissionMedium::SharedMemory => "s",
+            TransmissionMedium::TempFile => "t",
+        }
+    }
+}
+
+/// Which mediums to try, picked with --transmission
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransmissionChoice {
+    /// Shared memory, then temp files, then direct
+    Auto,
+    Direct,
+    SharedMemory,
+    TempFile,
+}
+
+impl FromStr for TransmissionChoice {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(TransmissionChoice::Auto),
+            "direct" => Ok(TransmissionChoice::Direct),
+            "shm" => Ok(TransmissionChoice::SharedMemory),
+            "file" => Ok(TransmissionChoice::TempFile),
+            _ => Err(format!(
+                "unknown transmission '{}', expected auto, direct, shm or file",
+                value
+            )),
+        }
+    }
+}
+
+impl TransmissionChoice {
+    /*
+     * Asks the terminal which of the chosen mediums it can read frames from,
+     * falling back to direct transmission when it acknowledges none of them
+     */
+    pub fn detect(&self) -> TransmissionMedium {
+        let candidates = match self {
+            TransmissionChoice::Direct => return TransmissionMedium::Direct,
+            TransmissionChoice::Auto => {
+                vec![
+                    TransmissionMedium::SharedMemory,
+                    TransmissionMedium::TempFile,
+                ]
+            }
+            TransmissionChoice::SharedMemory => vec![TransmissionMedium::SharedMemory],
+            TransmissionChoice::TempFile => vec![TransmissionMedium::TempFile],
+        };
+
+        // The answers come back on stdin, which only a terminal can give
+        let is_terminal = unsafe {
+            libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1
+        };
+
+        if !is_terminal {
+            return TransmissionMedium::Direct;
+        }
+
+        probe(&candidates).unwrap_or(TransmissionMedium::Direct)
+    }
+}
+
+/// Shared memory object or temp file holding a frame for the terminal to read.
+///
+/// The terminal removes it once read, so it is only removed here if it is
+/// dropped before its escape was written out, e.g. for a frame skipped after a seek.
+pub struct FrameFile {
+    medium: TransmissionMedium,
+    name: String,
+    written: bool,
+}
+
+impl FrameFile {
+    /// Creates the frame file for the medium, with a name unique to this process
+    pub fn create(medium: TransmissionMedium, data: &[u8]) -> io::Result<Self> {
+        let index = FRAME_FILE_COUNT.fetch_add(1, Ordering::SeqCst);
+        let frame_file = match medium {
+            TransmissionMedium::SharedMemory => {
+                let name = format!("/yt-term-{}-{}", process::id(), index);
+                write_shared_memory(&name, data)?;
+                FrameFile {
+                    medium,
+                    name,
+                    written: false,
+                }
+            }
+            TransmissionMedium::TempFile => {
+                let path: PathBuf = std::env::temp_dir().join(format!(
+                    "{}-yt-term-{}-{}",
+                    TEMP_FILE_MARKER,
+                    process::id(),
+                    index
+                ));
+                write_temp_file(&path, data)?;
+                FrameFile {
+                    medium,
+                    name: path.to_string_lossy().into_owned(),
+                    written: false,
+                }
+            }
+            TransmissionMedium::Direct => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "direct transmission has no frame file",
+                ))
+            }
+        };
+
+        Ok(frame_file)
+    }
+
+    /// Payload of the escape, the base64 encoded name
+    pub fn encoded_name(&self) -> Vec<u8> {
+        general_purpose::STANDARD
+            .encode(self.name.as_bytes())
+            .into_bytes()
+    }
+
+    /// Leaves the file to the terminal, once its escape has been written out
+    pub fn mark_written(mut self) {
+        self.written = true;
+    }
+}
+
+impl Drop for FrameFile {
+    fn drop(&mut self) {
+        if self.written {
+            return;
+        }
+
+        match self.medium {
+            TransmissionMedium::SharedMemory => {
+                if let Ok(name) = CString::new(self.name.as_str()) {
+                    unsafe {
+                        libc::shm_unlink(name.as_ptr());
+                    }
+                }
+            }
+            TransmissionMedium::TempFile => {
+                let _ = fs::remove_file(&self.name);
+            }
+            TransmissionMedium::Direct => {}
+        }
+    }
+}
+
+fn write_shared_memory(name: &str, data: &[u8]) -> io::Result<()> {
+    let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    unsafe {
+        let fd = libc::shm_open(
+            c_name.as_ptr(),
+            libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+            0o600 as libc::c_uint,
+        );
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Written through a mapping, since not every system can write to shared memory directly
+        let result = if libc::ftruncate(fd, data.len().max(1) as libc::off_t) == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            let mapping = libc::mmap(
+                std::ptr::null_mut(),
+                data.len().max(1),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+
+            if mapping == libc::MAP_FAILED {
+                Err(io::Error::last_os_error())
+            } else {
+                std::ptr::copy_nonoverlapping(data.as_ptr(), mapping as *mut u8, data.len());
+                libc::munmap(mapping, data.len().max(1));
+                Ok(())
+            }
+        };
+
+        libc::close(fd);
+        if result.is_err() {
+            libc::shm_unlink(c_name.as_ptr());
+        }
+
+        result
+    }
+}
+
+/*
+ * Creates the file only if nothing is at the path yet, readable by this user alone,
+ * so that a file or symlink another user put in the shared temp directory is never written to
+ */
+fn write_temp_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+
+    let result = file.write_all(data);
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+
+    result
+}
+
+/*
+ * Sends a query for a one pixel image through each medium, followed by a request
+ * for the device attributes, which every terminal answers. Queries the terminal
+ * answers before it are the ones it understood.
+ */
+fn probe(candidates: &[TransmissionMedium]) -> Option<TransmissionMedium> {
+    let pixel = [0u8; 3];
+    let mut queries = vec![];
+    let mut frame_files = vec![];
+
+    for (index, medium) in candidates.iter().enumerate() {
+        let Ok(frame_file) = FrameFile::create(*medium, &pixel) else {
+            continue;
+        };
+
+        queries.extend_from_slice(
+            format!(
+                "\x1b_Gi={},s=1,v=1,a=q,f=24,t={};",
+                PROBE_ID_BASE + index as u32,
+                medium.key()
+            )
+            .as_bytes(),
+        );
+        queries.extend_from_slice(&frame_file.encoded_name());
+        queries.extend_from_slice(b"\x1b\\");
+        frame_files.push(frame_file);
+    }
+
+    if frame_files.is_empty() {
+        return None;
+    }
+
+    queries.extend_from_slice(b"\x1b[c");
+
+    let was_raw = is_raw_mode_enabled().unwrap_or(false);
+    if !was_raw && enable_raw_mode().is_err() {
+        return None;
+    }
+
+    let mut stdout = io::stdout();
+    let responses = stdout
+        .write_all(&queries)
+        .and_then(|_| stdout.flush())
+        .ok()
+        .map(|_| read_responses());
+
+    if !was_raw {
+        let _ = disable_raw_mode();
+    }
+
+    let acknowledged = parse_acknowledged_ids(&responses?);
+
+    candidates
+        .iter()
+        .enumerate()
+        .find(|(index, _)| acknowledged.contains(&(PROBE_ID_BASE + *index as u32)))
+        .map(|(_, medium)| *medium)
+}
+
+/* Reads what the terminal answers, until the device attributes or the timeout */
+fn read_responses() -> Vec<u8> {
+    let started_at = Instant::now();
+    let mut responses = vec![];
+    let mut stdin = io::stdin();
+
+    while !has_device_attributes(&responses) {
+        let remaining = PROBE_TIMEOUT.saturating_sub(started_at.elapsed());
+        if remaining.is_zero() {
+            break;
+        }
+
+        let mut poll_fd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, remaining.as_millis() as libc::c_int) };
+        if ready <= 0 {
+            break;
+        }
+
+        let mut chunk = [0u8; 256];
+        match stdin.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(count) => responses.extend_from_slice(&chunk[..count]),
+        }
+    }
+
+    responses
+}
+
+/* Whether the answer to the device attributes request, ESC [ ? ... c, was read */
+fn has_device_attributes(responses: &[u8]) -> bool {
+    responses
+        .windows(3)
+        .position(|window| window == b"\x1b[?")
+        .is_some_and(|start| responses[start..].contains(&b'c'))
+}
+
+/*
+ * Ids of the images whose query the terminal answered with OK,
+ * in answers such as ESC _G i=31000;OK ESC \
+ */
+fn parse_acknowledged_ids(responses: &[u8]) -> Vec<u32> {
+    let text = String::from_utf8_lossy(responses);
+
+    text.split("\x1b_G")
+        .skip(1)
+        .filter_map(|response| {
+            let response = response.split("\x1b\\").next()?;
+            let (control, message) = response.split_once(';')?;
+
+            if message != "OK" {
+                return None;
+            }
+
+            control
+                .split(',')
+                .find_map(|key_value| key_value.strip_prefix("i="))
+                .and_then(|id| id.parse().ok())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_probe_responses() {
+        let responses = b"\x1b_Gi=31000;ENOENT:no such file\x1b\\\x1b_Gi=31001;OK\x1b\\\x1b[?62;c";
+
+        assert!(has_device_attributes(responses));
+        assert!(!has_device_attributes(b"\x1b_Gi=31001;OK\x1b\\"));
+        assert_eq!(parse_acknowledged_ids(responses), vec![31001]);
+    }
+
+    #[test]
+    fn test_unwritten_frame_file_is_removed() {
+        let frame_file = FrameFile::create(TransmissionMedium::TempFile, &[1, 2, 3]).unwrap();
+        let path = frame_file.name.clone();
+
+        assert!(path.contains(TEMP_FILE_MARKER));
+        assert_eq!(
+            general_purpose::STANDARD
+                .decode(frame_file.encoded_name())
+                .unwrap(),
+            path.as_bytes()
+        );
+        assert_eq!(fs::read(&path).unwrap(), vec![1, 2, 3]);
+
+        drop(frame_file);
+        assert!(fs::metadata(&path).is_err());
+    }
+
+    #[test]
+    fn test_temp_file_is_private_and_never_replaces_a_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("yt-term-test-{}", process::id()));
+        let _ = fs::remove_file(&path);
+
+        write_temp_file(&path, &[1, 2, 3]).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let error = write_temp_file(&path, &[4, 5, 6]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), vec![1, 2, 3]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}